@@ -1,7 +1,8 @@
 use std::cmp;
 use std::collections::VecDeque;
 use std::mem;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use graph::components::{
     ethereum::{blocks_with_triggers, triggers_in_block, EthereumNetworks, NodeCapabilities},
@@ -26,6 +27,12 @@ lazy_static! {
         .unwrap_or("100".into())
         .parse::<u64>()
         .expect("invalid GRAPH_ETHEREUM_TARGET_TRIGGERS_PER_BLOCK_RANGE");
+
+    /// Keep the blocks that the block stream buffered compressed in memory
+    /// until they are processed. This saves memory for blocks with many
+    /// triggers at the cost of CPU.
+    static ref COMPRESS_BUFFERED_BLOCKS: bool =
+        std::env::var("GRAPH_ETHEREUM_COMPRESS_BUFFERED_BLOCKS").is_ok();
 }
 
 enum BlockStreamState {
@@ -43,7 +50,7 @@ enum BlockStreamState {
     /// store up to date with the chain store.
     ///
     /// Valid next states: BeginReconciliation
-    YieldingBlocks(VecDeque<EthereumBlockWithTriggers>),
+    YieldingBlocks(VecDeque<BufferedBlock>),
 
     /// The BlockStream experienced an error and is pausing before attempting to produce
     /// blocks again.
@@ -61,6 +68,57 @@ enum BlockStreamState {
     Transition,
 }
 
+/// A block that the block stream holds on to until it is yielded, possibly
+/// compressed.
+enum BufferedBlock {
+    Plain(EthereumBlockWithTriggers),
+    Compressed(Vec<u8>),
+}
+
+impl BufferedBlock {
+    fn new(logger: &Logger, block: EthereumBlockWithTriggers, compress: bool) -> Self {
+        if !compress {
+            return BufferedBlock::Plain(block);
+        }
+
+        // Blocks that can't be compressed are simply kept as they are
+        match serde_json::to_vec(&block)
+            .map_err(Error::from)
+            .and_then(|json| zstd::stream::encode_all(json.as_slice(), 0).map_err(Error::from))
+        {
+            Ok(bytes) => BufferedBlock::Compressed(bytes),
+            Err(e) => {
+                debug!(
+                    logger,
+                    "Keeping buffered block uncompressed since compressing it failed";
+                    "block_number" => block.ethereum_block.number(),
+                    "error" => format!("{:#}", e),
+                );
+                BufferedBlock::Plain(block)
+            }
+        }
+    }
+
+    fn decompress(bytes: &[u8]) -> Result<EthereumBlockWithTriggers, Error> {
+        let json = zstd::stream::decode_all(bytes)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    fn into_block(self, metrics: &BlockStreamMetrics) -> Result<EthereumBlockWithTriggers, Error> {
+        match self {
+            BufferedBlock::Plain(block) => Ok(block),
+            BufferedBlock::Compressed(bytes) => {
+                let start = Instant::now();
+                let block = Self::decompress(&bytes)?;
+                metrics
+                    .decompression_duration
+                    .observe(start.elapsed().as_secs_f64());
+                Ok(block)
+            }
+        }
+    }
+}
+
 /// A single next step to take in reconciling the state of the subgraph store with the state of the
 /// chain store.
 enum ReconciliationStep {
@@ -101,6 +159,14 @@ struct BlockStreamContext<S, C> {
     previous_block_range_size: BlockNumber,
     // Not a BlockNumber, but the difference between two block numbers
     max_block_range_size: BlockNumber,
+    buffer_depth: usize,
+    // Not a BlockNumber, but the difference between two block numbers
+    confirmations: BlockNumber,
+    pointer: BlockStreamPointer,
+    // The last final block the stream yielded since it last reverted a
+    // block. Consumers may not write the pointer of every final block to
+    // the store, so it can be ahead of the pointer in the store
+    yielded_final_ptr: Option<EthereumBlockPointer>,
 }
 
 impl<S, C> Clone for BlockStreamContext<S, C> {
@@ -122,6 +188,10 @@ impl<S, C> Clone for BlockStreamContext<S, C> {
             previous_triggers_per_block: self.previous_triggers_per_block,
             previous_block_range_size: self.previous_block_range_size,
             max_block_range_size: self.max_block_range_size,
+            buffer_depth: self.buffer_depth,
+            confirmations: self.confirmations,
+            pointer: self.pointer.clone(),
+            yielded_final_ptr: self.yielded_final_ptr.clone(),
         }
     }
 }
@@ -129,6 +199,8 @@ impl<S, C> Clone for BlockStreamContext<S, C> {
 pub struct BlockStream<S, C> {
     state: BlockStreamState,
     consecutive_err_count: u32,
+    /// The number of blocks in `BlockStreamState::YieldingBlocks`
+    buffered_blocks: Arc<AtomicUsize>,
     chain_head_update_stream: ChainHeadUpdateStream,
     ctx: BlockStreamContext<S, C>,
 }
@@ -160,12 +232,15 @@ where
         start_blocks: Vec<BlockNumber>,
         include_calls_in_blocks: bool,
         reorg_threshold: BlockNumber,
+        profile: BlockStreamProfile,
+        pointer: BlockStreamPointer,
         logger: Logger,
         metrics: Arc<BlockStreamMetrics>,
     ) -> Self {
         BlockStream {
             state: BlockStreamState::BeginReconciliation,
             consecutive_err_count: 0,
+            buffered_blocks: Arc::new(AtomicUsize::new(0)),
             chain_head_update_stream: chain_store.chain_head_updates(),
             ctx: BlockStreamContext {
                 subgraph_store,
@@ -185,7 +260,14 @@ where
                 // A high number here forces a slow start, with a range of 1.
                 previous_triggers_per_block: 1_000_000.0,
                 previous_block_range_size: 1,
-                max_block_range_size: *MAX_BLOCK_RANGE_SIZE,
+                max_block_range_size: profile
+                    .max_block_range_size
+                    .min(*MAX_BLOCK_RANGE_SIZE)
+                    .max(1),
+                buffer_depth: profile.buffer_depth.max(1),
+                confirmations: profile.confirmations.max(0),
+                pointer,
+                yielded_final_ptr: None,
             },
         }
     }
@@ -233,6 +315,7 @@ where
         let block_filter = self.block_filter.clone();
         let start_blocks = self.start_blocks.clone();
         let max_block_range_size = self.max_block_range_size;
+        let buffer_depth = self.buffer_depth;
 
         // Get pointers from database for comparison
         let head_ptr_opt = match ctx.chain_store.chain_head_ptr() {
@@ -241,7 +324,7 @@ where
                 return Box::new(future::err(e)) as Box<dyn Future<Item = _, Error = _> + Send>
             }
         };
-        let subgraph_ptr = match ctx.subgraph_store.block_ptr(&ctx.subgraph_id) {
+        let subgraph_ptr = match ctx.subgraph_ptr() {
             Ok(ptr) => ptr,
             Err(e) => {
                 return Box::new(future::err(e)) as Box<dyn Future<Item = _, Error = _> + Send>
@@ -273,10 +356,11 @@ where
         // Make sure not to include genesis in the reorg threshold.
         let reorg_threshold = ctx.reorg_threshold.min(head_ptr.number);
 
-        // Only continue if the subgraph block ptr is behind the head block ptr.
+        // Only continue if the subgraph block ptr is behind the head block ptr by more than
+        // the number of confirmations we require.
         // subgraph_ptr > head_ptr shouldn't happen, but if it does, it's safest to just stop.
         if let Some(ptr) = &subgraph_ptr {
-            if ptr.number >= head_ptr.number {
+            if ptr.number + ctx.confirmations >= head_ptr.number {
                 return Box::new(future::ok(ReconciliationStep::Done))
                     as Box<dyn Future<Item = _, Error = _> + Send>;
             }
@@ -435,8 +519,22 @@ where
                                     call_filter.clone(),
                                     block_filter.clone(),
                                 )
-                                .map_ok(move |blocks| {
+                                .map_ok(move |mut blocks| {
                                     section.end();
+
+                                    // Only hold on to as many blocks as the buffer allows. The
+                                    // blocks we drop will be scanned again once the ones we keep
+                                    // have been processed, and the range size is shrunk to what
+                                    // we actually kept so the range size dynamics stay accurate.
+                                    let range_size = if blocks.len() > buffer_depth {
+                                        blocks.truncate(buffer_depth);
+                                        blocks
+                                            .last()
+                                            .map(|block| block.ethereum_block.number() - from + 1)
+                                            .unwrap_or(range_size)
+                                    } else {
+                                        range_size
+                                    };
                                     ReconciliationStep::ProcessDescendantBlocks(blocks, range_size)
                                 })
                                 .boxed()
@@ -567,13 +665,43 @@ where
         }
     }
 
+    fn subgraph_ptr(&self) -> Result<Option<EthereumBlockPointer>, Error> {
+        match &self.pointer {
+            BlockStreamPointer::Store => {
+                let ptr = self.subgraph_store.block_ptr(&self.subgraph_id)?;
+                match (&ptr, &self.yielded_final_ptr) {
+                    (Some(ptr), Some(yielded)) if yielded.number <= ptr.number => {
+                        Ok(Some(ptr.clone()))
+                    }
+                    (_, Some(yielded)) => Ok(Some(yielded.clone())),
+                    (_, None) => Ok(ptr),
+                }
+            }
+            BlockStreamPointer::Detached(ptr) => Ok(ptr.clone()),
+        }
+    }
+
     /// Set subgraph deployment entity synced flag if and only if the subgraph block pointer is
     /// caught up to the head block pointer.
     fn update_subgraph_synced_status(&self) -> Result<(), Error> {
+        // A detached stream does not touch the deployment
+        if let BlockStreamPointer::Detached(_) = self.pointer {
+            return Ok(());
+        }
+
         let head_ptr_opt = self.chain_store.chain_head_ptr()?;
         let subgraph_ptr = self.subgraph_store.block_ptr(&self.subgraph_id)?;
 
-        if head_ptr_opt != subgraph_ptr || head_ptr_opt.is_none() || subgraph_ptr.is_none() {
+        let synced = match (head_ptr_opt, subgraph_ptr) {
+            (Some(head_ptr), Some(subgraph_ptr)) => {
+                head_ptr == subgraph_ptr
+                    || (subgraph_ptr.number < head_ptr.number
+                        && subgraph_ptr.number + self.confirmations >= head_ptr.number)
+            }
+            _ => false,
+        };
+
+        if !synced {
             // Not synced yet
             Ok(())
         } else {
@@ -587,7 +715,11 @@ where
     }
 }
 
-impl<S: SubgraphStore, C: ChainStore> BlockStreamTrait for BlockStream<S, C> {}
+impl<S: SubgraphStore, C: ChainStore> BlockStreamTrait for BlockStream<S, C> {
+    fn buffered_blocks(&self) -> Arc<AtomicUsize> {
+        self.buffered_blocks.cheap_clone()
+    }
+}
 
 impl<S: SubgraphStore, C: ChainStore> Stream for BlockStream<S, C> {
     type Item = BlockStreamEvent;
@@ -628,7 +760,20 @@ impl<S: SubgraphStore, C: ChainStore> Stream for BlockStream<S, C> {
                                 debug!(self.ctx.logger, "Processing {} triggers", total_triggers);
                             }
 
-                            // Switch to yielding state until next_blocks is depleted
+                            // Switch to yielding state until next_blocks is depleted. The
+                            // first block is yielded right away, so there's no point in
+                            // compressing it
+                            let next_blocks = next_blocks
+                                .into_iter()
+                                .enumerate()
+                                .map(|(i, block)| {
+                                    BufferedBlock::new(
+                                        &self.ctx.logger,
+                                        block,
+                                        i > 0 && *COMPRESS_BUFFERED_BLOCKS,
+                                    )
+                                })
+                                .collect();
                             state = BlockStreamState::YieldingBlocks(next_blocks);
 
                             // Yield the first block in next_blocks
@@ -648,6 +793,7 @@ impl<S: SubgraphStore, C: ChainStore> Stream for BlockStream<S, C> {
                         }
 
                         Ok(Async::Ready(NextBlocks::Revert(block))) => {
+                            self.ctx.yielded_final_ptr = None;
                             state = BlockStreamState::BeginReconciliation;
                             break Ok(Async::Ready(Some(BlockStreamEvent::Revert(block))));
                         }
@@ -682,6 +828,23 @@ impl<S: SubgraphStore, C: ChainStore> Stream for BlockStream<S, C> {
                     match next_blocks.pop_front() {
                         // Yield one block
                         Some(next_block) => {
+                            let next_block = match next_block.into_block(&self.ctx.metrics) {
+                                Ok(next_block) => next_block,
+                                Err(e) => {
+                                    // Drop the remaining blocks, they'll be fetched again
+                                    state = BlockStreamState::BeginReconciliation;
+                                    break Err(e.context("failed to decompress buffered block"));
+                                }
+                            };
+                            let next_ptr = EthereumBlockPointer::from(&next_block.ethereum_block);
+                            match &mut self.ctx.pointer {
+                                BlockStreamPointer::Store => {
+                                    if let BlockFinality::Final(_) = next_block.ethereum_block {
+                                        self.ctx.yielded_final_ptr = Some(next_ptr);
+                                    }
+                                }
+                                BlockStreamPointer::Detached(ptr) => *ptr = Some(next_ptr),
+                            }
                             state = BlockStreamState::YieldingBlocks(next_blocks);
                             break Ok(Async::Ready(Some(BlockStreamEvent::Block(next_block))));
                         }
@@ -741,6 +904,13 @@ impl<S: SubgraphStore, C: ChainStore> Stream for BlockStream<S, C> {
             }
         };
 
+        self.buffered_blocks.store(
+            match &state {
+                BlockStreamState::YieldingBlocks(blocks) => blocks.len(),
+                _ => 0,
+            },
+            Ordering::SeqCst,
+        );
         self.state = state;
 
         result
@@ -808,13 +978,16 @@ where
         logger: Logger,
         deployment_id: SubgraphDeploymentId,
         network_name: String,
+        eth_adapter: Option<Arc<dyn EthereumAdapter>>,
         start_blocks: Vec<BlockNumber>,
         log_filter: EthereumLogFilter,
         call_filter: EthereumCallFilter,
         block_filter: EthereumBlockFilter,
         include_calls_in_blocks: bool,
+        profile: BlockStreamProfile,
+        pointer: BlockStreamPointer,
         metrics: Arc<BlockStreamMetrics>,
-    ) -> Self::Stream {
+    ) -> Result<Self::Stream, Error> {
         let logger = logger.new(o!(
             "component" => "BlockStream",
         ));
@@ -822,10 +995,7 @@ where
         let chain_store = self
             .block_store
             .chain_store(&network_name)
-            .expect(&format!(
-                "no store that supports network: {}",
-                &network_name
-            ))
+            .ok_or_else(|| anyhow!("no store that supports network: {}", &network_name))?
             .clone();
 
         let requirements = NodeCapabilities {
@@ -833,19 +1003,27 @@ where
             traces: include_calls_in_blocks,
         };
 
-        let eth_adapter = self
-            .eth_networks
-            .adapter_with_capabilities(network_name.clone(), &requirements)
-            .expect(&format!(
-                "no eth adapter that supports network: {} with {}",
-                &network_name, &requirements
-            ));
+        let eth_adapter = match eth_adapter {
+            Some(eth_adapter) => eth_adapter,
+            None => self
+                .eth_networks
+                .adapter_with_capabilities(network_name.clone(), &requirements)
+                .map_err(|e| {
+                    anyhow!(
+                        "no eth adapter that supports network: {} with {}: {}",
+                        &network_name,
+                        &requirements,
+                        e
+                    )
+                })?
+                .clone(),
+        };
 
         // Create the actual subgraph-specific block stream
-        BlockStream::new(
+        Ok(BlockStream::new(
             self.subgraph_store.clone(),
             chain_store,
-            eth_adapter.clone(),
+            eth_adapter,
             self.node_id.clone(),
             deployment_id,
             log_filter,
@@ -854,9 +1032,11 @@ where
             start_blocks,
             include_calls_in_blocks,
             self.reorg_threshold,
+            profile,
+            pointer,
             logger,
             metrics,
-        )
+        ))
     }
 }
 
@@ -883,3 +1063,38 @@ fn test_reorg(ptr: EthereumBlockPointer) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::types::H256;
+
+    #[test]
+    fn buffered_block_round_trip() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut light_block = LightEthereumBlock::default();
+        light_block.number = Some(7.into());
+        let block = EthereumBlockWithTriggers::new(
+            vec![EthereumTrigger::Block(
+                EthereumBlockPointer::from((H256::from_low_u64_be(7), 7u64)),
+                EthereumBlockTriggerType::Every,
+            )],
+            BlockFinality::Final(light_block),
+        );
+
+        let bytes = match BufferedBlock::new(&logger, block.clone(), true) {
+            BufferedBlock::Compressed(bytes) => bytes,
+            BufferedBlock::Plain(_) => panic!("the block was not compressed"),
+        };
+        let decompressed = BufferedBlock::decompress(&bytes).unwrap();
+        assert_eq!(
+            serde_json::to_value(&block).unwrap(),
+            serde_json::to_value(&decompressed).unwrap()
+        );
+
+        match BufferedBlock::new(&logger, block, false) {
+            BufferedBlock::Plain(_) => (),
+            BufferedBlock::Compressed(_) => panic!("the block was compressed"),
+        }
+    }
+}
@@ -28,6 +28,7 @@ fn create_subgraph(
         data_sources: vec![],
         graft: None,
         templates: vec![],
+        block_predicate: None,
     };
 
     let deployment = SubgraphDeploymentEntity::new(&manifest, false, start_block);
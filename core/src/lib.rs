@@ -5,4 +5,16 @@ pub mod three_box;
 
 pub use crate::link_resolver::LinkResolver;
 pub use crate::metrics::MetricsRegistry;
-pub use crate::subgraph::{SubgraphAssignmentProvider, SubgraphInstanceManager, SubgraphRegistrar};
+pub use crate::subgraph::{
+    AggregateThroughput, BackfillProgress, BlockCommittedHook, CallFilterDump,
+    CircuitBreakerConfig, CommittedBlock, DataSourceDump, DataSourceOrder, DeploymentDifference,
+    DeploymentHealth, Divergence, DuplicateDataSourcePolicy, EntityCacheCompaction,
+    EntityCacheWarmup, EntityDivergence, EntityModificationHook, FiltersDump, IndexingContextDump,
+    InjectTriggerError, LogFilterDump, MetricsPush, ModificationsPublisher, PoiBundle,
+    PoiBundleEntry, PoiBundleSigner, ReadThroughStore, ReindexError, ReorgNotifier,
+    RetryFailedError, RevertBlockFallback, SubgraphAssignmentProvider, SubgraphInstanceManager,
+    SubgraphInstanceManagerConfig, SubgraphRegistrar, TriggerCounts, TriggerFilterHook,
+    TriggerInjectionOutcome, Verification, WarmupKeySource,
+};
+#[cfg(feature = "kafka")]
+pub use crate::subgraph::{KafkaModificationsSink, KafkaOffset, KafkaTopics};
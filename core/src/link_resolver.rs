@@ -41,6 +41,20 @@ lazy_static! {
     );
 }
 
+/// Returns a function that is to be called before each attempt of a request
+/// and that increments `counter` for every attempt after the first one.
+fn retry_counter(counter: Option<Counter>) -> impl FnMut() + Send + 'static {
+    let mut attempts = 0;
+    move || {
+        attempts += 1;
+        if attempts > 1 {
+            if let Some(counter) = &counter {
+                counter.inc();
+            }
+        }
+    }
+}
+
 fn read_u64_from_env(name: &str) -> Option<u64> {
     env::var(name).ok().map(|s| {
         u64::from_str(&s).unwrap_or_else(|_| {
@@ -70,6 +84,7 @@ async fn select_fastest_client_with_stat(
     path: String,
     timeout: Duration,
     do_retry: bool,
+    retries: Option<Counter>,
 ) -> Result<(ObjectStatResponse, Arc<IpfsClient>), Error> {
     let mut err: Option<Error> = None;
 
@@ -86,8 +101,10 @@ async fn select_fastest_client_with_stat(
             .timeout(timeout);
 
             let path = path.clone();
+            let mut count_retry = retry_counter(retries.clone());
             retry_fut
                 .run(move || {
+                    count_retry();
                     let path = path.clone();
                     let c = c.cheap_clone();
                     async move { c.object_stat(path).map_ok(move |s| (s, i)).await }
@@ -140,6 +157,7 @@ pub struct LinkResolver {
     cache: Arc<Mutex<LruCache<String, Vec<u8>>>>,
     timeout: Duration,
     retry: bool,
+    retries: Option<Counter>,
 }
 
 impl CheapClone for LinkResolver {
@@ -149,6 +167,7 @@ impl CheapClone for LinkResolver {
             cache: self.cache.cheap_clone(),
             timeout: self.timeout,
             retry: self.retry,
+            retries: self.retries.clone(),
         }
     }
 }
@@ -168,6 +187,7 @@ impl From<Vec<IpfsClient>> for LinkResolver {
             ))),
             timeout: *IPFS_TIMEOUT,
             retry: false,
+            retries: None,
         }
     }
 }
@@ -184,6 +204,11 @@ impl LinkResolverTrait for LinkResolver {
         self
     }
 
+    fn with_retry_counter(mut self, counter: Counter) -> Self {
+        self.retries = Some(counter);
+        self
+    }
+
     /// Supports links of the form `/ipfs/ipfs_hash` or just `ipfs_hash`.
     async fn cat(&self, logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
         // Discard the `/ipfs/` prefix (if present) to get the hash.
@@ -201,6 +226,7 @@ impl LinkResolverTrait for LinkResolver {
             path.clone(),
             self.timeout,
             self.retry,
+            self.retries.clone(),
         )
         .await?;
 
@@ -219,8 +245,10 @@ impl LinkResolverTrait for LinkResolver {
 
         let this = self.clone();
         let logger = logger.clone();
+        let mut count_retry = retry_counter(self.retries.clone());
         let data = retry_fut
             .run(move || {
+                count_retry();
                 let path = path.clone();
                 let client = client.clone();
                 let this = this.clone();
@@ -265,6 +293,7 @@ impl LinkResolverTrait for LinkResolver {
             path.to_string(),
             self.timeout,
             self.retry,
+            self.retries.clone(),
         )
         .await?;
 
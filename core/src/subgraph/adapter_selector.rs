@@ -0,0 +1,525 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use graph::components::ethereum::{EthereumNetworks, NodeCapabilities};
+use graph::prelude::*;
+
+use super::SubgraphInstanceManagerConfig;
+
+#[derive(Default)]
+struct EthAdapterSelectorState {
+    /// The index of the alternate adapter in use, or `None` if the primary
+    /// adapter is in use.
+    current: Option<usize>,
+
+    /// The number of requests in a row that failed, by provider.
+    errors: HashMap<String, u32>,
+
+    /// When it was last checked whether the primary adapter works again.
+    recovery_checked_at: Option<Instant>,
+}
+
+/// Selects the Ethereum adapter a deployment uses for its block stream, the
+/// calls from its mappings and its own requests, for example to load the
+/// blocks it reverts. The deployment starts out with
+/// the primary adapter and switches to an alternate adapter for the network
+/// with the same capabilities once the adapter in use failed too often in a
+/// row. While an alternate is in use, the primary adapter is checked
+/// periodically, and the deployment switches back once it works again.
+pub(crate) struct EthAdapterSelector {
+    primary: Arc<dyn EthereumAdapter>,
+    alternates: Vec<Arc<dyn EthereumAdapter>>,
+    failover_errors: u32,
+    recovery_check_interval: Duration,
+    state: Mutex<EthAdapterSelectorState>,
+}
+
+impl EthAdapterSelector {
+    pub(crate) fn new(
+        primary: Arc<dyn EthereumAdapter>,
+        eth_networks: &EthereumNetworks,
+        network: &str,
+        required: &NodeCapabilities,
+        config: &SubgraphInstanceManagerConfig,
+    ) -> Self {
+        let alternates = match config.eth_adapter_failover_errors {
+            0 => vec![],
+            _ => eth_networks
+                .networks
+                .get(network)
+                .map(|adapters| {
+                    adapters
+                        .all_with(required)
+                        .into_iter()
+                        .filter(|adapter| adapter.provider() != primary.provider())
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+
+        EthAdapterSelector {
+            primary,
+            alternates,
+            failover_errors: config.eth_adapter_failover_errors,
+            recovery_check_interval: config.eth_adapter_recovery_check_interval,
+            state: Mutex::new(EthAdapterSelectorState::default()),
+        }
+    }
+
+    fn adapter(&self, index: Option<usize>) -> &Arc<dyn EthereumAdapter> {
+        match index {
+            Some(index) => &self.alternates[index],
+            None => &self.primary,
+        }
+    }
+
+    /// The adapter to use for the next request.
+    pub(crate) fn current(&self) -> Arc<dyn EthereumAdapter> {
+        self.adapter(self.state.lock().unwrap().current)
+            .cheap_clone()
+    }
+
+    /// Record whether a request to `adapter` succeeded, and switch adapters
+    /// if the one in use failed too often or the primary adapter works again.
+    pub(crate) fn record(&self, logger: &Logger, adapter: &dyn EthereumAdapter, success: bool) {
+        if self.alternates.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let provider = adapter.provider();
+
+        if success {
+            state.errors.remove(provider);
+            if provider == self.primary.provider() {
+                if let Some(alternate) = state.current.take() {
+                    info!(
+                        logger,
+                        "Switching back to the primary Ethereum adapter";
+                        "provider" => provider,
+                        "alternate" => self.alternates[alternate].provider(),
+                    );
+                    state.recovery_checked_at = None;
+                }
+            }
+            return;
+        }
+
+        let errors = state.errors.entry(provider.to_string()).or_insert(0);
+        *errors = errors.saturating_add(1);
+        let errors = *errors;
+        if provider != self.adapter(state.current).provider() || errors < self.failover_errors {
+            return;
+        }
+
+        let failover_errors = self.failover_errors;
+        let next = self
+            .alternates
+            .iter()
+            .enumerate()
+            .find(|(index, alternate)| {
+                Some(*index) != state.current
+                    && state
+                        .errors
+                        .get(alternate.provider())
+                        .map_or(true, |errors| *errors < failover_errors)
+            })
+            .map(|(index, _)| index);
+        match next {
+            Some(next) => {
+                warn!(
+                    logger,
+                    "Switching to another Ethereum adapter after repeated errors";
+                    "provider" => provider,
+                    "errors" => errors,
+                    "alternate" => self.alternates[next].provider(),
+                );
+                state.current = Some(next);
+                state.recovery_checked_at = Some(Instant::now());
+            }
+            None => debug!(
+                logger,
+                "No other Ethereum adapter for the network is healthy";
+                "provider" => provider,
+                "errors" => errors,
+            ),
+        }
+    }
+
+    /// While an alternate adapter is in use, check whether the primary
+    /// adapter works again, at most once per recovery check interval.
+    pub(crate) async fn check_primary(&self, logger: &Logger) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            if state.current.is_none()
+                || state.recovery_checked_at.map_or(false, |checked_at| {
+                    now.duration_since(checked_at) < self.recovery_check_interval
+                })
+            {
+                return;
+            }
+            state.recovery_checked_at = Some(now);
+        }
+
+        let res = self.primary.net_identifiers().await;
+        if let Err(e) = &res {
+            debug!(
+                logger,
+                "Primary Ethereum adapter still fails";
+                "provider" => self.primary.provider(),
+                "error" => format!("{:#}", e),
+            );
+        }
+        self.record(logger, &*self.primary, res.is_ok());
+    }
+}
+
+/// An Ethereum adapter that sends each request to the adapter that the
+/// deployment's `EthAdapterSelector` currently selects. The runtime hosts
+/// use it so that calls from mappings follow the deployment when it
+/// switches adapters; the outcome of calls is recorded with the selector.
+pub(crate) struct SelectedEthAdapter {
+    pub(crate) logger: Logger,
+    pub(crate) selector: Arc<EthAdapterSelector>,
+}
+
+#[async_trait]
+impl EthereumAdapter for SelectedEthAdapter {
+    /// The hostname of the primary adapter; it does not change when the
+    /// deployment switches adapters.
+    fn url_hostname(&self) -> &str {
+        self.selector.primary.url_hostname()
+    }
+
+    /// The provider of the primary adapter; it does not change when the
+    /// deployment switches adapters.
+    fn provider(&self) -> &str {
+        self.selector.primary.provider()
+    }
+
+    async fn net_identifiers(&self) -> Result<EthereumNetworkIdentifier, Error> {
+        self.selector.current().net_identifiers().await
+    }
+
+    fn latest_block(
+        &self,
+        logger: &Logger,
+    ) -> Box<dyn Future<Item = LightEthereumBlock, Error = EthereumAdapterError> + Send + Unpin>
+    {
+        self.selector.current().latest_block(logger)
+    }
+
+    fn latest_block_header(
+        &self,
+        logger: &Logger,
+    ) -> Box<
+        dyn Future<Item = web3::types::Block<web3::types::H256>, Error = EthereumAdapterError>
+            + Send,
+    > {
+        self.selector.current().latest_block_header(logger)
+    }
+
+    fn load_block(
+        &self,
+        logger: &Logger,
+        block_hash: web3::types::H256,
+    ) -> Box<dyn Future<Item = LightEthereumBlock, Error = Error> + Send> {
+        self.selector.current().load_block(logger, block_hash)
+    }
+
+    fn load_blocks(
+        &self,
+        logger: Logger,
+        chain_store: Arc<dyn ChainStore>,
+        block_hashes: HashSet<web3::types::H256>,
+    ) -> Box<dyn Stream<Item = LightEthereumBlock, Error = Error> + Send> {
+        self.selector
+            .current()
+            .load_blocks(logger, chain_store, block_hashes)
+    }
+
+    fn block_range_to_ptrs(
+        &self,
+        logger: Logger,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Box<dyn Future<Item = Vec<EthereumBlockPointer>, Error = Error> + Send> {
+        self.selector
+            .current()
+            .block_range_to_ptrs(logger, from, to)
+    }
+
+    fn block_by_hash(
+        &self,
+        logger: &Logger,
+        block_hash: web3::types::H256,
+    ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send> {
+        self.selector.current().block_by_hash(logger, block_hash)
+    }
+
+    fn block_by_number(
+        &self,
+        logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send> {
+        self.selector
+            .current()
+            .block_by_number(logger, block_number)
+    }
+
+    fn load_full_block(
+        &self,
+        logger: &Logger,
+        block: LightEthereumBlock,
+    ) -> Box<dyn Future<Item = EthereumBlock, Error = EthereumAdapterError> + Send> {
+        self.selector.current().load_full_block(logger, block)
+    }
+
+    fn block_pointer_from_number(
+        &self,
+        logger: &Logger,
+        chain_store: Arc<dyn ChainStore>,
+        block_number: BlockNumber,
+    ) -> Box<dyn Future<Item = EthereumBlockPointer, Error = EthereumAdapterError> + Send> {
+        self.selector
+            .current()
+            .block_pointer_from_number(logger, chain_store, block_number)
+    }
+
+    fn block_hash_by_block_number(
+        &self,
+        logger: &Logger,
+        chain_store: Arc<dyn ChainStore>,
+        block_number: BlockNumber,
+        block_is_final: bool,
+    ) -> Box<dyn Future<Item = Option<web3::types::H256>, Error = Error> + Send> {
+        self.selector.current().block_hash_by_block_number(
+            logger,
+            chain_store,
+            block_number,
+            block_is_final,
+        )
+    }
+
+    fn uncles(
+        &self,
+        logger: &Logger,
+        block: &LightEthereumBlock,
+    ) -> Box<
+        dyn Future<Item = Vec<Option<web3::types::Block<web3::types::H256>>>, Error = Error> + Send,
+    > {
+        self.selector.current().uncles(logger, block)
+    }
+
+    fn is_on_main_chain(
+        &self,
+        logger: &Logger,
+        metrics: Arc<SubgraphEthRpcMetrics>,
+        chain_store: Arc<dyn ChainStore>,
+        block_ptr: EthereumBlockPointer,
+    ) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+        self.selector
+            .current()
+            .is_on_main_chain(logger, metrics, chain_store, block_ptr)
+    }
+
+    fn calls_in_block(
+        &self,
+        logger: &Logger,
+        subgraph_metrics: Arc<SubgraphEthRpcMetrics>,
+        block_number: BlockNumber,
+        block_hash: web3::types::H256,
+    ) -> Box<dyn Future<Item = Vec<EthereumCall>, Error = Error> + Send> {
+        self.selector
+            .current()
+            .calls_in_block(logger, subgraph_metrics, block_number, block_hash)
+    }
+
+    fn logs_in_block_range(
+        &self,
+        logger: &Logger,
+        subgraph_metrics: Arc<SubgraphEthRpcMetrics>,
+        from: BlockNumber,
+        to: BlockNumber,
+        log_filter: EthereumLogFilter,
+    ) -> DynTryFuture<'static, Vec<web3::types::Log>, Error> {
+        self.selector
+            .current()
+            .logs_in_block_range(logger, subgraph_metrics, from, to, log_filter)
+    }
+
+    fn calls_in_block_range(
+        &self,
+        logger: &Logger,
+        subgraph_metrics: Arc<SubgraphEthRpcMetrics>,
+        from: BlockNumber,
+        to: BlockNumber,
+        call_filter: EthereumCallFilter,
+    ) -> Box<dyn Stream<Item = EthereumCall, Error = Error> + Send> {
+        self.selector.current().calls_in_block_range(
+            logger,
+            subgraph_metrics,
+            from,
+            to,
+            call_filter,
+        )
+    }
+
+    fn contract_call(
+        &self,
+        logger: &Logger,
+        call: EthereumContractCall,
+        cache: Arc<dyn EthereumCallCache>,
+    ) -> Box<dyn Future<Item = Vec<ethabi::Token>, Error = EthereumContractCallError> + Send> {
+        let adapter = self.selector.current();
+        let selector = self.selector.cheap_clone();
+        let record_logger = self.logger.clone();
+        Box::new(adapter.contract_call(logger, call, cache).then(move |res| {
+            // Reverts and encoding errors are the mapping's fault,
+            // not the adapter's
+            match &res {
+                Ok(_) => selector.record(&record_logger, &*adapter, true),
+                Err(EthereumContractCallError::Web3Error(_))
+                | Err(EthereumContractCallError::Timeout) => {
+                    selector.record(&record_logger, &*adapter, false)
+                }
+                Err(_) => {}
+            }
+            res
+        }))
+    }
+}
+
+/// Pick an Ethereum adapter for `network` that has the `required`
+/// capabilities. Not having a provider for the network at all and having
+/// providers that lack capabilities call for different fixes, and are
+/// therefore reported differently.
+pub(crate) fn select_eth_adapter(
+    eth_networks: &EthereumNetworks,
+    network: &str,
+    required: &NodeCapabilities,
+) -> Result<Arc<dyn EthereumAdapter>, Error> {
+    let adapters = match eth_networks.networks.get(network) {
+        Some(adapters) if !adapters.adapters.is_empty() => adapters,
+        _ => {
+            return Err(anyhow!(
+                "no Ethereum provider is configured for network `{}` that the subgraph uses; \
+                 add a provider for the network to the configuration of the node",
+                network
+            ))
+        }
+    };
+
+    adapters
+        .cheapest_with(required)
+        .map(|adapter| adapter.cheap_clone())
+        .map_err(|_| {
+            let available = adapters
+                .adapters
+                .iter()
+                .map(|adapter| match adapter.capabilities.to_string() {
+                    capabilities if capabilities.is_empty() => "none".to_string(),
+                    capabilities => capabilities,
+                })
+                .collect::<Vec<_>>()
+                .join("; ");
+            anyhow!(
+                "no Ethereum provider for network `{}` has the capabilities `{}` that the \
+                 subgraph requires, the providers have the capabilities: {}; use a provider \
+                 that supports them",
+                network,
+                required,
+                available
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::mock::MockEthereumAdapter;
+
+    fn adapter(provider: &str) -> Arc<dyn EthereumAdapter> {
+        let mut adapter = MockEthereumAdapter::new();
+        adapter.expect_provider().return_const(provider.to_owned());
+        Arc::new(adapter)
+    }
+
+    fn selector(alternates: &[&str]) -> EthAdapterSelector {
+        EthAdapterSelector {
+            primary: adapter("primary"),
+            alternates: alternates
+                .iter()
+                .map(|provider| adapter(provider))
+                .collect(),
+            failover_errors: 2,
+            recovery_check_interval: Duration::from_secs(60),
+            state: Mutex::new(EthAdapterSelectorState::default()),
+        }
+    }
+
+    #[test]
+    fn eth_adapter_selector_switches_over_and_back() {
+        let logger = Logger::root(slog::Discard, o!());
+        let selector = selector(&["alt1", "alt2"]);
+        let primary = selector.primary.cheap_clone();
+        let alt1 = selector.alternates[0].cheap_clone();
+
+        // A single error is not enough to switch
+        selector.record(&logger, &*primary, false);
+        assert_eq!("primary", selector.current().provider());
+
+        // Errors are counted in a row; a success resets them
+        selector.record(&logger, &*primary, true);
+        selector.record(&logger, &*primary, false);
+        assert_eq!("primary", selector.current().provider());
+
+        selector.record(&logger, &*primary, false);
+        assert_eq!("alt1", selector.current().provider());
+
+        // Errors of an adapter that is not in use don't cause a switch
+        selector.record(&logger, &*primary, false);
+        assert_eq!("alt1", selector.current().provider());
+
+        // An alternate that fails too often is replaced by the next one
+        selector.record(&logger, &*alt1, false);
+        selector.record(&logger, &*alt1, false);
+        assert_eq!("alt2", selector.current().provider());
+
+        // Once the primary adapter works again, the deployment switches back
+        selector.record(&logger, &*primary, true);
+        assert_eq!("primary", selector.current().provider());
+    }
+
+    #[test]
+    fn eth_adapter_selector_stays_without_healthy_alternate() {
+        let logger = Logger::root(slog::Discard, o!());
+        let selector = selector(&["alt1"]);
+        let primary = selector.primary.cheap_clone();
+        let alt1 = selector.alternates[0].cheap_clone();
+
+        selector.record(&logger, &*primary, false);
+        selector.record(&logger, &*primary, false);
+        assert_eq!("alt1", selector.current().provider());
+
+        // The primary adapter failed too often as well, so the deployment
+        // keeps using the alternate
+        selector.record(&logger, &*alt1, false);
+        selector.record(&logger, &*alt1, false);
+        assert_eq!("alt1", selector.current().provider());
+    }
+
+    #[test]
+    fn eth_adapter_selector_without_alternates_keeps_primary() {
+        let logger = Logger::root(slog::Discard, o!());
+        let selector = selector(&[]);
+        let primary = selector.primary.cheap_clone();
+        for _ in 0..5 {
+            selector.record(&logger, &*primary, false);
+        }
+        assert_eq!("primary", selector.current().provider());
+    }
+}
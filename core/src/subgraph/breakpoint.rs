@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+use graph::prelude::*;
+
+use super::instance_manager::BlockProcessingError;
+
+/// A block at which a deployment pauses right before processing it, so that
+/// its state can be inspected while the instance stays alive. Since the
+/// block stream skips blocks without triggers, the deployment pauses at the
+/// first block at or past the breakpoint. Breakpoints fire once; resuming
+/// continues processing past the breakpoint.
+#[derive(Default)]
+pub(crate) struct Breakpoint {
+    pub(crate) block: Mutex<Option<BlockNumber>>,
+    /// Pause at the next block that is processed, independent of `block`
+    pause_next: AtomicBool,
+    paused: AtomicBool,
+    resume: Notify,
+}
+
+impl Breakpoint {
+    pub(crate) fn set(&self, block: Option<BlockNumber>) {
+        *self.block.lock().unwrap() = block;
+    }
+
+    /// Pause before processing the next block. A breakpoint that is set
+    /// stays in place and still fires later.
+    pub(crate) fn pause_next(&self) {
+        self.pause_next.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Resume processing if the deployment is paused. Returns `true` if it
+    /// was paused.
+    pub(crate) fn resume(&self) -> bool {
+        if self.is_paused() {
+            self.resume.notify();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Wait until resumed if `block` is at or past the breakpoint, and clear
+    /// the breakpoint, or if pausing at the next block was requested.
+    /// Canceling the block stream also ends the wait.
+    pub(crate) async fn wait(
+        &self,
+        logger: &Logger,
+        block: BlockNumber,
+        cancel_handle: &CancelHandle,
+    ) -> Result<(), BlockProcessingError> {
+        let pause_next = self.pause_next.swap(false, Ordering::SeqCst);
+        {
+            let mut breakpoint = self.block.lock().unwrap();
+            if breakpoint.map_or(false, |breakpoint| block >= breakpoint) {
+                *breakpoint = None;
+            } else if !pause_next {
+                return Ok(());
+            }
+        }
+
+        info!(logger, "Pausing at breakpoint until resumed");
+        self.paused.store(true, Ordering::SeqCst);
+        let res = loop {
+            if cancel_handle.is_canceled() {
+                break Err(BlockProcessingError::Canceled);
+            }
+            let resumed =
+                tokio::time::timeout(Duration::from_secs(1), self.resume.notified()).await;
+            if resumed.is_ok() {
+                info!(logger, "Resuming from breakpoint");
+                break Ok(());
+            }
+        };
+        self.paused.store(false, Ordering::SeqCst);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn breakpoint_pauses_at_first_block_past_it_once() {
+        let logger = Logger::root(slog::Discard, o!());
+        let breakpoint = Breakpoint::default();
+        breakpoint.set(Some(10));
+
+        // Cancel right away so that waiting at the breakpoint returns
+        let guard = CancelGuard::new();
+        let cancel_handle = guard.handle();
+        guard.cancel();
+
+        assert!(breakpoint.wait(&logger, 9, &cancel_handle).await.is_ok());
+        assert_eq!(Some(10), *breakpoint.block.lock().unwrap());
+
+        // Block 10 was skipped by the block stream
+        assert!(matches!(
+            breakpoint.wait(&logger, 12, &cancel_handle).await,
+            Err(BlockProcessingError::Canceled)
+        ));
+        assert_eq!(None, *breakpoint.block.lock().unwrap());
+        assert!(!breakpoint.is_paused());
+
+        assert!(breakpoint.wait(&logger, 13, &cancel_handle).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn breakpoint_pause_next_keeps_breakpoint() {
+        let logger = Logger::root(slog::Discard, o!());
+        let breakpoint = Breakpoint::default();
+        breakpoint.set(Some(10));
+        breakpoint.pause_next();
+
+        let guard = CancelGuard::new();
+        let cancel_handle = guard.handle();
+        guard.cancel();
+
+        assert!(matches!(
+            breakpoint.wait(&logger, 5, &cancel_handle).await,
+            Err(BlockProcessingError::Canceled)
+        ));
+        assert_eq!(Some(10), *breakpoint.block.lock().unwrap());
+        assert!(breakpoint.wait(&logger, 6, &cancel_handle).await.is_ok());
+        assert!(breakpoint.wait(&logger, 10, &cancel_handle).await.is_err());
+    }
+}
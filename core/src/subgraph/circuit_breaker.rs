@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Settings for delaying the restart of deployments that keep failing
+/// non-deterministically, for example because an Ethereum node or the
+/// database is down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// The number of failures within `window` after which restarts are
+    /// delayed; 0 disables the circuit breaker.
+    pub failures: usize,
+
+    /// The period in which failures are counted.
+    pub window: Duration,
+
+    /// The delay of the first delayed restart. It doubles with every
+    /// further failure.
+    pub base_delay: Duration,
+
+    /// The maximum delay of a restart.
+    pub max_delay: Duration,
+
+    /// Forget all failures once a deployment has not failed for this long.
+    pub reset_after: Duration,
+}
+
+#[derive(Default)]
+struct CircuitBreakerState {
+    /// The failures within the window, oldest first.
+    failures: VecDeque<Instant>,
+
+    /// The number of failures since the breaker tripped, or `None` if it
+    /// has not tripped.
+    tripped: Option<u32>,
+}
+
+/// Tracks the non-deterministic failures of a deployment across restarts.
+/// Once the deployment has failed too often, each restart is delayed, with
+/// the delay increasing with every further failure until the deployment has
+/// run without failing for a while.
+#[derive(Default)]
+pub(crate) struct CircuitBreaker {
+    state: Mutex<CircuitBreakerState>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn record_failure(&self, config: &CircuitBreakerConfig) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        Self::reset_if_recovered(&mut state, config, now);
+
+        while state.failures.front().map_or(false, |failure| {
+            now.duration_since(*failure) > config.window
+        }) {
+            state.failures.pop_front();
+        }
+        state.failures.push_back(now);
+
+        state.tripped = match state.tripped {
+            Some(failures) => Some(failures.saturating_add(1)),
+            None if config.failures > 0 && state.failures.len() >= config.failures => Some(0),
+            None => None,
+        };
+    }
+
+    /// How much longer to wait before restarting the deployment, if at all.
+    pub(crate) fn restart_delay(&self, config: &CircuitBreakerConfig) -> Option<Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        Self::reset_if_recovered(&mut state, config, now);
+
+        let failures = state.tripped?;
+        let last_failure = *state.failures.back()?;
+        let delay = config
+            .base_delay
+            .checked_mul(2u32.checked_pow(failures).unwrap_or(u32::MAX))
+            .unwrap_or(config.max_delay)
+            .min(config.max_delay);
+        delay
+            .checked_sub(now.duration_since(last_failure))
+            .filter(|delay| *delay > Duration::from_secs(0))
+    }
+
+    fn reset_if_recovered(
+        state: &mut CircuitBreakerState,
+        config: &CircuitBreakerConfig,
+        now: Instant,
+    ) {
+        if state.failures.back().map_or(false, |failure| {
+            now.duration_since(*failure) >= config.reset_after
+        }) {
+            *state = CircuitBreakerState::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circuit_breaker_config(failures: usize) -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            failures,
+            window: Duration::from_secs(600),
+            base_delay: Duration::from_secs(60),
+            max_delay: Duration::from_secs(300),
+            reset_after: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn circuit_breaker_trips_and_backs_off() {
+        let config = circuit_breaker_config(3);
+        let breaker = CircuitBreaker::default();
+        let delay = |breaker: &CircuitBreaker| breaker.restart_delay(&config).unwrap();
+
+        breaker.record_failure(&config);
+        breaker.record_failure(&config);
+        assert_eq!(None, breaker.restart_delay(&config));
+
+        // The third failure trips the breaker, and every further failure
+        // doubles the delay until it reaches the maximum
+        breaker.record_failure(&config);
+        assert!(delay(&breaker) > Duration::from_secs(50));
+        assert!(delay(&breaker) <= Duration::from_secs(60));
+        breaker.record_failure(&config);
+        assert!(delay(&breaker) > Duration::from_secs(110));
+        assert!(delay(&breaker) <= Duration::from_secs(120));
+        for _ in 0..40 {
+            breaker.record_failure(&config);
+        }
+        assert!(delay(&breaker) > Duration::from_secs(290));
+        assert!(delay(&breaker) <= Duration::from_secs(300));
+    }
+
+    #[test]
+    fn circuit_breaker_disabled() {
+        let config = circuit_breaker_config(0);
+        let breaker = CircuitBreaker::default();
+        for _ in 0..10 {
+            breaker.record_failure(&config);
+        }
+        assert_eq!(None, breaker.restart_delay(&config));
+    }
+
+    #[test]
+    fn circuit_breaker_window_and_reset() {
+        let config = CircuitBreakerConfig {
+            window: Duration::from_secs(10),
+            reset_after: Duration::from_secs(20),
+            ..circuit_breaker_config(2)
+        };
+        let breaker = CircuitBreaker::default();
+
+        // A failure that is older than the window does not count
+        breaker
+            .state
+            .lock()
+            .unwrap()
+            .failures
+            .push_back(Instant::now() - Duration::from_secs(15));
+        breaker.record_failure(&config);
+        assert_eq!(None, breaker.restart_delay(&config));
+
+        breaker.record_failure(&config);
+        assert!(breaker.restart_delay(&config).is_some());
+
+        // Once the deployment hasn't failed for `reset_after`, the breaker
+        // forgets all failures
+        {
+            let mut state = breaker.state.lock().unwrap();
+            let long_ago = Instant::now() - Duration::from_secs(30);
+            state.failures = vec![long_ago, long_ago].into_iter().collect();
+        }
+        assert_eq!(None, breaker.restart_delay(&config));
+        breaker.record_failure(&config);
+        assert_eq!(None, breaker.restart_delay(&config));
+    }
+}
@@ -1,11 +1,12 @@
 use futures01::sync::mpsc::Sender;
+use futures03::stream::{self, StreamExt};
 use lazy_static::lazy_static;
 
 use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
 
-use graph::components::subgraph::{MappingError, SharedProofOfIndexing};
+use graph::components::subgraph::{BlockSeed, MappingError, SharedProofOfIndexing};
 use graph::prelude::*;
 
 lazy_static! {
@@ -20,6 +21,9 @@ pub struct SubgraphInstance<T: RuntimeHostBuilder> {
     network: String,
     host_builder: T,
 
+    /// The adapter for the Ethereum calls from the mappings.
+    eth_adapter: Arc<dyn EthereumAdapter>,
+
     /// Runtime hosts, one for each data source mapping.
     ///
     /// The runtime hosts are created and added in the same order the
@@ -27,6 +31,10 @@ pub struct SubgraphInstance<T: RuntimeHostBuilder> {
     /// stream events are processed by the mappings in this same order.
     hosts: Vec<Arc<T::Host>>,
 
+    /// Hosts that were replaced by a duplicate, together with the creation
+    /// block of the replacement. They are restored when that block is reverted.
+    replaced_hosts: Vec<(Option<BlockNumber>, Arc<T::Host>)>,
+
     /// Maps the hash of a module to a channel to the thread in which the module is instantiated.
     module_cache: HashMap<[u8; 32], Sender<T::Req>>,
 }
@@ -39,6 +47,7 @@ where
         logger: &Logger,
         manifest: SubgraphManifest,
         host_builder: T,
+        eth_adapter: Arc<dyn EthereumAdapter>,
         host_metrics: Arc<HostMetrics>,
     ) -> Result<Self, Error> {
         let subgraph_id = manifest.id.clone();
@@ -47,9 +56,11 @@ where
 
         let mut this = SubgraphInstance {
             host_builder,
+            eth_adapter,
             subgraph_id,
             network,
             hosts: Vec::new(),
+            replaced_hosts: Vec::new(),
             module_cache: HashMap::new(),
         };
 
@@ -106,6 +117,7 @@ where
         };
         self.host_builder.build(
             self.network.clone(),
+            Some(self.eth_adapter.cheap_clone()),
             self.subgraph_id.clone(),
             data_source,
             templates,
@@ -121,6 +133,8 @@ where
         trigger: EthereumTrigger,
         state: BlockState,
         proof_of_indexing: SharedProofOfIndexing,
+        seed: BlockSeed,
+        matched: Option<&mut Vec<String>>,
     ) -> Result<BlockState, MappingError> {
         Self::process_trigger_in_runtime_hosts(
             logger,
@@ -129,10 +143,53 @@ where
             trigger,
             state,
             proof_of_indexing,
+            seed,
+            matched,
         )
         .await
     }
 
+    /// Like `process_trigger`, but only run the handlers of the data
+    /// sources of the manifest, or only those of dynamic data sources if
+    /// `dynamic` is set.
+    pub(crate) async fn process_trigger_for_data_sources(
+        &self,
+        dynamic: bool,
+        logger: &Logger,
+        block: &Arc<LightEthereumBlock>,
+        trigger: EthereumTrigger,
+        state: BlockState,
+        proof_of_indexing: SharedProofOfIndexing,
+        seed: BlockSeed,
+        matched: Option<&mut Vec<String>>,
+    ) -> Result<BlockState, MappingError> {
+        // The hosts of the data sources of the manifest come first, and only
+        // dynamic data sources have a creation block
+        let static_count = self
+            .hosts
+            .iter()
+            .take_while(|host| host.data_source().creation_block.is_none())
+            .count();
+        let hosts = match dynamic {
+            true => &self.hosts[static_count..],
+            false => &self.hosts[..static_count],
+        };
+        Self::process_trigger_in_runtime_hosts(
+            logger,
+            hosts,
+            block,
+            trigger,
+            state,
+            proof_of_indexing,
+            seed,
+            matched,
+        )
+        .await
+    }
+
+    /// Run the handlers of the `hosts` that match `trigger`. If `matched` is
+    /// given, the data sources of these hosts are added to it, see
+    /// `data_source_label`.
     pub(crate) async fn process_trigger_in_runtime_hosts(
         logger: &Logger,
         hosts: &[Arc<T::Host>],
@@ -140,6 +197,8 @@ where
         trigger: EthereumTrigger,
         mut state: BlockState,
         proof_of_indexing: SharedProofOfIndexing,
+        seed: BlockSeed,
+        mut matched: Option<&mut Vec<String>>,
     ) -> Result<BlockState, MappingError> {
         for host in hosts {
             let mapping_trigger = match host.match_and_decode(&trigger, &block, logger)? {
@@ -149,6 +208,9 @@ where
                 // Trigger does not match, do not process it.
                 None => continue,
             };
+            if let Some(matched) = matched.as_mut() {
+                matched.push(data_source_label(host.data_source()));
+            }
 
             state = host
                 .process_mapping_trigger(
@@ -157,6 +219,7 @@ where
                     mapping_trigger,
                     state,
                     proof_of_indexing.cheap_clone(),
+                    seed,
                 )
                 .await?;
         }
@@ -164,12 +227,104 @@ where
         Ok(state)
     }
 
+    /// Like `process_trigger_in_runtime_hosts`, but run the handlers of up
+    /// to `concurrency` hosts at the same time. Each host works on its own
+    /// fork of `state`, and the forks are merged back in the order of the
+    /// hosts, so the result does not depend on the order in which the
+    /// handlers finish. If a handler changed an entity that another one
+    /// read or changed, the result would differ from running them one
+    /// after the other, and the handlers are run again one after the other.
+    ///
+    /// The proof of indexing records changes in the order in which they
+    /// are made, and must therefore not be used with a `concurrency` above 1.
+    pub(crate) async fn process_trigger_in_runtime_hosts_concurrently(
+        logger: &Logger,
+        hosts: &[Arc<T::Host>],
+        block: &Arc<LightEthereumBlock>,
+        trigger: EthereumTrigger,
+        mut state: BlockState,
+        proof_of_indexing: SharedProofOfIndexing,
+        seed: BlockSeed,
+        concurrency: usize,
+        mut matched: Option<&mut Vec<String>>,
+    ) -> Result<BlockState, MappingError> {
+        if concurrency <= 1 {
+            return Self::process_trigger_in_runtime_hosts(
+                logger,
+                hosts,
+                block,
+                trigger,
+                state,
+                proof_of_indexing,
+                seed,
+                matched,
+            )
+            .await;
+        }
+        assert!(proof_of_indexing.is_none());
+
+        let mut matches = Vec::new();
+        for host in hosts {
+            if let Some(mapping_trigger) = host.match_and_decode(&trigger, &block, logger)? {
+                if let Some(matched) = matched.as_mut() {
+                    matched.push(data_source_label(host.data_source()));
+                }
+                matches.push((host, mapping_trigger));
+            }
+        }
+
+        let match_count = matches.len();
+        let forks = state.forks(match_count);
+        let results: Vec<_> = stream::iter(matches.into_iter().zip(forks).map(
+            |((host, mapping_trigger), fork)| {
+                host.process_mapping_trigger(
+                    logger,
+                    block,
+                    mapping_trigger,
+                    fork,
+                    proof_of_indexing.cheap_clone(),
+                    seed,
+                )
+            },
+        ))
+        .buffered(concurrency)
+        .collect()
+        .await;
+
+        let mut forks = Vec::with_capacity(match_count);
+        for result in results {
+            forks.push(result?);
+        }
+        if state.join_forks(forks) {
+            return Ok(state);
+        }
+
+        debug!(
+            logger,
+            "Handlers for the trigger depend on each other's entity changes, \
+             running them one after the other";
+            "hosts" => match_count,
+        );
+        Self::process_trigger_in_runtime_hosts(
+            logger,
+            hosts,
+            block,
+            trigger,
+            state,
+            proof_of_indexing,
+            seed,
+            None,
+        )
+        .await
+    }
+
     pub(crate) fn add_dynamic_data_source(
         &mut self,
         logger: &Logger,
         data_source: DataSource,
         templates: Arc<Vec<DataSourceTemplate>>,
         metrics: Arc<HostMetrics>,
+        replace_duplicate: bool,
     ) -> Result<Option<Arc<T::Host>>, Error> {
         // Protect against creating more than the allowed maximum number of data sources
         if let Some(max_data_sources) = *MAX_DATA_SOURCES {
@@ -190,14 +345,29 @@ where
         let host =
             Arc::new(self.new_host(logger.clone(), data_source, templates, metrics.clone())?);
 
-        Ok(if self.hosts.contains(&host) {
-            None
-        } else {
-            self.hosts.push(host.clone());
-            Some(host)
+        Ok(match self.hosts.iter().position(|h| *h == host) {
+            None => {
+                self.hosts.push(host.clone());
+                Some(host)
+            }
+            Some(_) if !replace_duplicate => None,
+            Some(i) => {
+                // The replacement goes to the end to keep `hosts` ordered
+                let replaced = self.hosts.remove(i);
+                self.replaced_hosts
+                    .push((host.creation_block_number(), replaced));
+                self.hosts.push(host.clone());
+                Some(host)
+            }
         })
     }
 
+    /// The data sources of the hosts, in the order in which triggers are
+    /// processed.
+    pub(crate) fn data_sources(&self) -> impl Iterator<Item = &DataSource> {
+        self.hosts.iter().map(|host| host.data_source())
+    }
+
     pub(crate) fn revert_data_sources(&mut self, reverted_block: BlockNumber) {
         // `hosts` is ordered by the creation block.
         // See also 8f1bca33-d3b7-4035-affc-fd6161a12448.
@@ -209,5 +379,231 @@ where
         {
             self.hosts.pop();
         }
+
+        // Restore the hosts whose replacements were just removed, in
+        // reverse order of replacement so that chains of replacements
+        // unwind correctly
+        while self
+            .replaced_hosts
+            .last()
+            .filter(|(block, _)| *block >= Some(reverted_block))
+            .is_some()
+        {
+            let (_, host) = self.replaced_hosts.pop().unwrap();
+            let i = self
+                .hosts
+                .iter()
+                .position(|h| h.creation_block_number() > host.creation_block_number())
+                .unwrap_or(self.hosts.len());
+            self.hosts.insert(i, host);
+        }
+    }
+}
+
+/// How a data source that handled a trigger is reported: its name, and the
+/// address of its contract if it has one, since dynamic data sources created
+/// from the same template all have the same name.
+fn data_source_label(data_source: &DataSource) -> String {
+    match &data_source.source.address {
+        Some(address) => format!("{}@{:?}", data_source.name, address),
+        None => data_source.name.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::data::subgraph::{Mapping, Source};
+    use graph::mock::MockEthereumAdapter;
+    use graph::prelude::ethabi::Contract;
+    use graph::semver::Version;
+    use std::collections::BTreeSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use web3::types::H160;
+
+    #[derive(Debug)]
+    struct TestHost(DataSource);
+
+    impl PartialEq for TestHost {
+        fn eq(&self, other: &Self) -> bool {
+            self.0.name == other.0.name && self.0.source.address == other.0.source.address
+        }
+    }
+
+    #[async_trait]
+    impl RuntimeHost for TestHost {
+        fn match_and_decode(
+            &self,
+            _: &EthereumTrigger,
+            _: &LightEthereumBlock,
+            _: &Logger,
+        ) -> Result<Option<MappingTrigger>, Error> {
+            Ok(None)
+        }
+
+        async fn process_mapping_trigger(
+            &self,
+            _: &Logger,
+            _: &Arc<LightEthereumBlock>,
+            _: MappingTrigger,
+            state: BlockState,
+            _: SharedProofOfIndexing,
+            _: BlockSeed,
+        ) -> Result<BlockState, MappingError> {
+            Ok(state)
+        }
+
+        fn creation_block_number(&self) -> Option<BlockNumber> {
+            self.0.creation_block
+        }
+
+        fn data_source(&self) -> &DataSource {
+            &self.0
+        }
+    }
+
+    /// Counts the hosts it builds.
+    #[derive(Clone, Default)]
+    struct CountingHostBuilder(Arc<AtomicUsize>);
+
+    impl CountingHostBuilder {
+        fn built(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl RuntimeHostBuilder for CountingHostBuilder {
+        type Host = TestHost;
+        type Req = ();
+
+        fn build(
+            &self,
+            _: String,
+            _: Option<Arc<dyn EthereumAdapter>>,
+            _: SubgraphDeploymentId,
+            data_source: DataSource,
+            _: Arc<Vec<DataSourceTemplate>>,
+            _: Sender<()>,
+            _: Arc<HostMetrics>,
+        ) -> Result<TestHost, Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(TestHost(data_source))
+        }
+
+        fn spawn_mapping(
+            _: Vec<u8>,
+            _: Logger,
+            _: SubgraphDeploymentId,
+            _: Arc<HostMetrics>,
+        ) -> Result<Sender<()>, Error> {
+            Ok(futures01::sync::mpsc::channel(1).0)
+        }
+    }
+
+    fn data_source(name: &str, address: u64, creation_block: Option<BlockNumber>) -> DataSource {
+        let contract_abi = Arc::new(MappingABI {
+            name: "Contract".to_string(),
+            contract: Contract::load("[]".as_bytes()).unwrap(),
+        });
+        DataSource {
+            kind: String::from("ethereum/contract"),
+            name: String::from(name),
+            network: Some(String::from("mainnet")),
+            source: Source {
+                address: Some(H160::from_low_u64_be(address)),
+                abi: String::from("Contract"),
+                start_block: 0,
+            },
+            mapping: Mapping {
+                kind: String::from("ethereum/events"),
+                api_version: Version::parse("0.1.0").unwrap(),
+                language: String::from("wasm/assemblyscript"),
+                entities: vec![],
+                abis: vec![contract_abi.clone()],
+                event_handlers: vec![],
+                call_handlers: vec![],
+                block_handlers: vec![],
+                link: Link {
+                    link: "link".to_owned(),
+                },
+                runtime: Arc::new(vec![]),
+            },
+            context: Default::default(),
+            creation_block,
+            contract_abi,
+        }
+    }
+
+    // Restarting the block stream because a block created data sources keeps
+    // the instance; the hosts it already has must survive adding the new ones
+    #[test]
+    fn new_data_sources_keep_existing_hosts() {
+        let logger = Logger::root(slog::Discard, o!());
+        let id = SubgraphDeploymentId::new("QmHosts").unwrap();
+        let registry = Arc::new(graph_mock::MockMetricsRegistry::new());
+        let stopwatch = StopwatchMetrics::new(logger.clone(), id.clone(), registry.clone());
+        let metrics = Arc::new(HostMetrics::new(registry, id.as_str(), stopwatch));
+        let manifest = SubgraphManifest {
+            id: id.clone(),
+            spec_version: "0.0.2".to_owned(),
+            features: BTreeSet::new(),
+            description: None,
+            repository: None,
+            schema: Schema::parse("type Thing @entity { id: ID! }", id.clone()).unwrap(),
+            data_sources: vec![
+                data_source("Factory", 1, None),
+                data_source("Pair", 2, Some(5)),
+            ],
+            graft: None,
+            templates: vec![],
+            block_predicate: None,
+        };
+
+        let builder = CountingHostBuilder::default();
+        let mut instance = SubgraphInstance::from_manifest(
+            &logger,
+            manifest,
+            builder.clone(),
+            Arc::new(MockEthereumAdapter::new()),
+            metrics.clone(),
+        )
+        .unwrap();
+        assert_eq!(2, builder.built());
+        let hosts = instance.hosts.clone();
+
+        // Only the new data source gets a host
+        let host = instance
+            .add_dynamic_data_source(
+                &logger,
+                data_source("Pair", 3, Some(7)),
+                Arc::new(vec![]),
+                metrics.clone(),
+                false,
+            )
+            .unwrap();
+        assert!(host.is_some());
+        assert_eq!(3, builder.built());
+        assert_eq!(3, instance.hosts.len());
+        assert!(hosts
+            .iter()
+            .zip(&instance.hosts)
+            .all(|(old, new)| Arc::ptr_eq(old, new)));
+
+        // A duplicate builds a host to compare with, but keeps the one it has
+        let host = instance
+            .add_dynamic_data_source(
+                &logger,
+                data_source("Pair", 3, Some(8)),
+                Arc::new(vec![]),
+                metrics,
+                false,
+            )
+            .unwrap();
+        assert!(host.is_none());
+        assert_eq!(3, instance.hosts.len());
+        assert!(hosts
+            .iter()
+            .zip(&instance.hosts)
+            .all(|(old, new)| Arc::ptr_eq(old, new)));
     }
 }
@@ -1,326 +1,2953 @@
 use atomic_refcell::AtomicRefCell;
 use fail::fail_point;
-use lazy_static::lazy_static;
-use std::collections::{BTreeSet, HashMap, HashSet};
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use futures03::channel::{mpsc, oneshot};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
 use tokio::task;
 
-use graph::components::ethereum::{triggers_in_block, EthereumNetworks};
-use graph::components::store::{BlockStore, ModificationsAndCache};
-use graph::components::subgraph::{MappingError, ProofOfIndexing, SharedProofOfIndexing};
+use graph::components::ethereum::{triggers_in_block, EthereumNetworks, NodeCapabilities};
+use graph::components::store::{
+    set_connection_wait_recorder, BlockStore, EntityType, ModificationsAndCache,
+};
+use graph::components::subgraph::{
+    BlockSeed, DeploymentLabels, ErrorCategory, MappingError, ModificationsEvent,
+    NodeDeploymentLabels, ProofOfIndexing, ProofOfIndexingFinisher, ReorgEvent,
+    SharedProofOfIndexing,
+};
+use graph::data::graphql::DocumentExt;
+use graph::data::schema::EntityValidationError;
 use graph::data::store::scalar::Bytes;
 use graph::data::subgraph::schema::{SubgraphError, POI_OBJECT};
 use graph::data::subgraph::SubgraphFeature;
 use graph::prelude::{SubgraphInstanceManager as SubgraphInstanceManagerTrait, *};
 use graph::util::lfu_cache::LfuCache;
 
+use super::adapter_selector::{select_eth_adapter, EthAdapterSelector, SelectedEthAdapter};
+use super::breakpoint::Breakpoint;
+use super::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
 use super::loader::load_dynamic_data_sources;
+use super::metrics::{
+    has_own_metrics, InFlight, MetricsCardinality, MetricsPush, SubgraphInstanceManagerMetrics,
+    SubgraphInstanceMetrics, TriggerType, UnregisteredMetrics,
+};
+use super::publisher::{ModificationsPublisher, ModificationsQueue, ReorgNotifier};
+use super::verification::{
+    compare_deployments, compare_with_store, finish_verification, DeploymentDifference,
+    Verification,
+};
 use super::SubgraphInstance;
-use crate::subgraph::registrar::IPFS_SUBGRAPH_LOADING_TIMEOUT;
 
-lazy_static! {
-    /// Size limit of the entity LFU cache, in bytes.
-    // Multiplied by 1000 because the env var is in KB.
-    pub static ref ENTITY_CACHE_SIZE: usize = 1000
-        * std::env::var("GRAPH_ENTITY_CACHE_SIZE")
-            .unwrap_or("10000".into())
-            .parse::<usize>()
-            .expect("invalid GRAPH_ENTITY_CACHE_SIZE");
+/// What to do when the block that a subgraph reverts can't be loaded, which
+/// usually means that the Ethereum node pruned it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RevertBlockFallback {
+    /// Mark the subgraph as failed and stop it.
+    Fail,
+    /// Load the block from a provider for the network that is an archive
+    /// node. The subgraph fails if there is none.
+    Archive,
+}
 
-    // Keep deterministic errors non-fatal even if the subgraph is pending.
-    // Used for testing Graph Node itself.
-    pub static ref DISABLE_FAIL_FAST: bool =
-        std::env::var("GRAPH_DISABLE_FAIL_FAST").is_ok();
+impl FromStr for RevertBlockFallback {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(RevertBlockFallback::Fail),
+            "archive" => Ok(RevertBlockFallback::Archive),
+            _ => Err(anyhow!(
+                "invalid revert block fallback `{}`, expected one of `fail` or `archive`",
+                s
+            )),
+        }
+    }
 }
 
-type SharedInstanceKeepAliveMap = Arc<RwLock<HashMap<SubgraphDeploymentId, CancelGuard>>>;
+/// What to do when a mapping creates a data source from a template that is a
+/// duplicate of an existing data source, i.e., one that only differs in its
+/// creation block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateDataSourcePolicy {
+    /// Keep the existing data source and drop the new one.
+    Drop,
+    /// Fail the subgraph with a deterministic error at the block that
+    /// created the duplicate.
+    Error,
+    /// Replace the existing data source with the new one. The new data
+    /// source processes the triggers of the block that created it, even if
+    /// the existing data source already processed them.
+    Replace,
+}
 
-struct IndexingInputs<B, S, C> {
-    deployment_id: SubgraphDeploymentId,
-    features: BTreeSet<SubgraphFeature>,
-    network_name: String,
-    start_blocks: Vec<BlockNumber>,
-    store: Arc<S>,
-    chain_store: Arc<C>,
-    eth_adapter: Arc<dyn EthereumAdapter>,
-    stream_builder: B,
-    include_calls_in_blocks: bool,
-    templates: Arc<Vec<DataSourceTemplate>>,
+impl FromStr for DuplicateDataSourcePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(DuplicateDataSourcePolicy::Drop),
+            "error" => Ok(DuplicateDataSourcePolicy::Error),
+            "replace" => Ok(DuplicateDataSourcePolicy::Replace),
+            _ => Err(anyhow!(
+                "invalid duplicate data source policy `{}`, expected one of `drop`, `error` or `replace`",
+                s
+            )),
+        }
+    }
 }
 
-struct IndexingState<T: RuntimeHostBuilder> {
-    logger: Logger,
-    instance: SubgraphInstance<T>,
-    instances: SharedInstanceKeepAliveMap,
-    log_filter: EthereumLogFilter,
-    call_filter: EthereumCallFilter,
-    block_filter: EthereumBlockFilter,
-    entity_lfu_cache: LfuCache<EntityKey, Option<Entity>>,
+/// The order in which the handlers of the data sources of the manifest and
+/// those of dynamic data sources process the triggers of a block. Data
+/// sources that a block creates always process its triggers last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataSourceOrder {
+    /// Each trigger is processed by all data sources before the next
+    /// trigger, so handlers of both kinds interleave in the order of the
+    /// triggers.
+    Trigger,
+    /// All triggers are processed by the data sources of the manifest
+    /// first, so that dynamic data sources see everything they changed.
+    StaticFirst,
+    /// All triggers are processed by dynamic data sources first, so that
+    /// the data sources of the manifest see everything they changed.
+    DynamicFirst,
 }
 
-struct IndexingContext<B, T: RuntimeHostBuilder, S, C> {
-    /// Read only inputs that are needed while indexing a subgraph.
-    pub inputs: IndexingInputs<B, S, C>,
+impl FromStr for DataSourceOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trigger" => Ok(DataSourceOrder::Trigger),
+            "static-first" => Ok(DataSourceOrder::StaticFirst),
+            "dynamic-first" => Ok(DataSourceOrder::DynamicFirst),
+            _ => Err(anyhow!(
+                "invalid data source order `{}`, expected one of `trigger`, `static-first` or `dynamic-first`",
+                s
+            )),
+        }
+    }
+}
 
-    /// Mutable state that may be modified while indexing a subgraph.
-    pub state: IndexingState<T>,
+/// A function that rewrites the entity modifications of a block right before
+/// they are written to the store, for example to redact or enrich data.
+///
+/// The hook changes what the subgraph stores, and a hook that does not
+/// always produce the same result for the same input makes indexers
+/// disagree about the subgraph's data. It is therefore not run for
+/// subgraphs with a proof of indexing unless that is explicitly allowed.
+#[derive(Clone)]
+pub struct EntityModificationHook(pub Arc<dyn Fn(&mut Vec<EntityModification>) + Send + Sync>);
+
+impl fmt::Debug for EntityModificationHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EntityModificationHook")
+    }
+}
 
-    /// Sensors to measure the execution of the subgraph instance
-    pub subgraph_metrics: Arc<SubgraphInstanceMetrics>,
+/// A function that decides whether a deployment processes a trigger, for
+/// example to ignore the events of a spam contract. Triggers for which it
+/// returns `false` are dropped before any handler runs.
+///
+/// Dropping triggers changes what the subgraph stores, and the hook must
+/// therefore only depend on the deployment and the trigger, and give the
+/// same answer on every node. Like the `EntityModificationHook`, it is not
+/// run for subgraphs with a proof of indexing unless that is explicitly
+/// allowed.
+#[derive(Clone)]
+pub struct TriggerFilterHook(
+    pub Arc<dyn Fn(&SubgraphDeploymentId, &EthereumTrigger) -> bool + Send + Sync>,
+);
+
+impl fmt::Debug for TriggerFilterHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TriggerFilterHook")
+    }
+}
 
-    /// Sensors to measure the execution of the subgraph's runtime hosts
-    pub host_metrics: Arc<HostMetrics>,
+/// Signs the digest of a `PoiBundle` with the key of the node, which the
+/// embedder loads from wherever it keeps it. Returns the signature.
+#[derive(Clone)]
+pub struct PoiBundleSigner(
+    pub Arc<dyn Fn(&web3::types::H256) -> Result<Vec<u8>, Error> + Send + Sync>,
+);
 
-    /// Sensors to measure the execution of eth rpc calls
-    pub ethrpc_metrics: Arc<SubgraphEthRpcMetrics>,
+impl fmt::Debug for PoiBundleSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PoiBundleSigner")
+    }
+}
 
-    pub block_stream_metrics: Arc<BlockStreamMetrics>,
+/// A block that a deployment has just written to the store.
+#[derive(Clone, Debug)]
+pub struct CommittedBlock {
+    pub deployment: SubgraphDeploymentId,
+    pub block_ptr: EthereumBlockPointer,
+
+    /// The proof of indexing digest of each causality region that the block
+    /// changed. Only set if the deployment has a proof of indexing and
+    /// passing it to the hook is enabled.
+    pub proof_of_indexing: Option<Vec<(String, Bytes)>>,
+
+    /// The triggers that the deployment processed for the block.
+    pub trigger_counts: TriggerCounts,
 }
 
-pub struct SubgraphInstanceManager<B, S, BS, M, H, L> {
-    logger_factory: LoggerFactory,
-    subgraph_store: Arc<S>,
-    block_store: Arc<BS>,
-    eth_networks: EthereumNetworks,
-    host_builder: H,
-    block_stream_builder: B,
-    metrics_registry: Arc<M>,
-    manager_metrics: SubgraphInstanceManagerMetrics,
-    instances: SharedInstanceKeepAliveMap,
-    link_resolver: Arc<L>,
+/// The number of triggers of each type that a deployment processed for a
+/// block, including those for data sources that the block created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TriggerCounts {
+    pub event: usize,
+    pub call: usize,
+    pub block: usize,
+}
+
+impl TriggerCounts {
+    fn add(&mut self, trigger_type: &TriggerType) {
+        match trigger_type {
+            TriggerType::Event => self.event += 1,
+            TriggerType::Call => self.call += 1,
+            TriggerType::Block => self.block += 1,
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.event + self.call + self.block
+    }
 }
 
-struct SubgraphInstanceManagerMetrics {
-    pub subgraph_count: Box<Gauge>,
+/// A function that is called for every block that a deployment has written
+/// to the store. It runs on the deployment's indexing thread and should
+/// return quickly.
+#[derive(Clone)]
+pub struct BlockCommittedHook(pub Arc<dyn Fn(&CommittedBlock) + Send + Sync>);
+
+impl fmt::Debug for BlockCommittedHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BlockCommittedHook")
+    }
 }
 
-impl SubgraphInstanceManagerMetrics {
-    pub fn new(registry: Arc<impl MetricsRegistry>) -> Self {
-        let subgraph_count = registry
-            .new_gauge(
-                "deployment_count",
-                "Counts the number of deployments currently being indexed by the graph-node.",
-                HashMap::new(),
-            )
-            .expect("failed to create `deployment_count` gauge");
-        Self { subgraph_count }
+/// A secondary store that deployments read entities from before they read
+/// them from the primary store; see `SecondaryEntityStore`.
+#[derive(Clone)]
+pub struct ReadThroughStore(pub Arc<dyn SecondaryEntityStore>);
+
+impl fmt::Debug for ReadThroughStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadThroughStore")
     }
 }
 
-enum TriggerType {
-    Event,
-    Call,
-    Block,
+/// Names the entities that a deployment will probably read soon after it
+/// starts, like the ones it changed last before the node was restarted.
+pub trait WarmupKeySource: Send + Sync + 'static {
+    fn keys(&self, deployment: &SubgraphDeploymentId) -> Vec<EntityKey>;
 }
 
-impl TriggerType {
-    fn label_value(&self) -> &str {
-        match self {
-            TriggerType::Event => "event",
-            TriggerType::Call => "call",
-            TriggerType::Block => "block",
+/// The entities that deployments load into their entity cache before they
+/// process their first block; see `SubgraphInstanceManagerConfig`.
+#[derive(Clone)]
+pub struct EntityCacheWarmup(pub Arc<dyn WarmupKeySource>);
+
+impl fmt::Debug for EntityCacheWarmup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EntityCacheWarmup")
+    }
+}
+
+/// A summary of the state of a running deployment, made from what the
+/// manager keeps in memory. See `SubgraphInstanceManager::health_snapshot`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeploymentHealth {
+    pub deployment: SubgraphDeploymentId,
+
+    /// Whether the deployment was close enough to the chain head to use the
+    /// head processing profile when it processed its last block.
+    pub synced: bool,
+
+    /// The number of blocks the deployment was behind the chain head when it
+    /// processed its last block, or `None` if it hasn't processed a block
+    /// since it was started.
+    pub head_distance: Option<BlockNumber>,
+
+    /// The last block the deployment processed.
+    pub block_ptr: Option<EthereumBlockPointer>,
+
+    /// The number of deterministic errors and failures since the deployment
+    /// was started.
+    pub error_count: u64,
+
+    /// The number of times the block stream was restarted since the
+    /// deployment was started.
+    pub restart_count: u64,
+
+    /// Whether the deployment is syncing but did not get closer to the
+    /// chain head during the last `stall_window`.
+    pub stalled: bool,
+
+    /// Whether the deployment is paused at a breakpoint.
+    pub paused: bool,
+
+    /// Whether the deployment stopped processing blocks while it is still
+    /// assigned, because it reached its end block, failed, or finished a
+    /// verify-only run.
+    pub drained: bool,
+}
+
+/// How fast the deployments on this node process blocks and triggers
+/// together. See `SubgraphInstanceManager::aggregate_throughput`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AggregateThroughput {
+    pub blocks_per_second: f64,
+    pub triggers_per_second: f64,
+
+    /// The number of deployments whose throughput is included; deployments
+    /// that did not process enough blocks for an estimate are left out.
+    pub deployments: usize,
+}
+
+/// The indexing context of a running deployment, for debugging it offline
+/// or attaching it to bug reports. See
+/// `SubgraphInstanceManager::indexing_context`. All lists are sorted so that
+/// the dumps of the same deployment on different nodes can be diffed. Only
+/// what the deployment itself defines is included; the node's configuration,
+/// like Ethereum node URLs, and data source contexts are left out.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct IndexingContextDump {
+    pub deployment: String,
+    pub network: String,
+    pub block_number: Option<BlockNumber>,
+    pub block_hash: Option<String>,
+    pub features: Vec<String>,
+    pub data_sources: Vec<DataSourceDump>,
+    pub log_filters: Vec<LogFilterDump>,
+    pub call_filters: Vec<CallFilterDump>,
+
+    /// The contracts with block handlers and the block from which on they
+    /// are called.
+    pub block_filter_contracts: Vec<(String, BlockNumber)>,
+    pub trigger_every_block: bool,
+
+    /// Whether the filters are the ones the current data sources need.
+    /// They are broader if blocks that created data sources were reverted.
+    pub filters_match_data_sources: bool,
+    pub entity_cache_entries: usize,
+    pub entity_cache_weight: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct DataSourceDump {
+    pub name: String,
+    pub kind: String,
+    pub address: Option<String>,
+    pub start_block: BlockNumber,
+    pub creation_block: Option<BlockNumber>,
+}
+
+/// The filters with which a running deployment requests triggers from the
+/// Ethereum node. See `SubgraphInstanceManager::filters`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FiltersDump {
+    pub log_filters: Vec<LogFilterDump>,
+    pub call_filters: Vec<CallFilterDump>,
+
+    /// The contracts with block handlers and the block from which on they
+    /// are called.
+    pub block_filter_contracts: Vec<(String, BlockNumber)>,
+    pub trigger_every_block: bool,
+
+    /// Whether the filters are the ones the current data sources need.
+    /// They are broader if blocks that created data sources were reverted.
+    pub matches_data_sources: bool,
+}
+
+/// An `eth_getLogs` request that the block stream makes.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct LogFilterDump {
+    pub contracts: Vec<String>,
+    pub events: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct CallFilterDump {
+    pub contract: String,
+    pub start_block: BlockNumber,
+    pub functions: Vec<String>,
+}
+
+/// How far a deployment got with syncing historical blocks in chunks of
+/// `SubgraphInstanceManagerConfig::backfill_chunk_size` blocks. A restarted
+/// deployment finishes the chunk it was interrupted in instead of starting
+/// a new one at its block pointer. The chunk is stored with the deployment
+/// so that this also works when the node restarts; the other fields are
+/// only kept in memory and start over with the node.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackfillProgress {
+    /// The first and last block of the chunk that is being processed.
+    pub chunk: Option<(BlockNumber, BlockNumber)>,
+
+    /// The number of chunks that were completed since the node started.
+    pub chunks_completed: u64,
+
+    /// The last block of the last completed chunk, which was written to
+    /// the store.
+    pub checkpoint: Option<EthereumBlockPointer>,
+
+    /// When the last chunk was completed.
+    pub checkpointed_at: Option<SystemTime>,
+}
+
+impl BackfillProgress {
+    /// Start or resume the chunk that contains `next_block` and return its
+    /// last block. A chunk is resumed if it is the one that was interrupted
+    /// and `next_block` still lies in it; otherwise a new chunk starts at
+    /// `next_block`.
+    fn start_chunk(&mut self, next_block: BlockNumber, chunk_size: BlockNumber) -> BlockNumber {
+        let chunk = match self.chunk {
+            Some((first, last)) if first <= next_block && next_block <= last => (first, last),
+            _ => (next_block, next_block + chunk_size - 1),
+        };
+        self.chunk = Some(chunk);
+        chunk.1
+    }
+
+    fn complete_chunk(&mut self, ptr: EthereumBlockPointer) {
+        self.chunk = None;
+        self.chunks_completed += 1;
+        self.checkpoint = Some(ptr);
+        self.checkpointed_at = Some(SystemTime::now());
+    }
+}
+
+/// Why `SubgraphInstanceManager::retry_failed` did not restart a deployment.
+#[derive(thiserror::Error, Debug)]
+pub enum RetryFailedError {
+    #[error("deployment `{0}` is not assigned to this node")]
+    NotRunning(SubgraphDeploymentId),
+
+    #[error("deployment `{0}` has not failed")]
+    NotFailed(SubgraphDeploymentId),
+
+    #[error("deployment `{}` failed deterministically: {}", .0.subgraph_id, .0.message)]
+    Deterministic(SubgraphError),
+
+    #[error("store error: {0}")]
+    Store(#[from] StoreError),
+
+    #[error("failed to start deployment: {0:#}")]
+    Start(Error),
+}
+
+/// Why `SubgraphInstanceManager::inject_trigger` could not process a
+/// trigger.
+#[derive(thiserror::Error, Debug)]
+pub enum InjectTriggerError {
+    #[error("injecting triggers is disabled, set GRAPH_ALLOW_TRIGGER_INJECTION to enable it")]
+    Disabled,
+
+    #[error("deployment `{0}` is not running")]
+    NotRunning(SubgraphDeploymentId),
+
+    #[error("failed to process the injected trigger: {0:#}")]
+    Failed(Error),
+}
+
+/// What processing an injected trigger would have changed, see
+/// `SubgraphInstanceManager::inject_trigger`.
+#[derive(Debug)]
+pub struct TriggerInjectionOutcome {
+    /// The block the trigger was processed against.
+    pub block_ptr: EthereumBlockPointer,
+    pub modifications: Vec<EntityModification>,
+    pub deterministic_errors: Vec<SubgraphError>,
+}
+
+/// What compacting the entity cache of a deployment freed, see
+/// `SubgraphInstanceManager::compact_entity_cache`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntityCacheCompaction {
+    /// The weight of the evicted entries, in bytes.
+    pub freed_weight: usize,
+    pub evicted_count: usize,
+    /// The weight of the cache after compacting it, in bytes.
+    pub weight: usize,
+}
+
+/// The version of the format of `PoiBundle` and of how its digest is
+/// computed. Consumers of bundles rely on both, and any change to them must
+/// come with a new version.
+const POI_BUNDLE_VERSION: u32 = 1;
+
+/// The proofs of indexing of a deployment for a range of blocks, in a form
+/// that can be submitted to an arbitration contract in a dispute, see
+/// `SubgraphInstanceManager::proof_of_indexing_bundle`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoiBundle {
+    pub version: u32,
+    pub deployment: String,
+    pub indexer: Option<web3::types::Address>,
+    pub entries: Vec<PoiBundleEntry>,
+
+    /// The Keccak-256 hash of the version as 4 big endian bytes, the
+    /// deployment id, the indexer address or 20 zero bytes, and for each
+    /// entry the block number as 8 big endian bytes, the block hash and the
+    /// proof of indexing. The digests of the causality regions are part of
+    /// the proof of indexing, and are not hashed again.
+    pub digest: web3::types::H256,
+
+    /// The signature of `digest`, if the node has a `PoiBundleSigner`.
+    pub signature: Option<Bytes>,
+}
+
+/// The proof of indexing of a deployment at one block.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoiBundleEntry {
+    pub block_number: BlockNumber,
+    pub block_hash: web3::types::H256,
+    pub proof_of_indexing: web3::types::H256,
+
+    /// The digest of each causality region, by the id of the region.
+    pub causality_regions: BTreeMap<String, Bytes>,
+}
+
+impl PoiBundle {
+    fn compute_digest(
+        deployment: &str,
+        indexer: &Option<web3::types::Address>,
+        entries: &[PoiBundleEntry],
+    ) -> web3::types::H256 {
+        let mut data = Vec::new();
+        data.extend_from_slice(&POI_BUNDLE_VERSION.to_be_bytes());
+        data.extend_from_slice(deployment.as_bytes());
+        data.extend_from_slice(indexer.unwrap_or_default().as_bytes());
+        for entry in entries {
+            data.extend_from_slice(&(entry.block_number as u64).to_be_bytes());
+            data.extend_from_slice(entry.block_hash.as_bytes());
+            data.extend_from_slice(entry.proof_of_indexing.as_bytes());
         }
+        web3::types::H256::from(tiny_keccak::keccak256(&data))
     }
 }
 
-struct SubgraphInstanceMetrics {
-    pub block_trigger_count: Box<Histogram>,
-    pub block_processing_duration: Box<Histogram>,
-    pub block_ops_transaction_duration: Box<Histogram>,
+/// A trigger that an operator asked a running deployment to process.
+struct TriggerInjection {
+    trigger: EthereumTrigger,
+    outcome: oneshot::Sender<Result<TriggerInjectionOutcome, Error>>,
+}
 
-    trigger_processing_duration: Box<HistogramVec>,
+/// A request from an operator to evict entries from the entity cache of a
+/// running deployment until it weighs at most `target_weight`.
+struct CacheCompactionRequest {
+    target_weight: usize,
+    outcome: oneshot::Sender<EntityCacheCompaction>,
 }
 
-impl SubgraphInstanceMetrics {
-    pub fn new(registry: Arc<impl MetricsRegistry>, subgraph_hash: &str) -> Self {
-        let block_trigger_count = registry
-            .new_deployment_histogram(
-                "deployment_block_trigger_count",
-                "Measures the number of triggers in each block for a subgraph deployment",
-                subgraph_hash,
-                vec![1.0, 5.0, 10.0, 20.0, 50.0],
-            )
-            .expect("failed to create `deployment_block_trigger_count` histogram");
-        let trigger_processing_duration = registry
-            .new_deployment_histogram_vec(
-                "deployment_trigger_processing_duration",
-                "Measures duration of trigger processing for a subgraph deployment",
-                subgraph_hash,
-                vec![String::from("trigger_type")],
-                vec![0.01, 0.05, 0.1, 0.5, 1.5, 5.0, 10.0, 30.0, 120.0],
-            )
-            .expect("failed to create `deployment_trigger_processing_duration` histogram");
-        let block_processing_duration = registry
-            .new_deployment_histogram(
-                "deployment_block_processing_duration",
-                "Measures duration of block processing for a subgraph deployment",
-                subgraph_hash,
-                vec![0.05, 0.2, 0.7, 1.5, 4.0, 10.0, 60.0, 120.0, 240.0],
-            )
-            .expect("failed to create `deployment_block_processing_duration` histogram");
-        let block_ops_transaction_duration = registry
-            .new_deployment_histogram(
-                "deployment_transact_block_operations_duration",
-                "Measures duration of commiting all the entity operations in a block and updating the subgraph pointer",
-                subgraph_hash,
-                vec![0.01, 0.05, 0.1, 0.3, 0.7, 2.0],
-            )
-            .expect("failed to create `deployment_transact_block_operations_duration_{}");
+/// Why `SubgraphInstanceManager::reindex` did not reindex a deployment.
+#[derive(thiserror::Error, Debug)]
+pub enum ReindexError {
+    #[error("deployment `{0}` is not assigned to this node")]
+    NotRunning(SubgraphDeploymentId),
+
+    #[error("store error: {0}")]
+    Store(#[from] StoreError),
 
-        Self {
-            block_trigger_count,
-            block_processing_duration,
-            trigger_processing_duration,
-            block_ops_transaction_duration,
+    #[error("failed to start deployment: {0:#}")]
+    Start(Error),
+}
+
+/// Settings for a `SubgraphInstanceManager`. `Default` has the settings
+/// that a node uses when none of the environment variables that `from_env`
+/// reads are set.
+#[derive(Clone, Debug)]
+pub struct SubgraphInstanceManagerConfig {
+    /// Size limit of the entity LFU cache, in bytes.
+    pub entity_cache_size: usize,
+
+    /// Warn when a single eviction drops more than this fraction of the
+    /// entries in the entity cache.
+    pub entity_cache_eviction_warn_fraction: f64,
+
+    /// Keep deterministic errors non-fatal even if the subgraph is pending.
+    pub disable_fail_fast: bool,
+
+    /// Also stop pending subgraphs for deterministic errors in a block when
+    /// writing the block fails. The subgraph fails with the write error
+    /// either way, but it would only be stopped for the deterministic errors
+    /// once the block is processed and written again.
+    pub fail_fast_on_failed_write: bool,
+
+    /// Only make deterministic errors of these categories fatal for pending
+    /// subgraphs, or errors of all categories if `None`.
+    pub fail_fast_error_categories: Option<BTreeSet<ErrorCategory>>,
+
+    /// Deterministic errors of these categories fail a subgraph even if it
+    /// uses the `nonFatalErrors` feature, for example `abort` to tolerate
+    /// unexpected data but not bugs in mappings.
+    pub fatal_error_categories: BTreeSet<ErrorCategory>,
+
+    /// The timeout for IPFS requests made to load subgraph files.
+    pub ipfs_subgraph_loading_timeout: Duration,
+
+    /// How many deployments may resolve their manifest and dynamic data
+    /// sources at the same time, so that starting many deployments at once
+    /// does not overload the IPFS gateway; 0 does not limit them.
+    pub ipfs_resolution_concurrency: usize,
+
+    /// How often to retry starting a deployment that failed to start for a
+    /// reason that may go away. Starts that fail because of the subgraph
+    /// itself, like an invalid manifest, are not retried.
+    pub start_retries: usize,
+
+    /// The backoff before the first retry of starting a deployment; it
+    /// doubles with every further retry.
+    pub start_retry_backoff: Duration,
+
+    /// How often to retry `transact_block_operations` on transient errors.
+    pub transact_block_operations_retries: usize,
+
+    /// The initial backoff between retries of `transact_block_operations`.
+    pub transact_block_operations_retry_backoff: Duration,
+
+    /// How often to check whether a block that ran into a possible reorg is
+    /// still on the main chain according to the chain store, and process it
+    /// again if it is, before restarting the block stream. A possible reorg
+    /// is often a false alarm, for example when an Ethereum node lags
+    /// behind; 0 restarts the block stream right away.
+    pub possible_reorg_checks: usize,
+
+    /// How long to wait before each of these checks.
+    pub possible_reorg_check_delay: Duration,
+
+    /// How many requests in a row to the Ethereum adapter a deployment uses
+    /// may fail before it switches to another adapter for the network with
+    /// the capabilities the deployment requires; 0 never switches.
+    pub eth_adapter_failover_errors: u32,
+
+    /// How often a deployment that switched adapters checks whether the
+    /// adapter it started with works again, to switch back to it.
+    pub eth_adapter_recovery_check_interval: Duration,
+
+    /// How often to try again to write a block while the store is read-only,
+    /// for example during a database failover. Deployments keep the block
+    /// in memory and don't process further blocks until it is written. A
+    /// zero interval fails the deployment like other store errors do.
+    pub read_only_store_retry_interval: Duration,
+
+    /// Check entity modifications against the subgraph schema before
+    /// writing them to the store.
+    pub validate_entity_modifications: bool,
+
+    /// Deployments further behind the chain head than this use the
+    /// historical profile, all others the head profile.
+    pub historical_profile_head_distance: BlockNumber,
+
+    /// A deployment that uses the historical processing profile and does
+    /// not get closer to the chain head within this time while the chain
+    /// advances is flagged as stalled; zero disables this.
+    pub stall_window: Duration,
+
+    /// Block stream parameters used while catching up with the chain head.
+    pub historical_profile: BlockStreamProfile,
+
+    /// Block stream parameters used close to the chain head.
+    pub head_profile: BlockStreamProfile,
+
+    /// Process blocks with the historical profile in chunks of this many
+    /// blocks. The block pointer is always written at the end of a chunk,
+    /// and the block stream is restarted for the next one; see
+    /// `SubgraphInstanceManager::backfill_progress`. 0 disables chunking.
+    pub backfill_chunk_size: BlockNumber,
+
+    /// Hook that is run on the entity modifications of every block before
+    /// they are written to the store.
+    pub entity_modification_hook: Option<EntityModificationHook>,
+
+    /// Run `entity_modification_hook` for subgraphs with a proof of
+    /// indexing, too.
+    pub allow_entity_modification_hook_with_poi: bool,
+
+    /// Hook that decides for every trigger whether it is processed.
+    pub trigger_filter_hook: Option<TriggerFilterHook>,
+
+    /// Run `trigger_filter_hook` for subgraphs with a proof of indexing, too.
+    pub allow_trigger_filter_hook_with_poi: bool,
+
+    /// Signs the proof of indexing bundles that
+    /// `SubgraphInstanceManager::proof_of_indexing_bundle` produces; they are
+    /// not signed if this is `None`.
+    pub poi_bundle_signer: Option<PoiBundleSigner>,
+
+    /// The most blocks a proof of indexing bundle may cover.
+    pub poi_bundle_max_blocks: usize,
+
+    /// How many blocks apart the proof of indexing is written for
+    /// deployments that do not have their own setting; see
+    /// `SubgraphInstanceManager::set_poi_write_interval`.
+    pub poi_write_interval: BlockNumber,
+
+    /// Allow `SubgraphInstanceManager::inject_trigger`. Injected triggers
+    /// are never written, but they run handlers against the store and
+    /// Ethereum nodes of a production deployment.
+    pub allow_trigger_injection: bool,
+
+    /// Allow `SubgraphInstanceManager::set_synthetic_reorgs`. Synthetic
+    /// reorgs revert blocks that are on the main chain and are only meant
+    /// for testing.
+    pub allow_synthetic_reorgs: bool,
+
+    /// Hook that is called for every block after it has been written to
+    /// the store.
+    pub block_committed_hook: Option<BlockCommittedHook>,
+
+    /// Pass the proof of indexing digests of each block to
+    /// `block_committed_hook`.
+    pub block_committed_hook_with_poi: bool,
+
+    /// Read entities that are not in the entity cache from this store
+    /// before reading them from the primary store. It is bypassed for
+    /// blocks for which it does not have the entities as of the block the
+    /// deployment last processed.
+    pub read_through_store: Option<ReadThroughStore>,
+
+    /// Compare a sample of the entity cache against the store every this
+    /// many blocks; 0 disables the check.
+    pub entity_cache_check_interval: BlockNumber,
+
+    /// The maximum number of cache entries to compare in one check.
+    pub entity_cache_check_sample_size: usize,
+
+    /// Where to get the entities from that deployments load into their
+    /// entity cache before they process their first block.
+    pub entity_cache_warmup: Option<EntityCacheWarmup>,
+
+    /// How many entities to load with one query during the warmup.
+    pub entity_cache_warmup_batch_size: usize,
+
+    /// How many warmup queries may run at the same time.
+    pub entity_cache_warmup_concurrency: usize,
+
+    /// When to give up on the warmup and start processing blocks.
+    pub entity_cache_warmup_timeout: Duration,
+
+    /// What to do with duplicate dynamic data sources for deployments that
+    /// do not have their own policy.
+    pub duplicate_data_source_policy: DuplicateDataSourcePolicy,
+
+    /// The order in which the data sources of the manifest and dynamic data
+    /// sources process the triggers of a block. It changes the data and the
+    /// proof of indexing of subgraphs whose handlers depend on each other.
+    pub data_source_order: DataSourceOrder,
+
+    /// Yield to other tasks on the indexing thread after processing this
+    /// many triggers of a block, so that blocks with many triggers don't
+    /// starve them; 0 never yields.
+    pub trigger_yield_interval: usize,
+
+    /// Log which data sources handled the first and then every this many
+    /// triggers of a block, for example to check that the filters of a
+    /// subgraph with overlapping addresses send triggers to the intended
+    /// handlers; 0 never logs them.
+    pub trigger_match_log_interval: usize,
+
+    /// How many hosts may process a trigger at the same time for deployments
+    /// that do not have their own setting; see
+    /// `SubgraphInstanceManager::set_dynamic_data_source_trigger_concurrency`.
+    pub dynamic_data_source_trigger_concurrency: usize,
+
+    /// Drop duplicate log and block triggers of a block so that their
+    /// handlers only run once; see `dedupe_triggers`.
+    pub dedupe_triggers: bool,
+
+    /// Wait for the next event of the block stream while a block is being
+    /// processed, so that it is ready once the block has been committed. At
+    /// most one event is fetched ahead, and only from the blocks the stream
+    /// has buffered.
+    pub prefetch_next_block: bool,
+
+    /// When to delay restarting deployments that keep failing.
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// For final blocks that don't change any data, only write the block
+    /// pointer every this many blocks, or once `pointer_commit_interval`
+    /// has passed since the last write. 1 writes it for every block.
+    pub pointer_commit_interval_blocks: BlockNumber,
+
+    /// The longest time for which writing the block pointer may be put off.
+    pub pointer_commit_interval: Duration,
+
+    /// Also push the metrics of each deployment to a sink.
+    pub metrics_push: Option<MetricsPush>,
+
+    /// Publish the entity modifications of each block that a deployment
+    /// writes, and the reverts of blocks, to a sink.
+    pub modifications_publisher: Option<ModificationsPublisher>,
+
+    /// Tell a listener when deployments start and finish reverting blocks.
+    pub reorg_notifier: Option<ReorgNotifier>,
+
+    /// Log the types of the entities that each reverted block changed.
+    pub log_reverted_entity_types: bool,
+
+    /// Warn about blocks with more triggers than this before processing
+    /// them.
+    pub trigger_count_warn_threshold: usize,
+
+    /// Warn when the log, call and block filters of a subgraph have more
+    /// than this many entries combined; 0 means no limit.
+    pub max_filter_size: usize,
+
+    /// Fail subgraphs whose filters are larger than `max_filter_size`
+    /// instead of only warning about them.
+    pub fail_on_max_filter_size: bool,
+
+    /// Refuse to start subgraphs on networks other than these, or accept
+    /// all networks if `None`.
+    pub allowed_networks: Option<BTreeSet<String>>,
+
+    /// A subgraph whose block stream asks to revert the genesis block is
+    /// stopped; this also marks it as failed.
+    pub fail_on_genesis_revert: bool,
+
+    /// How often loading the block that a subgraph reverts may fail before
+    /// `revert_block_fallback` applies; 0 retries forever. Store errors while
+    /// reverting the block don't count.
+    pub revert_block_load_attempts: usize,
+
+    /// What to do once loading the block that a subgraph reverts failed
+    /// `revert_block_load_attempts` times.
+    pub revert_block_fallback: RevertBlockFallback,
+
+    /// How many recently processed blocks each deployment remembers the
+    /// parent of. Reverting one of them then takes the parent from memory
+    /// instead of loading the block; 0 disables this.
+    pub revert_block_buffer_size: usize,
+
+    /// Pause a subgraph, as if it had reached a breakpoint, once it wrote
+    /// this many deterministic errors within `auto_pause_window` blocks, so
+    /// that it can be inspected before it writes more; 0 disables pausing.
+    /// Only subgraphs with the `nonFatalErrors` feature keep processing
+    /// blocks after a deterministic error.
+    pub auto_pause_errors: usize,
+
+    /// The number of blocks in which errors are counted for
+    /// `auto_pause_errors`.
+    pub auto_pause_window: BlockNumber,
+
+    /// How many of the deployments assigned to a node get their own
+    /// `SubgraphInstanceMetrics`, picked as described for `has_own_metrics`;
+    /// the metrics of all other deployments are added up in metrics whose
+    /// `deployment` label is `other`. 0 means that every deployment gets its
+    /// own metrics.
+    pub metrics_deployment_limit: usize,
+
+    /// The IPFS hashes of the deployments that get their own metrics even
+    /// beyond `metrics_deployment_limit`.
+    pub metrics_deployments: BTreeSet<String>,
+
+    /// The labels that operators attach to the metrics of deployments. All
+    /// deployments get the same label names.
+    pub deployment_labels: NodeDeploymentLabels,
+}
+
+impl SubgraphInstanceManagerConfig {
+    /// Read the settings from the environment variables described in
+    /// `docs/environment-variables.md`, and take the `Default` for those
+    /// that are not set. The hooks, sinks and stores that can't be set in
+    /// the environment are left unset. Panics if a variable is invalid.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        SubgraphInstanceManagerConfig {
+            entity_cache_size: 1000
+                * env_var("GRAPH_ENTITY_CACHE_SIZE", defaults.entity_cache_size / 1000),
+            entity_cache_eviction_warn_fraction: env_var(
+                "GRAPH_ENTITY_CACHE_EVICTION_WARN_FRACTION",
+                defaults.entity_cache_eviction_warn_fraction,
+            ),
+            disable_fail_fast: env_flag("GRAPH_DISABLE_FAIL_FAST"),
+            fail_fast_on_failed_write: !env_flag("GRAPH_NO_FAIL_FAST_ON_FAILED_WRITE"),
+            fail_fast_error_categories: env_list("GRAPH_FAIL_FAST_ERROR_CATEGORIES"),
+            fatal_error_categories: env_list("GRAPH_FATAL_ERROR_CATEGORIES")
+                .unwrap_or(defaults.fatal_error_categories),
+            ipfs_subgraph_loading_timeout: env_secs(
+                "GRAPH_IPFS_SUBGRAPH_LOADING_TIMEOUT",
+                defaults.ipfs_subgraph_loading_timeout,
+            ),
+            ipfs_resolution_concurrency: env_var(
+                "GRAPH_IPFS_RESOLUTION_CONCURRENCY",
+                defaults.ipfs_resolution_concurrency,
+            ),
+            start_retries: env_var("GRAPH_START_RETRIES", defaults.start_retries),
+            start_retry_backoff: env_millis(
+                "GRAPH_START_RETRY_BACKOFF",
+                defaults.start_retry_backoff,
+            ),
+            transact_block_operations_retries: env_var(
+                "GRAPH_TRANSACT_BLOCK_OPERATIONS_RETRIES",
+                defaults.transact_block_operations_retries,
+            ),
+            transact_block_operations_retry_backoff: env_millis(
+                "GRAPH_TRANSACT_BLOCK_OPERATIONS_RETRY_BACKOFF",
+                defaults.transact_block_operations_retry_backoff,
+            ),
+            possible_reorg_checks: env_var(
+                "GRAPH_POSSIBLE_REORG_CHECKS",
+                defaults.possible_reorg_checks,
+            ),
+            possible_reorg_check_delay: env_millis(
+                "GRAPH_POSSIBLE_REORG_CHECK_DELAY",
+                defaults.possible_reorg_check_delay,
+            ),
+            eth_adapter_failover_errors: env_var(
+                "GRAPH_ETH_ADAPTER_FAILOVER_ERRORS",
+                defaults.eth_adapter_failover_errors,
+            ),
+            eth_adapter_recovery_check_interval: env_secs(
+                "GRAPH_ETH_ADAPTER_RECOVERY_CHECK_INTERVAL",
+                defaults.eth_adapter_recovery_check_interval,
+            ),
+            read_only_store_retry_interval: env_secs(
+                "GRAPH_READ_ONLY_STORE_RETRY_INTERVAL",
+                defaults.read_only_store_retry_interval,
+            ),
+            validate_entity_modifications: env_flag("GRAPH_VALIDATE_ENTITY_MODIFICATIONS"),
+            historical_profile_head_distance: env_var(
+                "GRAPH_HISTORICAL_PROFILE_HEAD_DISTANCE",
+                defaults.historical_profile_head_distance,
+            ),
+            stall_window: env_secs("GRAPH_STALL_WINDOW", defaults.stall_window),
+            historical_profile: BlockStreamProfile {
+                max_block_range_size: env_var(
+                    "GRAPH_HISTORICAL_PROFILE_MAX_BLOCK_RANGE_SIZE",
+                    defaults.historical_profile.max_block_range_size,
+                ),
+                buffer_depth: env_var(
+                    "GRAPH_HISTORICAL_PROFILE_BUFFER_DEPTH",
+                    defaults.historical_profile.buffer_depth,
+                ),
+                confirmations: env_var(
+                    "GRAPH_HISTORICAL_PROFILE_CONFIRMATIONS",
+                    defaults.historical_profile.confirmations,
+                ),
+            },
+            head_profile: BlockStreamProfile {
+                max_block_range_size: env_var(
+                    "GRAPH_HEAD_PROFILE_MAX_BLOCK_RANGE_SIZE",
+                    defaults.head_profile.max_block_range_size,
+                ),
+                buffer_depth: env_var(
+                    "GRAPH_HEAD_PROFILE_BUFFER_DEPTH",
+                    defaults.head_profile.buffer_depth,
+                ),
+                confirmations: env_var(
+                    "GRAPH_HEAD_PROFILE_CONFIRMATIONS",
+                    defaults.head_profile.confirmations,
+                ),
+            },
+            backfill_chunk_size: env_var("GRAPH_BACKFILL_CHUNK_SIZE", defaults.backfill_chunk_size),
+            allow_entity_modification_hook_with_poi: env_flag(
+                "GRAPH_ALLOW_ENTITY_MODIFICATION_HOOK_WITH_POI",
+            ),
+            allow_trigger_filter_hook_with_poi: env_flag(
+                "GRAPH_ALLOW_TRIGGER_FILTER_HOOK_WITH_POI",
+            ),
+            allow_trigger_injection: env_flag("GRAPH_ALLOW_TRIGGER_INJECTION"),
+            allow_synthetic_reorgs: env_flag("GRAPH_ALLOW_SYNTHETIC_REORGS"),
+            poi_bundle_max_blocks: env_var(
+                "GRAPH_POI_BUNDLE_MAX_BLOCKS",
+                defaults.poi_bundle_max_blocks,
+            ),
+            poi_write_interval: env_var("GRAPH_POI_WRITE_INTERVAL", defaults.poi_write_interval),
+            block_committed_hook_with_poi: env_flag("GRAPH_BLOCK_COMMITTED_HOOK_WITH_POI"),
+            entity_cache_check_interval: env_var(
+                "GRAPH_ENTITY_CACHE_CHECK_INTERVAL",
+                defaults.entity_cache_check_interval,
+            ),
+            entity_cache_check_sample_size: env_var(
+                "GRAPH_ENTITY_CACHE_CHECK_SAMPLE_SIZE",
+                defaults.entity_cache_check_sample_size,
+            ),
+            entity_cache_warmup_batch_size: env_var(
+                "GRAPH_ENTITY_CACHE_WARMUP_BATCH_SIZE",
+                defaults.entity_cache_warmup_batch_size,
+            ),
+            entity_cache_warmup_concurrency: env_var(
+                "GRAPH_ENTITY_CACHE_WARMUP_CONCURRENCY",
+                defaults.entity_cache_warmup_concurrency,
+            ),
+            entity_cache_warmup_timeout: env_secs(
+                "GRAPH_ENTITY_CACHE_WARMUP_TIMEOUT",
+                defaults.entity_cache_warmup_timeout,
+            ),
+            duplicate_data_source_policy: env_var(
+                "GRAPH_DUPLICATE_DATA_SOURCE_POLICY",
+                defaults.duplicate_data_source_policy,
+            ),
+            data_source_order: env_var("GRAPH_DATA_SOURCE_ORDER", defaults.data_source_order),
+            trigger_yield_interval: env_var(
+                "GRAPH_TRIGGER_YIELD_INTERVAL",
+                defaults.trigger_yield_interval,
+            ),
+            trigger_match_log_interval: env_var(
+                "GRAPH_TRIGGER_MATCH_LOG_INTERVAL",
+                defaults.trigger_match_log_interval,
+            ),
+            dynamic_data_source_trigger_concurrency: env_var(
+                "GRAPH_DYNAMIC_DATA_SOURCE_TRIGGER_CONCURRENCY",
+                defaults.dynamic_data_source_trigger_concurrency,
+            ),
+            dedupe_triggers: env_flag("GRAPH_DEDUPE_TRIGGERS"),
+            prefetch_next_block: env_flag("GRAPH_PREFETCH_NEXT_BLOCK"),
+            circuit_breaker: CircuitBreakerConfig {
+                failures: env_var(
+                    "GRAPH_CIRCUIT_BREAKER_FAILURES",
+                    defaults.circuit_breaker.failures,
+                ),
+                window: env_secs(
+                    "GRAPH_CIRCUIT_BREAKER_WINDOW",
+                    defaults.circuit_breaker.window,
+                ),
+                base_delay: env_secs(
+                    "GRAPH_CIRCUIT_BREAKER_BASE_DELAY",
+                    defaults.circuit_breaker.base_delay,
+                ),
+                max_delay: env_secs(
+                    "GRAPH_CIRCUIT_BREAKER_MAX_DELAY",
+                    defaults.circuit_breaker.max_delay,
+                ),
+                reset_after: env_secs(
+                    "GRAPH_CIRCUIT_BREAKER_RESET_AFTER",
+                    defaults.circuit_breaker.reset_after,
+                ),
+            },
+            pointer_commit_interval_blocks: env_var(
+                "GRAPH_POINTER_COMMIT_INTERVAL_BLOCKS",
+                defaults.pointer_commit_interval_blocks,
+            ),
+            pointer_commit_interval: env_secs(
+                "GRAPH_POINTER_COMMIT_INTERVAL",
+                defaults.pointer_commit_interval,
+            ),
+            log_reverted_entity_types: env_flag("GRAPH_LOG_REVERTED_ENTITY_TYPES"),
+            trigger_count_warn_threshold: env_var(
+                "GRAPH_TRIGGER_COUNT_WARN_THRESHOLD",
+                defaults.trigger_count_warn_threshold,
+            ),
+            max_filter_size: env_var("GRAPH_MAX_FILTER_SIZE", defaults.max_filter_size),
+            fail_on_max_filter_size: env_flag("GRAPH_FAIL_ON_MAX_FILTER_SIZE"),
+            allowed_networks: env_list("GRAPH_ALLOWED_NETWORKS"),
+            fail_on_genesis_revert: env_flag("GRAPH_FAIL_ON_GENESIS_REVERT"),
+            revert_block_load_attempts: env_var(
+                "GRAPH_REVERT_BLOCK_LOAD_ATTEMPTS",
+                defaults.revert_block_load_attempts,
+            ),
+            revert_block_fallback: env_var(
+                "GRAPH_REVERT_BLOCK_FALLBACK",
+                defaults.revert_block_fallback,
+            ),
+            revert_block_buffer_size: env_var(
+                "GRAPH_REVERT_BLOCK_BUFFER_SIZE",
+                defaults.revert_block_buffer_size,
+            ),
+            auto_pause_errors: env_var("GRAPH_AUTO_PAUSE_ERRORS", defaults.auto_pause_errors),
+            auto_pause_window: env_var(
+                "GRAPH_AUTO_PAUSE_WINDOW_BLOCKS",
+                defaults.auto_pause_window,
+            ),
+            metrics_deployment_limit: env_var(
+                "GRAPH_METRICS_DEPLOYMENT_LIMIT",
+                defaults.metrics_deployment_limit,
+            ),
+            metrics_deployments: env_list("GRAPH_METRICS_DEPLOYMENTS")
+                .unwrap_or(defaults.metrics_deployments),
+            deployment_labels: std::env::var("GRAPH_DEPLOYMENT_LABELS")
+                .map(|labels| {
+                    NodeDeploymentLabels::parse(&labels).expect("invalid GRAPH_DEPLOYMENT_LABELS")
+                })
+                .unwrap_or(defaults.deployment_labels),
+            ..defaults
         }
     }
 
-    pub fn observe_trigger_processing_duration(&self, duration: f64, trigger: TriggerType) {
-        self.trigger_processing_duration
-            .with_label_values(vec![trigger.label_value()].as_slice())
-            .observe(duration);
+    /// Whether a deployment that is `head_distance` blocks behind the chain
+    /// head should use the historical profile.
+    fn use_historical_profile(&self, head_distance: BlockNumber) -> bool {
+        head_distance > self.historical_profile_head_distance
+    }
+
+    fn profile(&self, historical: bool) -> BlockStreamProfile {
+        if historical {
+            self.historical_profile
+        } else {
+            self.head_profile
+        }
     }
 
-    pub fn unregister<M: MetricsRegistry>(&self, registry: Arc<M>) {
-        registry.unregister(self.block_processing_duration.clone());
-        registry.unregister(self.block_trigger_count.clone());
-        registry.unregister(self.trigger_processing_duration.clone());
-        registry.unregister(self.block_ops_transaction_duration.clone());
+    fn check_network_allowed(&self, network: &str) -> Result<(), Error> {
+        match &self.allowed_networks {
+            Some(allowed) if !allowed.contains(network) => Err(anyhow!(
+                "subgraphs on network `{}` are not indexed by this node, which only \
+                 indexes subgraphs on {}",
+                network,
+                allowed.iter().cloned().collect::<Vec<_>>().join(", ")
+            )),
+            _ => Ok(()),
+        }
     }
 }
 
-#[async_trait]
-impl<B, S, BS, M, H, L> SubgraphInstanceManagerTrait for SubgraphInstanceManager<B, S, BS, M, H, L>
-where
-    S: SubgraphStore,
-    BS: BlockStore,
-    B: BlockStreamBuilder,
-    M: MetricsRegistry,
-    H: RuntimeHostBuilder,
-    L: LinkResolver + Clone,
-{
-    async fn start_subgraph(
-        self: Arc<Self>,
-        id: SubgraphDeploymentId,
-        manifest: serde_yaml::Mapping,
-    ) {
-        let logger = self.logger_factory.subgraph_logger(&id);
+/// The network of the unresolved `manifest`, taken from its first data
+/// source that names one. This does not need to access IPFS.
+fn raw_manifest_network(manifest: &serde_yaml::Mapping) -> Option<String> {
+    manifest
+        .get(&serde_yaml::Value::from("dataSources"))?
+        .as_sequence()?
+        .iter()
+        .filter_map(|data_source| data_source.get("network")?.as_str())
+        .next()
+        .map(|network| network.to_string())
+}
 
-        match Self::start_subgraph_inner(
-            logger.clone(),
-            self.instances.clone(),
-            self.host_builder.clone(),
-            self.block_stream_builder.clone(),
-            self.subgraph_store.cheap_clone(),
-            self.block_store.cheap_clone(),
-            self.eth_networks.clone(),
-            id,
-            manifest,
-            self.metrics_registry.cheap_clone(),
-            self.link_resolver.cheap_clone(),
-        )
-        .await
-        {
-            Ok(()) => self.manager_metrics.subgraph_count.inc(),
-            Err(err) => error!(
-                logger,
-                "Failed to start subgraph";
-                "error" => format!("{}", err),
-                "code" => LogCode::SubgraphStartFailure
-            ),
+impl Default for SubgraphInstanceManagerConfig {
+    fn default() -> Self {
+        SubgraphInstanceManagerConfig {
+            entity_cache_size: 10_000_000,
+            entity_cache_eviction_warn_fraction: 0.5,
+            disable_fail_fast: false,
+            fail_fast_on_failed_write: true,
+            fail_fast_error_categories: None,
+            fatal_error_categories: BTreeSet::new(),
+            ipfs_subgraph_loading_timeout: Duration::from_secs(60),
+            ipfs_resolution_concurrency: 0,
+            start_retries: 0,
+            start_retry_backoff: Duration::from_millis(1000),
+            transact_block_operations_retries: 3,
+            transact_block_operations_retry_backoff: Duration::from_millis(500),
+            possible_reorg_checks: 0,
+            possible_reorg_check_delay: Duration::from_millis(500),
+            eth_adapter_failover_errors: 0,
+            eth_adapter_recovery_check_interval: Duration::from_secs(60),
+            read_only_store_retry_interval: Duration::from_secs(10),
+            validate_entity_modifications: false,
+            historical_profile_head_distance: 1000,
+            stall_window: Duration::from_secs(600),
+            historical_profile: BlockStreamProfile {
+                max_block_range_size: 2000,
+                buffer_depth: 2000,
+                confirmations: 0,
+            },
+            head_profile: BlockStreamProfile {
+                max_block_range_size: 100,
+                buffer_depth: 100,
+                confirmations: 0,
+            },
+            backfill_chunk_size: 0,
+            entity_modification_hook: None,
+            allow_entity_modification_hook_with_poi: false,
+            trigger_filter_hook: None,
+            allow_trigger_filter_hook_with_poi: false,
+            poi_bundle_signer: None,
+            poi_bundle_max_blocks: 1000,
+            poi_write_interval: 1,
+            allow_trigger_injection: false,
+            allow_synthetic_reorgs: false,
+            block_committed_hook: None,
+            block_committed_hook_with_poi: false,
+            read_through_store: None,
+            entity_cache_check_interval: 0,
+            entity_cache_check_sample_size: 100,
+            entity_cache_warmup: None,
+            entity_cache_warmup_batch_size: 1000,
+            entity_cache_warmup_concurrency: 4,
+            entity_cache_warmup_timeout: Duration::from_secs(60),
+            duplicate_data_source_policy: DuplicateDataSourcePolicy::Drop,
+            data_source_order: DataSourceOrder::Trigger,
+            trigger_yield_interval: 0,
+            trigger_match_log_interval: 0,
+            dynamic_data_source_trigger_concurrency: 1,
+            dedupe_triggers: false,
+            prefetch_next_block: false,
+            circuit_breaker: CircuitBreakerConfig {
+                failures: 5,
+                window: Duration::from_secs(600),
+                base_delay: Duration::from_secs(30),
+                max_delay: Duration::from_secs(1800),
+                reset_after: Duration::from_secs(3600),
+            },
+            pointer_commit_interval_blocks: 1,
+            pointer_commit_interval: Duration::from_secs(60),
+            metrics_push: None,
+            modifications_publisher: None,
+            reorg_notifier: None,
+            log_reverted_entity_types: false,
+            trigger_count_warn_threshold: 10000,
+            max_filter_size: 0,
+            fail_on_max_filter_size: false,
+            allowed_networks: None,
+            fail_on_genesis_revert: false,
+            revert_block_load_attempts: 0,
+            revert_block_fallback: RevertBlockFallback::Fail,
+            revert_block_buffer_size: 0,
+            auto_pause_errors: 0,
+            auto_pause_window: 1000,
+            metrics_deployment_limit: 0,
+            metrics_deployments: BTreeSet::new(),
+            deployment_labels: NodeDeploymentLabels::default(),
         }
     }
+}
+
+/// The value of the environment variable `name`, or `default` if it is not
+/// set. Panics if the value can't be parsed.
+fn env_var<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("invalid {}", name)),
+        Err(_) => default,
+    }
+}
+
+/// A duration given in seconds in the environment variable `name`.
+fn env_secs(name: &str, default: Duration) -> Duration {
+    Duration::from_secs(env_var(name, default.as_secs()))
+}
+
+/// A duration given in milliseconds in the environment variable `name`.
+fn env_millis(name: &str, default: Duration) -> Duration {
+    Duration::from_millis(env_var(name, default.as_millis() as u64))
+}
+
+/// Whether the environment variable `name` is set, to any value.
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok()
+}
+
+/// The comma separated list in the environment variable `name`, or `None`
+/// if it is not set. Empty entries are skipped.
+fn env_list<T: FromStr + Ord>(name: &str) -> Option<BTreeSet<T>> {
+    std::env::var(name).ok().map(|list| {
+        list.split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+            .map(|item| item.parse().unwrap_or_else(|_| panic!("invalid {}", name)))
+            .collect()
+    })
+}
+
+type SharedInstanceKeepAliveMap = Arc<RwLock<HashMap<SubgraphDeploymentId, CancelGuard>>>;
+
+type SharedBreakpointMap = Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<Breakpoint>>>>;
+
+type SharedHeadRecheckMap = Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<Notify>>>>;
+
+/// Dropping the sender for a deployment cancels resolving its files if it is
+/// still starting.
+type SharedStartCancelMap = Arc<RwLock<HashMap<SubgraphDeploymentId, oneshot::Sender<()>>>>;
+
+/// Resolves once the thread that indexes a deployment has exited, since the
+/// thread holds on to the sender until then.
+type SharedThreadExitMap =
+    Arc<RwLock<HashMap<SubgraphDeploymentId, futures03::future::Shared<oneshot::Receiver<()>>>>>;
+
+type SharedTriggerInjectionMap =
+    Arc<RwLock<HashMap<SubgraphDeploymentId, mpsc::UnboundedSender<TriggerInjection>>>>;
+
+type SharedCacheCompactionMap =
+    Arc<RwLock<HashMap<SubgraphDeploymentId, mpsc::UnboundedSender<CacheCompactionRequest>>>>;
+
+type SharedBackfillProgressMap =
+    Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<Mutex<BackfillProgress>>>>>;
+
+type SharedManifestMap = Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<SubgraphManifest>>>>;
+
+type SharedLogLevelMap = Arc<RwLock<HashMap<SubgraphDeploymentId, LogLevelHandle>>>;
+
+type SharedDeploymentOverridesMap = Arc<RwLock<HashMap<SubgraphDeploymentId, DeploymentOverrides>>>;
+
+type SharedCircuitBreakerMap = Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<CircuitBreaker>>>>;
+
+type SharedVerificationMap = Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<Mutex<Verification>>>>>;
+
+type SharedHealthMap = Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<Mutex<HealthState>>>>>;
+
+type SharedContextDumpMap =
+    Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<Mutex<IndexingContextDump>>>>>;
+
+type SharedStartArgsMap =
+    Arc<RwLock<HashMap<SubgraphDeploymentId, (Arc<serde_yaml::Mapping>, DeploymentLabels)>>>;
+
+/// What operators changed for a deployment through the `set_*` methods of
+/// `SubgraphInstanceManager` that don't store their setting with the
+/// deployment. Like the stored settings, they take effect the next time the
+/// deployment is started.
+#[derive(Clone, Default)]
+struct DeploymentOverrides {
+    end_block: Option<BlockNumber>,
+    duplicate_data_source_policy: Option<DuplicateDataSourcePolicy>,
+    dynamic_data_source_trigger_concurrency: Option<usize>,
+    include_calls: Option<bool>,
+    verify_only: bool,
+    catch_up_only: bool,
+    synthetic_reorgs: BTreeSet<BlockNumber>,
+    entity_cache_limit_group: Option<Arc<EntityCacheLimitGroup>>,
+}
+
+/// The settings a deployment runs with. They are looked up once when it
+/// starts, from its overrides, the settings stored with it and the config
+/// of the node; see `SubgraphInstanceManager::deployment_settings`.
+#[derive(Clone)]
+struct DeploymentSettings {
+    /// See `SubgraphInstanceManager::set_end_block`.
+    end_block: Option<BlockNumber>,
+
+    /// See `SubgraphInstanceManager::set_catch_up_only`.
+    catch_up_only: bool,
+
+    /// See `SubgraphInstanceManager::set_verify_only`.
+    verify_only: bool,
+
+    /// Whether to include calls in blocks regardless of the manifest; see
+    /// `SubgraphInstanceManager::set_include_calls_in_blocks`.
+    include_calls: Option<bool>,
+    duplicate_data_source_policy: DuplicateDataSourcePolicy,
+    dynamic_data_source_trigger_concurrency: usize,
+
+    /// See `SubgraphInstanceManager::set_poi_write_interval`.
+    poi_write_interval: BlockNumber,
+    disable_fail_fast: bool,
+
+    /// The blocks whose triggers are not processed; see
+    /// `SubgraphInstanceManager::set_skip_blocks`.
+    skip_blocks: Arc<BTreeSet<BlockNumber>>,
+
+    /// See `SubgraphInstanceManager::set_synthetic_reorgs`.
+    synthetic_reorgs: BTreeSet<BlockNumber>,
+
+    /// The group whose size limit the entity cache shares; see
+    /// `SubgraphInstanceManager::set_entity_cache_limit_group`.
+    entity_cache_limit_group: Option<Arc<EntityCacheLimitGroup>>,
+}
+
+/// What a running deployment shares with the `SubgraphInstanceManager`.
+/// Only `health` and `context_dump` are new for every start; the others
+/// are kept across restarts.
+#[derive(Clone)]
+struct DeploymentHandles {
+    breakpoint: Arc<Breakpoint>,
+    head_recheck: Arc<Notify>,
+    circuit_breaker: Arc<CircuitBreaker>,
+    verification: Option<Arc<Mutex<Verification>>>,
+    health: Arc<Mutex<HealthState>>,
+    context_dump: Arc<Mutex<IndexingContextDump>>,
+    backfill_progress: Arc<Mutex<BackfillProgress>>,
+}
+
+#[derive(Default)]
+struct EntityCacheLimitGroupState {
+    /// The schema and network that all members must have. It is taken from
+    /// the first member that joins an empty group.
+    signature: Option<(String, String)>,
+
+    /// The weight of the entity cache of each running member.
+    weights: HashMap<SubgraphDeploymentId, usize>,
+}
+
+/// A group of deployments whose entity caches share one size limit instead
+/// of each getting `entity_cache_size` for itself, so that running many
+/// versions of the same subgraph does not multiply the memory that their
+/// caches use. Only the limit is shared, not the entries: each member keeps
+/// its own cache, since the same entity can have a different value in a
+/// deployment that is at a different block. See
+/// `SubgraphInstanceManager::set_entity_cache_limit_group`.
+struct EntityCacheLimitGroup {
+    name: String,
+    state: Mutex<EntityCacheLimitGroupState>,
+}
+
+impl EntityCacheLimitGroup {
+    fn new(name: String) -> Self {
+        EntityCacheLimitGroup {
+            name,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Add the deployment `id` to the group, which fails if its schema or
+    /// network differ from those of the other members.
+    fn join(&self, id: &SubgraphDeploymentId, schema: &Schema, network: &str) -> Result<(), Error> {
+        let signature = (schema.document.to_string(), network.to_owned());
+        let mut state = self.state.lock().unwrap();
+        state.weights.remove(id);
+        if state.weights.is_empty() {
+            state.signature = Some(signature);
+        } else if state.signature.as_ref() != Some(&signature) {
+            return Err(anyhow!(
+                "deployment {} can not share the entity cache size limit of group `{}` since \
+                 its schema or network differ from those of the deployments in the group",
+                id,
+                self.name
+            ));
+        }
+        state.weights.insert(id.clone(), 0);
+        Ok(())
+    }
+
+    fn leave(&self, id: &SubgraphDeploymentId) {
+        self.state.lock().unwrap().weights.remove(id);
+    }
+
+    /// The weight that the cache of the member `id` may have, given that the
+    /// whole group may use `limit`. Every member can use at least its fair
+    /// share of `limit`, even if other members use more than theirs.
+    fn limit(&self, id: &SubgraphDeploymentId, limit: usize) -> usize {
+        let state = self.state.lock().unwrap();
+        let others: usize = state
+            .weights
+            .iter()
+            .filter(|(member, _)| *member != id)
+            .map(|(_, weight)| weight)
+            .sum();
+        let fair_share = limit / state.weights.len().max(1);
+        limit.saturating_sub(others).max(fair_share)
+    }
+
+    fn set_weight(&self, id: &SubgraphDeploymentId, weight: usize) {
+        if let Some(member) = self.state.lock().unwrap().weights.get_mut(id) {
+            *member = weight;
+        }
+    }
+}
+
+/// The number of recently processed blocks from which the throughput of a
+/// deployment is estimated.
+const THROUGHPUT_WINDOW: usize = 100;
+
+/// The part of `DeploymentHealth` that the indexing loop of a deployment
+/// keeps up to date.
+#[derive(Default)]
+struct HealthState {
+    synced: bool,
+    head_distance: Option<BlockNumber>,
+    block_ptr: Option<EthereumBlockPointer>,
+    error_count: u64,
+    restart_count: u64,
+    stalled: bool,
+    drained: bool,
+
+    /// The number of triggers the deployment processed since it was
+    /// started.
+    trigger_count: u64,
+
+    /// When the deployment got to each of the last `THROUGHPUT_WINDOW`
+    /// blocks it processed, and its `trigger_count` at that point.
+    recent_blocks: VecDeque<(Instant, BlockNumber, u64)>,
+}
+
+impl HealthState {
+    fn record_block(&mut self, number: BlockNumber) {
+        if self.recent_blocks.len() == THROUGHPUT_WINDOW {
+            self.recent_blocks.pop_front();
+        }
+        self.recent_blocks
+            .push_back((Instant::now(), number, self.trigger_count));
+    }
+
+    /// The number of blocks per second that the deployment processed
+    /// recently, or `None` if it did not process enough blocks for an
+    /// estimate.
+    fn blocks_per_second(&self) -> Option<f64> {
+        let (first_time, first_number, _) = self.recent_blocks.front()?;
+        let (last_time, last_number, _) = self.recent_blocks.back()?;
+        let elapsed = last_time.duration_since(*first_time).as_secs_f64();
+        let blocks = (last_number - first_number) as f64;
+        if elapsed > 0.0 && blocks > 0.0 {
+            Some(blocks / elapsed)
+        } else {
+            None
+        }
+    }
+
+    /// The number of triggers per second that the deployment processed
+    /// over the same blocks as `blocks_per_second`.
+    fn triggers_per_second(&self) -> Option<f64> {
+        let (first_time, _, first_count) = self.recent_blocks.front()?;
+        let (last_time, _, last_count) = self.recent_blocks.back()?;
+        let elapsed = last_time.duration_since(*first_time).as_secs_f64();
+        if elapsed > 0.0 {
+            Some((last_count - first_count) as f64 / elapsed)
+        } else {
+            None
+        }
+    }
+}
+
+struct IndexingInputs<B, S, C> {
+    deployment_id: SubgraphDeploymentId,
+    features: BTreeSet<SubgraphFeature>,
+    network_name: String,
+    start_blocks: Vec<BlockNumber>,
+    store: Arc<S>,
+    chain_store: Arc<C>,
+    eth_networks: EthereumNetworks,
+    required_capabilities: NodeCapabilities,
+    eth_adapters: Arc<EthAdapterSelector>,
+    stream_builder: B,
+    include_calls_in_blocks: bool,
+    templates: Arc<Vec<DataSourceTemplate>>,
+    schema: Arc<Schema>,
+    config: Arc<SubgraphInstanceManagerConfig>,
+    breakpoint: Arc<Breakpoint>,
+
+    /// Notified to make the deployment check whether its block pointer is
+    /// still on the main chain; see `SubgraphInstanceManager::recheck_head`.
+    head_recheck: Arc<Notify>,
+    entity_modification_hook: Option<EntityModificationHook>,
+    trigger_filter_hook: Option<TriggerFilterHook>,
+    settings: DeploymentSettings,
+    circuit_breaker: Arc<CircuitBreaker>,
+
+    /// Set for a verify-only run, which compares the data it computes with
+    /// the store instead of writing it.
+    verification: Option<Arc<Mutex<Verification>>>,
+    health: Arc<Mutex<HealthState>>,
+
+    /// The blocks whose triggers the manifest asks to process
+    block_predicate: Option<BlockPredicate>,
+
+    /// Kept up to date for `SubgraphInstanceManager::indexing_context`.
+    context_dump: Arc<Mutex<IndexingContextDump>>,
+
+    /// See `SubgraphInstanceManager::backfill_progress`.
+    backfill_progress: Arc<Mutex<BackfillProgress>>,
+
+    /// Set once the deployment reached its end block, or the chain head if
+    /// it only catches up; see `complete_subgraph`.
+    completed: Arc<AtomicBool>,
+
+    /// The queue of the entity modifications to publish, which the publisher
+    /// thread of the deployment drains and then stops once it is dropped.
+    modifications: Option<Arc<ModificationsQueue>>,
+}
+
+struct IndexingState<T: RuntimeHostBuilder> {
+    logger: Logger,
+    instance: SubgraphInstance<T>,
+    instances: SharedInstanceKeepAliveMap,
+    log_filter: EthereumLogFilter,
+    call_filter: EthereumCallFilter,
+    block_filter: EthereumBlockFilter,
+    entity_lfu_cache: LfuCache<EntityKey, Option<Entity>>,
+
+    /// The last block the subgraph processed. Unless the pointer for some
+    /// blocks was not written, this is also the last block the subgraph
+    /// wrote to the store.
+    block_ptr: Option<EthereumBlockPointer>,
+
+    /// The number of the last block the subgraph wrote to the store, and
+    /// when it did so.
+    last_commit: Option<(BlockNumber, Instant)>,
+
+    /// The last block of the backfill chunk that is being processed. The
+    /// pointer for this block is always written to the store.
+    chunk_end: Option<BlockNumber>,
+
+    /// The recent blocks with deterministic errors and how many errors each
+    /// of them had, oldest first; see `auto_pause_errors`.
+    recent_errors: VecDeque<(BlockNumber, usize)>,
+
+    /// The triggers that operators inject, if that is allowed.
+    trigger_injections: Option<mpsc::UnboundedReceiver<TriggerInjection>>,
+
+    /// The requests of operators to compact the entity cache.
+    cache_compactions: Option<mpsc::UnboundedReceiver<CacheCompactionRequest>>,
+
+    /// The synthetic reorg boundaries the deployment has not reached yet;
+    /// see `SubgraphInstanceManager::set_synthetic_reorgs`.
+    synthetic_reorgs: BTreeSet<BlockNumber>,
+
+    /// When the current window of `stall_window` started, and how far
+    /// behind the chain head the deployment was and where the chain head
+    /// was at that point.
+    stall_window_start: Option<(Instant, BlockNumber, BlockNumber)>,
+
+    /// The digests of the proof of indexing that were not written to the
+    /// store yet.
+    pending_poi: PendingPoi,
+
+    /// How many blocks the reorg that the deployment is in has reverted so
+    /// far; `None` if it is not in a reorg.
+    reorg_depth: Option<BlockNumber>,
+
+    /// The most recently processed blocks and their parents, oldest first;
+    /// see `revert_block_buffer_size`.
+    recent_blocks: VecDeque<(EthereumBlockPointer, EthereumBlockPointer)>,
+}
+
+/// The digests of the proof of indexing of a deployment that writes it only
+/// every `poi_write_interval` blocks. The digest of a block builds on the
+/// previous digest of its causality region, so the digests are the same as
+/// if they were written with every block; the store just lags behind.
+struct PendingPoi {
+    /// The digests of the causality regions that each block changed, since
+    /// the proof of indexing was last written.
+    digests: BTreeMap<BlockNumber, Vec<(String, Bytes)>>,
+
+    /// Whether the store has any digest of the deployment.
+    written: bool,
+}
+
+impl PendingPoi {
+    /// The most recent digest of `causality_region` that is not in the
+    /// store yet.
+    fn digest(&self, causality_region: &str) -> Option<&Bytes> {
+        self.digests
+            .values()
+            .rev()
+            .flat_map(|digests| digests.iter())
+            .find(|(region, _)| region == causality_region)
+            .map(|(_, digest)| digest)
+    }
+
+    /// Whether the digests are written with the block `block`. That is the
+    /// case for the first digests of a deployment, once the oldest pending
+    /// digest is from an earlier interval of `interval` blocks than `block`,
+    /// and for the last block of each interval. Since all nodes process the
+    /// same blocks, they write the digests with the same blocks.
+    fn write_at(&self, block: BlockNumber, interval: BlockNumber) -> bool {
+        let oldest = self.digests.keys().next().cloned().unwrap_or(block);
+        !self.written || oldest / interval != block / interval || block % interval == interval - 1
+    }
+
+    /// Remember the digests that `block` computed once it was processed.
+    fn record(&mut self, block: BlockNumber, digests: &[(String, Bytes)], written: bool) {
+        if written {
+            self.digests.clear();
+            self.written = true;
+        } else if !digests.is_empty() {
+            self.digests.insert(block, digests.to_vec());
+        }
+    }
+
+    /// Forget the digests of the blocks after `ptr`, which will be
+    /// processed again.
+    fn forget_after(&mut self, ptr: Option<&EthereumBlockPointer>) {
+        match ptr {
+            Some(ptr) => {
+                self.digests.split_off(&(ptr.number + 1));
+            }
+            None => self.digests.clear(),
+        }
+    }
+}
+
+struct IndexingContext<B, T: RuntimeHostBuilder, S, C> {
+    /// Read only inputs that are needed while indexing a subgraph.
+    pub inputs: IndexingInputs<B, S, C>,
+
+    /// Mutable state that may be modified while indexing a subgraph.
+    pub state: IndexingState<T>,
+
+    /// Sensors to measure the execution of the subgraph instance
+    pub subgraph_metrics: Arc<SubgraphInstanceMetrics>,
+
+    /// Sensors to measure the execution of the subgraph's runtime hosts
+    pub host_metrics: Arc<HostMetrics>,
+
+    /// Sensors to measure the execution of eth rpc calls
+    pub ethrpc_metrics: Arc<SubgraphEthRpcMetrics>,
+
+    pub block_stream_metrics: Arc<BlockStreamMetrics>,
+
+    pub manager_metrics: Arc<SubgraphInstanceManagerMetrics>,
+}
+
+pub struct SubgraphInstanceManager<B, S, BS, M, H, L> {
+    logger_factory: LoggerFactory,
+    subgraph_store: Arc<S>,
+    block_store: Arc<BS>,
+    eth_networks: EthereumNetworks,
+    host_builder: H,
+    block_stream_builder: B,
+    metrics_registry: Arc<M>,
+    manager_metrics: Arc<SubgraphInstanceManagerMetrics>,
+    instances: SharedInstanceKeepAliveMap,
+    link_resolver: Arc<L>,
+    config: Arc<SubgraphInstanceManagerConfig>,
+    breakpoints: SharedBreakpointMap,
+    head_rechecks: SharedHeadRecheckMap,
+    overrides: SharedDeploymentOverridesMap,
+    manifests: SharedManifestMap,
+    log_levels: SharedLogLevelMap,
+    circuit_breakers: SharedCircuitBreakerMap,
+    verifications: SharedVerificationMap,
+    health: SharedHealthMap,
+    start_args: SharedStartArgsMap,
+    context_dumps: SharedContextDumpMap,
+    backfill_progress: SharedBackfillProgressMap,
+    start_cancels: SharedStartCancelMap,
+    thread_exits: SharedThreadExitMap,
+    trigger_injections: SharedTriggerInjectionMap,
+    cache_compactions: SharedCacheCompactionMap,
+    metrics_cardinality: Arc<MetricsCardinality>,
+
+    /// Limits how many deployments resolve their files at the same time;
+    /// see `SubgraphInstanceManagerConfig::ipfs_resolution_concurrency`.
+    ipfs_resolution_permits: Option<Arc<tokio::sync::Semaphore>>,
+}
+
+/// An error that starting a deployment would run into again if it was
+/// retried, like an invalid manifest or a network this node does not index.
+#[derive(thiserror::Error, Debug)]
+#[error("{0:#}")]
+struct PermanentStartError(Error);
+
+fn permanent(e: Error) -> Error {
+    PermanentStartError(e).into()
+}
+
+/// Whether retrying to start a deployment that failed to start with `e` is
+/// pointless.
+fn is_permanent_start_error(e: &Error) -> bool {
+    use SubgraphManifestResolveError::*;
+
+    e.is::<PermanentStartError>()
+        || e.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<SubgraphManifestResolveError>(),
+                Some(ParseError(_)) | Some(NonUtf8) | Some(InvalidFormat)
+            )
+        })
+}
+
+/// How starting a deployment ended, unless it failed
+enum StartOutcome {
+    Started,
+    /// The deployment was stopped while its files were being resolved
+    Canceled,
+}
+
+/// Why the block stream of a subgraph is restarted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RestartReason {
+    /// The block created new data sources
+    DataSource,
+    /// Processing the block ran into a possible reorg
+    PossibleReorg,
+    /// The subgraph switched between the historical and head profile
+    ProcessingProfile,
+    /// The block stream delivered a block that does not follow the last
+    /// block the subgraph processed
+    OutOfOrderBlock,
+    /// The subgraph finished a chunk of historical blocks
+    Checkpoint,
+    /// The subgraph was about to write a block that is not past the last
+    /// block it wrote
+    RegressedBlock,
+}
+
+impl RestartReason {
+    fn label_value(&self) -> &str {
+        match self {
+            RestartReason::DataSource => "data_source",
+            RestartReason::PossibleReorg => "possible_reorg",
+            RestartReason::ProcessingProfile => "processing_profile",
+            RestartReason::OutOfOrderBlock => "out_of_order_block",
+            RestartReason::Checkpoint => "checkpoint",
+            RestartReason::RegressedBlock => "regressed_block",
+        }
+    }
+
+    /// Whether the block that led to the restart was processed
+    fn block_processed(&self) -> bool {
+        match self {
+            RestartReason::PossibleReorg
+            | RestartReason::OutOfOrderBlock
+            | RestartReason::RegressedBlock => false,
+            RestartReason::DataSource
+            | RestartReason::ProcessingProfile
+            | RestartReason::Checkpoint => true,
+        }
+    }
+}
+
+#[async_trait]
+impl<B, S, BS, M, H, L> SubgraphInstanceManagerTrait for SubgraphInstanceManager<B, S, BS, M, H, L>
+where
+    S: SubgraphStore,
+    BS: BlockStore,
+    B: BlockStreamBuilder,
+    M: MetricsRegistry,
+    H: RuntimeHostBuilder,
+    L: LinkResolver + Clone,
+{
+    async fn start_subgraph(
+        self: Arc<Self>,
+        id: SubgraphDeploymentId,
+        manifest: serde_yaml::Mapping,
+        labels: DeploymentLabels,
+    ) {
+        // Failures are logged and counted by `start`
+        let _ = self.start(id, Arc::new(manifest), labels).await;
+    }
+
+    fn stop_subgraph(&self, id: SubgraphDeploymentId) {
+        let logger = self.logger_factory.subgraph_logger(&id);
+        info!(logger, "Stop subgraph");
+
+        // Abort resolving the subgraph if it is still starting
+        self.start_cancels.write().unwrap().remove(&id);
+        self.thread_exits.write().unwrap().remove(&id);
+
+        // Drop the cancel guard to shut down the subgraph now
+        let mut instances = self.instances.write().unwrap();
+        instances.remove(&id);
+        self.start_args.write().unwrap().remove(&id);
+
+        // Keep the breakpoint only if it still has to fire after a restart
+        let mut breakpoints = self.breakpoints.write().unwrap();
+        if let Some(breakpoint) = breakpoints.get(&id) {
+            if breakpoint.block.lock().unwrap().is_none() {
+                breakpoints.remove(&id);
+            }
+        }
+        drop(breakpoints);
+
+        self.log_levels.write().unwrap().remove(&id);
+
+        forget_running_subgraph(
+            &id,
+            &self.manifests,
+            &self.health,
+            &self.context_dumps,
+            &self.trigger_injections,
+            &self.cache_compactions,
+            &self.manager_metrics,
+        );
+    }
+
+    fn stop_and_tombstone_subgraph(&self, id: SubgraphDeploymentId) -> Result<(), StoreError> {
+        self.stop_subgraph(id.clone());
+
+        let logger = self.logger_factory.subgraph_logger(&id);
+        self.subgraph_store.tombstone(&id)?;
+        info!(logger, "Marked subgraph data as removable");
+        Ok(())
+    }
+}
+
+impl<B, S, BS, M, H, L> SubgraphInstanceManager<B, S, BS, M, H, L>
+where
+    S: SubgraphStore,
+    BS: BlockStore,
+    B: BlockStreamBuilder,
+    M: MetricsRegistry,
+    H: RuntimeHostBuilder,
+    L: LinkResolver + Clone,
+{
+    pub fn new(
+        logger_factory: &LoggerFactory,
+        subgraph_store: Arc<S>,
+        block_store: Arc<BS>,
+        eth_networks: EthereumNetworks,
+        host_builder: H,
+        block_stream_builder: B,
+        metrics_registry: Arc<M>,
+        link_resolver: Arc<L>,
+        config: SubgraphInstanceManagerConfig,
+    ) -> Self {
+        let logger = logger_factory.component_logger("SubgraphInstanceManager", None);
+        let logger_factory = logger_factory.with_parent(logger.clone());
+
+        let link_resolver = Arc::new(
+            link_resolver
+                .as_ref()
+                .clone()
+                .with_timeout(config.ipfs_subgraph_loading_timeout)
+                .with_retries(),
+        );
+        let ipfs_resolution_permits = match config.ipfs_resolution_concurrency {
+            0 => None,
+            concurrency => Some(Arc::new(tokio::sync::Semaphore::new(concurrency))),
+        };
+
+        SubgraphInstanceManager {
+            logger_factory,
+            subgraph_store,
+            block_store,
+            eth_networks,
+            host_builder,
+            block_stream_builder,
+            manager_metrics: Arc::new(SubgraphInstanceManagerMetrics::new(
+                metrics_registry.cheap_clone(),
+            )),
+            metrics_registry,
+            instances: SharedInstanceKeepAliveMap::default(),
+            link_resolver,
+            config: Arc::new(config),
+            breakpoints: SharedBreakpointMap::default(),
+            head_rechecks: SharedHeadRecheckMap::default(),
+            overrides: SharedDeploymentOverridesMap::default(),
+            manifests: SharedManifestMap::default(),
+            log_levels: SharedLogLevelMap::default(),
+            circuit_breakers: SharedCircuitBreakerMap::default(),
+            verifications: SharedVerificationMap::default(),
+            health: SharedHealthMap::default(),
+            start_args: SharedStartArgsMap::default(),
+            context_dumps: SharedContextDumpMap::default(),
+            backfill_progress: SharedBackfillProgressMap::default(),
+            start_cancels: SharedStartCancelMap::default(),
+            thread_exits: SharedThreadExitMap::default(),
+            trigger_injections: SharedTriggerInjectionMap::default(),
+            cache_compactions: SharedCacheCompactionMap::default(),
+            metrics_cardinality: Arc::new(MetricsCardinality::default()),
+            ipfs_resolution_permits,
+        }
+    }
+
+    /// Start the deployment `id`, retrying if that fails for reasons that
+    /// may go away, and report how starting it ended.
+    async fn start(
+        self: Arc<Self>,
+        id: SubgraphDeploymentId,
+        manifest: Arc<serde_yaml::Mapping>,
+        labels: DeploymentLabels,
+    ) -> Result<StartOutcome, Error> {
+        let logger = dynamic_level_logger(
+            self.logger_factory.subgraph_logger(&id),
+            self.log_level(&id),
+        );
+        let settings = match self.deployment_settings(&id) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!(
+                    logger,
+                    "Failed to start subgraph";
+                    "error" => format!("{}", e),
+                    "code" => LogCode::SubgraphStartFailure
+                );
+                return Err(e.into());
+            }
+        };
+        let labels = match self.config.deployment_labels.labels_of(&id, &labels) {
+            Ok(labels) => labels,
+            Err(e) => {
+                warn!(
+                    logger,
+                    "Ignoring the labels the subgraph was started with";
+                    "error" => format!("{:#}", e),
+                );
+                self.config
+                    .deployment_labels
+                    .labels_of(&id, &DeploymentLabels::default())
+                    .expect("the configured deployment labels are valid")
+            }
+        };
+        if !settings.skip_blocks.is_empty() {
+            warn!(
+                logger,
+                "Skipping the triggers of some blocks; the proof of indexing of this \
+                 subgraph will differ from that of a node that processes them";
+                "blocks" => settings
+                    .skip_blocks
+                    .iter()
+                    .map(|block| block.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        }
+
+        if !settings.synthetic_reorgs.is_empty() {
+            warn!(
+                logger,
+                "Reverting blocks for synthetic reorgs; this is only meant for testing";
+                "blocks" => settings
+                    .synthetic_reorgs
+                    .iter()
+                    .map(|block| block.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        }
+
+        let verification = if settings.verify_only {
+            let verification = Arc::new(Mutex::new(Verification::Running(None)));
+            self.verifications
+                .write()
+                .unwrap()
+                .insert(id.clone(), verification.cheap_clone());
+            Some(verification)
+        } else {
+            None
+        };
+        let handles = DeploymentHandles {
+            breakpoint: self.breakpoint(&id),
+            head_recheck: self.head_recheck(&id),
+            circuit_breaker: self
+                .circuit_breakers
+                .write()
+                .unwrap()
+                .entry(id.clone())
+                .or_default()
+                .cheap_clone(),
+            verification,
+            health: Arc::new(Mutex::new(HealthState::default())),
+            context_dump: Arc::new(Mutex::new(IndexingContextDump::default())),
+            backfill_progress: self
+                .backfill_progress
+                .write()
+                .unwrap()
+                .entry(id.clone())
+                .or_default()
+                .cheap_clone(),
+        };
+
+        let (cancel_sender, mut cancel) = oneshot::channel();
+        self.start_cancels
+            .write()
+            .unwrap()
+            .insert(id.clone(), cancel_sender);
+        self.start_args
+            .write()
+            .unwrap()
+            .insert(id.clone(), (manifest.cheap_clone(), labels.clone()));
+
+        // Retry starts that failed for reasons that may go away, like IPFS
+        // or the database being briefly unavailable
+        let mut retries = 0;
+        let res = loop {
+            let res = self
+                .start_subgraph_inner(
+                    logger.clone(),
+                    id.clone(),
+                    manifest.cheap_clone(),
+                    labels.clone(),
+                    settings.clone(),
+                    handles.clone(),
+                    &mut cancel,
+                )
+                .await;
+            match res {
+                Err(e) if !is_permanent_start_error(&e) && retries < self.config.start_retries => {
+                    retries += 1;
+                    let backoff =
+                        self.config.start_retry_backoff * 2u32.saturating_pow(retries as u32 - 1);
+                    warn!(
+                        logger,
+                        "Failed to start subgraph, retrying";
+                        "error" => format!("{:#}", e),
+                        "attempt" => retries,
+                        "backoff_ms" => backoff.as_millis() as u64,
+                    );
+                    self.manager_metrics
+                        .start_retry_count
+                        .with_label_values(&[id.as_str()])
+                        .inc();
+                    let delay = tokio::time::delay_for(backoff);
+                    if let futures03::future::Either::Right(_) =
+                        futures03::future::select(Box::pin(delay), &mut cancel).await
+                    {
+                        break Ok(StartOutcome::Canceled);
+                    }
+                }
+                res => break res,
+            }
+        };
+
+        // Forget the senders of all starts that are over, including this one
+        self.start_cancels
+            .write()
+            .unwrap()
+            .retain(|_, sender| !sender.is_canceled());
+
+        let outcome = match &res {
+            Ok(StartOutcome::Started) => "started",
+            Ok(StartOutcome::Canceled) => {
+                if let Some(group) = &settings.entity_cache_limit_group {
+                    group.leave(&id);
+                }
+                info!(logger, "Subgraph was stopped while it was starting");
+                "canceled"
+            }
+            Err(err) => {
+                if let Some(group) = &settings.entity_cache_limit_group {
+                    group.leave(&id);
+                }
+                let is_permanent = is_permanent_start_error(err);
+                error!(
+                    logger,
+                    "Failed to start subgraph";
+                    "error" => format!("{}", err),
+                    "permanent" => is_permanent,
+                    "retries" => retries,
+                    "code" => LogCode::SubgraphStartFailure
+                );
+                if is_permanent {
+                    "failed_permanent"
+                } else {
+                    "failed_transient"
+                }
+            }
+        };
+        self.manager_metrics
+            .start_outcome_count
+            .with_label_values(&[outcome])
+            .inc();
+        res
+    }
+
+    /// Return the deterministic errors of the deployment `id` in the order
+    /// in which they happened. Use `limit` and `offset` to page through them.
+    pub fn deterministic_errors(
+        &self,
+        id: &SubgraphDeploymentId,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<SubgraphError>, StoreError> {
+        self.subgraph_store.deterministic_errors(id, limit, offset)
+    }
+
+    fn breakpoint(&self, id: &SubgraphDeploymentId) -> Arc<Breakpoint> {
+        self.breakpoints
+            .write()
+            .unwrap()
+            .entry(id.clone())
+            .or_default()
+            .cheap_clone()
+    }
+
+    /// Pause the deployment `id` right before it processes `block`, or the
+    /// first block after it that it processes. The breakpoint can be set
+    /// before or while the deployment is running.
+    pub fn set_breakpoint(&self, id: &SubgraphDeploymentId, block: BlockNumber) {
+        self.breakpoint(id).set(Some(block));
+    }
+
+    pub fn clear_breakpoint(&self, id: &SubgraphDeploymentId) {
+        let mut breakpoints = self.breakpoints.write().unwrap();
+        if let Some(breakpoint) = breakpoints.get(id) {
+            breakpoint.set(None);
+            // Nothing else holds on to the breakpoint of a deployment that
+            // is not running
+            if Arc::strong_count(breakpoint) == 1 {
+                breakpoints.remove(id);
+            }
+        }
+    }
+
+    /// Whether the deployment `id` is paused at a breakpoint.
+    pub fn is_paused(&self, id: &SubgraphDeploymentId) -> bool {
+        self.breakpoints
+            .read()
+            .unwrap()
+            .get(id)
+            .map_or(false, |breakpoint| breakpoint.is_paused())
+    }
+
+    /// Resume the deployment `id` if it is paused at a breakpoint. Returns
+    /// `true` if it was paused.
+    pub fn resume(&self, id: &SubgraphDeploymentId) -> bool {
+        self.breakpoints
+            .read()
+            .unwrap()
+            .get(id)
+            .map_or(false, |breakpoint| breakpoint.resume())
+    }
+
+    fn head_recheck(&self, id: &SubgraphDeploymentId) -> Arc<Notify> {
+        self.head_rechecks
+            .write()
+            .unwrap()
+            .entry(id.clone())
+            .or_default()
+            .cheap_clone()
+    }
+
+    /// Make the running deployment `id` check whether the block it processed
+    /// last is still on the main chain according to the chain store, and
+    /// revert it if it is not. This helps deployments that are stuck on a
+    /// block that was reorged away without the block stream noticing, for
+    /// example because the Ethereum node lagged behind. The check happens
+    /// between blocks; once the deployment is behind the chain head again,
+    /// the block stream reverts further blocks as needed. Returns `false` if
+    /// the deployment is not running.
+    pub fn recheck_head(&self, id: &SubgraphDeploymentId) -> bool {
+        if !self.instances.read().unwrap().contains_key(id) {
+            return false;
+        }
+        self.head_recheck(id).notify();
+        true
+    }
+
+    /// Make the running deployment `id` process `trigger` against the block
+    /// it processed last, and return the entity changes and deterministic
+    /// errors this causes without writing them. The deployment processes
+    /// the trigger with its current data sources between two blocks; data
+    /// sources that the handlers create are discarded, and the time it takes
+    /// does not show up in the trigger processing metrics of the deployment.
+    /// This is meant for testing handlers on real data, and only possible if
+    /// `allow_trigger_injection` is set.
+    pub async fn inject_trigger(
+        &self,
+        id: &SubgraphDeploymentId,
+        trigger: EthereumTrigger,
+    ) -> Result<TriggerInjectionOutcome, InjectTriggerError> {
+        if !self.config.allow_trigger_injection {
+            return Err(InjectTriggerError::Disabled);
+        }
+        let sender = self
+            .trigger_injections
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| InjectTriggerError::NotRunning(id.clone()))?;
+
+        let (outcome, receiver) = oneshot::channel();
+        sender
+            .unbounded_send(TriggerInjection { trigger, outcome })
+            .map_err(|_| InjectTriggerError::NotRunning(id.clone()))?;
+        receiver
+            .await
+            .map_err(|_| InjectTriggerError::NotRunning(id.clone()))?
+            .map_err(InjectTriggerError::Failed)
+    }
+
+    /// Evict entries from the entity cache of the running deployment `id`
+    /// until it weighs at most `target_weight` bytes, the same way the
+    /// deployment evicts entries after each block, and report how much this
+    /// freed. This frees memory without restarting the deployment. The
+    /// deployment compacts its cache between blocks, so that this waits
+    /// until it is done with the block it is processing.
+    pub async fn compact_entity_cache(
+        &self,
+        id: &SubgraphDeploymentId,
+        target_weight: usize,
+    ) -> Result<EntityCacheCompaction, Error> {
+        let sender = self
+            .cache_compactions
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!("deployment `{}` is not running", id))?;
+
+        let (outcome, receiver) = oneshot::channel();
+        sender
+            .unbounded_send(CacheCompactionRequest {
+                target_weight,
+                outcome,
+            })
+            .map_err(|_| anyhow!("deployment `{}` is not running", id))?;
+        receiver
+            .await
+            .map_err(|_| anyhow!("deployment `{}` stopped before compacting its cache", id))
+    }
+
+    /// The manifest of the deployment `id` as it was resolved when the
+    /// deployment started, including the dynamic data sources that existed
+    /// at that point. Returns `None` if the deployment is not running.
+    pub fn manifest(&self, id: &SubgraphDeploymentId) -> Option<Arc<SubgraphManifest>> {
+        self.manifests.read().unwrap().get(id).cloned()
+    }
+
+    /// The capabilities that the Ethereum node for the deployment `id` must
+    /// have, based on the manifest the deployment was started with. Returns
+    /// `None` if the deployment is not running.
+    pub fn required_capabilities(&self, id: &SubgraphDeploymentId) -> Option<NodeCapabilities> {
+        self.manifests
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|manifest| manifest.required_ethereum_capabilities())
+    }
+
+    fn log_level(&self, id: &SubgraphDeploymentId) -> LogLevelHandle {
+        self.log_levels
+            .write()
+            .unwrap()
+            .entry(id.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Only log messages of the deployment `id` that are at least as severe
+    /// as `level`, or all of them if `level` is `None`. This takes effect
+    /// immediately, also for a running deployment. Messages that the node
+    /// does not log at all, for example debug messages when the node does
+    /// not run with `--debug`, are not logged for the deployment either. The
+    /// level is kept when the deployment restarts, but forgotten when it is
+    /// stopped.
+    pub fn set_log_level(&self, id: &SubgraphDeploymentId, level: Option<slog::Level>) {
+        self.log_level(id)
+            .set_level(level.unwrap_or(slog::Level::Trace));
+    }
+
+    fn update_overrides(
+        &self,
+        id: &SubgraphDeploymentId,
+        update: impl FnOnce(&mut DeploymentOverrides),
+    ) {
+        update(
+            self.overrides
+                .write()
+                .unwrap()
+                .entry(id.clone())
+                .or_default(),
+        );
+    }
+
+    /// The settings the deployment `id` runs with if it is started now.
+    fn deployment_settings(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<DeploymentSettings, StoreError> {
+        let overrides = self
+            .overrides
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .unwrap_or_default();
+        Ok(DeploymentSettings {
+            end_block: overrides.end_block,
+            catch_up_only: overrides.catch_up_only,
+            verify_only: overrides.verify_only,
+            include_calls: overrides.include_calls,
+            duplicate_data_source_policy: overrides
+                .duplicate_data_source_policy
+                .unwrap_or(self.config.duplicate_data_source_policy),
+            dynamic_data_source_trigger_concurrency: overrides
+                .dynamic_data_source_trigger_concurrency
+                .unwrap_or(self.config.dynamic_data_source_trigger_concurrency),
+            poi_write_interval: self
+                .subgraph_store
+                .poi_write_interval(id)?
+                .unwrap_or(self.config.poi_write_interval)
+                .max(1),
+            disable_fail_fast: self
+                .subgraph_store
+                .disable_fail_fast(id)?
+                .unwrap_or(self.config.disable_fail_fast),
+            skip_blocks: Arc::new(self.subgraph_store.skip_blocks(id)?),
+            synthetic_reorgs: overrides.synthetic_reorgs,
+            entity_cache_limit_group: overrides.entity_cache_limit_group,
+        })
+    }
+
+    /// Make the deployment `id` stop once it has processed `block`, or
+    /// process blocks indefinitely if `block` is `None`. Blocks past `block`
+    /// are never processed, even if the block stream skips `block` because
+    /// it has no triggers. A deployment that stopped this way no longer
+    /// counts as running, but stays assigned. This takes effect the next
+    /// time the deployment is started.
+    pub fn set_end_block(&self, id: &SubgraphDeploymentId, block: Option<BlockNumber>) {
+        self.update_overrides(id, |overrides| overrides.end_block = block);
+    }
+
+    /// Handle duplicate dynamic data sources of the deployment `id`
+    /// according to `policy`, or according to the node's default policy if
+    /// `policy` is `None`. This takes effect the next time the deployment is
+    /// started.
+    pub fn set_duplicate_data_source_policy(
+        &self,
+        id: &SubgraphDeploymentId,
+        policy: Option<DuplicateDataSourcePolicy>,
+    ) {
+        self.update_overrides(id, |overrides| {
+            overrides.duplicate_data_source_policy = policy
+        });
+    }
+
+    /// Let up to `concurrency` hosts of the deployment `id` process a trigger
+    /// at the same time when the triggers of a block are processed again for
+    /// the data sources that the block created, or use the node's default if
+    /// `concurrency` is `None`. Hosts then do not see each other's changes
+    /// for the same trigger, which is only correct if the data sources of the
+    /// deployment are independent of each other. Deployments with a proof of
+    /// indexing always process triggers one host at a time. This takes effect
+    /// the next time the deployment is started.
+    pub fn set_dynamic_data_source_trigger_concurrency(
+        &self,
+        id: &SubgraphDeploymentId,
+        concurrency: Option<usize>,
+    ) {
+        self.update_overrides(id, |overrides| {
+            overrides.dynamic_data_source_trigger_concurrency = concurrency
+        });
+    }
+
+    /// Write the proof of indexing of the deployment `id` to the store only
+    /// every `interval` blocks, or use the node's default if `interval` is
+    /// `None`. The digests of the blocks in between are kept in memory, and
+    /// the deployment goes back to the block at which it last wrote them
+    /// when it starts. The proof of indexing for a block in between is the
+    /// one of the last block that was written, so nodes that are compared
+    /// with each other must use the same interval. Setting the interval
+    /// back to 1 does not rewind the deployment; reindex it from the last
+    /// block that wrote its proof of indexing first. The interval is stored
+    /// with the deployment. This takes effect the next time the deployment
+    /// is started.
+    pub fn set_poi_write_interval(
+        &self,
+        id: &SubgraphDeploymentId,
+        interval: Option<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        self.subgraph_store.set_poi_write_interval(id, interval)
+    }
+
+    /// Keep deterministic errors of the deployment `id` non-fatal even if it
+    /// is pending, or not, regardless of `GRAPH_DISABLE_FAIL_FAST`. With
+    /// `None`, the deployment follows `GRAPH_DISABLE_FAIL_FAST` again. The
+    /// override is stored with the deployment. This takes effect the next
+    /// time the deployment is started.
+    pub fn set_disable_fail_fast(
+        &self,
+        id: &SubgraphDeploymentId,
+        disable: Option<bool>,
+    ) -> Result<(), StoreError> {
+        self.subgraph_store.set_disable_fail_fast(id, disable)
+    }
+
+    /// Include the calls of each block in the blocks that the deployment
+    /// `id` processes, or not, regardless of whether its manifest needs
+    /// them. With `None`, the deployment includes calls if its manifest
+    /// needs them again. Leaving calls out for a deployment that needs them
+    /// makes it miss data, and is only meant for diagnosing problems with
+    /// collecting traces. This takes effect the next time the deployment is
+    /// started.
+    pub fn set_include_calls_in_blocks(&self, id: &SubgraphDeploymentId, include: Option<bool>) {
+        self.update_overrides(id, |overrides| overrides.include_calls = include);
+    }
+
+    /// Instead of indexing the deployment `id`, process the blocks it has
+    /// already indexed again and compare the entities and proof of indexing
+    /// that they produce with the store, stopping at the first block that
+    /// differs. Nothing is written to the store. This takes effect the next
+    /// time the deployment is started; use `verification` to get the outcome.
+    pub fn set_verify_only(&self, id: &SubgraphDeploymentId, verify_only: bool) {
+        self.update_overrides(id, |overrides| overrides.verify_only = verify_only);
+    }
+
+    /// Make the deployment `id` stop once it has caught up with the chain
+    /// head, or keep following the chain head if `catch_up_only` is
+    /// `false`. The deployment stops after processing a block that is the
+    /// chain head at that time; if the chain head has a different hash, the
+    /// deployment first reverts to the main chain and then continues. This
+    /// takes effect the next time the deployment is started.
+    pub fn set_catch_up_only(&self, id: &SubgraphDeploymentId, catch_up_only: bool) {
+        self.update_overrides(id, |overrides| overrides.catch_up_only = catch_up_only);
+    }
+
+    /// The state of the last verify-only run of the deployment `id`, or
+    /// `None` if it never ran in verify-only mode.
+    pub fn verification(&self, id: &SubgraphDeploymentId) -> Option<Verification> {
+        self.verifications
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|verification| verification.lock().unwrap().clone())
+    }
+
+    /// Do not process the triggers of the given blocks of the deployment
+    /// `id`, but still move its block pointer past them. This is an escape
+    /// hatch for blocks that run into a bug that can't be fixed otherwise.
+    /// Skipping a block that has triggers makes the proof of indexing of the
+    /// deployment differ from that of a node that processes the block; every
+    /// skipped block is logged, counted in the
+    /// `deployment_skipped_block_count` metric and recorded in the store,
+    /// see `skipped_blocks`. The blocks are stored with the deployment. An
+    /// empty set turns skipping off. This takes effect the next time the
+    /// deployment is started.
+    pub fn set_skip_blocks(
+        &self,
+        id: &SubgraphDeploymentId,
+        blocks: BTreeSet<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        self.subgraph_store.set_skip_blocks(id, &blocks)
+    }
+
+    /// The blocks whose triggers the deployment `id` skipped because of
+    /// `set_skip_blocks`, in ascending order. The record is kept when the
+    /// blocks to skip change.
+    pub fn skipped_blocks(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Vec<BlockNumber>, StoreError> {
+        self.subgraph_store.skipped_blocks(id)
+    }
+
+    /// Make the deployment `id` revert the last block it processed before
+    /// it processes a block at or past each of `blocks`, as if the block
+    /// stream had run into a reorg, and then continue from the reverted
+    /// block. This exercises reverting data, data sources and the entity
+    /// cache, and rebuilding the block stream, without waiting for a real
+    /// reorg. Each block number causes one revert per start of the
+    /// deployment, and only for blocks past where the deployment is. An
+    /// empty set turns this off. This takes effect the next time the
+    /// deployment is started, and is only possible if
+    /// `allow_synthetic_reorgs` is set.
+    pub fn set_synthetic_reorgs(
+        &self,
+        id: &SubgraphDeploymentId,
+        blocks: BTreeSet<BlockNumber>,
+    ) -> Result<(), Error> {
+        if !self.config.allow_synthetic_reorgs {
+            return Err(anyhow!(
+                "synthetic reorgs are disabled, set GRAPH_ALLOW_SYNTHETIC_REORGS to enable them"
+            ));
+        }
+        self.update_overrides(id, |overrides| overrides.synthetic_reorgs = blocks);
+        Ok(())
+    }
+
+    /// Clear the failure of the deployment `id` and start indexing it again
+    /// from where it stopped. The deployment must be assigned to this node,
+    /// and the running instance, if there still is one, is stopped first and
+    /// has exited before the failure is cleared. Deterministic failures
+    /// would just happen again, and are therefore only retried if `force` is
+    /// set. Returns an error if the deployment could not be started again.
+    pub async fn retry_failed(
+        self: Arc<Self>,
+        id: &SubgraphDeploymentId,
+        force: bool,
+    ) -> Result<(), RetryFailedError> {
+        let (manifest, labels) = self
+            .start_args
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| RetryFailedError::NotRunning(id.clone()))?;
+
+        let error = self
+            .subgraph_store
+            .fatal_error(id)?
+            .ok_or_else(|| RetryFailedError::NotFailed(id.clone()))?;
+        if error.deterministic && !force {
+            return Err(RetryFailedError::Deterministic(error));
+        }
+
+        let logger = self.logger_factory.subgraph_logger(id);
+        info!(
+            logger,
+            "Retrying failed subgraph";
+            "error" => &error.message,
+            "deterministic" => error.deterministic,
+        );
+
+        // The old instance must be gone before the failure is cleared, or it
+        // could fail the deployment again
+        self.stop_subgraph_and_wait(id.clone()).await;
+        self.subgraph_store.unfail(id)?;
+        self.start(id.clone(), manifest, labels)
+            .await
+            .map_err(RetryFailedError::Start)?;
+        Ok(())
+    }
+
+    /// Compare the entities of the deployments `left` and `right` as of
+    /// `block`, for example to check that a new version of a subgraph or a
+    /// graft produces the same data as the deployment it replaces. Returns
+    /// the first entity that differs, or `None` if both deployments have
+    /// the same entities. Both deployments must have reached `block` and
+    /// have the same entity types; they don't need to be running. This
+    /// reads all entities of both deployments and can take a long time.
+    pub async fn compare_deployments(
+        &self,
+        left: &SubgraphDeploymentId,
+        right: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<Option<DeploymentDifference>, Error> {
+        let store = self.subgraph_store.cheap_clone();
+        let left = left.clone();
+        let right = right.clone();
+        task::spawn_blocking(move || compare_deployments(store.as_ref(), &left, &right, block))
+            .await
+            .map_err(Error::from)
+            .and_then(|res| res)
+    }
+
+    /// Collect the proofs of indexing of the deployment `id` as computed for
+    /// `indexer` at the blocks `start` to `end`, both included, into a
+    /// bundle, and sign it if the node has a `PoiBundleSigner`. The blocks
+    /// are the ancestors of the block the deployment processed last, and
+    /// the deployment must be running on this node.
+    pub async fn proof_of_indexing_bundle(
+        &self,
+        id: &SubgraphDeploymentId,
+        indexer: Option<web3::types::Address>,
+        start: BlockNumber,
+        end: BlockNumber,
+    ) -> Result<PoiBundle, Error> {
+        if start > end || (end - start) as usize >= self.config.poi_bundle_max_blocks {
+            return Err(anyhow!(
+                "invalid block range {} to {}, a bundle covers between 1 and {} blocks",
+                start,
+                end,
+                self.config.poi_bundle_max_blocks
+            ));
+        }
+
+        let network = self
+            .manifests
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|manifest| manifest.network_name())
+            .ok_or_else(|| anyhow!("deployment `{}` is not running", id))?;
+        let chain_store = self
+            .block_store
+            .chain_store(&network)
+            .ok_or_else(|| anyhow!("no chain store for network `{}`", network))?;
+        let head = self
+            .subgraph_store
+            .block_ptr(id)?
+            .filter(|head| head.number >= end)
+            .ok_or_else(|| anyhow!("deployment `{}` has not reached block {} yet", id, end))?;
+
+        let mut entries = Vec::new();
+        for number in start..=end {
+            let block = chain_store
+                .ancestor_block(head.clone(), head.number - number)?
+                .ok_or_else(|| anyhow!("block {} is missing from the chain store", number))?;
+            let block_ptr = EthereumBlockPointer::from(&block);
+            let regions = self
+                .subgraph_store
+                .cheap_clone()
+                .get_proof_of_indexing_regions(id, block_ptr.clone())
+                .await?
+                .ok_or_else(|| {
+                    anyhow!(
+                        "deployment `{}` has no proof of indexing at block {}",
+                        id,
+                        number
+                    )
+                })?;
+
+            let mut finisher = ProofOfIndexingFinisher::new(&block_ptr, id, &indexer);
+            for (name, region) in &regions {
+                finisher.add_causality_region(name, region);
+            }
+            entries.push(PoiBundleEntry {
+                block_number: block_ptr.number,
+                block_hash: block_ptr.hash_as_h256(),
+                proof_of_indexing: web3::types::H256::from(finisher.finish()),
+                causality_regions: regions
+                    .iter()
+                    .map(|(name, region)| (name.clone(), Bytes::from(region.as_slice())))
+                    .collect(),
+            });
+        }
+
+        let digest = PoiBundle::compute_digest(id.as_str(), &indexer, &entries);
+        let signature = match &self.config.poi_bundle_signer {
+            Some(signer) => Some(Bytes::from((signer.0)(&digest)?.as_slice())),
+            None => None,
+        };
+        Ok(PoiBundle {
+            version: POI_BUNDLE_VERSION,
+            deployment: id.to_string(),
+            indexer,
+            entries,
+            digest,
+            signature,
+        })
+    }
+
+    /// Rewind the deployment `id` to `block_ptr_to` and index it again from
+    /// there. The deployment must be assigned to this node; its running
+    /// instance is stopped, and the rewind waits until it has exited. If
+    /// the rewind fails, the deployment is started again without it, and
+    /// the error is returned; an error is also returned if the deployment
+    /// could not be started again.
+    ///
+    /// The metrics of a deployment are cumulative, and would mix the blocks
+    /// and errors of the previous run with those of the new one. They are
+    /// therefore started from scratch: the deployment's own metrics are
+    /// unregistered when it exits and registered anew when it starts, and
+    /// its series in the metrics that all deployments share are reset.
+    /// Metrics that the deployment shares with the other deployments
+    /// without their own metrics are left alone.
+    pub async fn reindex(
+        self: Arc<Self>,
+        id: &SubgraphDeploymentId,
+        block_ptr_to: EthereumBlockPointer,
+    ) -> Result<(), ReindexError> {
+        let (manifest, labels) = self
+            .start_args
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ReindexError::NotRunning(id.clone()))?;
+
+        let logger = self.logger_factory.subgraph_logger(id);
+        info!(
+            logger,
+            "Reindexing subgraph";
+            "block_number" => block_ptr_to.number,
+            "block_hash" => block_ptr_to.hash_hex(),
+        );
+
+        self.stop_subgraph_and_wait(id.clone()).await;
+
+        // The deployment is started again even if it could not be rewound,
+        // and then continues from where it was stopped
+        let rewound = self.subgraph_store.rewind(id.clone(), block_ptr_to);
+        if rewound.is_ok() {
+            self.manager_metrics.reset(id);
+        }
+        let started = self.start(id.clone(), manifest, labels).await;
+        rewound?;
+        started.map_err(ReindexError::Start)?;
+        Ok(())
+    }
+
+    /// Make the entity cache of the deployment `id` share its size limit,
+    /// `GRAPH_ENTITY_CACHE_SIZE`, with the other deployments in the group
+    /// `group`, or give it its own limit again if `group` is `None`. The
+    /// deployments keep separate caches and only share the limit. All
+    /// deployments in a group must have the same schema and network; a
+    /// deployment that differs from the running members of its group fails
+    /// to start. This takes effect the next time the deployment is started.
+    pub fn set_entity_cache_limit_group(&self, id: &SubgraphDeploymentId, group: Option<&str>) {
+        let mut overrides = self.overrides.write().unwrap();
+        let group = group.map(|name| {
+            overrides
+                .values()
+                .filter_map(|overrides| overrides.entity_cache_limit_group.as_ref())
+                .find(|group| group.name == name)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(EntityCacheLimitGroup::new(name.to_owned())))
+        });
+        overrides
+            .entry(id.clone())
+            .or_default()
+            .entity_cache_limit_group = group;
+    }
+
+    /// The current indexing context of the deployment `id`, or `None` if it
+    /// is not running. This only reads what the deployment keeps in memory,
+    /// and is safe to call while it is indexing.
+    pub fn indexing_context(&self, id: &SubgraphDeploymentId) -> Option<IndexingContextDump> {
+        self.context_dumps
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|dump| dump.lock().unwrap().clone())
+    }
+
+    /// The filters the deployment `id` currently requests triggers with, or
+    /// `None` if it is not running. They change when the block stream is
+    /// restarted for new data sources; see `indexing_context`.
+    pub fn filters(&self, id: &SubgraphDeploymentId) -> Option<FiltersDump> {
+        self.indexing_context(id).map(|dump| FiltersDump {
+            log_filters: dump.log_filters,
+            call_filters: dump.call_filters,
+            block_filter_contracts: dump.block_filter_contracts,
+            trigger_every_block: dump.trigger_every_block,
+            matches_data_sources: dump.filters_match_data_sources,
+        })
+    }
+
+    /// Write the indexing context of the deployment `id` to the file `path`
+    /// as JSON; see `indexing_context`.
+    pub fn write_indexing_context(
+        &self,
+        id: &SubgraphDeploymentId,
+        path: &std::path::Path,
+    ) -> Result<(), Error> {
+        let dump = self
+            .indexing_context(id)
+            .ok_or_else(|| anyhow!("deployment {} is not running", id))?;
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        serde_json::to_writer_pretty(file, &dump)?;
+        Ok(())
+    }
+
+    /// Stop the deployment `id` like `stop_subgraph`, and return a future
+    /// that resolves once the thread that indexed it has exited. Stopping
+    /// only asks the thread to exit, and it may still be processing or
+    /// writing a block when this returns. The future resolves right away if
+    /// the deployment was not running.
+    pub fn stop_subgraph_and_wait(
+        &self,
+        id: SubgraphDeploymentId,
+    ) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let exit = self.thread_exits.write().unwrap().remove(&id);
+        self.stop_subgraph(id);
+        async move {
+            if let Some(exit) = exit {
+                // The sender is never used, and the channel is therefore
+                // always canceled
+                let _ = exit.await;
+            }
+        }
+    }
+
+    /// How far the deployment `id` got with syncing historical blocks in
+    /// chunks, or `None` if it was not started since the node started. See
+    /// `SubgraphInstanceManagerConfig::backfill_chunk_size`.
+    pub fn backfill_progress(&self, id: &SubgraphDeploymentId) -> Option<BackfillProgress> {
+        self.backfill_progress
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|progress| progress.lock().unwrap().clone())
+    }
+
+    /// Summarize the state of every running deployment. This only looks at
+    /// what the manager keeps in memory and does not access the store.
+    pub fn health_snapshot(&self) -> Vec<DeploymentHealth> {
+        let breakpoints = self.breakpoints.read().unwrap();
+        self.health
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, health)| {
+                let health = health.lock().unwrap();
+                DeploymentHealth {
+                    deployment: id.clone(),
+                    synced: health.synced,
+                    head_distance: health.head_distance,
+                    block_ptr: health.block_ptr.clone(),
+                    error_count: health.error_count,
+                    restart_count: health.restart_count,
+                    stalled: health.stalled,
+                    paused: breakpoints
+                        .get(id)
+                        .map_or(false, |breakpoint| breakpoint.is_paused()),
+                    drained: health.drained,
+                }
+            })
+            .collect()
+    }
 
-    fn stop_subgraph(&self, id: SubgraphDeploymentId) {
-        let logger = self.logger_factory.subgraph_logger(&id);
-        info!(logger, "Stop subgraph");
-
-        // Drop the cancel guard to shut down the subgraph now
-        let mut instances = self.instances.write().unwrap();
-        instances.remove(&id);
+    /// Estimate how long the deployment `id` will take to catch up with the
+    /// chain head from its distance to the head and the rate at which it
+    /// processed its recent blocks. Returns `None` if the deployment is not
+    /// running, is already synced, or did not process enough blocks since
+    /// it was started for an estimate.
+    pub fn eta_to_head(&self, id: &SubgraphDeploymentId) -> Option<Duration> {
+        let health = self.health.read().unwrap();
+        let health = health.get(id)?.lock().unwrap();
+        if health.synced {
+            return None;
+        }
+        let head_distance = health.head_distance.filter(|distance| *distance > 0)?;
+        let blocks_per_second = health.blocks_per_second()?;
+        Some(Duration::from_secs_f64(
+            head_distance as f64 / blocks_per_second,
+        ))
+    }
 
-        self.manager_metrics.subgraph_count.dec();
+    /// The number of blocks and triggers per second that all running
+    /// deployments on this node processed recently, as the sum of the
+    /// estimates that `eta_to_head` uses for each deployment. Deployments
+    /// that stopped processing blocks are left out. This only looks at what
+    /// the manager keeps in memory and does not access the store.
+    pub fn aggregate_throughput(&self) -> AggregateThroughput {
+        self.health.read().unwrap().values().fold(
+            AggregateThroughput::default(),
+            |mut total, health| {
+                let health = health.lock().unwrap();
+                if health.drained {
+                    return total;
+                }
+                if let (Some(blocks_per_second), Some(triggers_per_second)) =
+                    (health.blocks_per_second(), health.triggers_per_second())
+                {
+                    total.blocks_per_second += blocks_per_second;
+                    total.triggers_per_second += triggers_per_second;
+                    total.deployments += 1;
+                }
+                total
+            },
+        )
     }
-}
 
-impl<B, S, BS, M, H, L> SubgraphInstanceManager<B, S, BS, M, H, L>
-where
-    S: SubgraphStore,
-    BS: BlockStore,
-    B: BlockStreamBuilder,
-    M: MetricsRegistry,
-    H: RuntimeHostBuilder,
-    L: LinkResolver + Clone,
-{
-    pub fn new(
-        logger_factory: &LoggerFactory,
-        subgraph_store: Arc<S>,
-        block_store: Arc<BS>,
-        eth_networks: EthereumNetworks,
-        host_builder: H,
-        block_stream_builder: B,
-        metrics_registry: Arc<M>,
-        link_resolver: Arc<L>,
-    ) -> Self {
-        let logger = logger_factory.component_logger("SubgraphInstanceManager", None);
-        let logger_factory = logger_factory.with_parent(logger.clone());
+    async fn start_subgraph_inner(
+        &self,
+        logger: Logger,
+        subgraph_id: SubgraphDeploymentId,
+        manifest: Arc<serde_yaml::Mapping>,
+        labels: DeploymentLabels,
+        settings: DeploymentSettings,
+        handles: DeploymentHandles,
+        cancel: &mut oneshot::Receiver<()>,
+    ) -> Result<StartOutcome, Error> {
+        let config = self.config.cheap_clone();
+        let store = self.subgraph_store.cheap_clone();
+        let registry = self.metrics_registry.cheap_clone();
+        let metrics_cardinality = self.metrics_cardinality.cheap_clone();
+        let manager_metrics = self.manager_metrics.cheap_clone();
+        let verification = handles.verification.clone();
+
+        // Refuse subgraphs on other networks before fetching their files
+        if let Some(network) = raw_manifest_network(&manifest) {
+            config.check_network_allowed(&network).map_err(permanent)?;
+        }
 
+        let metrics_id = subgraph_id.clone();
+        let own_metrics = has_own_metrics(store.as_ref(), &config, &metrics_id)?;
+        let subgraph_metrics =
+            metrics_cardinality.acquire(registry.clone(), &metrics_id, &labels, own_metrics);
+
+        // Count the retries of IPFS requests made while resolving the
+        // subgraph for this deployment
         let link_resolver = Arc::new(
-            link_resolver
+            self.link_resolver
                 .as_ref()
                 .clone()
-                .with_timeout(*IPFS_SUBGRAPH_LOADING_TIMEOUT)
-                .with_retries(),
+                .with_retry_counter(subgraph_metrics.ipfs_retries.as_ref().clone()),
         );
 
-        SubgraphInstanceManager {
-            logger_factory,
-            subgraph_store,
-            block_store,
-            eth_networks,
-            host_builder,
-            block_stream_builder,
-            manager_metrics: SubgraphInstanceManagerMetrics::new(metrics_registry.cheap_clone()),
-            metrics_registry,
-            instances: SharedInstanceKeepAliveMap::default(),
-            link_resolver,
-        }
-    }
+        // A failed start may be retried, which registers the metrics again
+        let unregister_on_error = |e: Error| {
+            metrics_cardinality.release(registry.clone(), &metrics_id, &subgraph_metrics);
+            e
+        };
+
+        let resolve = async {
+            // Waiting for a permit counts towards the time it takes to
+            // resolve, and can be canceled the same way
+            let _permit = match &self.ipfs_resolution_permits {
+                Some(permits) => Some(permits.cheap_clone().acquire_owned().await),
+                None => None,
+            };
+            let _in_flight = InFlight::new(&manager_metrics.ipfs_resolutions_in_flight);
 
-    async fn start_subgraph_inner(
-        logger: Logger,
-        instances: SharedInstanceKeepAliveMap,
-        host_builder: impl RuntimeHostBuilder,
-        stream_builder: B,
-        store: Arc<S>,
-        block_store: Arc<BS>,
-        eth_networks: EthereumNetworks,
-        subgraph_id: SubgraphDeploymentId,
-        manifest: serde_yaml::Mapping,
-        registry: Arc<M>,
-        link_resolver: Arc<L>,
-    ) -> Result<(), Error> {
-        let manifest = {
             info!(logger, "Resolve subgraph files using IPFS");
 
             let mut manifest = SubgraphManifest::resolve_from_raw(
                 subgraph_id.cheap_clone(),
-                manifest,
+                manifest.as_ref().clone(),
                 &*link_resolver,
                 &logger,
             )
             .await
             .context("Failed to resolve subgraph from IPFS")?;
 
-            let data_sources = load_dynamic_data_sources(
-                &*store,
-                subgraph_id,
-                logger.clone(),
-                manifest.templates.clone(),
-            )
-            .await
-            .context("Failed to load dynamic data sources")?;
+            // A verify-only run starts from scratch and creates the dynamic
+            // data sources again as it processes blocks
+            let data_sources = if verification.is_some() {
+                vec![]
+            } else {
+                load_dynamic_data_sources(
+                    &*store,
+                    subgraph_id,
+                    logger.clone(),
+                    manifest.templates.clone(),
+                )
+                .await
+                .context("Failed to load dynamic data sources")?
+            };
 
             info!(logger, "Successfully resolved subgraph files using IPFS");
 
@@ -333,27 +2960,74 @@ where
                 manifest.data_sources.len()
             );
 
-            manifest
+            Ok::<_, Error>(manifest)
+        };
+
+        // Resolving can take long, and the deployment may be stopped in the
+        // meantime
+        let manifest = match futures03::future::select(Box::pin(resolve), cancel).await {
+            futures03::future::Either::Left((manifest, _)) => {
+                manifest.map_err(unregister_on_error)?
+            }
+            futures03::future::Either::Right(_) => {
+                metrics_cardinality.release(registry.clone(), &metrics_id, &subgraph_metrics);
+                return Ok(StartOutcome::Canceled);
+            }
         };
 
         let required_capabilities = manifest.required_ethereum_capabilities();
         let network = manifest.network_name();
+        config
+            .check_network_allowed(&network)
+            .map_err(permanent)
+            .map_err(unregister_on_error)?;
+
+        let chain_store = self
+            .block_store
+            .chain_store(&network)
+            .ok_or_else(|| {
+                permanent(anyhow!(
+                    "expected chain store that matches subgraph network: {}",
+                    &network
+                ))
+            })
+            .map_err(unregister_on_error)?;
+
+        let eth_adapter = select_eth_adapter(&self.eth_networks, &network, &required_capabilities)
+            .map_err(unregister_on_error)?;
+        let eth_adapters = Arc::new(EthAdapterSelector::new(
+            eth_adapter,
+            &self.eth_networks,
+            &network,
+            &required_capabilities,
+            &config,
+        ));
 
-        let chain_store = block_store.chain_store(&network).ok_or_else(|| {
-            anyhow!(
-                "expected chain store that matches subgraph network: {}",
-                &network
-            )
-        })?;
+        // Check that the graft can be done before starting the deployment
+        // copies the data of the base, to fail with a clear error instead of
+        // somewhere in the middle of copying or syncing
+        if let Some(graft) = &manifest.graft {
+            let graft_pending = store
+                .block_ptr(&manifest.id)
+                .map_err(|e| unregister_on_error(e.into()))?
+                .map_or(true, |ptr| ptr.number < graft.block);
+            if graft_pending && verification.is_none() {
+                graft
+                    .validate_start(store.as_ref(), &manifest.id)
+                    .map_err(|e| unregister_on_error(permanent(e.into())))?;
+            }
+        }
 
-        let eth_adapter = eth_networks
-            .adapter_with_capabilities(network.clone(), &required_capabilities).map_err(|e|
-                anyhow!(
-                "expected eth adapter that matches subgraph network {} with required capabilities: {}: {}",
-                &network,
-                &required_capabilities, e))?.clone();
+        if let Some(group) = &settings.entity_cache_limit_group {
+            group
+                .join(&manifest.id, &manifest.schema, &network)
+                .map_err(permanent)
+                .map_err(unregister_on_error)?;
+            info!(logger, "Sharing the entity cache size limit"; "group" => &group.name);
+        }
 
-        {
+        // A verify-only run must not touch the deployment in the store
+        if verification.is_none() {
             let store = store.clone();
             let logger = logger.clone();
             let id = manifest.id.clone();
@@ -366,9 +3040,31 @@ where
             })
             .await
             .map_err(Error::from)
-            .and_then(|x| x)?;
+            .and_then(|x| x)
+            .map_err(unregister_on_error)?;
         }
 
+        // The digests of the proof of indexing that were not written yet
+        // were lost when the deployment stopped; process their blocks again
+        let poi_written = if settings.poi_write_interval > 1 {
+            if verification.is_none() {
+                rewind_to_last_poi_write(
+                    &logger,
+                    store.as_ref(),
+                    chain_store.as_ref(),
+                    &manifest.id,
+                    manifest.graft.as_ref().map(|graft| graft.block),
+                )
+                .map_err(unregister_on_error)?;
+            }
+            store
+                .last_proof_of_indexing_block(&manifest.id)
+                .map_err(|e| unregister_on_error(e.into()))?
+                .is_some()
+        } else {
+            true
+        };
+
         // Clone the deployment ID for later
         let deployment_id = manifest.id.clone();
         let network_name = manifest.network_name();
@@ -381,19 +3077,24 @@ where
 
         // Identify whether there are mappings with call handlers or
         // block handlers with call filters; in this case, we need to
-        // include calls in all blocks
-        let include_calls_in_blocks = manifest.requires_traces();
+        // include calls in all blocks, unless the operator decided otherwise
+        let requires_traces = manifest.requires_traces();
+        let include_calls_in_blocks = settings.include_calls.unwrap_or(requires_traces);
+        if requires_traces && !include_calls_in_blocks {
+            warn!(
+                logger,
+                "Not including calls in blocks although the subgraph needs them; \
+                 call handlers and block handlers with a call filter will miss data"
+            );
+        }
 
         let templates = Arc::new(manifest.templates.clone());
+        let schema = Arc::new(manifest.schema.clone());
 
         // Create a subgraph instance from the manifest; this moves
         // ownership of the manifest and host builder into the new instance
         let stopwatch_metrics =
             StopwatchMetrics::new(logger.clone(), deployment_id.clone(), registry.clone());
-        let subgraph_metrics = Arc::new(SubgraphInstanceMetrics::new(
-            registry.clone(),
-            deployment_id.as_str(),
-        ));
         let subgraph_metrics_unregister = subgraph_metrics.clone();
         let host_metrics = Arc::new(HostMetrics::new(
             registry.clone(),
@@ -409,8 +3110,58 @@ where
             stopwatch_metrics,
         ));
         let features = manifest.features.clone();
-        let instance =
-            SubgraphInstance::from_manifest(&logger, manifest, host_builder, host_metrics.clone())?;
+        let block_predicate = manifest.block_predicate.clone();
+        let resolved_manifest = Arc::new(manifest.clone());
+        let host_eth_adapter = Arc::new(SelectedEthAdapter {
+            logger: logger.clone(),
+            selector: eth_adapters.cheap_clone(),
+        });
+        let instance = SubgraphInstance::from_manifest(
+            &logger,
+            manifest,
+            self.host_builder.clone(),
+            host_eth_adapter,
+            host_metrics.clone(),
+        )
+        .map_err(permanent)
+        .map_err(unregister_on_error)?;
+
+        // Keep a copy of the manifest around for inspection
+        self.manifests
+            .write()
+            .unwrap()
+            .insert(deployment_id.clone(), resolved_manifest);
+        manager_metrics
+            .required_capabilities
+            .with_label_values(&SubgraphInstanceManagerMetrics::capability_labels(
+                &deployment_id,
+                &required_capabilities,
+            ))
+            .set(1.0);
+
+        let trigger_injections = if config.allow_trigger_injection {
+            let (sender, receiver) = mpsc::unbounded();
+            self.trigger_injections
+                .write()
+                .unwrap()
+                .insert(deployment_id.clone(), sender);
+            Some(receiver)
+        } else {
+            None
+        };
+        let (sender, cache_compactions) = mpsc::unbounded();
+        self.cache_compactions
+            .write()
+            .unwrap()
+            .insert(deployment_id.clone(), sender);
+
+        let completed = Arc::new(AtomicBool::new(false));
+        let modifications = config
+            .modifications_publisher
+            .as_ref()
+            .map(|publisher| publisher.queue(&deployment_id));
+        let synthetic_reorgs = settings.synthetic_reorgs.clone();
+        let entity_cache_limit_group = settings.entity_cache_limit_group.clone();
 
         // The subgraph state tracks the state of the subgraph instance over time
         let ctx = IndexingContext {
@@ -421,26 +3172,63 @@ where
                 start_blocks,
                 chain_store,
                 store,
-                eth_adapter,
-                stream_builder,
+                eth_networks: self.eth_networks.clone(),
+                required_capabilities,
+                eth_adapters,
+                stream_builder: self.block_stream_builder.clone(),
                 include_calls_in_blocks,
                 templates,
+                schema,
+                entity_modification_hook: config.entity_modification_hook.clone(),
+                trigger_filter_hook: config.trigger_filter_hook.clone(),
+                config,
+                breakpoint: handles.breakpoint,
+                head_recheck: handles.head_recheck,
+                settings,
+                circuit_breaker: handles.circuit_breaker.cheap_clone(),
+                verification,
+                health: handles.health.cheap_clone(),
+                block_predicate,
+                context_dump: handles.context_dump.cheap_clone(),
+                backfill_progress: handles.backfill_progress,
+                completed: completed.cheap_clone(),
+                modifications,
             },
             state: IndexingState {
                 logger: logger.cheap_clone(),
                 instance,
-                instances,
+                instances: self.instances.cheap_clone(),
                 log_filter,
                 call_filter,
                 block_filter,
                 entity_lfu_cache: LfuCache::new(),
+                block_ptr: None,
+                last_commit: None,
+                chunk_end: None,
+                recent_errors: VecDeque::new(),
+                trigger_injections,
+                cache_compactions: Some(cache_compactions),
+                synthetic_reorgs,
+                stall_window_start: None,
+                pending_poi: PendingPoi {
+                    digests: BTreeMap::new(),
+                    written: poi_written,
+                },
+                reorg_depth: None,
+                recent_blocks: VecDeque::new(),
             },
             subgraph_metrics,
             host_metrics,
             ethrpc_metrics,
             block_stream_metrics,
+            manager_metrics,
         };
 
+        log_deployment_started(&logger, &ctx);
+        ctx.subgraph_metrics
+            .data_source_count
+            .set(ctx.state.instance.data_sources().count() as f64);
+
         // Keep restarting the subgraph until it terminates. The subgraph
         // will usually only run once, but is restarted whenever a block
         // creates dynamic data sources. This allows us to recreate the
@@ -451,21 +3239,172 @@ where
         // its own thread. When upgrading to tokio 1.0 it would be logical to run this with
         // `task::unconstrained`, since it has a dedicated OS thread so the OS will handle the
         // preemption.
+        let instances = ctx.state.instances.cheap_clone();
+        let breaker_config = ctx.inputs.config.circuit_breaker;
+        let manager_metrics = ctx.manager_metrics.cheap_clone();
+        let connection_waits = ctx.subgraph_metrics.cheap_clone();
+        let (exited, exit) = oneshot::channel::<()>();
+        self.thread_exits
+            .write()
+            .unwrap()
+            .insert(deployment_id.clone(), futures03::FutureExt::shared(exit));
+        self.health
+            .write()
+            .unwrap()
+            .insert(deployment_id.clone(), handles.health.cheap_clone());
+        self.context_dumps
+            .write()
+            .unwrap()
+            .insert(deployment_id.clone(), handles.context_dump);
+        let manifests = self.manifests.cheap_clone();
+        let healths = self.health.cheap_clone();
+        let context_dumps = self.context_dumps.cheap_clone();
+        let trigger_injection_senders = self.trigger_injections.cheap_clone();
+        let cache_compaction_senders = self.cache_compactions.cheap_clone();
+        let circuit_breaker = handles.circuit_breaker;
+        let health = handles.health;
+        manager_metrics.subgraph_count.inc();
         graph::spawn_thread(deployment_id.to_string(), move || {
+            // Dropped when the thread exits, even if it panics
+            let _exited = exited;
+
+            // Attribute the waits for database connections of this thread,
+            // which does all the indexing, to the deployment
+            set_connection_wait_recorder(Some(connection_waits));
+
+            // Back off if the subgraph keeps failing. Stopping the subgraph
+            // drops the placeholder guard, which ends the wait right away
+            if let Some(delay) = circuit_breaker.restart_delay(&breaker_config) {
+                warn!(
+                    logger,
+                    "Delaying the start of the subgraph since it keeps failing";
+                    "delay_ms" => delay.as_millis(),
+                );
+                manager_metrics
+                    .delayed_restart_count
+                    .with_label_values(&[deployment_id.as_str()])
+                    .inc();
+                let guard = CancelGuard::new();
+                let wait = tokio::time::delay_for(delay)
+                    .unit_error()
+                    .boxed()
+                    .compat()
+                    .cancelable(&guard, || ());
+                instances
+                    .write()
+                    .unwrap()
+                    .insert(deployment_id.clone(), guard);
+                if graph::block_on(wait.compat()).is_err() {
+                    info!(logger, "Subgraph was stopped before it started");
+                    if let Some(group) = &entity_cache_limit_group {
+                        group.leave(&deployment_id);
+                    }
+                    metrics_cardinality.release(
+                        registry,
+                        &deployment_id,
+                        &subgraph_metrics_unregister,
+                    );
+                    return;
+                }
+            }
+
             if let Err(e) = graph::block_on(run_subgraph(ctx)) {
                 error!(
                     &logger,
                     "Subgraph instance failed to run: {}",
                     format!("{:#}", e)
                 );
+                if !e.is::<DeterministicFailure>() {
+                    circuit_breaker.record_failure(&breaker_config);
+                }
+                health.lock().unwrap().error_count += 1;
+            }
+            health.lock().unwrap().drained = true;
+            if let Some(group) = &entity_cache_limit_group {
+                group.leave(&deployment_id);
+            }
+            metrics_cardinality.release(registry, &deployment_id, &subgraph_metrics_unregister);
+
+            // Stopping the deployment already forgot about it, and it may
+            // have been started again since
+            let mut instances = instances.write().unwrap();
+            let running = healths
+                .read()
+                .unwrap()
+                .get(&deployment_id)
+                .map_or(false, |running| Arc::ptr_eq(running, &health));
+
+            // The instance is not running anymore, for example because a
+            // verify-only run finished or it failed
+            if running {
+                instances.remove(&deployment_id);
+                forget_manifest(&deployment_id, &manifests, &manager_metrics);
+            }
+            drop(instances);
+
+            // A completed deployment stays assigned, but is no longer
+            // running
+            if completed.load(Ordering::SeqCst) && running {
+                forget_running_subgraph(
+                    &deployment_id,
+                    &manifests,
+                    &healths,
+                    &context_dumps,
+                    &trigger_injection_senders,
+                    &cache_compaction_senders,
+                    &manager_metrics,
+                );
             }
-            subgraph_metrics_unregister.unregister(registry);
         });
 
-        Ok(())
+        Ok(StartOutcome::Started)
     }
 }
 
+/// Log one event that summarizes how the deployment was just started. The
+/// names of its fields are kept stable so that the event can be used for
+/// audits and searched for in the logs.
+fn log_deployment_started<B, T: RuntimeHostBuilder, S: SubgraphStore, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S, C>,
+) {
+    let inputs = &ctx.inputs;
+    let resume_ptr = match inputs.verification {
+        Some(_) => None,
+        None => inputs.store.block_ptr(&inputs.deployment_id).ok().flatten(),
+    };
+    let start_block = match resume_ptr {
+        Some(ptr) => ptr.number + 1,
+        None => inputs.start_blocks.iter().cloned().min().unwrap_or(0),
+    };
+    let features = inputs
+        .features
+        .iter()
+        .map(|feature| feature.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    info!(
+        logger,
+        "Deployment started";
+        "network" => &inputs.network_name,
+        "required_capabilities" => inputs.required_capabilities.to_string(),
+        "start_block" => start_block,
+        "end_block" => inputs.settings.end_block.map_or("none".to_string(), |block| block.to_string()),
+        "catch_up_only" => inputs.settings.catch_up_only,
+        "data_source_count" => ctx.state.instance.data_sources().count(),
+        "template_count" => inputs.templates.len(),
+        "features" => features,
+        "entity_cache_size" => inputs.config.entity_cache_size,
+        "entity_cache_limit_group" => inputs
+            .settings.entity_cache_limit_group
+            .as_ref()
+            .map_or("none", |group| group.name.as_str()),
+        "requires_traces" => inputs.include_calls_in_blocks,
+        "verify_only" => inputs.verification.is_some(),
+    );
+}
+
 async fn run_subgraph<B, T, S, C>(mut ctx: IndexingContext<B, T, S, C>) -> Result<(), Error>
 where
     B: BlockStreamBuilder,
@@ -479,26 +3418,206 @@ where
     let logger = ctx.state.logger.cheap_clone();
     let id_for_err = ctx.inputs.deployment_id.clone();
     let mut first_run = true;
+    let mut net_identifier = None;
+    let mut historical = None;
+    let metrics_push = ctx.inputs.config.metrics_push.clone();
+    let mut last_push = Instant::now();
+
+    // Don't let a hook change the data of a subgraph with a proof of
+    // indexing unless we've been told that the hook is deterministic
+    if ctx.inputs.entity_modification_hook.is_some()
+        && !ctx.inputs.config.allow_entity_modification_hook_with_poi
+        && ctx
+            .inputs
+            .store
+            .clone()
+            .supports_proof_of_indexing(&ctx.inputs.deployment_id)
+            .await?
+    {
+        warn!(
+            logger,
+            "Disabling the entity modification hook since the subgraph has a proof of indexing. \
+             Set GRAPH_ALLOW_ENTITY_MODIFICATION_HOOK_WITH_POI to run it anyway"
+        );
+        ctx.inputs.entity_modification_hook = None;
+    }
+
+    // The same goes for a hook that drops triggers
+    if ctx.inputs.trigger_filter_hook.is_some()
+        && !ctx.inputs.config.allow_trigger_filter_hook_with_poi
+        && ctx
+            .inputs
+            .store
+            .clone()
+            .supports_proof_of_indexing(&ctx.inputs.deployment_id)
+            .await?
+    {
+        warn!(
+            logger,
+            "Disabling the trigger filter hook since the subgraph has a proof of indexing. \
+             Set GRAPH_ALLOW_TRIGGER_FILTER_HOOK_WITH_POI to run it anyway"
+        );
+        ctx.inputs.trigger_filter_hook = None;
+    }
+
+    // A verify-only run processes the blocks up to the one the deployment
+    // has indexed and keeps track of its progress in memory
+    let verification = ctx.inputs.verification.clone();
+    let verify_until = match &verification {
+        Some(verification) => match ctx.inputs.store.block_ptr(&ctx.inputs.deployment_id)? {
+            Some(ptr) => {
+                info!(logger, "Verifying the data of the subgraph"; "until_block" => ptr.number);
+                Some(ptr)
+            }
+            None => {
+                finish_verification(&logger, verification, Verification::Matched(None));
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    // A verify-only run reads entities as of earlier blocks, which the
+    // warmup can't provide
+    if verification.is_none() {
+        warm_up_entity_cache(&logger, &mut ctx).await;
+    }
+
+    // How often loading the block that is reverted failed in a row
+    let mut revert_load_failures = 0;
+
+    // A revert that the deployment started on its own and that failed; it
+    // is retried before the next event, even across restarts
+    let mut failed_revert: Option<ForcedRevert> = None;
+
+    // When the block stream was last stopped for a restart
+    let mut restarted_at: Option<Instant> = None;
 
     loop {
         debug!(logger, "Starting or restarting subgraph");
 
+        check_network_drift(&logger, &ctx, &mut net_identifier).await;
+
+        let subgraph_ptr = match verify_until {
+            Some(_) => ctx.state.block_ptr.clone(),
+            None => ctx.inputs.store.block_ptr(&ctx.inputs.deployment_id)?,
+        };
+        ctx.state.block_ptr = subgraph_ptr.clone();
+        ctx.state.pending_poi.forget_after(subgraph_ptr.as_ref());
+        ctx.state.last_commit = subgraph_ptr
+            .as_ref()
+            .map(|ptr| (ptr.number, Instant::now()));
+        let stream_pointer = match verify_until {
+            Some(_) => BlockStreamPointer::Detached(subgraph_ptr.clone()),
+            None => BlockStreamPointer::Store,
+        };
+
+        if let Some(ptr) = &subgraph_ptr {
+            if reached_end_block(&ctx, ptr.number) || caught_up(&ctx, ptr) {
+                complete_subgraph(&logger, &ctx, ptr.number);
+                return Ok(());
+            }
+        }
+        let stream_historical = ctx
+            .inputs
+            .config
+            .use_historical_profile(head_distance(&ctx, subgraph_ptr.map(|ptr| ptr.number)));
+        let stream_profile = ctx.inputs.config.profile(stream_historical);
+        if historical != Some(stream_historical) {
+            info!(
+                logger,
+                "Using {} processing profile",
+                if stream_historical { "historical" } else { "head" };
+                "max_block_range_size" => stream_profile.max_block_range_size,
+                "buffer_depth" => stream_profile.buffer_depth,
+                "confirmations" => stream_profile.confirmations,
+            );
+            historical = Some(stream_historical);
+        }
+        ctx.state.chunk_end = match subgraph_ptr.as_ref().map_or(0, |ptr| ptr.number + 1) {
+            next_block
+                if stream_historical
+                    && verify_until.is_none()
+                    && ctx.inputs.config.backfill_chunk_size > 0 =>
+            {
+                let (chunk, chunk_end) = {
+                    let mut progress = ctx.inputs.backfill_progress.lock().unwrap();
+
+                    // Only the store knows the chunk that was interrupted
+                    // when the node restarted
+                    if progress.chunk.is_none() {
+                        progress.chunk =
+                            ctx.inputs.store.backfill_chunk(&ctx.inputs.deployment_id)?;
+                    }
+                    let previous = progress.chunk;
+                    let chunk_end =
+                        progress.start_chunk(next_block, ctx.inputs.config.backfill_chunk_size);
+                    (
+                        Some(progress.chunk).filter(|chunk| *chunk != previous),
+                        chunk_end,
+                    )
+                };
+                if let Some(chunk) = chunk {
+                    ctx.inputs
+                        .store
+                        .set_backfill_chunk(&ctx.inputs.deployment_id, chunk)?;
+                }
+                debug!(
+                    logger,
+                    "Processing backfill chunk";
+                    "next_block" => next_block,
+                    "chunk_end" => chunk_end,
+                );
+                Some(chunk_end)
+            }
+            _ => None,
+        };
+
+        // The filters and data sources only change when the block stream
+        // is restarted or blocks are reverted
+        update_context_dump(&ctx);
+        check_filter_size(&logger, &ctx)?;
+
+        // The block stream uses the adapter the deployment selected, unless
+        // the operator included calls in blocks for a deployment that does
+        // not require traces, in which case the selected adapter may not
+        // support them
+        let stream_adapter = match ctx.inputs.include_calls_in_blocks {
+            true if !ctx.inputs.required_capabilities.traces => None,
+            _ => Some(ctx.inputs.eth_adapters.current()),
+        };
+
         let block_stream_canceler = CancelGuard::new();
         let block_stream_cancel_handle = block_stream_canceler.handle();
-        let mut block_stream = ctx
+        let block_stream = ctx
             .inputs
             .stream_builder
             .build(
                 logger.clone(),
                 ctx.inputs.deployment_id.clone(),
                 ctx.inputs.network_name.clone(),
+                stream_adapter.clone(),
                 ctx.inputs.start_blocks.clone(),
                 ctx.state.log_filter.clone(),
                 ctx.state.call_filter.clone(),
                 ctx.state.block_filter.clone(),
                 ctx.inputs.include_calls_in_blocks,
+                stream_profile,
+                stream_pointer,
                 ctx.block_stream_metrics.clone(),
             )
+            .with_context(|| {
+                format!(
+                    "Failed to start block stream for deployment `{}` on network `{}`{}",
+                    ctx.inputs.deployment_id,
+                    ctx.inputs.network_name,
+                    stream_build_hint(&ctx)
+                        .map(|hint| format!(" ({})", hint))
+                        .unwrap_or_default()
+                )
+            })?;
+        let buffered_blocks = block_stream.buffered_blocks();
+        let mut block_stream = block_stream
             .map_err(CancelableError::Error)
             .cancelable(&block_stream_canceler, || CancelableError::Cancel)
             .compat();
@@ -513,10 +3632,159 @@ where
 
         debug!(logger, "Starting block stream");
 
+        if let Some(restarted_at) = restarted_at.take() {
+            ctx.subgraph_metrics
+                .restart_duration
+                .observe(restarted_at.elapsed().as_secs_f64());
+        }
+
+        // The event that was fetched while processing the previous block. It
+        // belongs to this block stream and is dropped with it on a restart
+        let mut prefetched = None;
+
         // Process events from the stream as long as no restart is needed
         loop {
-            let block = match block_stream.next().await {
-                Some(Ok(BlockStreamEvent::Block(block))) => block,
+            // Restart the block stream once the deployment switched away
+            // from the adapter the stream uses
+            if let Some(adapter) = &stream_adapter {
+                let current = ctx.inputs.eth_adapters.current();
+                if current.provider() != adapter.provider() {
+                    info!(
+                        logger,
+                        "Restarting block stream with another Ethereum adapter";
+                        "provider" => adapter.provider(),
+                        "current" => current.provider(),
+                    );
+                    ctx.state
+                        .instances
+                        .write()
+                        .unwrap()
+                        .remove(&ctx.inputs.deployment_id);
+                    restarted_at = Some(Instant::now());
+                    break;
+                }
+            }
+
+            // A revert that the deployment starts on its own rather than
+            // because the block stream asked for it
+            let mut forced_revert = match failed_revert.take() {
+                Some(revert) => {
+                    tokio::time::delay_for(FORCED_REVERT_RETRY_DELAY).await;
+                    revert.retry(|| orphaned_block_ptr(&logger, &ctx))
+                }
+                None => None,
+            };
+            let event = match forced_revert.as_ref().map(|revert| revert.ptr().clone()) {
+                Some(ptr) => Some(Ok(BlockStreamEvent::Revert(ptr))),
+                None => match prefetched.take() {
+                    Some(event) => event,
+                    None => loop {
+                        use futures03::future::{select, Either};
+
+                        // A request from an operator, or `None` if the head
+                        // should be rechecked
+                        let request = match select(
+                            block_stream.next(),
+                            select(
+                                Box::pin(ctx.inputs.head_recheck.notified()),
+                                select(
+                                    Box::pin(next_trigger_injection(
+                                        &mut ctx.state.trigger_injections,
+                                    )),
+                                    Box::pin(next_cache_compaction(
+                                        &mut ctx.state.cache_compactions,
+                                    )),
+                                ),
+                            ),
+                        )
+                        .await
+                        {
+                            Either::Left((event, _)) => break event,
+                            Either::Right((Either::Left(_), _)) => None,
+                            Either::Right((
+                                Either::Right((Either::Left((injection, _)), _)),
+                                _,
+                            )) => Some(Either::Left(injection)),
+                            Either::Right((
+                                Either::Right((Either::Right((compaction, _)), _)),
+                                _,
+                            )) => Some(Either::Right(compaction)),
+                        };
+
+                        match request {
+                            Some(Either::Right(compaction)) => {
+                                compact_entity_cache(&logger, &mut ctx, compaction)
+                            }
+                            Some(Either::Left(injection)) => {
+                                handle_trigger_injection(&logger, &ctx, injection).await
+                            }
+                            None => {
+                                if let Some(ptr) = orphaned_block_ptr(&logger, &ctx) {
+                                    forced_revert = Some(ForcedRevert::Orphaned(ptr.clone()));
+                                    break Some(Ok(BlockStreamEvent::Revert(ptr)));
+                                }
+                            }
+                        }
+                    },
+                },
+            };
+
+            // A block at or past a synthetic reorg boundary is put back,
+            // and the block the deployment processed last is reverted first
+            let synthetic_revert = match &event {
+                Some(Ok(BlockStreamEvent::Block(block)))
+                    if forced_revert.is_none() && ctx.inputs.verification.is_none() =>
+                {
+                    synthetic_reorg_ptr(
+                        &logger,
+                        &mut ctx.state.synthetic_reorgs,
+                        ctx.state.block_ptr.as_ref(),
+                        EthereumBlockPointer::from(&block.ethereum_block).number,
+                    )
+                }
+                _ => None,
+            };
+            let event = match synthetic_revert {
+                Some(ptr) => {
+                    prefetched = Some(event);
+                    forced_revert = Some(ForcedRevert::Synthetic(ptr.clone()));
+                    Some(Ok(BlockStreamEvent::Revert(ptr)))
+                }
+                None => event,
+            };
+
+            // Compactions requested while the deployment was busy are
+            // applied before the next block
+            while let Some(compaction) = ctx
+                .state
+                .cache_compactions
+                .as_mut()
+                .and_then(|compactions| compactions.try_next().ok().flatten())
+            {
+                compact_entity_cache(&logger, &mut ctx, compaction);
+            }
+
+            // The same goes for injected triggers, which would otherwise
+            // wait until the deployment is synced, since the block stream
+            // always has the next block ready until then
+            while let Some(injection) = ctx
+                .state
+                .trigger_injections
+                .as_mut()
+                .and_then(|injections| injections.try_next().ok().flatten())
+            {
+                handle_trigger_injection(&logger, &ctx, injection).await;
+            }
+
+            check_stalled(&logger, &mut ctx);
+
+            let block = match event {
+                Some(Ok(BlockStreamEvent::Block(block))) => {
+                    if let Some(adapter) = &stream_adapter {
+                        ctx.inputs.eth_adapters.record(&logger, &**adapter, true);
+                    }
+                    block
+                }
                 Some(Ok(BlockStreamEvent::Revert(subgraph_ptr))) => {
                     info!(
                         logger,
@@ -525,49 +3793,203 @@ where
                         "block_hash" => format!("{}", subgraph_ptr.hash)
                     );
 
+                    // The stored data of a block that is no longer on the
+                    // main chain can't be verified
+                    if let Some(verification) = &verification {
+                        finish_verification(
+                            &logger,
+                            verification,
+                            Verification::Inconclusive(format!(
+                                "block {} was reverted",
+                                subgraph_ptr.number
+                            )),
+                        );
+                        return Ok(());
+                    }
+
+                    // The genesis block has no parent to revert to; having to
+                    // revert it means that the Ethereum node follows another
+                    // chain than the one the subgraph was indexed on
+                    if subgraph_ptr.number == 0 {
+                        let message = format!(
+                            "the block stream asked to revert the genesis block {} of network \
+                             `{}`, which means that the Ethereum node for the network follows \
+                             a different chain than the one the subgraph was indexed on. Check \
+                             the Ethereum node configured for the network",
+                            subgraph_ptr.hash, ctx.inputs.network_name
+                        );
+                        error!(logger, "Stopping the subgraph: {}", message);
+                        if !ctx.inputs.config.fail_on_genesis_revert {
+                            return Ok(());
+                        }
+
+                        let error = SubgraphError {
+                            subgraph_id: id_for_err.clone(),
+                            message: message.clone(),
+                            block_ptr: Some(subgraph_ptr),
+                            handler: None,
+                            deterministic: false,
+                            details: None,
+                        };
+                        store_for_err
+                            .fail_subgraph(id_for_err.clone(), error)
+                            .await
+                            .context("Failed to set subgraph status to `failed`")?;
+                        return Err(anyhow!("{}", message));
+                    }
+
+                    // Once the block can't be loaded from the regular
+                    // provider, try an archive node if the operator said so
+                    let attempts = ctx.inputs.config.revert_block_load_attempts;
+                    let exhausted = attempts > 0 && revert_load_failures >= attempts;
+                    let eth_adapter = match ctx.inputs.config.revert_block_fallback {
+                        RevertBlockFallback::Archive if exhausted => {
+                            let archive = NodeCapabilities {
+                                archive: true,
+                                traces: false,
+                            };
+                            match select_eth_adapter(
+                                &ctx.inputs.eth_networks,
+                                &ctx.inputs.network_name,
+                                &archive,
+                            ) {
+                                Ok(adapter) => Some(adapter),
+                                Err(e) => {
+                                    warn!(
+                                        logger,
+                                        "Can't load the block to revert from an archive node";
+                                        "error" => format!("{:#}", e),
+                                    );
+                                    None
+                                }
+                            }
+                        }
+                        _ if exhausted => None,
+                        _ => Some(ctx.inputs.eth_adapters.current()),
+                    };
+                    let eth_adapter = match eth_adapter {
+                        Some(eth_adapter) => eth_adapter,
+                        None => {
+                            let message = format!(
+                                "could not load block {} ({}) to revert it after {} attempts, \
+                                 most likely because the Ethereum node for network `{}` pruned \
+                                 the history that the revert needs. Use an archive node for \
+                                 the network",
+                                subgraph_ptr.number,
+                                subgraph_ptr.hash,
+                                revert_load_failures,
+                                ctx.inputs.network_name
+                            );
+                            error!(logger, "Stopping the subgraph: {}", message);
+                            let error = SubgraphError {
+                                subgraph_id: id_for_err.clone(),
+                                message: message.clone(),
+                                block_ptr: Some(subgraph_ptr),
+                                handler: None,
+                                deterministic: false,
+                                details: None,
+                            };
+                            store_for_err
+                                .fail_subgraph(id_for_err.clone(), error)
+                                .await
+                                .context("Failed to set subgraph status to `failed`")?;
+                            return Err(anyhow!("{}", message));
+                        }
+                    };
+
                     // We would like to revert the DB state to the parent of the current block.
-                    // First, load the block in order to get the parent hash.
-                    if let Err(e) = ctx
-                        .inputs
-                        .eth_adapter
-                        .load_blocks(
-                            logger.cheap_clone(),
-                            ctx.inputs.chain_store.cheap_clone(),
-                            HashSet::from_iter(Some(subgraph_ptr.hash_as_h256())),
-                        )
-                        .collect()
-                        .compat()
-                        .await
-                        .map(|blocks| {
-                            assert_eq!(blocks.len(), 1);
-                            blocks.into_iter().next().unwrap()
-                        })
-                        .and_then(|block| {
-                            // Produce pointer to parent block (using parent hash).
-                            let parent_ptr = block
-                                .parent_ptr()
-                                .expect("genesis block cannot be reverted");
-
-                            // Revert entity changes from this block, and update subgraph ptr.
-                            ctx.inputs
-                                .store
-                                .revert_block_operations(
-                                    ctx.inputs.deployment_id.clone(),
-                                    parent_ptr,
+                    // Shallow reverts usually find it among the blocks we processed recently;
+                    // otherwise, load the block in order to get the parent hash.
+                    let buffered_parent = ctx
+                        .state
+                        .recent_blocks
+                        .iter()
+                        .rev()
+                        .find(|(ptr, _)| ptr == &subgraph_ptr)
+                        .map(|(_, parent_ptr)| parent_ptr.clone());
+                    let parent_ptr = match buffered_parent {
+                        Some(parent_ptr) => Ok(parent_ptr),
+                        None => {
+                            let blocks = eth_adapter
+                                .load_blocks(
+                                    logger.cheap_clone(),
+                                    ctx.inputs.chain_store.cheap_clone(),
+                                    HashSet::from_iter(Some(subgraph_ptr.hash_as_h256())),
                                 )
-                                .map_err(Into::into)
-                        })
+                                .collect()
+                                .compat()
+                                .await;
+                            ctx.inputs
+                                .eth_adapters
+                                .record(&logger, &*eth_adapter, blocks.is_ok());
+                            blocks
+                                .map(|blocks| {
+                                    assert_eq!(blocks.len(), 1);
+                                    blocks.into_iter().next().unwrap()
+                                })
+                                .and_then(|block| {
+                                    // Produce pointer to parent block (using parent hash).
+                                    block.parent_ptr().ok_or_else(|| {
+                                        anyhow!("the genesis block cannot be reverted")
+                                    })
+                                })
+                        }
+                    };
+                    // Only failures to load the block count towards giving
+                    // up on the provider; store errors are always retried
+                    let parent_ptr = match parent_ptr {
+                        Ok(parent_ptr) => {
+                            revert_load_failures = 0;
+                            parent_ptr
+                        }
+                        Err(e) => {
+                            revert_load_failures += 1;
+                            debug!(
+                                &logger,
+                                "Could not load block to revert. \
+                                The likely cause is the block not being found due to a deep reorg. \
+                                Retrying";
+                                "block_number" => format!("{}", subgraph_ptr.number),
+                                "block_hash" => format!("{}", subgraph_ptr.hash),
+                                "attempts" => revert_load_failures,
+                                "error" => e.to_string(),
+                            );
+                            failed_revert = forced_revert;
+                            continue;
+                        }
+                    };
+
+                    // Revert entity changes from this block, and update subgraph ptr.
+                    let reverted_entity_types = match ctx
+                        .inputs
+                        .store
+                        .revert_block_operations(ctx.inputs.deployment_id.clone(), parent_ptr)
                     {
-                        debug!(
-                            &logger,
-                            "Could not revert block. \
-                            The likely cause is the block not being found due to a deep reorg. \
-                            Retrying";
-                            "block_number" => format!("{}", subgraph_ptr.number),
-                            "block_hash" => format!("{}", subgraph_ptr.hash),
-                            "error" => e.to_string(),
+                        Ok(entity_types) => entity_types,
+                        Err(e) => {
+                            debug!(
+                                &logger,
+                                "Could not revert block. Retrying";
+                                "block_number" => format!("{}", subgraph_ptr.number),
+                                "block_hash" => format!("{}", subgraph_ptr.hash),
+                                "error" => e.to_string(),
+                            );
+                            failed_revert = forced_revert;
+                            continue;
+                        }
+                    };
+
+                    if ctx.inputs.config.log_reverted_entity_types {
+                        info!(
+                            logger,
+                            "Reverted entity changes";
+                            "block_number" => subgraph_ptr.number,
+                            "entity_types" => reverted_entity_types
+                                .iter()
+                                .map(|entity_type| entity_type.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", "),
                         );
-                        continue;
                     }
 
                     ctx.block_stream_metrics
@@ -583,7 +4005,68 @@ where
                     // incorrect since we will discard triggers that match the filters but do not
                     // match any data sources.
                     ctx.state.instance.revert_data_sources(subgraph_ptr.number);
+                    ctx.subgraph_metrics
+                        .data_source_count
+                        .set(ctx.state.instance.data_sources().count() as f64);
                     ctx.state.entity_lfu_cache = LfuCache::new();
+                    ctx.state
+                        .recent_blocks
+                        .retain(|(ptr, _)| ptr.number < subgraph_ptr.number);
+                    ctx.state.block_ptr = ctx.inputs.store.block_ptr(&ctx.inputs.deployment_id)?;
+                    if ctx.inputs.settings.poi_write_interval > 1 {
+                        ctx.state
+                            .pending_poi
+                            .forget_after(ctx.state.block_ptr.as_ref());
+                        ctx.state.pending_poi.written = ctx
+                            .inputs
+                            .store
+                            .last_proof_of_indexing_block(&ctx.inputs.deployment_id)?
+                            .is_some();
+                    }
+                    {
+                        // Blocks before the revert say nothing about the
+                        // throughput after it
+                        let mut health = ctx.inputs.health.lock().unwrap();
+                        health.block_ptr = ctx.state.block_ptr.clone();
+                        health.recent_blocks.clear();
+                    }
+                    if let (Some(modifications), Some(block_ptr)) =
+                        (&ctx.inputs.modifications, &ctx.state.block_ptr)
+                    {
+                        modifications.publish(ModificationsEvent::Revert {
+                            deployment: ctx.inputs.deployment_id.clone(),
+                            block_ptr: block_ptr.clone(),
+                        });
+                    }
+                    if ctx.state.reorg_depth.is_none() {
+                        if let Some(notifier) = &ctx.inputs.config.reorg_notifier {
+                            notifier.notify(ReorgEvent::Started {
+                                deployment: ctx.inputs.deployment_id.clone(),
+                                block_ptr: subgraph_ptr.clone(),
+                            });
+                        }
+                    }
+                    *ctx.state.reorg_depth.get_or_insert(0) += 1;
+                    update_context_dump(&ctx);
+                    ctx.state.last_commit = ctx
+                        .state
+                        .block_ptr
+                        .as_ref()
+                        .map(|ptr| (ptr.number, Instant::now()));
+
+                    // The block stream is already past the block that a
+                    // synthetic reorg reverted, or may have buffered blocks
+                    // from the fork that the head recheck found orphaned,
+                    // and has to start over
+                    if forced_revert.is_some() {
+                        ctx.state
+                            .instances
+                            .write()
+                            .unwrap()
+                            .remove(&ctx.inputs.deployment_id);
+                        restarted_at = Some(Instant::now());
+                        break;
+                    }
                     continue;
                 }
                 // Log and drop the errors from the block_stream
@@ -594,6 +4077,9 @@ where
                         "Block stream produced a non-fatal error";
                         "error" => format!("{}", e),
                     );
+                    if let (Some(adapter), CancelableError::Error(_)) = (&stream_adapter, &e) {
+                        ctx.inputs.eth_adapters.record(&logger, &**adapter, false);
+                    }
                     continue;
                 }
                 None => unreachable!("The block stream stopped producing blocks"),
@@ -601,6 +4087,56 @@ where
 
             let block_ptr = EthereumBlockPointer::from(&block.ethereum_block);
 
+            // The block stream skips blocks without triggers, and the first
+            // block it delivers at or past the end block may be past it; the
+            // deployment has then completed without processing that block
+            if ctx
+                .inputs
+                .settings
+                .end_block
+                .map_or(false, |end_block| block_ptr.number > end_block)
+            {
+                complete_subgraph(&logger, &ctx, block_ptr.number);
+                return Ok(());
+            }
+
+            // Remember the parent of the block in case we have to revert it
+            let buffer_size = ctx.inputs.config.revert_block_buffer_size;
+            if buffer_size > 0 {
+                if let Some(parent_ptr) = block.ethereum_block.parent_ptr() {
+                    if ctx.state.recent_blocks.len() >= buffer_size {
+                        ctx.state.recent_blocks.pop_front();
+                    }
+                    ctx.state
+                        .recent_blocks
+                        .push_back((block_ptr.clone(), parent_ptr));
+                }
+            }
+
+            // The first block after reverts ends the reorg
+            if let Some(depth) = ctx.state.reorg_depth.take() {
+                if let (Some(notifier), Some(reverted_to)) =
+                    (&ctx.inputs.config.reorg_notifier, &ctx.state.block_ptr)
+                {
+                    notifier.notify(ReorgEvent::Finished {
+                        deployment: ctx.inputs.deployment_id.clone(),
+                        block_ptr: reverted_to.clone(),
+                        depth,
+                    });
+                }
+            }
+
+            if let (Some(verification), Some(until)) = (&verification, &verify_until) {
+                if block_ptr.number > until.number {
+                    finish_verification(
+                        &logger,
+                        verification,
+                        Verification::Matched(ctx.state.block_ptr.clone()),
+                    );
+                    return Ok(());
+                }
+            }
+
             if block.triggers.len() > 0 {
                 subgraph_metrics
                     .block_trigger_count
@@ -609,31 +4145,151 @@ where
 
             let start = Instant::now();
 
-            let res = process_block(
+            // The stopwatch sections of the block become children of this
+            // span for distributed tracing
+            let block_span = tracing::info_span!(
+                "process_block",
+                deployment = id_for_err.as_str(),
+                block_number = block_ptr.number,
+                block_hash = block_ptr.hash_hex().as_str(),
+            );
+            ctx.inputs.eth_adapters.check_primary(&logger).await;
+            // Once its buffer is empty, the block stream continues from the
+            // block pointer in the store, which does not include the block
+            // that is being processed yet; it would yield that block again
+            let prefetch =
+                ctx.inputs.config.prefetch_next_block && buffered_blocks.load(Ordering::SeqCst) > 0;
+            let process = process_block(
                 &logger,
-                ctx.inputs.eth_adapter.cheap_clone(),
+                ctx.inputs.eth_adapters.current(),
                 ctx,
                 block_stream_cancel_handle.clone(),
                 block,
             )
-            .await;
+            .instrument(block_span);
+            let res = if prefetch {
+                // Stop waiting for the next event once the block has been
+                // processed; waiting for it is then up to the next iteration
+                match futures03::future::select(Box::pin(process), block_stream.next()).await {
+                    futures03::future::Either::Left((res, _)) => res,
+                    futures03::future::Either::Right((event, process)) => {
+                        prefetched = Some(event);
+                        process.await
+                    }
+                }
+            } else {
+                process.await
+            };
 
             let elapsed = start.elapsed().as_secs_f64();
             subgraph_metrics.block_processing_duration.observe(elapsed);
 
+            if let Some(push) = &metrics_push {
+                if last_push.elapsed() >= push.interval {
+                    push.sink.push(subgraph_metrics.samples());
+                    last_push = Instant::now();
+                }
+            }
+
             match res {
-                Ok((c, needs_restart)) => {
+                Ok((c, restart)) => {
                     ctx = c;
 
                     // Unfail the subgraph if it was previously failed.
                     // As an optimization we check this only on the first run.
-                    if first_run {
+                    if first_run && verify_until.is_none() {
                         first_run = false;
 
                         ctx.inputs.store.unfail(&ctx.inputs.deployment_id)?;
                     }
 
-                    if needs_restart {
+                    // Reverts only ever move the subgraph back, so once it
+                    // has processed its end block, or the chain head, on the
+                    // main chain, we're done
+                    if restart.map_or(true, |reason| reason.block_processed())
+                        && (reached_end_block(&ctx, block_ptr.number)
+                            || caught_up(&ctx, &block_ptr))
+                    {
+                        complete_subgraph(&logger, &ctx, block_ptr.number);
+                        return Ok(());
+                    }
+
+                    if let (Some(verification), Some(until)) = (&verification, &verify_until) {
+                        if ctx
+                            .state
+                            .block_ptr
+                            .as_ref()
+                            .map_or(false, |ptr| ptr.number >= until.number)
+                        {
+                            finish_verification(
+                                &logger,
+                                verification,
+                                Verification::Matched(ctx.state.block_ptr.clone()),
+                            );
+                            return Ok(());
+                        }
+                    }
+
+                    let distance = head_distance(&ctx, Some(block_ptr.number));
+                    let block_historical = ctx.inputs.config.use_historical_profile(distance);
+                    {
+                        let mut health = ctx.inputs.health.lock().unwrap();
+                        health.synced = !block_historical;
+                        health.head_distance = Some(distance);
+                        health.block_ptr = ctx.state.block_ptr.clone();
+                        health.record_block(block_ptr.number);
+                    }
+                    {
+                        let mut dump = ctx.inputs.context_dump.lock().unwrap();
+                        dump.block_number = ctx.state.block_ptr.as_ref().map(|ptr| ptr.number);
+                        dump.block_hash = ctx
+                            .state
+                            .block_ptr
+                            .as_ref()
+                            .map(|ptr| format!("{:x}", ptr.hash));
+                        dump.entity_cache_entries = ctx.state.entity_lfu_cache.len();
+                        dump.entity_cache_weight = ctx.state.entity_lfu_cache.total_weight();
+                    }
+
+                    // Switch the block stream over to the other processing
+                    // profile if the distance to the chain head calls for it
+                    let restart = restart.or_else(|| {
+                        if block_historical != stream_historical {
+                            Some(RestartReason::ProcessingProfile)
+                        } else {
+                            None
+                        }
+                    });
+
+                    // Record a checkpoint once the pointer for the end of the
+                    // backfill chunk was written, and start the next chunk
+                    // with a fresh block stream
+                    let restart = match (ctx.state.chunk_end, &ctx.state.block_ptr) {
+                        (Some(chunk_end), Some(ptr))
+                            if ptr.number >= chunk_end
+                                && restart.map_or(true, |reason| reason.block_processed()) =>
+                        {
+                            let mut progress = ctx.inputs.backfill_progress.lock().unwrap();
+                            progress.complete_chunk(ptr.clone());
+                            info!(
+                                logger,
+                                "Completed backfill chunk";
+                                "checkpoint" => ptr.number,
+                                "chunks_completed" => progress.chunks_completed,
+                            );
+                            ctx.state.chunk_end = None;
+                            restart.or(Some(RestartReason::Checkpoint))
+                        }
+                        _ => restart,
+                    };
+
+                    if let Some(reason) = restart {
+                        subgraph_metrics
+                            .restart_count
+                            .with_label_values(&[reason.label_value()])
+                            .inc();
+                        ctx.inputs.health.lock().unwrap().restart_count += 1;
+
                         // Cancel the stream for real
                         ctx.state
                             .instances
@@ -642,6 +4298,7 @@ where
                             .remove(&ctx.inputs.deployment_id);
 
                         // And restart the subgraph
+                        restarted_at = Some(Instant::now());
                         break;
                     }
                 }
@@ -659,28 +4316,376 @@ where
                     let message = format!("{:#}", e).replace("\n", "\t");
                     let err = anyhow!("{}, code: {}", message, LogCode::SubgraphSyncingFailure);
 
+                    // A verify-only run never marks the deployment as failed
+                    if let Some(verification) = &verification {
+                        finish_verification(
+                            &logger,
+                            verification,
+                            Verification::Inconclusive(message),
+                        );
+                        return Err(err);
+                    }
+
                     let error = SubgraphError {
                         subgraph_id: id_for_err.clone(),
                         message,
                         block_ptr: Some(block_ptr),
                         handler: None,
                         deterministic: e.is_deterministic(),
+                        details: None,
                     };
 
+                    let deterministic = error.deterministic;
                     store_for_err
                         .fail_subgraph(id_for_err.clone(), error)
                         .await
                         .context("Failed to set subgraph status to `failed`")?;
 
-                    return Err(err);
+                    return Err(if deterministic {
+                        DeterministicFailure(err.to_string()).into()
+                    } else {
+                        err
+                    });
                 }
             }
         }
     }
 }
 
+fn reached_end_block<B, T: RuntimeHostBuilder, S, C>(
+    ctx: &IndexingContext<B, T, S, C>,
+    block_number: BlockNumber,
+) -> bool {
+    ctx.inputs
+        .settings
+        .end_block
+        .map_or(false, |end_block| block_number >= end_block)
+}
+
+/// Whether `block_ptr` is on the main chain according to `chain_store`. If
+/// the chain store can't tell, for example because its chain head is behind
+/// `block_ptr`, we assume that it is not.
+fn block_on_main_chain<C: ChainStore>(
+    logger: &Logger,
+    chain_store: &C,
+    block_ptr: &EthereumBlockPointer,
+) -> bool {
+    let head_ptr = match chain_store.chain_head_ptr() {
+        Ok(Some(head_ptr)) if head_ptr.number >= block_ptr.number => head_ptr,
+        Ok(_) => return false,
+        Err(e) => {
+            debug!(logger, "Failed to get chain head pointer"; "error" => e.to_string());
+            return false;
+        }
+    };
+    let offset = head_ptr.number - block_ptr.number;
+    match chain_store.ancestor_block(head_ptr, offset) {
+        Ok(Some(block)) => EthereumBlockPointer::from(&block) == *block_ptr,
+        Ok(None) => false,
+        Err(e) => {
+            debug!(logger, "Failed to get block from the chain store"; "error" => e.to_string());
+            false
+        }
+    }
+}
+
+/// Whether a catch-up only deployment is done after processing `block_ptr`
+/// because that is the chain head. A chain head with the same number but a
+/// different hash means that `block_ptr` is about to be reverted, and a
+/// chain head that is behind `block_ptr` just hasn't been updated yet.
+fn caught_up<B, T: RuntimeHostBuilder, S, C>(
+    ctx: &IndexingContext<B, T, S, C>,
+    block_ptr: &EthereumBlockPointer,
+) -> bool
+where
+    C: ChainStore,
+{
+    if !ctx.inputs.settings.catch_up_only || ctx.inputs.verification.is_some() {
+        return false;
+    }
+    match ctx.inputs.chain_store.chain_head_ptr() {
+        Ok(Some(head_ptr)) => {
+            head_ptr.number < block_ptr.number
+                || (head_ptr.number == block_ptr.number && head_ptr.hash == block_ptr.hash)
+        }
+        Ok(None) => false,
+        Err(e) => {
+            debug!(
+                ctx.state.logger,
+                "Failed to get chain head pointer";
+                "error" => e.to_string(),
+            );
+            false
+        }
+    }
+}
+
+/// Stop a subgraph that has reached its end block, or the chain head if it
+/// only catches up. This is a successful outcome, unlike the subgraph
+/// failing or being canceled.
+fn complete_subgraph<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S, C>,
+    block_number: BlockNumber,
+) {
+    ctx.state
+        .instances
+        .write()
+        .unwrap()
+        .remove(&ctx.inputs.deployment_id);
+    ctx.inputs.completed.store(true, Ordering::SeqCst);
+    ctx.manager_metrics.completed_count.inc();
+
+    if reached_end_block(ctx, block_number) {
+        info!(
+            logger,
+            "Subgraph reached its end block, stopping";
+            "end_block" => ctx.inputs.settings.end_block,
+            "block_number" => block_number,
+        );
+    } else {
+        info!(
+            logger,
+            "Subgraph caught up with the chain head, stopping";
+            "block_number" => block_number,
+        );
+    }
+}
+
+/// Forget the deployment `id` that was running, either because it was
+/// stopped or because it completed.
+fn forget_running_subgraph(
+    id: &SubgraphDeploymentId,
+    manifests: &SharedManifestMap,
+    health: &SharedHealthMap,
+    context_dumps: &SharedContextDumpMap,
+    trigger_injections: &SharedTriggerInjectionMap,
+    cache_compactions: &SharedCacheCompactionMap,
+    manager_metrics: &SubgraphInstanceManagerMetrics,
+) {
+    trigger_injections.write().unwrap().remove(id);
+    cache_compactions.write().unwrap().remove(id);
+    context_dumps.write().unwrap().remove(id);
+    forget_manifest(id, manifests, manager_metrics);
+
+    // Only deployments whose thread was started count as running
+    if health.write().unwrap().remove(id).is_some() {
+        manager_metrics.subgraph_count.dec();
+    }
+}
+
+/// Forget the manifest of the deployment `id`, which is only kept while the
+/// deployment runs.
+fn forget_manifest(
+    id: &SubgraphDeploymentId,
+    manifests: &SharedManifestMap,
+    manager_metrics: &SubgraphInstanceManagerMetrics,
+) {
+    if let Some(manifest) = manifests.write().unwrap().remove(id) {
+        let capabilities = manifest.required_ethereum_capabilities();
+        let _ = manager_metrics.required_capabilities.remove_label_values(
+            &SubgraphInstanceManagerMetrics::capability_labels(id, &capabilities),
+        );
+    }
+}
+
+/// Guess why building the block stream for the subgraph failed by checking
+/// the most common reasons.
+fn stream_build_hint<B, T: RuntimeHostBuilder, S, C>(
+    ctx: &IndexingContext<B, T, S, C>,
+) -> Option<String>
+where
+    C: ChainStore,
+{
+    let inputs = &ctx.inputs;
+
+    if let Err(e) = inputs
+        .eth_networks
+        .adapter_with_capabilities(inputs.network_name.clone(), &inputs.required_capabilities)
+    {
+        return Some(format!(
+            "probably no Ethereum provider for this network supports {}: {}",
+            inputs.required_capabilities, e
+        ));
+    }
+
+    let head_ptr = match inputs.chain_store.chain_head_ptr() {
+        Ok(Some(head_ptr)) => head_ptr,
+        Ok(None) => {
+            return Some("probably the chain head for this network is not known yet".to_string())
+        }
+        Err(e) => {
+            return Some(format!(
+                "probably the chain store is not available: {:#}",
+                e
+            ))
+        }
+    };
+
+    inputs
+        .start_blocks
+        .iter()
+        .min()
+        .filter(|start_block| **start_block > head_ptr.number)
+        .map(|start_block| {
+            format!(
+                "probably the start block {} is after the chain head {}",
+                start_block, head_ptr.number
+            )
+        })
+}
+
+/// Flag the deployment as stalled if it uses the historical processing
+/// profile, the chain head advanced during the last `stall_window`, and the
+/// deployment did not get closer to it, for example because it keeps
+/// reverting blocks or its handlers are too slow. This is checked whenever
+/// the deployment gets an event from its block stream.
+fn check_stalled<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &mut IndexingContext<B, T, S, C>,
+) where
+    C: ChainStore,
+{
+    let window = ctx.inputs.config.stall_window;
+    if window == Duration::from_secs(0) {
+        return;
+    }
+    if let Some((started, _, _)) = ctx.state.stall_window_start {
+        if started.elapsed() < window {
+            return;
+        }
+    }
+
+    let chain_head = match ctx.inputs.chain_store.chain_head_ptr() {
+        Ok(Some(head_ptr)) => head_ptr.number,
+        _ => return,
+    };
+    let subgraph_block = ctx.state.block_ptr.as_ref().map_or(0, |ptr| ptr.number);
+    let distance = (chain_head - subgraph_block).max(0);
+
+    if let Some((_, start_distance, start_chain_head)) = ctx.state.stall_window_start {
+        let stalled = chain_head > start_chain_head
+            && distance >= start_distance
+            && ctx.inputs.config.use_historical_profile(distance);
+        let mut health = ctx.inputs.health.lock().unwrap();
+        if stalled && !health.stalled {
+            warn!(
+                logger,
+                "Subgraph is not getting closer to the chain head";
+                "head_distance" => distance,
+                "previous_head_distance" => start_distance,
+                "window_s" => window.as_secs(),
+            );
+        } else if !stalled && health.stalled {
+            info!(
+                logger,
+                "Subgraph is getting closer to the chain head again";
+                "head_distance" => distance,
+            );
+        }
+        health.stalled = stalled;
+        ctx.subgraph_metrics
+            .stalled
+            .set(if stalled { 1.0 } else { 0.0 });
+    }
+    ctx.state.stall_window_start = Some((Instant::now(), distance, chain_head));
+}
+
+/// The number of blocks the subgraph at `subgraph_block` is behind the chain
+/// head. If the chain head is not known, we assume that the subgraph is
+/// caught up; a subgraph that hasn't processed any blocks yet is as far
+/// behind as the chain is long.
+fn head_distance<B, T: RuntimeHostBuilder, S, C>(
+    ctx: &IndexingContext<B, T, S, C>,
+    subgraph_block: Option<BlockNumber>,
+) -> BlockNumber
+where
+    C: ChainStore,
+{
+    match ctx.inputs.chain_store.chain_head_ptr() {
+        Ok(Some(head_ptr)) => (head_ptr.number - subgraph_block.unwrap_or(0)).max(0),
+        Ok(None) => 0,
+        Err(e) => {
+            debug!(
+                ctx.state.logger,
+                "Failed to get chain head pointer";
+                "error" => e.to_string(),
+            );
+            0
+        }
+    }
+}
+
+/// Check that the network configuration the subgraph runs against is still
+/// consistent with what it was when the subgraph started, and warn if it is
+/// not. The identifiers of the network are recorded in `net_identifier` on
+/// the first call and compared against on subsequent calls.
+async fn check_network_drift<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S, C>,
+    net_identifier: &mut Option<EthereumNetworkIdentifier>,
+) where
+    S: SubgraphStore,
+{
+    let inputs = &ctx.inputs;
+
+    match inputs.store.network_name(&inputs.deployment_id) {
+        Ok(network) if network != inputs.network_name => warn!(
+            logger,
+            "Network of the deployment in the store differs from the network it is indexing";
+            "store_network" => network,
+            "network" => &inputs.network_name,
+        ),
+        Ok(_) => {}
+        Err(e) => debug!(
+            logger,
+            "Could not check the network of the deployment";
+            "error" => e.to_string(),
+        ),
+    }
+
+    if let Err(e) = inputs
+        .eth_networks
+        .adapter_with_capabilities(inputs.network_name.clone(), &inputs.required_capabilities)
+    {
+        warn!(
+            logger,
+            "Ethereum adapters configured for the network no longer have the required capabilities";
+            "network" => &inputs.network_name,
+            "required_capabilities" => &inputs.required_capabilities,
+            "error" => e.to_string(),
+        );
+    }
+
+    let eth_adapter = inputs.eth_adapters.current();
+    let res = eth_adapter.net_identifiers().await;
+    inputs
+        .eth_adapters
+        .record(logger, &*eth_adapter, res.is_ok());
+    match res {
+        Ok(current) => match net_identifier {
+            Some(initial) if *initial != current => warn!(
+                logger,
+                "Ethereum node now reports a different chain than when the subgraph started";
+                "network" => &inputs.network_name,
+                "net_version" => &current.net_version,
+                "genesis_block_hash" => format!("{:x}", current.genesis_block_hash),
+                "initial_net_version" => &initial.net_version,
+                "initial_genesis_block_hash" => format!("{:x}", initial.genesis_block_hash),
+            ),
+            Some(_) => {}
+            None => *net_identifier = Some(current),
+        },
+        Err(e) => debug!(
+            logger,
+            "Could not check the identifiers of the Ethereum node";
+            "error" => format!("{:#}", e),
+        ),
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
-enum BlockProcessingError {
+pub(crate) enum BlockProcessingError {
     #[error("{0:#}")]
     Unknown(Error),
 
@@ -693,30 +4698,575 @@ enum BlockProcessingError {
     Canceled,
 }
 
-impl BlockProcessingError {
-    fn is_deterministic(&self) -> bool {
-        match self {
-            BlockProcessingError::Deterministic(_) => true,
-            _ => false,
+impl BlockProcessingError {
+    fn is_deterministic(&self) -> bool {
+        match self {
+            BlockProcessingError::Deterministic(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The error with which `run_subgraph` ends when the subgraph failed
+/// deterministically. Such failures are not caused by a flaky environment,
+/// so the circuit breaker ignores them.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct DeterministicFailure(String);
+
+impl From<Error> for BlockProcessingError {
+    fn from(e: Error) -> Self {
+        BlockProcessingError::Unknown(e)
+    }
+}
+
+/// Record the size of the filters of the deployment, and warn about or
+/// refuse filters larger than `max_filter_size`. Huge filters slow down
+/// matching triggers and can make `eth_getLogs` requests time out.
+fn check_filter_size<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S, C>,
+) -> Result<(), Error> {
+    let sizes = [
+        ("log", ctx.state.log_filter.size()),
+        ("call", ctx.state.call_filter.size()),
+        ("block", ctx.state.block_filter.size()),
+    ];
+    for (filter, size) in &sizes {
+        ctx.subgraph_metrics
+            .filter_size
+            .with_label_values(&[filter])
+            .set(*size as f64);
+    }
+
+    let config = &ctx.inputs.config;
+    let total: usize = sizes.iter().map(|(_, size)| size).sum();
+    if config.max_filter_size == 0 || total <= config.max_filter_size {
+        return Ok(());
+    }
+    if config.fail_on_max_filter_size {
+        return Err(anyhow!(
+            "the filters of the subgraph have {} entries, more than the maximum of {} \
+             set with GRAPH_MAX_FILTER_SIZE",
+            total,
+            config.max_filter_size
+        ));
+    }
+    warn!(
+        logger,
+        "The filters of the subgraph are very large, which slows down indexing";
+        "log_filter_size" => sizes[0].1,
+        "call_filter_size" => sizes[1].1,
+        "block_filter_size" => sizes[2].1,
+        "max_filter_size" => config.max_filter_size,
+    );
+    Ok(())
+}
+
+/// How long to wait before retrying a `ForcedRevert` that failed.
+const FORCED_REVERT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A revert that the deployment starts on its own, rather than because the
+/// block stream asked for it. The block stream is restarted after it, since
+/// it is past the reverted block or may have buffered blocks of a fork.
+#[derive(Clone, Debug, PartialEq)]
+enum ForcedRevert {
+    /// Revert the block for a synthetic reorg, see `synthetic_reorg_ptr`.
+    /// The block stream put the block at the boundary back, and its
+    /// boundary is used up.
+    Synthetic(EthereumBlockPointer),
+    /// Revert the block that the head recheck found to be orphaned, see
+    /// `orphaned_block_ptr`.
+    Orphaned(EthereumBlockPointer),
+}
+
+impl ForcedRevert {
+    fn ptr(&self) -> &EthereumBlockPointer {
+        match self {
+            ForcedRevert::Synthetic(ptr) | ForcedRevert::Orphaned(ptr) => ptr,
+        }
+    }
+
+    /// The revert to retry after this one failed. A synthetic revert has to
+    /// be retried since its boundary is used up; otherwise the deployment
+    /// would process the block at the boundary without the revert. For an
+    /// orphaned block, `recheck` checks the head again, since the chain
+    /// store may have moved on; otherwise the deployment would stay on the
+    /// orphaned block until the head is rechecked again.
+    fn retry(self, recheck: impl FnOnce() -> Option<EthereumBlockPointer>) -> Option<Self> {
+        match self {
+            ForcedRevert::Synthetic(ptr) => Some(ForcedRevert::Synthetic(ptr)),
+            ForcedRevert::Orphaned(_) => recheck().map(ForcedRevert::Orphaned),
+        }
+    }
+}
+
+/// The block pointer to revert before processing the block `block_number` if
+/// the block is at or past a synthetic reorg boundary that the deployment,
+/// which processed `block_ptr` last, has not reached yet. The boundaries up
+/// to the block are used up. See `SubgraphInstanceManager::set_synthetic_reorgs`.
+fn synthetic_reorg_ptr(
+    logger: &Logger,
+    synthetic_reorgs: &mut BTreeSet<BlockNumber>,
+    block_ptr: Option<&EthereumBlockPointer>,
+    block_number: BlockNumber,
+) -> Option<EthereumBlockPointer> {
+    if synthetic_reorgs.is_empty() {
+        return None;
+    }
+
+    let remaining = synthetic_reorgs.split_off(&(block_number + 1));
+    let reached = std::mem::replace(synthetic_reorgs, remaining);
+    let block_ptr = block_ptr?;
+    let boundary = *reached.range((block_ptr.number + 1)..).next()?;
+
+    // The genesis block can't be reverted
+    if block_ptr.number == 0 {
+        return None;
+    }
+    info!(
+        logger,
+        "Reverting the last processed block for a synthetic reorg";
+        "boundary" => boundary,
+        "block_number" => block_ptr.number,
+    );
+    Some(block_ptr.clone())
+}
+
+/// The block pointer of the deployment if the chain store says that it is not
+/// on the main chain. See `SubgraphInstanceManager::recheck_head`.
+fn orphaned_block_ptr<B, T: RuntimeHostBuilder, S, C: ChainStore>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S, C>,
+) -> Option<EthereumBlockPointer> {
+    let block_ptr = match &ctx.state.block_ptr {
+        Some(block_ptr) => block_ptr.clone(),
+        None => {
+            info!(
+                logger,
+                "Not checking the head since no block was processed yet"
+            );
+            return None;
+        }
+    };
+    let chain_head = match ctx.inputs.chain_store.chain_head_ptr() {
+        Ok(Some(chain_head)) if chain_head.number >= block_ptr.number => chain_head,
+        Ok(_) => {
+            info!(
+                logger,
+                "Not checking the head since the chain store is behind the subgraph";
+                "block_number" => block_ptr.number,
+            );
+            return None;
+        }
+        Err(e) => {
+            warn!(logger, "Failed to load the chain head to check the head"; "error" => e.to_string());
+            return None;
+        }
+    };
+    let main_chain_hash = ctx
+        .inputs
+        .chain_store
+        .ancestor_block(chain_head.clone(), chain_head.number - block_ptr.number)
+        .map(|block| block.and_then(|block| block.block.hash));
+    match main_chain_hash {
+        Ok(Some(hash)) if hash == block_ptr.hash_as_h256() => {
+            info!(
+                logger,
+                "Checked the head, it is on the main chain";
+                "block_number" => block_ptr.number,
+                "block_hash" => format!("{}", block_ptr.hash),
+            );
+            None
+        }
+        Ok(Some(hash)) => {
+            warn!(
+                logger,
+                "Checked the head, it is not on the main chain";
+                "block_number" => block_ptr.number,
+                "block_hash" => format!("{}", block_ptr.hash),
+                "main_chain_hash" => format!("{:x}", hash),
+            );
+            Some(block_ptr)
+        }
+        Ok(None) => {
+            warn!(
+                logger,
+                "Could not check the head since the chain store is missing blocks";
+                "block_number" => block_ptr.number,
+            );
+            None
+        }
+        Err(e) => {
+            warn!(logger, "Failed to check the head"; "error" => e.to_string());
+            None
+        }
+    }
+}
+
+/// Describe the current indexing context of the deployment for
+/// `SubgraphInstanceManager::indexing_context`.
+fn update_context_dump<B, T: RuntimeHostBuilder, S, C>(ctx: &IndexingContext<B, T, S, C>) {
+    let mut data_sources: Vec<_> = ctx
+        .state
+        .instance
+        .data_sources()
+        .map(|data_source| DataSourceDump {
+            name: data_source.name.clone(),
+            kind: data_source.kind.clone(),
+            address: data_source
+                .source
+                .address
+                .map(|address| format!("{:x}", address)),
+            start_block: data_source.source.start_block,
+            creation_block: data_source.creation_block,
+        })
+        .collect();
+    data_sources.sort();
+
+    let filters = filters_dump(
+        &ctx.state.log_filter,
+        &ctx.state.call_filter,
+        &ctx.state.block_filter,
+    );
+
+    // Filters are not narrowed when blocks that created data sources are
+    // reverted; compare them to the filters of the current data sources
+    let data_source_filters = filters_dump(
+        &EthereumLogFilter::from_data_sources(ctx.state.instance.data_sources()),
+        &EthereumCallFilter::from_data_sources(ctx.state.instance.data_sources()),
+        &EthereumBlockFilter::from_data_sources(ctx.state.instance.data_sources()),
+    );
+    let filters_match_data_sources = filters == data_source_filters;
+
+    let block_ptr = ctx.state.block_ptr.as_ref();
+    *ctx.inputs.context_dump.lock().unwrap() = IndexingContextDump {
+        deployment: ctx.inputs.deployment_id.to_string(),
+        network: ctx.inputs.network_name.clone(),
+        block_number: block_ptr.map(|ptr| ptr.number),
+        block_hash: block_ptr.map(|ptr| format!("{:x}", ptr.hash)),
+        features: ctx
+            .inputs
+            .features
+            .iter()
+            .map(|feature| feature.to_string())
+            .collect(),
+        data_sources,
+        log_filters: filters.log_filters,
+        call_filters: filters.call_filters,
+        block_filter_contracts: filters.block_filter_contracts,
+        trigger_every_block: filters.trigger_every_block,
+        filters_match_data_sources,
+        entity_cache_entries: ctx.state.entity_lfu_cache.len(),
+        entity_cache_weight: ctx.state.entity_lfu_cache.total_weight(),
+    };
+}
+
+/// The filters a deployment requests triggers with, in the form in which
+/// they appear in `IndexingContextDump`.
+fn filters_dump(
+    log_filter: &EthereumLogFilter,
+    call_filter: &EthereumCallFilter,
+    block_filter: &EthereumBlockFilter,
+) -> FiltersDump {
+    let mut log_filters: Vec<_> = log_filter
+        .clone()
+        .eth_get_logs_filters()
+        .map(|filter| {
+            let mut contracts: Vec<_> = filter
+                .contracts
+                .iter()
+                .map(|address| format!("{:x}", address))
+                .collect();
+            contracts.sort();
+            let mut events: Vec<_> = filter
+                .event_signatures
+                .iter()
+                .map(|event| format!("{:x}", event))
+                .collect();
+            events.sort();
+            LogFilterDump { contracts, events }
+        })
+        .collect();
+    log_filters.sort();
+
+    let mut call_filters: Vec<_> = call_filter
+        .contract_addresses_function_signatures
+        .iter()
+        .map(|(address, (start_block, functions))| {
+            let mut functions: Vec<_> = functions
+                .iter()
+                .map(|function| {
+                    function
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect::<String>()
+                })
+                .collect();
+            functions.sort();
+            CallFilterDump {
+                contract: format!("{:x}", address),
+                start_block: *start_block,
+                functions,
+            }
+        })
+        .collect();
+    call_filters.sort();
+
+    let mut block_filter_contracts: Vec<_> = block_filter
+        .contract_addresses
+        .iter()
+        .map(|(start_block, address)| (format!("{:x}", address), *start_block))
+        .collect();
+    block_filter_contracts.sort();
+
+    FiltersDump {
+        log_filters,
+        call_filters,
+        block_filter_contracts,
+        trigger_every_block: block_filter.trigger_every_block,
+        matches_data_sources: true,
+    }
+}
+
+/// What identifies a trigger within a block. Calls are not included since
+/// they have no stable identity: a transaction can make the same call
+/// several times.
+#[derive(PartialEq, Eq, Hash)]
+enum TriggerKey {
+    Log(Option<web3::types::H256>, Option<web3::types::U256>),
+    Block(EthereumBlockTriggerType),
+}
+
+impl TriggerKey {
+    fn of(trigger: &EthereumTrigger) -> Option<Self> {
+        match trigger {
+            EthereumTrigger::Log(log) => Some(TriggerKey::Log(log.transaction_hash, log.log_index)),
+            EthereumTrigger::Block(_, kind) => Some(TriggerKey::Block(kind.clone())),
+            EthereumTrigger::Call(_) => None,
+        }
+    }
+}
+
+/// Drop the triggers of a block that duplicate an earlier trigger, keeping
+/// the order of the rest. Since that only depends on the triggers, every
+/// node drops the same ones. A trigger runs all the handlers that match it,
+/// and dropping its duplicates therefore runs each handler once.
+fn dedupe_triggers(
+    logger: &Logger,
+    metrics: &SubgraphInstanceMetrics,
+    triggers: Vec<EthereumTrigger>,
+) -> Vec<EthereumTrigger> {
+    let count = triggers.len();
+    let mut seen = HashSet::new();
+    let triggers: Vec<_> = triggers
+        .into_iter()
+        .filter(|trigger| match TriggerKey::of(trigger) {
+            Some(key) => seen.insert(key),
+            None => true,
+        })
+        .collect();
+
+    let dropped = count - triggers.len();
+    if dropped > 0 {
+        warn!(
+            logger,
+            "Dropped duplicate triggers";
+            "count" => dropped,
+        );
+        metrics.duplicate_trigger_count.inc_by(dropped as f64);
+    }
+    triggers
+}
+
+/// Load the entities that `SubgraphInstanceManagerConfig.entity_cache_warmup`
+/// names into the entity cache before the first block is processed. The
+/// entities are loaded in batches, several of them at the same time. The
+/// warmup only saves store reads, and errors and running out of time are
+/// therefore only logged; what was loaded until then is kept.
+async fn warm_up_entity_cache<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &mut IndexingContext<B, T, S, C>,
+) where
+    S: SubgraphStore,
+{
+    let warmup = match &ctx.inputs.config.entity_cache_warmup {
+        Some(warmup) => warmup.0.cheap_clone(),
+        None => return,
+    };
+    let start = Instant::now();
+    let deployment_id = ctx.inputs.deployment_id.clone();
+    let mut keys = warmup.keys(&deployment_id);
+    keys.retain(|key| key.subgraph_id == deployment_id);
+    if keys.is_empty() {
+        return;
+    }
+
+    let batch_size = ctx.inputs.config.entity_cache_warmup_batch_size.max(1);
+    let concurrency = ctx.inputs.config.entity_cache_warmup_concurrency.max(1);
+    let batches = keys
+        .chunks(batch_size)
+        .map(|batch| batch.to_vec())
+        .collect::<Vec<_>>();
+    let store = ctx.inputs.store.cheap_clone();
+    let mut results = futures03::stream::iter(batches.into_iter().map(|batch| {
+        let store = store.cheap_clone();
+        let deployment_id = deployment_id.clone();
+        graph::spawn_blocking_allow_panic(move || {
+            let mut ids_for_type: BTreeMap<&EntityType, Vec<&str>> = BTreeMap::new();
+            for key in &batch {
+                ids_for_type
+                    .entry(&key.entity_type)
+                    .or_default()
+                    .push(&key.entity_id);
+            }
+            let entities = store.get_many(&deployment_id, ids_for_type)?;
+            Ok::<_, StoreError>((batch, entities))
+        })
+    }))
+    .buffered(concurrency);
+
+    let cache = &mut ctx.state.entity_lfu_cache;
+    let mut loaded = 0;
+    let load = async {
+        while let Some(res) = results.next().await {
+            let (batch, entities) = match res.map_err(Error::from).and_then(|res| Ok(res?)) {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!(
+                        logger,
+                        "Failed to warm up the entity cache";
+                        "error" => format!("{:#}", e),
+                    );
+                    continue;
+                }
+            };
+            for (entity_type, entities) in entities {
+                for entity in entities {
+                    let key = EntityKey {
+                        subgraph_id: deployment_id.clone(),
+                        entity_type: entity_type.clone(),
+                        entity_id: entity.id().unwrap(),
+                    };
+                    cache.insert(key, Some(entity));
+                }
+            }
+            // Entities that were not found don't exist
+            loaded += batch.len();
+            for key in batch {
+                if !cache.contains_key(&key) {
+                    cache.insert(key, None);
+                }
+            }
+        }
+    };
+    let timed_out = tokio::time::timeout(ctx.inputs.config.entity_cache_warmup_timeout, load)
+        .await
+        .is_err();
+
+    let elapsed = start.elapsed();
+    ctx.subgraph_metrics
+        .entity_cache_warmup_duration
+        .set(elapsed.as_secs_f64());
+    info!(
+        logger,
+        "Warmed up the entity cache";
+        "entities" => loaded,
+        "requested_entities" => keys.len(),
+        "cache_entries" => ctx.state.entity_lfu_cache.len(),
+        "timed_out" => timed_out,
+        "duration_ms" => elapsed.as_millis() as u64,
+    );
+}
+
+/// Load the entities that the preload hints of the event handlers for
+/// `triggers` name into the entity cache. Hints that can't be resolved are
+/// ignored, and since preloading only saves store reads, so are errors.
+fn preload_entities<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S, C>,
+    block_state: &mut BlockState,
+    block: &LightEthereumBlock,
+    triggers: &[EthereumTrigger],
+) {
+    let data_sources: Vec<_> = ctx
+        .state
+        .instance
+        .data_sources()
+        .filter(|data_source| {
+            data_source
+                .mapping
+                .event_handlers
+                .iter()
+                .any(|handler| !handler.preload.is_empty())
+        })
+        .collect();
+    if data_sources.is_empty() {
+        return;
+    }
+
+    // Problems with decoding triggers are reported when they are processed
+    let discard = Logger::root(slog::Discard, o!());
+    let mut keys = BTreeSet::new();
+    for trigger in triggers {
+        if !matches!(trigger, EthereumTrigger::Log(_)) {
+            continue;
+        }
+        for data_source in &data_sources {
+            let mapping_trigger = match data_source.match_and_decode(trigger, block, &discard) {
+                Ok(Some(mapping_trigger)) => mapping_trigger,
+                Ok(None) | Err(_) => continue,
+            };
+            let hints = match &mapping_trigger {
+                MappingTrigger::Log { handler, .. } => &handler.preload,
+                _ => continue,
+            };
+            for hint in hints {
+                if ctx
+                    .inputs
+                    .schema
+                    .document
+                    .get_object_type_definition(&hint.entity)
+                    .is_none()
+                {
+                    continue;
+                }
+                if let Some(id) = hint.entity_id(&mapping_trigger) {
+                    keys.insert(EntityKey::data(
+                        ctx.inputs.deployment_id.clone(),
+                        hint.entity.clone(),
+                        id,
+                    ));
+                }
+            }
         }
     }
-}
+    if keys.is_empty() {
+        return;
+    }
 
-impl From<Error> for BlockProcessingError {
-    fn from(e: Error) -> Self {
-        BlockProcessingError::Unknown(e)
+    match block_state
+        .entity_cache
+        .prefetch(keys.into_iter().collect())
+    {
+        Ok(loaded) => debug!(logger, "Preloaded entities"; "entities" => loaded),
+        Err(e) => warn!(
+            logger,
+            "Failed to preload entities";
+            "error" => e.to_string(),
+        ),
     }
 }
 
-/// Processes a block and returns the updated context and a boolean flag indicating
-/// whether new dynamic data sources have been added to the subgraph.
+/// Processes a block and returns the updated context and the reason why the
+/// block stream needs to be restarted, if it does.
 async fn process_block<B: BlockStreamBuilder, T: RuntimeHostBuilder, S, C>(
     logger: &Logger,
     eth_adapter: Arc<dyn EthereumAdapter>,
     mut ctx: IndexingContext<B, T, S, C>,
     block_stream_cancel_handle: CancelHandle,
     block: EthereumBlockWithTriggers,
-) -> Result<(IndexingContext<B, T, S, C>, bool), BlockProcessingError>
+) -> Result<(IndexingContext<B, T, S, C>, Option<RestartReason>), BlockProcessingError>
 where
     S: SubgraphStore,
     C: ChainStore,
@@ -730,6 +5280,115 @@ where
         "block_hash" => format!("{}", block_ptr.hash)
     ));
 
+    // The block stream only yields blocks with triggers, so blocks may be
+    // skipped, but they must never go backwards or fork off from the last
+    // block we wrote. Restart to resync with the store if they do.
+    if let Some(last_ptr) = &ctx.state.block_ptr {
+        let out_of_order = block_ptr.number <= last_ptr.number
+            || (block_ptr.number == last_ptr.number + 1
+                && block.parent_ptr().as_ref() != Some(last_ptr));
+        if out_of_order {
+            warn!(
+                logger,
+                "Block stream delivered a block out of order, restarting";
+                "last_block_number" => last_ptr.number,
+                "last_block_hash" => format!("{}", last_ptr.hash),
+            );
+            return Ok((ctx, Some(RestartReason::OutOfOrderBlock)));
+        }
+    }
+
+    // Writing a block that is not past the last block we wrote would corrupt
+    // the data of the subgraph. The check for out of order blocks above
+    // should catch this; if it doesn't, the in-memory state is off, and we
+    // restart to resync it with the store before the block changes the
+    // entity cache or the data sources. Reverts reset `last_commit`
+    if let Some((committed, _)) = ctx.state.last_commit {
+        if block_ptr.number <= committed {
+            error!(
+                logger,
+                "Refusing to write a block that is not past the last block written, restarting";
+                "last_written_block_number" => committed,
+                "last_processed_block" => ctx.state.block_ptr.as_ref().map(|ptr| ptr.to_string()),
+            );
+            return Ok((ctx, Some(RestartReason::RegressedBlock)));
+        }
+    }
+
+    // Block timestamps are set by miners and the local clock can be off,
+    // so a block can appear to be from the future
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    ctx.subgraph_metrics
+        .head_lag
+        .set(now.saturating_sub(block.timestamp().low_u64()) as f64);
+
+    // Checking for proof of indexing support is independent of waiting at
+    // a breakpoint, so do both at the same time; either failing fails the
+    // block
+    let (_, supports_proof_of_indexing) = futures03::future::try_join(
+        ctx.inputs
+            .breakpoint
+            .wait(&logger, block_ptr.number, &block_stream_cancel_handle),
+        ctx.inputs
+            .store
+            .clone()
+            .supports_proof_of_indexing(&ctx.inputs.deployment_id)
+            .map_err(BlockProcessingError::from),
+    )
+    .await?;
+
+    // Drop the triggers of blocks that an operator told us to skip
+    let triggers = if ctx.inputs.settings.skip_blocks.contains(&block_ptr.number) {
+        error!(
+            &logger,
+            "Skipping the triggers of this block as configured; the proof of indexing \
+             of this subgraph will differ from that of a node that processes them";
+            "skipped_triggers" => triggers.len(),
+        );
+        ctx.manager_metrics
+            .skipped_block_count
+            .with_label_values(&[ctx.inputs.deployment_id.as_str()])
+            .inc();
+        ctx.inputs
+            .store
+            .record_skipped_block(&ctx.inputs.deployment_id, block_ptr.number)
+            .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
+        vec![]
+    } else if let Some(predicate) = ctx
+        .inputs
+        .block_predicate
+        .as_ref()
+        .filter(|predicate| !predicate.matches(&block.light_block()))
+    {
+        // Skipping these blocks is part of the subgraph, and therefore the
+        // same on every node
+        if !triggers.is_empty() {
+            debug!(
+                &logger,
+                "Skipping the triggers of this block since it does not match the block predicate \
+                 of the subgraph";
+                "skipped_triggers" => triggers.len(),
+                "block_predicate" => format!("{:?}", predicate),
+            );
+        }
+        vec![]
+    } else {
+        triggers
+    };
+
+    if triggers.len() > ctx.inputs.config.trigger_count_warn_threshold {
+        warn!(
+            &logger,
+            "Block has an unusually large number of triggers for this subgraph, \
+             processing it may take long and use a lot of memory";
+            "triggers" => triggers.len(),
+            "threshold" => ctx.inputs.config.trigger_count_warn_threshold,
+        );
+        ctx.subgraph_metrics.large_block_count.inc();
+    }
+
     if triggers.len() == 1 {
         info!(&logger, "1 trigger found in this block for this subgraph");
     } else if triggers.len() > 1 {
@@ -743,65 +5402,138 @@ where
     // Obtain current and new block pointer (after this block is processed)
     let light_block = Arc::new(block.light_block());
     let block_ptr_after = EthereumBlockPointer::from(&block);
+    let seed = BlockSeed::from_block(&light_block);
 
     let metrics = ctx.subgraph_metrics.clone();
 
-    let proof_of_indexing = if ctx
-        .inputs
-        .store
-        .clone()
-        .supports_proof_of_indexing(&ctx.inputs.deployment_id)
-        .await?
-    {
-        Some(Arc::new(AtomicRefCell::new(ProofOfIndexing::new(
-            block_ptr.number,
-        ))))
-    } else {
-        None
+    let triggers = match ctx.inputs.config.dedupe_triggers {
+        true => dedupe_triggers(&logger, &ctx.subgraph_metrics, triggers),
+        false => triggers,
     };
 
-    // Process events one after the other, passing in entity operations
-    // collected previously to every new event being processed
-    let mut block_state = match process_triggers(
-        &logger,
-        BlockState::new(
+    // A possible reorg is often a false alarm, for example when the Ethereum
+    // node that served a call lags behind the others. As long as the block
+    // is still on the main chain, we can process it again instead of
+    // restarting the block stream
+    let mut reorg_checks = 0;
+    let (proof_of_indexing, mut block_state, mut trigger_counts) = loop {
+        let proof_of_indexing = if supports_proof_of_indexing {
+            Some(Arc::new(AtomicRefCell::new(ProofOfIndexing::new(
+                block_ptr.number,
+            ))))
+        } else {
+            None
+        };
+
+        let mut block_state = BlockState::new(
             ctx.inputs.store.clone(),
             std::mem::take(&mut ctx.state.entity_lfu_cache),
-        ),
-        proof_of_indexing.cheap_clone(),
-        ctx.subgraph_metrics.clone(),
-        &ctx.state.instance,
-        &light_block,
-        triggers,
-    )
-    .await
-    {
-        // Triggers processed with no errors or with only determinstic errors.
-        Ok(block_state) => block_state,
-
-        // Some form of unknown or non-deterministic error ocurred.
-        Err(MappingError::Unknown(e)) => return Err(BlockProcessingError::Unknown(e)),
-        Err(MappingError::PossibleReorg(e)) => {
-            info!(ctx.state.logger,
-                    "Possible reorg detected, retrying";
-                    "error" => format!("{:#}", e),
-                    "id" => ctx.inputs.deployment_id.to_string(),
-            );
+        );
+
+        // The store already contains the changes of this and later blocks when
+        // we verify, so we need to read entities as of the previous block
+        if ctx.inputs.verification.is_some() {
+            block_state.entity_cache.read_at_block(block_ptr.number - 1);
+        } else if let Some(read_through) = &ctx.inputs.config.read_through_store {
+            // Only read through to a store that has exactly the entities we
+            // would find in the primary store
+            match read_through.0.block_ptr(&ctx.inputs.deployment_id) {
+                Ok(ptr) if ptr.is_some() && ptr == ctx.state.block_ptr => {
+                    block_state
+                        .entity_cache
+                        .read_through(read_through.0.cheap_clone());
+                }
+                Ok(ptr) => {
+                    debug!(
+                        &logger,
+                        "Bypassing the read-through store since it is not up to date";
+                        "read_through_block" => ptr.map_or("none".to_string(), |ptr| ptr.to_string()),
+                    );
+                    metrics.read_through_bypass_count.inc();
+                }
+                Err(e) => {
+                    debug!(
+                        &logger,
+                        "Bypassing the read-through store since its block pointer can't be read";
+                        "error" => format!("{:#}", e),
+                    );
+                    metrics.read_through_bypass_count.inc();
+                }
+            }
+        }
+
+        preload_entities(&logger, &ctx, &mut block_state, &light_block, &triggers);
+
+        // Process events one after the other, passing in entity operations
+        // collected previously to every new event being processed
+        match process_triggers(
+            &logger,
+            block_state,
+            proof_of_indexing.cheap_clone(),
+            ctx.subgraph_metrics.clone(),
+            &ctx.state.instance,
+            &light_block,
+            &triggers,
+            seed,
+            &ctx.inputs.deployment_id,
+            ctx.inputs.trigger_filter_hook.as_ref(),
+            ctx.inputs.config.data_source_order,
+            ctx.inputs.config.trigger_yield_interval,
+            ctx.inputs.config.trigger_match_log_interval,
+        )
+        .await
+        {
+            // Triggers processed with no errors or with only determinstic errors.
+            Ok((block_state, trigger_counts)) => {
+                break (proof_of_indexing, block_state, trigger_counts)
+            }
+
+            // Some form of unknown or non-deterministic error ocurred.
+            Err(MappingError::Unknown(e)) => return Err(BlockProcessingError::Unknown(e)),
+            Err(MappingError::PossibleReorg(e)) => {
+                if reorg_checks < ctx.inputs.config.possible_reorg_checks {
+                    reorg_checks += 1;
+                    tokio::time::delay_for(ctx.inputs.config.possible_reorg_check_delay).await;
+                    if block_on_main_chain(&logger, ctx.inputs.chain_store.as_ref(), &block_ptr) {
+                        info!(
+                            logger,
+                            "Possible reorg detected, but the block is still on the main chain, \
+                             processing it again";
+                            "error" => format!("{:#}", e),
+                            "attempt" => reorg_checks,
+                        );
+                        continue;
+                    }
+                }
 
-            // In case of a possible reorg, we want this function to do nothing and restart the
-            // block stream so it has a chance to detect the reorg.
-            //
-            // The `ctx` is unchanged at this point, except for having cleared the entity cache.
-            // Losing the cache is a bit annoying but not an issue for correctness.
-            //
-            // See also b21fa73b-6453-4340-99fb-1a78ec62efb1.
-            return Ok((ctx, true));
+                info!(ctx.state.logger,
+                        "Possible reorg detected, retrying";
+                        "error" => format!("{:#}", e),
+                        "id" => ctx.inputs.deployment_id.to_string(),
+                );
+
+                // In case of a possible reorg, we want this function to do nothing and restart the
+                // block stream so it has a chance to detect the reorg.
+                //
+                // The `ctx` is unchanged at this point, except for having cleared the entity cache.
+                // Losing the cache is a bit annoying but not an issue for correctness.
+                //
+                // See also b21fa73b-6453-4340-99fb-1a78ec62efb1.
+                return Ok((ctx, Some(RestartReason::PossibleReorg)));
+            }
         }
     };
 
     // If new data sources have been created, restart the subgraph after this block.
-    // This is necessary to re-create the block stream.
-    let needs_restart = block_state.has_created_data_sources();
+    // This is necessary to re-create the block stream. The restart keeps the
+    // instance with its hosts and the filters, which are extended with the new
+    // data sources below; dynamic data sources are only loaded from the store
+    // and resolved when the deployment starts.
+    let restart = if block_state.has_created_data_sources() {
+        Some(RestartReason::DataSource)
+    } else {
+        None
+    };
     let host_metrics = ctx.host_metrics.clone();
 
     // This loop will:
@@ -814,12 +5546,20 @@ where
     // very contrived subgraph would be able to observe this.
     while block_state.has_created_data_sources() {
         // Instantiate dynamic data sources, removing them from the block state.
+        let start = Instant::now();
         let (data_sources, runtime_hosts) = create_dynamic_data_sources(
             logger.clone(),
             &mut ctx,
             host_metrics.clone(),
             block_state.drain_created_data_sources(),
+            &block_ptr,
         )?;
+        ctx.subgraph_metrics
+            .create_data_sources_duration
+            .observe(start.elapsed().as_secs_f64());
+        ctx.subgraph_metrics
+            .data_source_count
+            .set(ctx.state.instance.data_sources().count() as f64);
 
         // Reprocess the triggers from this block that match the new data sources
         let block_with_triggers = triggers_in_block(
@@ -832,9 +5572,16 @@ where
             EthereumBlockFilter::from_data_sources(data_sources.iter()),
             block.clone(),
         )
-        .await?;
-
-        let triggers = block_with_triggers.triggers;
+        .await;
+        ctx.inputs
+            .eth_adapters
+            .record(&logger, &*eth_adapter, block_with_triggers.is_ok());
+        let block_with_triggers = block_with_triggers?;
+
+        let triggers = match ctx.inputs.config.dedupe_triggers {
+            true => dedupe_triggers(&logger, &ctx.subgraph_metrics, block_with_triggers.triggers),
+            false => block_with_triggers.triggers,
+        };
 
         if triggers.len() == 1 {
             info!(
@@ -851,23 +5598,50 @@ where
 
         // Add entity operations for the new data sources to the block state
         // and add runtimes for the data sources to the subgraph instance.
+        let start = Instant::now();
         persist_dynamic_data_sources(
             logger.clone(),
             &mut ctx,
             &mut block_state.entity_cache,
             data_sources,
         );
+        ctx.subgraph_metrics
+            .persist_data_sources_duration
+            .observe(start.elapsed().as_secs_f64());
 
         // Process the triggers in each host in the same order the
-        // corresponding data sources have been created.
-        for trigger in triggers.into_iter() {
-            block_state = SubgraphInstance::<T>::process_trigger_in_runtime_hosts(
+        // corresponding data sources have been created. The proof of
+        // indexing depends on the order of changes, and hosts can therefore
+        // only run concurrently for deployments without one.
+        let concurrency = match proof_of_indexing {
+            Some(_) => 1,
+            None => ctx.inputs.settings.dynamic_data_source_trigger_concurrency,
+        };
+        let match_log_interval = ctx.inputs.config.trigger_match_log_interval;
+        for (i, trigger) in triggers.into_iter().enumerate() {
+            if !keep_trigger(
+                ctx.inputs.trigger_filter_hook.as_ref(),
+                &ctx.inputs.deployment_id,
+                &trigger,
+                &ctx.subgraph_metrics,
+            ) {
+                continue;
+            }
+            let trigger_type = TriggerType::from(&trigger);
+            trigger_counts.add(&trigger_type);
+            let report = match_log_interval > 0 && i % match_log_interval == 0;
+            let transaction_id = trigger_transaction_id(&trigger);
+            let mut matched = Vec::new();
+            block_state = SubgraphInstance::<T>::process_trigger_in_runtime_hosts_concurrently(
                 &logger,
                 &runtime_hosts,
                 &light_block,
                 trigger,
                 block_state,
                 proof_of_indexing.cheap_clone(),
+                seed,
+                concurrency,
+                if report { Some(&mut matched) } else { None },
             )
             .await
             .map_err(|e| {
@@ -881,17 +5655,39 @@ where
                     }
                 }
             })?;
+            if report {
+                log_trigger_match(
+                    &logger,
+                    block_ptr_after.number,
+                    &trigger_type,
+                    transaction_id,
+                    &matched,
+                );
+            }
         }
     }
 
+    for category in &block_state.error_categories {
+        ctx.subgraph_metrics
+            .error_category_count
+            .with_label_values(&[&category.to_string()])
+            .inc();
+    }
+
     // The triggers were processed but some were skipped due to deterministic errors, if the
-    // `nonFatalErrors` feature is not present, return early with an error.
+    // `nonFatalErrors` feature is not present, or an error is of a category the operator
+    // considers fatal, return early with an error.
     let has_errors = block_state.has_errors();
+    let fatal_category = block_state
+        .error_categories
+        .iter()
+        .any(|category| ctx.inputs.config.fatal_error_categories.contains(category));
     if has_errors
-        && !ctx
-            .inputs
-            .features
-            .contains(&SubgraphFeature::nonFatalErrors)
+        && (fatal_category
+            || !ctx
+                .inputs
+                .features
+                .contains(&SubgraphFeature::nonFatalErrors))
     {
         // Take just the first error to report.
         return Err(BlockProcessingError::Deterministic(
@@ -906,38 +5702,136 @@ where
         return Err(BlockProcessingError::Canceled);
     }
 
-    if let Some(proof_of_indexing) = proof_of_indexing {
-        let proof_of_indexing = Arc::try_unwrap(proof_of_indexing).unwrap().into_inner();
-        update_proof_of_indexing(
-            proof_of_indexing,
-            &ctx.host_metrics.stopwatch,
-            &ctx.inputs.deployment_id,
-            &mut block_state.entity_cache,
-        )
-        .await?;
-    }
+    let (poi_digests, poi_written) = match proof_of_indexing {
+        Some(proof_of_indexing) => {
+            let proof_of_indexing = Arc::try_unwrap(proof_of_indexing).unwrap().into_inner();
+            let (digests, written) = update_proof_of_indexing(
+                proof_of_indexing,
+                block_ptr.number,
+                ctx.inputs.settings.poi_write_interval,
+                &ctx.state.pending_poi,
+                &ctx.host_metrics.stopwatch,
+                &ctx.inputs.deployment_id,
+                &mut block_state.entity_cache,
+            )
+            .await?;
+            (Some(digests), written)
+        }
+        None => (None, false),
+    };
+
+    let config = ctx.inputs.config.cheap_clone();
 
     let section = ctx.host_metrics.stopwatch.start_section("as_modifications");
     let ModificationsAndCache {
         modifications: mods,
         data_sources,
         entity_lfu_cache: mut cache,
-    } = block_state
-        .entity_cache
-        .as_modifications(ctx.inputs.store.as_ref())
+        store_reads,
+    } = section
+        .in_scope(|| {
+            block_state
+                .entity_cache
+                .as_modifications(ctx.inputs.store.as_ref())
+        })
         .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
     section.end();
+    ctx.subgraph_metrics
+        .store_reads_per_block
+        .observe(store_reads as f64);
+
+    let mods = match &ctx.inputs.entity_modification_hook {
+        Some(hook) => {
+            let section = ctx
+                .host_metrics
+                .stopwatch
+                .start_section("entity_modification_hook");
+            let mut mods = mods;
+            section.in_scope(|| (hook.0)(&mut mods));
+            section.end();
+            mods
+        }
+        None => mods,
+    };
+
+    if config.validate_entity_modifications {
+        let section = ctx
+            .host_metrics
+            .stopwatch
+            .start_section("validate_entity_modifications");
+        let errors = section.in_scope(|| validate_entity_modifications(&ctx.inputs.schema, &mods));
+        section.end();
+
+        if !errors.is_empty() {
+            for e in &errors {
+                error!(&logger, "Entity modification violates the schema";
+                    "error" => e.to_string(),
+                    "code" => LogCode::SubgraphSyncingFailure
+                );
+            }
+
+            // Report the first violation, all of them have been logged.
+            let error = errors.into_iter().next().unwrap();
+            return Err(BlockProcessingError::Deterministic(SubgraphError {
+                subgraph_id: ctx.inputs.deployment_id.clone(),
+                message: error.to_string(),
+                block_ptr: Some(block_ptr_after),
+                handler: None,
+                deterministic: true,
+                details: None,
+            }));
+        }
+    }
 
     let section = ctx
         .host_metrics
         .stopwatch
         .start_section("entity_cache_evict");
-    cache.evict(*ENTITY_CACHE_SIZE);
+    let cache_limit = match &ctx.inputs.settings.entity_cache_limit_group {
+        Some(group) => group.limit(&ctx.inputs.deployment_id, config.entity_cache_size),
+        None => config.entity_cache_size,
+    };
+    if let Some(stats) = cache.evict(cache_limit) {
+        metrics
+            .entity_cache_evicted_count
+            .observe(stats.evicted_count as f64);
+        metrics
+            .entity_cache_evicted_weight
+            .observe(stats.evicted_weight as f64);
+
+        if stats.evicted_fraction() > config.entity_cache_eviction_warn_fraction {
+            warn!(
+                &logger,
+                "Evicted a large fraction of the entity cache, \
+                 consider increasing GRAPH_ENTITY_CACHE_SIZE";
+                "evicted_count" => stats.evicted_count,
+                "old_count" => stats.old_count,
+                "evicted_weight" => stats.evicted_weight,
+                "new_weight" => stats.new_weight,
+            );
+        }
+    }
+    if let Some(group) = &ctx.inputs.settings.entity_cache_limit_group {
+        group.set_weight(&ctx.inputs.deployment_id, cache.total_weight());
+    }
     section.end();
 
-    // Put the cache back in the ctx, asserting that the placeholder cache was not used.
-    assert!(ctx.state.entity_lfu_cache.is_empty());
-    ctx.state.entity_lfu_cache = cache;
+    // Put the cache back in the ctx. The placeholder cache should not have
+    // been used; if it was, we can't tell which of the two caches is right,
+    // so we start over with an empty cache rather than risk stale entries.
+    debug_assert!(ctx.state.entity_lfu_cache.is_empty());
+    if ctx.state.entity_lfu_cache.is_empty() {
+        ctx.state.entity_lfu_cache = cache;
+    } else {
+        error!(
+            &logger,
+            "Entity cache placeholder was used while processing the block, \
+             discarding the entity cache";
+            "placeholder_entries" => ctx.state.entity_lfu_cache.len(),
+            "cache_entries" => cache.len(),
+        );
+        ctx.state.entity_lfu_cache = LfuCache::new();
+    }
 
     if !mods.is_empty() {
         info!(&logger, "Applying {} entity operation(s)", mods.len());
@@ -952,64 +5846,482 @@ where
         );
     }
 
-    // Transact entity operations into the store and update the
-    // subgraph's block stream pointer
-    let _section = ctx.host_metrics.stopwatch.start_section("transact_block");
-    let subgraph_id = ctx.inputs.deployment_id.clone();
-    let stopwatch = ctx.host_metrics.stopwatch.clone();
-    let start = Instant::now();
-
-    let store = &ctx.inputs.store;
-    let id = &ctx.inputs.deployment_id;
-    let fail_fast = || -> Result<bool, BlockProcessingError> {
-        Ok(!*DISABLE_FAIL_FAST
-            && !store
-                .is_deployment_synced(id)
-                .map_err(BlockProcessingError::Unknown)?)
-    };
+    // Compare the entity operations with the store instead of writing them
+    // when we verify
+    if let Some(verification) = ctx.inputs.verification.clone() {
+        let section = ctx.host_metrics.stopwatch.start_section("verify_block");
+        let divergence = compare_with_store(ctx.inputs.store.as_ref(), &block_ptr_after, &mods)
+            .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
+        section.end();
+
+        return match divergence {
+            None => {
+                *verification.lock().unwrap() =
+                    Verification::Running(Some(block_ptr_after.clone()));
+                ctx.state.pending_poi.record(
+                    block_ptr_after.number,
+                    poi_digests.as_deref().unwrap_or(&[]),
+                    poi_written,
+                );
+                ctx.state.block_ptr = Some(block_ptr_after);
+                Ok((ctx, restart))
+            }
+            Some(divergence) => {
+                for entity in &divergence.entities {
+                    error!(&logger, "Entity differs from the store";
+                        "entity_type" => entity.key.entity_type.as_str(),
+                        "entity_id" => &entity.key.entity_id,
+                        "stored" => format!("{:?}", entity.stored),
+                        "computed" => format!("{:?}", entity.computed),
+                    );
+                }
+                finish_verification(&logger, &verification, Verification::Diverged(divergence));
+
+                // Stop without touching the deployment, like when it is
+                // unassigned
+                Err(BlockProcessingError::Canceled)
+            }
+        };
+    }
+
+    {
+        let mut health = ctx.inputs.health.lock().unwrap();
+        health.error_count += err_count as u64;
+        health.trigger_count += trigger_counts.total() as u64;
+    }
+
+    // Put off writing the pointer for final blocks that don't change
+    // anything. Reverts never touch final blocks, so the pointer in the
+    // store stays valid
+    let pointer_only = mods.is_empty()
+        && data_sources.is_empty()
+        && block_state.deterministic_errors.is_empty()
+        && restart.is_none();
+    if pointer_only
+        && matches!(block, BlockFinality::Final(_))
+        && !reached_end_block(&ctx, block_ptr_after.number)
+        && !ctx
+            .state
+            .chunk_end
+            .map_or(false, |chunk_end| block_ptr_after.number >= chunk_end)
+    {
+        if let Some((committed, committed_at)) = ctx.state.last_commit {
+            if block_ptr_after.number - committed < config.pointer_commit_interval_blocks
+                && committed_at.elapsed() < config.pointer_commit_interval
+            {
+                ctx.subgraph_metrics
+                    .block_commit_count
+                    .with_label_values(&["deferred"])
+                    .inc();
+                ctx.state.pending_poi.record(
+                    block_ptr_after.number,
+                    poi_digests.as_deref().unwrap_or(&[]),
+                    poi_written,
+                );
+                ctx.state.block_ptr = Some(block_ptr_after);
+                return Ok((ctx, restart));
+            }
+        }
+    }
+
+    // Transact entity operations into the store and update the
+    // subgraph's block stream pointer
+    let _section = ctx.host_metrics.stopwatch.start_section("transact_block");
+    let subgraph_id = ctx.inputs.deployment_id.clone();
+    let stopwatch = ctx.host_metrics.stopwatch.clone();
+    let start = Instant::now();
+
+    let store = &ctx.inputs.store;
+    let id = &ctx.inputs.deployment_id;
+    let disable_fail_fast = ctx.inputs.settings.disable_fail_fast;
+    let error_categories = std::mem::take(&mut block_state.error_categories);
+    let fail_fast_categories = config.fail_fast_error_categories.as_ref();
+    let fail_fast = || -> Result<bool, BlockProcessingError> {
+        let fatal_category = fail_fast_categories.map_or(true, |fail_fast_categories| {
+            error_categories
+                .iter()
+                .any(|category| fail_fast_categories.contains(category))
+        });
+        Ok(!disable_fail_fast
+            && fatal_category
+            && !store
+                .is_deployment_synced(id)
+                .map_err(BlockProcessingError::Unknown)?)
+    };
+
+    // Transient store errors like deadlocks or lost connections are retried
+    // with exponential backoff before they fail the subgraph. While the
+    // store is read-only, we keep trying until it is writable again.
+    let deterministic_errors = block_state.deterministic_errors;
+    let mut retries = 0;
+    let mut read_only_since = None;
+    let res = loop {
+        let section = stopwatch.start_section("transact_block");
+        let res = section.in_scope(|| {
+            ctx.inputs.store.transact_block_operations(
+                subgraph_id.cheap_clone(),
+                block_ptr_after.clone(),
+                mods.clone(),
+                stopwatch.clone(),
+                data_sources.clone(),
+                deterministic_errors.clone(),
+            )
+        });
+        section.end();
+        match res {
+            Err(e) if e.is_transient() && retries < config.transact_block_operations_retries => {
+                retries += 1;
+                let backoff = config.transact_block_operations_retry_backoff
+                    * 2u32.saturating_pow(retries as u32 - 1);
+                warn!(
+                    &logger,
+                    "Transient error while transacting block operations, retrying";
+                    "error" => e.to_string(),
+                    "attempt" => retries,
+                    "backoff_ms" => backoff.as_millis() as u64,
+                );
+                metrics.transact_block_operations_retries.inc();
+                tokio::time::delay_for(backoff).await;
+            }
+            Err(e)
+                if e.is_read_only()
+                    && config.read_only_store_retry_interval > Duration::from_secs(0) =>
+            {
+                if read_only_since.is_none() {
+                    warn!(
+                        &logger,
+                        "The store is read-only, waiting for it to become writable to write \
+                         this block";
+                        "error" => e.to_string(),
+                        "retry_interval_s" => config.read_only_store_retry_interval.as_secs(),
+                    );
+                    metrics.store_read_only.set(1.0);
+                    read_only_since = Some(Instant::now());
+                }
+                tokio::time::delay_for(config.read_only_store_retry_interval).await;
+                if block_stream_cancel_handle.is_canceled() {
+                    metrics.store_read_only.set(0.0);
+                    return Err(BlockProcessingError::Canceled);
+                }
+            }
+            res => break res,
+        }
+    };
+    if let Some(since) = read_only_since {
+        metrics.store_read_only.set(0.0);
+        info!(
+            &logger,
+            "The store is writable again";
+            "read_only_s" => since.elapsed().as_secs(),
+        );
+    }
+
+    match res {
+        Ok(_) => {
+            let elapsed = start.elapsed().as_secs_f64();
+            metrics.block_ops_transaction_duration.observe(elapsed);
+            metrics
+                .block_commit_count
+                .with_label_values(&["transaction"])
+                .inc();
+            ctx.state.pending_poi.record(
+                block_ptr_after.number,
+                poi_digests.as_deref().unwrap_or(&[]),
+                poi_written,
+            );
+            ctx.state.block_ptr = Some(block_ptr_after.clone());
+            ctx.state.last_commit = Some((block_ptr_after.number, Instant::now()));
+
+            // The block stream can be canceled after we checked above but
+            // before the block was written; the block then still counts as
+            // processed, which can look surprising when a subgraph is stopped
+            if block_stream_cancel_handle.is_canceled() {
+                metrics.late_commit_count.inc();
+                info!(
+                    &logger,
+                    "Committed block although the block stream was canceled while writing it";
+                    "block_number" => block_ptr_after.number,
+                    "block_hash" => format!("{}", block_ptr_after.hash),
+                );
+            }
+
+            if err_count > 0 {
+                check_error_rate(&logger, &mut ctx, block_ptr_after.number, err_count);
+            }
+
+            if let Some(modifications) = &ctx.inputs.modifications {
+                if !mods.is_empty() {
+                    modifications.publish(ModificationsEvent::Block {
+                        deployment: subgraph_id.clone(),
+                        block_ptr: block_ptr_after.clone(),
+                        modifications: mods,
+                    });
+                }
+            }
+
+            if let Some(hook) = &config.block_committed_hook {
+                let _section = ctx
+                    .host_metrics
+                    .stopwatch
+                    .start_section("block_committed_hook");
+                (hook.0)(&CommittedBlock {
+                    deployment: subgraph_id.clone(),
+                    block_ptr: block_ptr_after.clone(),
+                    proof_of_indexing: poi_digests.filter(|_| config.block_committed_hook_with_poi),
+                    trigger_counts,
+                });
+            }
+
+            // To prevent a buggy pending version from replacing a current version, if errors are
+            // present the subgraph will be unassigned.
+            if has_errors && fail_fast()? {
+                store
+                    .unassign_subgraph(&subgraph_id)
+                    .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
+
+                // Use `Canceled` to avoiding setting the subgraph health to failed, an error was
+                // just transacted so it will be already be set to unhealthy.
+                return Err(BlockProcessingError::Canceled);
+            }
+
+            // The entity modification hook changes what is written to the
+            // store, but not the cache, so they are expected to differ
+            if config.entity_cache_check_interval > 0
+                && block_ptr_after.number % config.entity_cache_check_interval == 0
+                && ctx.inputs.entity_modification_hook.is_none()
+            {
+                let _section = ctx
+                    .host_metrics
+                    .stopwatch
+                    .start_section("entity_cache_check");
+                check_entity_cache(
+                    &logger,
+                    ctx.inputs.store.as_ref(),
+                    &ctx.state.entity_lfu_cache,
+                    config.entity_cache_check_sample_size,
+                    &metrics,
+                );
+            }
+
+            Ok((ctx, restart))
+        }
+
+        Err(e) => {
+            stop_for_errors_of_failed_write(
+                &logger,
+                &deterministic_errors,
+                block_ptr_after.number,
+                config.fail_fast_on_failed_write,
+                fail_fast,
+                || store.unassign_subgraph(&subgraph_id),
+            );
+            Err(anyhow!("Error while processing block stream for a subgraph: {}", e).into())
+        }
+    }
+}
+
+/// Handle the `deterministic_errors` of a block that could not be written.
+/// They are logged so that they are not lost, and, as after a successful
+/// write, a pending subgraph is stopped with `unassign` if `fail_fast` says
+/// so, so that it does not replace the current version before the block is
+/// written again. Errors while doing that must not hide the error from the
+/// write and are only logged. Returns whether the subgraph was unassigned.
+fn stop_for_errors_of_failed_write(
+    logger: &Logger,
+    deterministic_errors: &[SubgraphError],
+    block_number: BlockNumber,
+    fail_fast_on_failed_write: bool,
+    fail_fast: impl FnOnce() -> Result<bool, BlockProcessingError>,
+    unassign: impl FnOnce() -> Result<(), StoreError>,
+) -> bool {
+    if deterministic_errors.is_empty() {
+        return false;
+    }
+
+    for error in deterministic_errors {
+        error!(
+            logger,
+            "Could not write a block with a deterministic error";
+            "error" => &error.message,
+            "handler" => error.handler.as_deref().unwrap_or(""),
+            "block_number" => block_number,
+        );
+    }
+    if !fail_fast_on_failed_write {
+        return false;
+    }
+
+    match fail_fast() {
+        Ok(true) => match unassign() {
+            Ok(()) => true,
+            Err(unassign_err) => {
+                error!(
+                    logger,
+                    "Failed to unassign subgraph after a deterministic error";
+                    "error" => unassign_err.to_string(),
+                );
+                false
+            }
+        },
+        Ok(false) => false,
+        Err(fail_fast_err) => {
+            error!(
+                logger,
+                "Could not check whether to stop the subgraph for a deterministic error";
+                "error" => fail_fast_err.to_string(),
+            );
+            false
+        }
+    }
+}
+
+/// Pause the subgraph before the block after `block` if, with the `errors`
+/// that `block` just wrote, it wrote too many deterministic errors recently.
+fn check_error_rate<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &mut IndexingContext<B, T, S, C>,
+    block: BlockNumber,
+    errors: usize,
+) {
+    let config = &ctx.inputs.config;
+    if config.auto_pause_errors == 0 {
+        return;
+    }
+
+    if let Some(count) = record_errors(
+        &mut ctx.state.recent_errors,
+        block,
+        errors,
+        config.auto_pause_errors,
+        config.auto_pause_window,
+    ) {
+        warn!(
+            logger,
+            "Pausing subgraph since it wrote too many deterministic errors; \
+             resume it to continue";
+            "errors" => count,
+            "window_blocks" => config.auto_pause_window,
+        );
+        ctx.inputs.breakpoint.pause_next();
+    }
+}
+
+/// Add the `errors` of `block` to `recent_errors`, which holds the errors of
+/// the last `window` blocks. If those blocks wrote `max_errors` or more
+/// errors, forget them and return how many they wrote.
+fn record_errors(
+    recent_errors: &mut VecDeque<(BlockNumber, usize)>,
+    block: BlockNumber,
+    errors: usize,
+    max_errors: usize,
+    window: BlockNumber,
+) -> Option<usize> {
+    recent_errors.push_back((block, errors));
+    while recent_errors
+        .front()
+        .map_or(false, |(first, _)| *first <= block - window)
+    {
+        recent_errors.pop_front();
+    }
 
-    match ctx.inputs.store.transact_block_operations(
-        subgraph_id.cheap_clone(),
-        block_ptr_after,
-        mods,
-        stopwatch,
-        data_sources,
-        block_state.deterministic_errors,
-    ) {
-        Ok(_) => {
-            let elapsed = start.elapsed().as_secs_f64();
-            metrics.block_ops_transaction_duration.observe(elapsed);
+    let count: usize = recent_errors.iter().map(|(_, errors)| errors).sum();
+    if count >= max_errors {
+        recent_errors.clear();
+        Some(count)
+    } else {
+        None
+    }
+}
 
-            // To prevent a buggy pending version from replacing a current version, if errors are
-            // present the subgraph will be unassigned.
-            if has_errors && fail_fast()? {
-                store
-                    .unassign_subgraph(&subgraph_id)
-                    .map_err(|e| BlockProcessingError::Unknown(e.into()))?;
+/// Compare up to `sample_size` entries of the entity cache with what is in
+/// the store, and log and count the entries that differ. The sample is
+/// spread evenly over the cache.
+fn check_entity_cache<S: SubgraphStore>(
+    logger: &Logger,
+    store: &S,
+    cache: &LfuCache<EntityKey, Option<Entity>>,
+    sample_size: usize,
+    metrics: &SubgraphInstanceMetrics,
+) {
+    if cache.is_empty() || sample_size == 0 {
+        return;
+    }
 
-                // Use `Canceled` to avoiding setting the subgraph health to failed, an error was
-                // just transacted so it will be already be set to unhealthy.
-                return Err(BlockProcessingError::Canceled);
+    let step = (cache.len() / sample_size).max(1);
+    let mut checked = 0;
+    let mut mismatches = 0;
+    for (key, cached) in cache.iter().step_by(step).take(sample_size) {
+        checked += 1;
+        match store.get(key.clone()) {
+            Ok(stored) if &stored == cached => {}
+            Ok(stored) => {
+                mismatches += 1;
+                metrics.entity_cache_mismatches.inc();
+                error!(
+                    logger,
+                    "Entity cache differs from the store";
+                    "entity_type" => key.entity_type.as_str(),
+                    "entity_id" => &key.entity_id,
+                    "cached" => format!("{:?}", cached),
+                    "stored" => format!("{:?}", stored),
+                );
+            }
+            Err(e) => {
+                warn!(
+                    logger,
+                    "Failed to load entity for entity cache check";
+                    "entity_type" => key.entity_type.as_str(),
+                    "entity_id" => &key.entity_id,
+                    "error" => e.to_string(),
+                );
             }
-
-            Ok((ctx, needs_restart))
         }
-
-        Err(e) => Err(anyhow!("Error while processing block stream for a subgraph: {}", e).into()),
     }
+
+    debug!(
+        logger,
+        "Checked entity cache against the store";
+        "cache_size" => cache.len(),
+        "checked" => checked,
+        "mismatches" => mismatches,
+    );
+}
+
+/// Check the entities that are about to be written against the schema of
+/// the subgraph. Proof of indexing entities are not part of the schema and
+/// are skipped.
+fn validate_entity_modifications(
+    schema: &Schema,
+    mods: &[EntityModification],
+) -> Vec<EntityValidationError> {
+    mods.iter()
+        .filter_map(|md| match md {
+            EntityModification::Insert { key, data }
+            | EntityModification::Overwrite { key, data } => Some((key, data)),
+            EntityModification::Remove { .. } => None,
+        })
+        .filter(|(key, _)| key.entity_type != *POI_OBJECT)
+        .flat_map(|(key, data)| schema.validate_entity(&key.entity_type, &key.entity_id, data))
+        .collect()
 }
 
-/// Transform the proof of indexing changes into entity updates that will be
-/// inserted when as_modifications is called.
+/// Compute the digests of the causality regions that the block
+/// `block_number` changed, and write them and the pending digests to
+/// `entity_cache` if the proof of indexing is written with this block.
+/// Returns the digests of the block and whether anything was written.
 async fn update_proof_of_indexing(
     proof_of_indexing: ProofOfIndexing,
+    block_number: BlockNumber,
+    write_interval: BlockNumber,
+    pending: &PendingPoi,
     stopwatch: &StopwatchMetrics,
     deployment_id: &SubgraphDeploymentId,
     entity_cache: &mut EntityCache,
-) -> Result<(), Error> {
+) -> Result<(Vec<(String, Bytes)>, bool), Error> {
     let _section_guard = stopwatch.start_section("update_proof_of_indexing");
 
     let mut proof_of_indexing = proof_of_indexing.take();
+    let mut digests = Vec::new();
 
     for (causality_region, stream) in proof_of_indexing.drain() {
         // Create the special POI entity key specific to this causality_region
@@ -1019,30 +6331,96 @@ async fn update_proof_of_indexing(
             entity_id: causality_region,
         };
 
-        // Grab the current digest attribute on this entity
-        let prev_poi =
-            entity_cache
+        // Grab the current digest, which is pending if it was not written
+        // yet, or the digest attribute on this entity
+        let prev_poi = match pending.digest(&entity_key.entity_id) {
+            Some(digest) => Some(digest.clone()),
+            None => entity_cache
                 .get(&entity_key)
                 .map_err(Error::from)?
                 .map(|entity| match entity.get("digest") {
                     Some(Value::Bytes(b)) => b.clone(),
                     _ => panic!("Expected POI entity to have a digest and for it to be bytes"),
-                });
+                }),
+        };
 
         // Finish the POI stream, getting the new POI value.
         let updated_proof_of_indexing = stream.pause(prev_poi.as_deref());
         let updated_proof_of_indexing: Bytes = (&updated_proof_of_indexing[..]).into();
 
+        digests.push((entity_key.entity_id, updated_proof_of_indexing));
+    }
+
+    if !pending.write_at(block_number, write_interval) {
+        return Ok((digests, false));
+    }
+
+    // The latest digest of each causality region, of this block if it
+    // changed the region
+    let latest: BTreeMap<_, _> = pending
+        .digests
+        .values()
+        .flat_map(|digests| digests.iter())
+        .chain(digests.iter())
+        .cloned()
+        .collect();
+    let written = !latest.is_empty();
+
+    for (causality_region, digest) in latest {
+        let entity_key = EntityKey {
+            subgraph_id: deployment_id.clone(),
+            entity_type: POI_OBJECT.to_owned(),
+            entity_id: causality_region,
+        };
+
         // Put this onto an entity with the same digest attribute
         // that was expected before when reading.
         let new_poi_entity = entity! {
             id: entity_key.entity_id.clone(),
-            digest: updated_proof_of_indexing,
+            digest: digest,
         };
 
         entity_cache.set(entity_key, new_poi_entity);
     }
 
+    Ok((digests, written))
+}
+
+/// Rewind the deployment `id` to the last block at which it wrote a digest
+/// of its proof of indexing, unless that is before `graft_block`. The
+/// digests of the blocks after it were only kept in memory.
+fn rewind_to_last_poi_write<S: SubgraphStore, C: ChainStore>(
+    logger: &Logger,
+    store: &S,
+    chain_store: &C,
+    id: &SubgraphDeploymentId,
+    graft_block: Option<BlockNumber>,
+) -> Result<(), Error> {
+    let last_write = match store.last_proof_of_indexing_block(id)? {
+        Some(block) => block,
+        None => return Ok(()),
+    };
+    let ptr = match store.block_ptr(id)? {
+        Some(ptr) => ptr,
+        None => return Ok(()),
+    };
+
+    // The blocks up to the graft point were indexed by the base deployment
+    let target = last_write.max(graft_block.unwrap_or(last_write));
+    if ptr.number <= target {
+        return Ok(());
+    }
+
+    let block = chain_store
+        .ancestor_block(ptr.clone(), ptr.number - target)?
+        .ok_or_else(|| anyhow!("block {} is missing from the chain store", target))?;
+    info!(
+        logger,
+        "Rewinding to the last block that wrote the proof of indexing";
+        "from_block" => ptr.number,
+        "to_block" => target,
+    );
+    store.rewind(id.clone(), EthereumBlockPointer::from(&block))?;
     Ok(())
 }
 
@@ -1053,31 +6431,79 @@ async fn process_triggers(
     subgraph_metrics: Arc<SubgraphInstanceMetrics>,
     instance: &SubgraphInstance<impl RuntimeHostBuilder>,
     block: &Arc<LightEthereumBlock>,
-    triggers: Vec<EthereumTrigger>,
-) -> Result<BlockState, MappingError> {
-    for trigger in triggers.into_iter() {
-        let block_ptr = EthereumBlockPointer::from(block.as_ref());
-        let trigger_type = match trigger {
-            EthereumTrigger::Log(_) => TriggerType::Event,
-            EthereumTrigger::Call(_) => TriggerType::Call,
-            EthereumTrigger::Block(..) => TriggerType::Block,
-        };
-        let transaction_id = match &trigger {
-            EthereumTrigger::Log(log) => log.transaction_hash,
-            EthereumTrigger::Call(call) => call.transaction_hash,
-            EthereumTrigger::Block(..) => None,
-        };
-        let start = Instant::now();
-        block_state = instance
-            .process_trigger(
-                &logger,
-                &block,
-                trigger,
-                block_state,
-                proof_of_indexing.cheap_clone(),
-            )
-            .await
-            .map_err(move |e| {
+    triggers: &[EthereumTrigger],
+    seed: BlockSeed,
+    deployment_id: &SubgraphDeploymentId,
+    trigger_filter: Option<&TriggerFilterHook>,
+    order: DataSourceOrder,
+    yield_interval: usize,
+    match_log_interval: usize,
+) -> Result<(BlockState, TriggerCounts), MappingError> {
+    let mut counts = TriggerCounts::default();
+    let triggers: Vec<_> = triggers
+        .iter()
+        .filter(|trigger| keep_trigger(trigger_filter, deployment_id, trigger, &subgraph_metrics))
+        .cloned()
+        .collect();
+    for trigger in &triggers {
+        counts.add(&TriggerType::from(trigger));
+    }
+
+    // Go through the triggers once for all data sources, or once for each
+    // kind of data source; `Some(true)` stands for dynamic data sources
+    let passes: &[Option<bool>] = match order {
+        DataSourceOrder::Trigger => &[None],
+        DataSourceOrder::StaticFirst => &[Some(false), Some(true)],
+        DataSourceOrder::DynamicFirst => &[Some(true), Some(false)],
+    };
+    let mut processed = 0;
+    for dynamic in passes {
+        for trigger in triggers.iter().cloned() {
+            // The indexing thread runs other tasks, too, and a block with
+            // many triggers would keep them from running
+            if yield_interval > 0 && processed > 0 && processed % yield_interval == 0 {
+                task::yield_now().await;
+            }
+            let report = match_log_interval > 0 && processed % match_log_interval == 0;
+            processed += 1;
+
+            let block_ptr = EthereumBlockPointer::from(block.as_ref());
+            let block_number = block_ptr.number;
+            let trigger_type = TriggerType::from(&trigger);
+            let transaction_id = trigger_transaction_id(&trigger);
+            let mut matched = Vec::new();
+            let matched_ref = if report { Some(&mut matched) } else { None };
+            let start = Instant::now();
+            let res = match dynamic {
+                None => {
+                    instance
+                        .process_trigger(
+                            &logger,
+                            &block,
+                            trigger,
+                            block_state,
+                            proof_of_indexing.cheap_clone(),
+                            seed,
+                            matched_ref,
+                        )
+                        .await
+                }
+                Some(dynamic) => {
+                    instance
+                        .process_trigger_for_data_sources(
+                            *dynamic,
+                            &logger,
+                            &block,
+                            trigger,
+                            block_state,
+                            proof_of_indexing.cheap_clone(),
+                            seed,
+                            matched_ref,
+                        )
+                        .await
+                }
+            };
+            block_state = res.map_err(move |e| {
                 e.context(match transaction_id {
                     Some(tx_hash) => format!(
                         "Failed to process trigger in block {}, transaction {:x}",
@@ -1086,10 +6512,240 @@ async fn process_triggers(
                     None => "Failed to process trigger".to_string(),
                 })
             })?;
-        let elapsed = start.elapsed().as_secs_f64();
-        subgraph_metrics.observe_trigger_processing_duration(elapsed, trigger_type);
+            let elapsed = start.elapsed().as_secs_f64();
+            if report {
+                log_trigger_match(
+                    logger,
+                    block_number,
+                    &trigger_type,
+                    transaction_id,
+                    &matched,
+                );
+            }
+            subgraph_metrics.observe_trigger_processing_duration(elapsed, trigger_type);
+        }
+    }
+    Ok((block_state, counts))
+}
+
+fn trigger_transaction_id(trigger: &EthereumTrigger) -> Option<web3::types::H256> {
+    match trigger {
+        EthereumTrigger::Log(log) => log.transaction_hash,
+        EthereumTrigger::Call(call) => call.transaction_hash,
+        EthereumTrigger::Block(..) => None,
+    }
+}
+
+/// Log the data sources that handled a trigger; see
+/// `SubgraphInstanceManagerConfig::trigger_match_log_interval`. A trigger
+/// that no data source handled is logged, too, since that is often what
+/// the author of a subgraph is looking for.
+fn log_trigger_match(
+    logger: &Logger,
+    block_number: BlockNumber,
+    trigger_type: &TriggerType,
+    transaction_id: Option<web3::types::H256>,
+    matched: &[String],
+) {
+    let data_sources = match matched.is_empty() {
+        true => "none".to_string(),
+        false => matched.join(", "),
+    };
+    info!(
+        logger,
+        "Processed trigger";
+        "block_number" => block_number,
+        "trigger_type" => trigger_type.label_value(),
+        "transaction_id" => transaction_id.map(|tx_hash| format!("{:x}", tx_hash)),
+        "data_sources" => data_sources,
+    );
+}
+
+/// The next trigger that an operator injected. Never resolves if injecting
+/// triggers is disabled or the manager dropped the sender because the
+/// deployment was stopped.
+async fn next_trigger_injection(
+    injections: &mut Option<mpsc::UnboundedReceiver<TriggerInjection>>,
+) -> TriggerInjection {
+    let next = match injections {
+        Some(receiver) => receiver.next().await,
+        None => None,
+    };
+    match next {
+        Some(injection) => injection,
+        None => {
+            *injections = None;
+            futures03::future::pending().await
+        }
+    }
+}
+
+/// The next request of an operator to compact the entity cache. Never
+/// resolves once the manager dropped the sender because the deployment was
+/// stopped.
+async fn next_cache_compaction(
+    compactions: &mut Option<mpsc::UnboundedReceiver<CacheCompactionRequest>>,
+) -> CacheCompactionRequest {
+    let next = match compactions {
+        Some(receiver) => receiver.next().await,
+        None => None,
+    };
+    match next {
+        Some(compaction) => compaction,
+        None => {
+            *compactions = None;
+            futures03::future::pending().await
+        }
+    }
+}
+
+/// Evict entries from the entity cache of the deployment between blocks
+/// until it weighs at most the weight that the operator asked for.
+fn compact_entity_cache<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &mut IndexingContext<B, T, S, C>,
+    request: CacheCompactionRequest,
+) {
+    let cache = &mut ctx.state.entity_lfu_cache;
+    let stats = cache.evict(request.target_weight).unwrap_or_default();
+    let compaction = EntityCacheCompaction {
+        freed_weight: stats.evicted_weight,
+        evicted_count: stats.evicted_count,
+        weight: cache.total_weight(),
+    };
+    if let Some(group) = &ctx.inputs.settings.entity_cache_limit_group {
+        group.set_weight(&ctx.inputs.deployment_id, compaction.weight);
+    }
+
+    info!(
+        logger,
+        "Compacted the entity cache";
+        "target_weight" => request.target_weight,
+        "freed_weight" => compaction.freed_weight,
+        "evicted_count" => compaction.evicted_count,
+        "weight" => compaction.weight,
+    );
+    let _ = request.outcome.send(compaction);
+}
+
+/// Dry-run the trigger of `injection` and send the outcome to the operator
+/// that injected it.
+async fn handle_trigger_injection<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S, C>,
+    injection: TriggerInjection,
+) where
+    S: SubgraphStore,
+    C: ChainStore,
+{
+    let outcome = dry_run_trigger(logger, ctx, injection.trigger).await;
+    if let Err(e) = &outcome {
+        warn!(
+            logger,
+            "Failed to process injected trigger";
+            "error" => format!("{:#}", e),
+        );
+    }
+    let _ = injection.outcome.send(outcome);
+}
+
+/// Process `trigger` against the block the deployment processed last the
+/// way `process_block` processes triggers, and return what it changes.
+/// The time it takes is not recorded in the trigger processing metrics of
+/// the deployment.
+/// Nothing is written, and neither the entity cache nor the data sources of
+/// the deployment are touched.
+async fn dry_run_trigger<B, T: RuntimeHostBuilder, S, C>(
+    logger: &Logger,
+    ctx: &IndexingContext<B, T, S, C>,
+    trigger: EthereumTrigger,
+) -> Result<TriggerInjectionOutcome, Error>
+where
+    S: SubgraphStore,
+    C: ChainStore,
+{
+    let block_ptr = ctx
+        .state
+        .block_ptr
+        .clone()
+        .ok_or_else(|| anyhow!("the deployment has not processed any blocks yet"))?;
+    info!(
+        logger,
+        "Processing injected trigger without writing its changes";
+        "block_number" => block_ptr.number,
+        "trigger" => TriggerType::from(&trigger).label_value(),
+    );
+
+    let eth_adapter = ctx.inputs.eth_adapters.current();
+    let blocks = eth_adapter
+        .load_blocks(
+            logger.cheap_clone(),
+            ctx.inputs.chain_store.cheap_clone(),
+            HashSet::from_iter(Some(block_ptr.hash_as_h256())),
+        )
+        .collect()
+        .compat()
+        .await;
+    ctx.inputs
+        .eth_adapters
+        .record(logger, &*eth_adapter, blocks.is_ok());
+    let block = blocks?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("block {} could not be loaded", block_ptr))?;
+    let block = Arc::new(block);
+
+    let metrics = Arc::new(SubgraphInstanceMetrics::new(
+        Arc::new(UnregisteredMetrics),
+        ctx.inputs.deployment_id.as_str(),
+        &DeploymentLabels::default(),
+    ));
+    let block_state = BlockState::new(ctx.inputs.store.clone(), LfuCache::new());
+    let (block_state, _) = process_triggers(
+        logger,
+        block_state,
+        None,
+        metrics,
+        &ctx.state.instance,
+        &block,
+        &[trigger],
+        BlockSeed::from_block(&block),
+        &ctx.inputs.deployment_id,
+        None,
+        ctx.inputs.config.data_source_order,
+        ctx.inputs.config.trigger_yield_interval,
+        0,
+    )
+    .await
+    .map_err(|e| match e {
+        MappingError::PossibleReorg(e) | MappingError::Unknown(e) => e,
+    })?;
+
+    let deterministic_errors = block_state.deterministic_errors;
+    let modifications = block_state
+        .entity_cache
+        .as_modifications(ctx.inputs.store.as_ref())?
+        .modifications;
+    Ok(TriggerInjectionOutcome {
+        block_ptr,
+        modifications,
+        deterministic_errors,
+    })
+}
+
+/// Whether `trigger` should be processed according to the trigger filter
+/// hook; dropped triggers are counted.
+fn keep_trigger(
+    trigger_filter: Option<&TriggerFilterHook>,
+    deployment_id: &SubgraphDeploymentId,
+    trigger: &EthereumTrigger,
+    metrics: &SubgraphInstanceMetrics,
+) -> bool {
+    let keep = trigger_filter.map_or(true, |hook| (hook.0)(deployment_id, trigger));
+    if !keep {
+        metrics.filtered_trigger_count.inc();
     }
-    Ok(block_state)
+    keep
 }
 
 fn create_dynamic_data_sources<B, T: RuntimeHostBuilder, S, C>(
@@ -1097,7 +6753,8 @@ fn create_dynamic_data_sources<B, T: RuntimeHostBuilder, S, C>(
     ctx: &mut IndexingContext<B, T, S, C>,
     host_metrics: Arc<HostMetrics>,
     created_data_sources: Vec<DataSourceTemplateInfo>,
-) -> Result<(Vec<DataSource>, Vec<Arc<T::Host>>), Error>
+    block_ptr: &EthereumBlockPointer,
+) -> Result<(Vec<DataSource>, Vec<Arc<T::Host>>), BlockProcessingError>
 where
     B: BlockStreamBuilder,
     S: SubgraphStore,
@@ -1106,6 +6763,8 @@ where
     let mut data_sources = vec![];
     let mut runtime_hosts = vec![];
 
+    let policy = ctx.inputs.settings.duplicate_data_source_policy;
+
     for info in created_data_sources {
         // Try to instantiate a data source from the template
         let data_source = DataSource::try_from(info)?;
@@ -1116,15 +6775,45 @@ where
             data_source.clone(),
             ctx.inputs.templates.clone(),
             host_metrics.clone(),
+            policy == DuplicateDataSourcePolicy::Replace,
         )?;
 
         match host {
             Some(host) => {
+                // A data source created earlier in this block may have been
+                // replaced; it is neither persisted nor run on this block
+                if let Some(i) = data_sources
+                    .iter()
+                    .position(|ds: &DataSource| ds.is_duplicate_of(&data_source))
+                {
+                    data_sources.remove(i);
+                    runtime_hosts.remove(i);
+                }
                 data_sources.push(data_source);
                 runtime_hosts.push(host);
             }
+            None if policy == DuplicateDataSourcePolicy::Error => {
+                return Err(BlockProcessingError::Deterministic(SubgraphError {
+                    subgraph_id: ctx.inputs.deployment_id.clone(),
+                    message: format!(
+                        "data source `{}` for address `{}` duplicates an existing data source",
+                        data_source.name,
+                        data_source
+                            .source
+                            .address
+                            .map(|address| address.to_string())
+                            .unwrap_or("none".to_string()),
+                    ),
+                    block_ptr: Some(block_ptr.clone()),
+                    handler: None,
+                    deterministic: true,
+                    details: None,
+                }));
+            }
             None => {
-                fail_point!("error_on_duplicate_ds", |_| Err(anyhow!("duplicate ds")));
+                fail_point!("error_on_duplicate_ds", |_| Err(
+                    anyhow!("duplicate ds").into()
+                ));
                 warn!(
                     logger,
                     "no runtime hosted created, there is already a runtime host instantiated for \
@@ -1187,3 +6876,468 @@ fn persist_dynamic_data_sources<B, T: RuntimeHostBuilder, S, C>(
         .block_filter
         .extend(EthereumBlockFilter::from_data_sources(&data_sources));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use web3::types::{Index, Log, H160, H256, U64};
+
+    fn manifest(id: &SubgraphDeploymentId) -> Arc<SubgraphManifest> {
+        Arc::new(SubgraphManifest {
+            id: id.clone(),
+            spec_version: "0.0.2".to_owned(),
+            features: BTreeSet::new(),
+            description: None,
+            repository: None,
+            schema: Schema::parse("type Thing @entity { id: ID! }", id.clone()).unwrap(),
+            data_sources: vec![],
+            graft: None,
+            templates: vec![],
+            block_predicate: None,
+        })
+    }
+
+    #[test]
+    fn forget_running_subgraph_only_forgets_deployment() {
+        let id = SubgraphDeploymentId::new("QmStopped").unwrap();
+        let other = SubgraphDeploymentId::new("QmRunning").unwrap();
+        let metrics =
+            SubgraphInstanceManagerMetrics::new(Arc::new(graph_mock::MockMetricsRegistry::new()));
+        let manifests = SharedManifestMap::default();
+        let health = SharedHealthMap::default();
+        for id in &[&id, &other] {
+            manifests
+                .write()
+                .unwrap()
+                .insert((*id).clone(), manifest(id));
+            health
+                .write()
+                .unwrap()
+                .insert((*id).clone(), Arc::new(Mutex::new(HealthState::default())));
+            metrics.subgraph_count.inc();
+        }
+
+        let forget = || {
+            forget_running_subgraph(
+                &id,
+                &manifests,
+                &health,
+                &SharedContextDumpMap::default(),
+                &SharedTriggerInjectionMap::default(),
+                &SharedCacheCompactionMap::default(),
+                &metrics,
+            )
+        };
+        forget();
+        assert!(!manifests.read().unwrap().contains_key(&id));
+        assert!(!health.read().unwrap().contains_key(&id));
+        assert!(manifests.read().unwrap().contains_key(&other));
+        assert!(health.read().unwrap().contains_key(&other));
+        assert_eq!(metrics.subgraph_count.get(), 1.0);
+
+        // Forgetting the deployment again does not count it twice
+        forget();
+        assert_eq!(metrics.subgraph_count.get(), 1.0);
+    }
+
+    #[test]
+    fn forget_manifest_of_stopped_deployment() {
+        let id = SubgraphDeploymentId::new("QmStopped").unwrap();
+        let metrics =
+            SubgraphInstanceManagerMetrics::new(Arc::new(graph_mock::MockMetricsRegistry::new()));
+        let manifests = SharedManifestMap::default();
+        manifests.write().unwrap().insert(id.clone(), manifest(&id));
+
+        forget_manifest(&id, &manifests, &metrics);
+        assert!(manifests.read().unwrap().is_empty());
+    }
+
+    fn deterministic_error(id: &SubgraphDeploymentId) -> SubgraphError {
+        SubgraphError {
+            subgraph_id: id.clone(),
+            message: "handler failed".to_owned(),
+            block_ptr: None,
+            handler: Some("handleTransfer".to_owned()),
+            deterministic: true,
+            details: None,
+        }
+    }
+
+    #[test]
+    fn failed_write_with_errors_stops_pending_subgraph() {
+        let logger = Logger::root(slog::Discard, o!());
+        let id = SubgraphDeploymentId::new("QmFailedWrite").unwrap();
+        let errors = vec![deterministic_error(&id)];
+        let unassigned = AtomicBool::new(false);
+        let unassign = || {
+            unassigned.store(true, Ordering::SeqCst);
+            Ok(())
+        };
+
+        // Fail fast, the subgraph is stopped
+        assert!(stop_for_errors_of_failed_write(
+            &logger,
+            &errors,
+            10,
+            true,
+            || Ok(true),
+            unassign,
+        ));
+        assert!(unassigned.load(Ordering::SeqCst));
+
+        // Failing to unassign does not hide the error from the write
+        assert!(!stop_for_errors_of_failed_write(
+            &logger,
+            &errors,
+            10,
+            true,
+            || Ok(true),
+            || Err(StoreError::Unknown(anyhow!("database is gone"))),
+        ));
+    }
+
+    #[test]
+    fn failed_write_with_errors_keeps_subgraph_without_fail_fast() {
+        let logger = Logger::root(slog::Discard, o!());
+        let id = SubgraphDeploymentId::new("QmFailedWrite").unwrap();
+        let errors = vec![deterministic_error(&id)];
+        let unassign = || -> Result<(), StoreError> { panic!("the subgraph must not be stopped") };
+
+        // Not fail fast, e.g., because the subgraph is synced
+        assert!(!stop_for_errors_of_failed_write(
+            &logger,
+            &errors,
+            10,
+            true,
+            || Ok(false),
+            unassign,
+        ));
+
+        // Checking for fail fast failed
+        assert!(!stop_for_errors_of_failed_write(
+            &logger,
+            &errors,
+            10,
+            true,
+            || Err(BlockProcessingError::Unknown(anyhow!("database is gone"))),
+            unassign,
+        ));
+
+        // Stopping for failed writes is turned off
+        assert!(!stop_for_errors_of_failed_write(
+            &logger,
+            &errors,
+            10,
+            false,
+            || panic!("fail fast must not be checked"),
+            unassign,
+        ));
+
+        // No deterministic errors
+        assert!(!stop_for_errors_of_failed_write(
+            &logger,
+            &[],
+            10,
+            true,
+            || panic!("fail fast must not be checked"),
+            unassign,
+        ));
+    }
+
+    fn trigger_log(tx_hash: u64, log_index: u64) -> EthereumTrigger {
+        EthereumTrigger::Log(Arc::new(Log {
+            address: H160::zero(),
+            topics: vec![],
+            data: web3::types::Bytes::default(),
+            block_hash: Some(H256::zero()),
+            block_number: Some(U64::zero()),
+            transaction_hash: Some(H256::from_low_u64_be(tx_hash)),
+            transaction_index: Some(Index::zero()),
+            log_index: Some(log_index.into()),
+            transaction_log_index: Some(log_index.into()),
+            log_type: None,
+            removed: Some(false),
+        }))
+    }
+
+    #[test]
+    fn dedupe_triggers_drops_duplicates() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics = SubgraphInstanceMetrics::new(
+            Arc::new(graph_mock::MockMetricsRegistry::new()),
+            "QmDedupe",
+            &DeploymentLabels::default(),
+        );
+        let ptr = EthereumBlockPointer::from((H256::zero(), 1 as u64));
+        let every = EthereumTrigger::Block(ptr.clone(), EthereumBlockTriggerType::Every);
+        let call_to = EthereumTrigger::Block(
+            ptr.clone(),
+            EthereumBlockTriggerType::WithCallTo(H160::zero()),
+        );
+        let call = EthereumTrigger::Call(Arc::new(EthereumCall::default()));
+
+        let triggers = vec![
+            trigger_log(1, 0),
+            every.clone(),
+            call.clone(),
+            trigger_log(1, 1),
+            trigger_log(1, 0),
+            call.clone(),
+            every.clone(),
+            call_to.clone(),
+            trigger_log(2, 0),
+        ];
+        let deduped = dedupe_triggers(&logger, &metrics, triggers);
+
+        // Calls are never dropped, and the order of the rest is kept
+        assert_eq!(
+            vec![
+                trigger_log(1, 0),
+                every,
+                call.clone(),
+                trigger_log(1, 1),
+                call,
+                call_to,
+                trigger_log(2, 0),
+            ],
+            deduped
+        );
+        assert_eq!(2.0, metrics.duplicate_trigger_count.get());
+
+        // Nothing to drop
+        let deduped = dedupe_triggers(&logger, &metrics, deduped);
+        assert_eq!(7, deduped.len());
+        assert_eq!(2.0, metrics.duplicate_trigger_count.get());
+    }
+
+    #[test]
+    fn parse_duplicate_data_source_policy() {
+        use DuplicateDataSourcePolicy::*;
+
+        assert_eq!(Drop, "drop".parse().unwrap());
+        assert_eq!(Error, "error".parse().unwrap());
+        assert_eq!(Replace, "replace".parse().unwrap());
+        assert!("Drop".parse::<DuplicateDataSourcePolicy>().is_err());
+        assert!("".parse::<DuplicateDataSourcePolicy>().is_err());
+    }
+
+    fn pending_poi(written: bool, blocks: &[BlockNumber]) -> PendingPoi {
+        let mut pending = PendingPoi {
+            digests: BTreeMap::new(),
+            written,
+        };
+        for block in blocks {
+            let digest = Bytes::from(&[*block as u8][..]);
+            pending.record(*block, &[("region".to_owned(), digest)], false);
+        }
+        pending
+    }
+
+    #[test]
+    fn pending_poi_write_at() {
+        // The first digests of a deployment are written right away
+        let pending = pending_poi(false, &[]);
+        assert!(pending.write_at(3, 10));
+
+        // Otherwise, digests are written with the last block of an interval
+        let pending = pending_poi(true, &[]);
+        assert!(!pending.write_at(3, 10));
+        assert!(pending.write_at(9, 10));
+        assert!(pending.write_at(19, 10));
+        assert!(!pending.write_at(20, 10));
+
+        let pending = pending_poi(true, &[11, 12]);
+        assert!(!pending.write_at(13, 10));
+        assert!(pending.write_at(19, 10));
+
+        // Blocks were skipped past the end of the interval of the oldest
+        // pending digest
+        assert!(pending.write_at(23, 10));
+
+        // Writing with every block
+        let pending = pending_poi(true, &[]);
+        assert!(pending.write_at(0, 1));
+        assert!(pending.write_at(7, 1));
+    }
+
+    #[test]
+    fn pending_poi_record() {
+        let mut pending = pending_poi(true, &[11, 12]);
+        assert_eq!(Some(&Bytes::from(&[12u8][..])), pending.digest("region"));
+        assert_eq!(None, pending.digest("other"));
+
+        // Blocks that do not change any causality region are not recorded
+        pending.record(13, &[], false);
+        assert_eq!(
+            vec![11, 12],
+            pending.digests.keys().cloned().collect::<Vec<_>>()
+        );
+
+        // Writing the digests clears them
+        let mut pending = pending_poi(false, &[11]);
+        pending.record(12, &[("region".to_owned(), Bytes::from(&[12u8][..]))], true);
+        assert!(pending.written);
+        assert!(pending.digests.is_empty());
+        assert_eq!(None, pending.digest("region"));
+    }
+
+    #[test]
+    fn pending_poi_forget_after() {
+        let mut pending = pending_poi(true, &[11, 12, 13]);
+        pending.forget_after(Some(&EthereumBlockPointer::from((H256::zero(), 12 as u64))));
+        assert_eq!(
+            vec![11, 12],
+            pending.digests.keys().cloned().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(&Bytes::from(&[12u8][..])), pending.digest("region"));
+
+        // Reverting to before the oldest pending digest
+        pending.forget_after(Some(&EthereumBlockPointer::from((H256::zero(), 5 as u64))));
+        assert!(pending.digests.is_empty());
+
+        // Reverting past the start of the deployment
+        let mut pending = pending_poi(true, &[11, 12]);
+        pending.forget_after(None);
+        assert!(pending.digests.is_empty());
+        assert!(pending.written);
+    }
+
+    fn block_ptr(number: u64) -> EthereumBlockPointer {
+        EthereumBlockPointer::from((H256::from_low_u64_be(number), number))
+    }
+
+    #[test]
+    fn synthetic_reorg_ptr_reverts_at_boundary() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut reorgs: BTreeSet<BlockNumber> = vec![5, 8, 12].into_iter().collect();
+
+        // Before the first boundary
+        assert_eq!(
+            None,
+            synthetic_reorg_ptr(&logger, &mut reorgs, Some(&block_ptr(3)), 4)
+        );
+        assert_eq!(3, reorgs.len());
+
+        // The block stream skipped past the boundaries 5 and 8; the last
+        // processed block is reverted once and both boundaries are used up
+        assert_eq!(
+            Some(block_ptr(4)),
+            synthetic_reorg_ptr(&logger, &mut reorgs, Some(&block_ptr(4)), 9)
+        );
+        assert_eq!(vec![12], reorgs.iter().cloned().collect::<Vec<_>>());
+
+        // The block at the boundary itself
+        assert_eq!(
+            Some(block_ptr(11)),
+            synthetic_reorg_ptr(&logger, &mut reorgs, Some(&block_ptr(11)), 12)
+        );
+        assert!(reorgs.is_empty());
+        assert_eq!(
+            None,
+            synthetic_reorg_ptr(&logger, &mut reorgs, Some(&block_ptr(12)), 13)
+        );
+    }
+
+    #[test]
+    fn synthetic_reorg_ptr_ignores_reached_boundaries() {
+        let logger = Logger::root(slog::Discard, o!());
+
+        // The deployment is already past the boundary, e.g., after a restart
+        let mut reorgs: BTreeSet<BlockNumber> = vec![5].into_iter().collect();
+        assert_eq!(
+            None,
+            synthetic_reorg_ptr(&logger, &mut reorgs, Some(&block_ptr(7)), 8)
+        );
+        assert!(reorgs.is_empty());
+
+        // Nothing was processed yet, or only the genesis block
+        let mut reorgs: BTreeSet<BlockNumber> = vec![5].into_iter().collect();
+        assert_eq!(None, synthetic_reorg_ptr(&logger, &mut reorgs, None, 6));
+        let mut reorgs: BTreeSet<BlockNumber> = vec![1].into_iter().collect();
+        assert_eq!(
+            None,
+            synthetic_reorg_ptr(&logger, &mut reorgs, Some(&block_ptr(0)), 1)
+        );
+    }
+
+    #[test]
+    fn failed_synthetic_revert_is_retried() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut reorgs: BTreeSet<BlockNumber> = vec![5].into_iter().collect();
+        let ptr = synthetic_reorg_ptr(&logger, &mut reorgs, Some(&block_ptr(4)), 5).unwrap();
+
+        // The boundary is used up, and the revert is the only trace of it;
+        // retrying it keeps it, as often as needed
+        assert!(reorgs.is_empty());
+        let revert = ForcedRevert::Synthetic(ptr.clone());
+        let revert = revert.retry(|| None).unwrap();
+        assert_eq!(ForcedRevert::Synthetic(ptr.clone()), revert);
+        assert_eq!(&ptr, revert.retry(|| None).unwrap().ptr());
+    }
+
+    #[test]
+    fn failed_orphaned_revert_rechecks_head() {
+        let revert = ForcedRevert::Orphaned(block_ptr(7));
+
+        // Still orphaned
+        let rechecks = AtomicUsize::new(0);
+        let recheck = || {
+            rechecks.fetch_add(1, Ordering::SeqCst);
+            Some(block_ptr(7))
+        };
+        assert_eq!(
+            Some(ForcedRevert::Orphaned(block_ptr(7))),
+            revert.clone().retry(recheck)
+        );
+        assert_eq!(1, rechecks.load(Ordering::SeqCst));
+
+        // The block is back on the main chain, nothing to retry
+        assert_eq!(None, revert.retry(|| None));
+    }
+
+    #[test]
+    fn record_errors_in_window() {
+        let mut recent_errors = VecDeque::new();
+
+        assert_eq!(None, record_errors(&mut recent_errors, 1, 2, 5, 10));
+        assert_eq!(None, record_errors(&mut recent_errors, 5, 2, 5, 10));
+
+        // The errors of block 1 are out of the window of block 11
+        assert_eq!(None, record_errors(&mut recent_errors, 11, 2, 5, 10));
+        assert_eq!(vec![(5, 2), (11, 2)], Vec::from(recent_errors.clone()));
+
+        // Reaching the limit forgets the errors, so that the deployment
+        // isn't paused again right after it is resumed
+        assert_eq!(Some(5), record_errors(&mut recent_errors, 12, 1, 5, 10));
+        assert!(recent_errors.is_empty());
+        assert_eq!(None, record_errors(&mut recent_errors, 13, 1, 5, 10));
+    }
+
+    #[test]
+    fn entity_cache_limit_group() {
+        let a = SubgraphDeploymentId::new("QmLimitA").unwrap();
+        let b = SubgraphDeploymentId::new("QmLimitB").unwrap();
+        let c = SubgraphDeploymentId::new("QmLimitC").unwrap();
+        let schema = Schema::parse("type Thing @entity { id: ID! }", a.clone()).unwrap();
+        let group = EntityCacheLimitGroup::new("group".to_string());
+
+        group.join(&a, &schema, "mainnet").unwrap();
+        group.join(&b, &schema, "mainnet").unwrap();
+        assert!(group.join(&c, &schema, "ropsten").is_err());
+        assert_eq!(100, group.limit(&a, 100));
+
+        // `a` gets what `b` leaves over, but at least its fair share
+        group.set_weight(&b, 30);
+        assert_eq!(70, group.limit(&a, 100));
+        group.set_weight(&b, 90);
+        assert_eq!(50, group.limit(&a, 100));
+
+        // Deployments that are not members don't count
+        group.set_weight(&c, 100);
+        assert_eq!(50, group.limit(&a, 100));
+
+        group.leave(&b);
+        assert_eq!(100, group.limit(&a, 100));
+    }
+}
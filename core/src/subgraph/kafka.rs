@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rdkafka::config::ClientConfig;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use graph::components::subgraph::{ModificationsEvent, ModificationsSink};
+use graph::prelude::*;
+
+/// How long a message waits in the producer for the broker to acknowledge
+/// it before it counts as failed and is published again.
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The topics that `KafkaModificationsSink` publishes to.
+#[derive(Clone, Debug)]
+pub enum KafkaTopics {
+    /// One topic for all deployments. Messages are keyed by deployment, so
+    /// that the events of a deployment land in one partition, in order.
+    Shared(String),
+
+    /// A topic per deployment, named by appending the deployment to the
+    /// prefix.
+    PerDeployment(String),
+}
+
+impl KafkaTopics {
+    fn topic(&self, deployment: &SubgraphDeploymentId) -> String {
+        match self {
+            KafkaTopics::Shared(topic) => topic.clone(),
+            KafkaTopics::PerDeployment(prefix) => format!("{}{}", prefix, deployment),
+        }
+    }
+}
+
+/// Where the last event of a deployment was published to. Consumers that
+/// replay a deployment from a block start after the offset of the event
+/// for that block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KafkaOffset {
+    pub block_ptr: EthereumBlockPointer,
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// Publishes entity modifications to Kafka, as the JSON documents of
+/// `ModificationsEvent::to_json`. Each message also carries the number and
+/// hash of its block in the `block_number` and `block_hash` headers, and
+/// an event only counts as published once the broker acknowledged it, so
+/// that the offsets of a deployment follow the blocks it committed.
+pub struct KafkaModificationsSink {
+    producer: FutureProducer,
+    topics: KafkaTopics,
+    offsets: Mutex<HashMap<SubgraphDeploymentId, KafkaOffset>>,
+    logger: Logger,
+}
+
+impl KafkaModificationsSink {
+    /// Publish to the comma-separated Kafka `brokers`. The producer is
+    /// idempotent, so that retries never reorder or duplicate messages.
+    pub fn new(logger: Logger, brokers: &str, topics: KafkaTopics) -> Result<Self, Error> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("enable.idempotence", "true")
+            .set(
+                "message.timeout.ms",
+                &MESSAGE_TIMEOUT.as_millis().to_string(),
+            )
+            .create::<FutureProducer>()?;
+        Ok(KafkaModificationsSink {
+            producer,
+            topics,
+            offsets: Mutex::new(HashMap::new()),
+            logger,
+        })
+    }
+
+    /// Where the last event of `deployment` was published to, if any event
+    /// was published since this node started.
+    pub fn offset(&self, deployment: &SubgraphDeploymentId) -> Option<KafkaOffset> {
+        self.offsets.lock().unwrap().get(deployment).cloned()
+    }
+}
+
+impl ModificationsSink for KafkaModificationsSink {
+    fn publish(&self, event: &ModificationsEvent) -> Result<(), Error> {
+        let deployment = event.deployment();
+        let block_ptr = event.block_ptr();
+        let topic = self.topics.topic(deployment);
+        let payload = event.to_json().to_string();
+        let block_number = block_ptr.number.to_string();
+        let block_hash = format!("0x{:x}", block_ptr.hash);
+        let record = FutureRecord::to(&topic)
+            .key(deployment.as_str())
+            .payload(&payload)
+            .headers(
+                OwnedHeaders::new()
+                    .add("block_number", &block_number)
+                    .add("block_hash", &block_hash),
+            );
+
+        // We are on the publisher thread of the deployment, which may
+        // wait for the broker
+        let delivery = self.producer.send_result(record).map_err(|(e, _)| e)?;
+        let (partition, offset) = futures03::executor::block_on(delivery)
+            .map_err(|_| anyhow!("the Kafka producer was dropped"))?
+            .map_err(|(e, _)| e)?;
+
+        debug!(
+            self.logger,
+            "Published entity modifications to Kafka";
+            "deployment" => deployment.as_str(),
+            "block_number" => block_ptr.number,
+            "topic" => &topic,
+            "partition" => partition,
+            "offset" => offset,
+        );
+        self.offsets.lock().unwrap().insert(
+            deployment.clone(),
+            KafkaOffset {
+                block_ptr: block_ptr.clone(),
+                topic,
+                partition,
+                offset,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics() {
+        let deployment = SubgraphDeploymentId::new("QmTopics").unwrap();
+        assert_eq!(
+            KafkaTopics::Shared("modifications".to_string()).topic(&deployment),
+            "modifications"
+        );
+        assert_eq!(
+            KafkaTopics::PerDeployment("modifications-".to_string()).topic(&deployment),
+            "modifications-QmTopics"
+        );
+    }
+}
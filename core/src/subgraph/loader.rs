@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::time::Instant;
 
@@ -65,6 +65,8 @@ pub async fn load_dynamic_data_sources(
         data_sources.push(ds);
     }
 
+    let data_sources = drop_replaced_data_sources(data_sources);
+
     trace!(
         logger,
         "Loaded dynamic data sources";
@@ -73,3 +75,121 @@ pub async fn load_dynamic_data_sources(
 
     Ok(data_sources)
 }
+
+/// Remove data sources that have a later duplicate. Duplicates are only
+/// stored when they replace an existing data source, see
+/// `DuplicateDataSourcePolicy::Replace`.
+fn drop_replaced_data_sources(data_sources: Vec<DataSource>) -> Vec<DataSource> {
+    let mut candidates: HashMap<(&str, Option<web3::types::Address>), Vec<usize>> = HashMap::new();
+    let mut replaced = HashSet::new();
+
+    for (i, ds) in data_sources.iter().enumerate() {
+        let same_source = candidates
+            .entry((ds.name.as_str(), ds.source.address))
+            .or_default();
+        if let Some(pos) = same_source
+            .iter()
+            .position(|j| data_sources[*j].is_duplicate_of(ds))
+        {
+            replaced.insert(same_source.remove(pos));
+        }
+        same_source.push(i);
+    }
+
+    if replaced.is_empty() {
+        return data_sources;
+    }
+
+    data_sources
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !replaced.contains(i))
+        .map(|(_, ds)| ds)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::data::subgraph::{Mapping, Source};
+    use graph::prelude::ethabi::Contract;
+    use graph::semver::Version;
+    use web3::types::H160;
+
+    fn data_source(name: &str, address: u64, creation_block: Option<BlockNumber>) -> DataSource {
+        let contract_abi = Arc::new(MappingABI {
+            name: "Contract".to_string(),
+            contract: Contract::load("[]".as_bytes()).unwrap(),
+        });
+        DataSource {
+            kind: String::from("ethereum/contract"),
+            name: String::from(name),
+            network: Some(String::from("mainnet")),
+            source: Source {
+                address: Some(H160::from_low_u64_be(address)),
+                abi: String::from("Contract"),
+                start_block: 0,
+            },
+            mapping: Mapping {
+                kind: String::from("ethereum/events"),
+                api_version: Version::parse("0.1.0").unwrap(),
+                language: String::from("wasm/assemblyscript"),
+                entities: vec![],
+                abis: vec![contract_abi.clone()],
+                event_handlers: vec![],
+                call_handlers: vec![],
+                block_handlers: vec![],
+                link: Link {
+                    link: "link".to_owned(),
+                },
+                runtime: Arc::new(vec![]),
+            },
+            context: Default::default(),
+            creation_block,
+            contract_abi,
+        }
+    }
+
+    fn names(data_sources: &[DataSource]) -> Vec<(&str, Option<BlockNumber>)> {
+        data_sources
+            .iter()
+            .map(|ds| (ds.name.as_str(), ds.creation_block))
+            .collect()
+    }
+
+    #[test]
+    fn drop_replaced_data_sources_keeps_latest_duplicate() {
+        let data_sources = vec![
+            data_source("Token", 1, Some(5)),
+            data_source("Pair", 1, Some(6)),
+            data_source("Token", 1, Some(10)),
+            data_source("Token", 2, Some(11)),
+            data_source("Token", 1, Some(20)),
+        ];
+
+        let data_sources = drop_replaced_data_sources(data_sources);
+        assert_eq!(
+            vec![("Pair", Some(6)), ("Token", Some(11)), ("Token", Some(20))],
+            names(&data_sources)
+        );
+        assert_eq!(
+            Some(H160::from_low_u64_be(2)),
+            data_sources[1].source.address
+        );
+    }
+
+    #[test]
+    fn drop_replaced_data_sources_without_duplicates() {
+        let data_sources = vec![
+            data_source("Token", 1, Some(5)),
+            data_source("Token", 2, Some(6)),
+            data_source("Pair", 1, Some(7)),
+        ];
+
+        let data_sources = drop_replaced_data_sources(data_sources);
+        assert_eq!(
+            vec![("Token", Some(5)), ("Token", Some(6)), ("Pair", Some(7))],
+            names(&data_sources)
+        );
+    }
+}
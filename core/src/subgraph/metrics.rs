@@ -0,0 +1,776 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use graph::components::metrics::sink::{metric_samples, MetricSample, MetricsSink};
+use graph::components::store::ConnectionWaitRecorder;
+use graph::components::subgraph::DeploymentLabels;
+use graph::prelude::*;
+
+use super::SubgraphInstanceManagerConfig;
+
+/// Where and how often to push the metrics of each deployment, in addition
+/// to exposing them through the metrics registry.
+#[derive(Clone)]
+pub struct MetricsPush {
+    pub sink: Arc<dyn MetricsSink>,
+
+    /// The minimum time between two pushes of the metrics of a deployment.
+    /// Metrics are pushed while the deployment processes blocks.
+    pub interval: Duration,
+}
+
+impl fmt::Debug for MetricsPush {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MetricsPush {{ interval: {:?} }}", self.interval)
+    }
+}
+
+/// Counts something in progress in a gauge for as long as it exists, also
+/// when the future that does the work is dropped.
+pub(crate) struct InFlight<'a>(&'a Gauge);
+
+impl<'a> InFlight<'a> {
+    pub(crate) fn new(gauge: &'a Gauge) -> Self {
+        gauge.inc();
+        InFlight(gauge)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub(crate) struct SubgraphInstanceManagerMetrics {
+    pub subgraph_count: Box<Gauge>,
+    pub completed_count: Box<Counter>,
+    pub delayed_restart_count: Box<CounterVec>,
+    pub required_capabilities: Box<GaugeVec>,
+    pub skipped_block_count: Box<CounterVec>,
+    pub start_retry_count: Box<CounterVec>,
+    pub start_outcome_count: Box<CounterVec>,
+    pub ipfs_resolutions_in_flight: Box<Gauge>,
+}
+
+impl SubgraphInstanceManagerMetrics {
+    pub fn new(registry: Arc<impl MetricsRegistry>) -> Self {
+        let subgraph_count = registry
+            .new_gauge(
+                "deployment_count",
+                "Counts the number of deployments currently being indexed by the graph-node.",
+                HashMap::new(),
+            )
+            .expect("failed to create `deployment_count` gauge");
+        let completed_count = registry
+            .new_counter(
+                "deployment_completed_count",
+                "Counts the deployments that stopped because they reached their end block",
+            )
+            .expect("failed to create `deployment_completed_count` counter");
+        let delayed_restart_count = registry
+            .new_counter_vec(
+                "deployment_delayed_restart_count",
+                "Counts the restarts of deployments that were delayed because they kept failing",
+                vec![String::from("deployment")],
+            )
+            .expect("failed to create `deployment_delayed_restart_count` counter");
+        let required_capabilities = registry
+            .new_gauge_vec(
+                "deployment_required_capabilities",
+                "Set to 1 for the Ethereum node capabilities a running deployment requires",
+                vec![
+                    String::from("deployment"),
+                    String::from("archive"),
+                    String::from("traces"),
+                ],
+            )
+            .expect("failed to create `deployment_required_capabilities` gauge");
+        let skipped_block_count = registry
+            .new_counter_vec(
+                "deployment_skipped_block_count",
+                "Counts the blocks whose triggers were skipped because an operator said so",
+                vec![String::from("deployment")],
+            )
+            .expect("failed to create `deployment_skipped_block_count` counter");
+        let start_retry_count = registry
+            .new_counter_vec(
+                "deployment_start_retry_count",
+                "Counts the retries of starting deployments that failed to start",
+                vec![String::from("deployment")],
+            )
+            .expect("failed to create `deployment_start_retry_count` counter");
+        let start_outcome_count = registry
+            .new_counter_vec(
+                "deployment_start_outcome_count",
+                "Counts the starts of deployments by how they ended",
+                vec![String::from("outcome")],
+            )
+            .expect("failed to create `deployment_start_outcome_count` counter");
+        let ipfs_resolutions_in_flight = registry
+            .new_gauge(
+                "deployment_ipfs_resolutions_in_flight",
+                "The number of deployments that are resolving their files from IPFS",
+                HashMap::new(),
+            )
+            .expect("failed to create `deployment_ipfs_resolutions_in_flight` gauge");
+        Self {
+            subgraph_count,
+            completed_count,
+            delayed_restart_count,
+            required_capabilities,
+            skipped_block_count,
+            start_retry_count,
+            start_outcome_count,
+            ipfs_resolutions_in_flight,
+        }
+    }
+
+    /// Forget what the counters that are labeled by deployment counted for
+    /// the deployment `id`, so that they start from zero again.
+    pub(crate) fn reset(&self, id: &SubgraphDeploymentId) {
+        for counter in &[
+            &self.delayed_restart_count,
+            &self.skipped_block_count,
+            &self.start_retry_count,
+        ] {
+            let _ = counter.remove_label_values(&[id.as_str()]);
+        }
+    }
+
+    pub(crate) fn capability_labels<'a>(
+        id: &'a SubgraphDeploymentId,
+        capabilities: &NodeCapabilities,
+    ) -> [&'a str; 3] {
+        let label = |required: bool| if required { "true" } else { "false" };
+        [
+            id.as_str(),
+            label(capabilities.archive),
+            label(capabilities.traces),
+        ]
+    }
+}
+
+pub(crate) enum TriggerType {
+    Event,
+    Call,
+    Block,
+}
+
+impl From<&EthereumTrigger> for TriggerType {
+    fn from(trigger: &EthereumTrigger) -> Self {
+        match trigger {
+            EthereumTrigger::Log(_) => TriggerType::Event,
+            EthereumTrigger::Call(_) => TriggerType::Call,
+            EthereumTrigger::Block(..) => TriggerType::Block,
+        }
+    }
+}
+
+impl TriggerType {
+    pub(crate) fn label_value(&self) -> &str {
+        match self {
+            TriggerType::Event => "event",
+            TriggerType::Call => "call",
+            TriggerType::Block => "block",
+        }
+    }
+}
+
+pub(crate) struct SubgraphInstanceMetrics {
+    pub block_trigger_count: Box<Histogram>,
+    pub block_processing_duration: Box<Histogram>,
+    pub block_ops_transaction_duration: Box<Histogram>,
+    pub entity_cache_evicted_count: Box<Histogram>,
+    pub entity_cache_evicted_weight: Box<Histogram>,
+    pub transact_block_operations_retries: Box<Counter>,
+    pub ipfs_retries: Box<Counter>,
+    pub entity_cache_mismatches: Box<Counter>,
+    pub store_reads_per_block: Box<Histogram>,
+    pub large_block_count: Box<Counter>,
+    pub head_lag: Box<Gauge>,
+
+    /// Set to 1 while the deployment is considered stalled, see
+    /// `stall_window`.
+    pub stalled: Box<Gauge>,
+
+    /// The number of data sources of the deployment, including dynamic
+    /// ones; updated whenever data sources are created or reverted.
+    pub data_source_count: Box<Gauge>,
+    pub entity_cache_warmup_duration: Box<Gauge>,
+    pub create_data_sources_duration: Box<Histogram>,
+    pub persist_data_sources_duration: Box<Histogram>,
+    pub late_commit_count: Box<Counter>,
+
+    /// Set to 1 while the deployment waits for the store to become
+    /// writable again.
+    pub store_read_only: Box<Gauge>,
+
+    /// Counts the blocks for which the read-through store was bypassed
+    /// because it did not have the entities of the last processed block.
+    pub read_through_bypass_count: Box<Counter>,
+
+    /// Counts the triggers that were dropped because the block stream
+    /// yielded them more than once for a block.
+    pub duplicate_trigger_count: Box<Counter>,
+
+    /// Counts the triggers that the trigger filter hook dropped.
+    pub filtered_trigger_count: Box<Counter>,
+
+    /// The database connections that the indexing thread of the deployment
+    /// checked out, and how long it waited for them.
+    pub connection_checkout_count: Box<Counter>,
+    pub connection_wait_duration: Box<Histogram>,
+
+    /// Measures how long it takes to get going again after the block
+    /// stream was torn down for a restart.
+    pub restart_duration: Box<Histogram>,
+
+    /// Counts the blocks in which handlers failed deterministically, by the
+    /// category of the errors.
+    pub error_category_count: Box<CounterVec>,
+
+    /// Counts blocks by whether they were written in a store transaction or
+    /// their pointer write was put off because they changed nothing.
+    pub block_commit_count: Box<CounterVec>,
+
+    trigger_processing_duration: Box<HistogramVec>,
+    pub(crate) restart_count: Box<CounterVec>,
+    pub(crate) filter_size: Box<GaugeVec>,
+}
+
+/// The value of the `deployment` label, and of all deployment labels, of
+/// the metrics that deployments without their own metrics share.
+const OTHER_DEPLOYMENTS: &str = "other";
+
+/// Whether the deployment `id` gets its own `SubgraphInstanceMetrics`,
+/// since every deployment adds many time series. The deployments in
+/// `metrics_deployments` always get their own metrics. Of the other
+/// deployments that are assigned to the same node, the first
+/// `metrics_deployment_limit` in the order of their IPFS hashes get their
+/// own metrics, so that the selection does not depend on the order in which
+/// deployments start. It is made when a deployment starts and kept until it
+/// stops, even if the assignments of the node change in the meantime.
+pub(crate) fn has_own_metrics<S: SubgraphStore>(
+    store: &S,
+    config: &SubgraphInstanceManagerConfig,
+    id: &SubgraphDeploymentId,
+) -> Result<bool, StoreError> {
+    if config.metrics_deployment_limit == 0 || config.metrics_deployments.contains(id.as_str()) {
+        return Ok(true);
+    }
+
+    let node = match store.assigned_node(id)? {
+        Some(node) => node,
+        None => return Ok(false),
+    };
+    let mut assigned: Vec<_> = store
+        .assignments(&node)?
+        .into_iter()
+        .filter(|other| !config.metrics_deployments.contains(other.as_str()))
+        .collect();
+    assigned.sort();
+    Ok(assigned
+        .iter()
+        .take(config.metrics_deployment_limit)
+        .any(|other| other == id))
+}
+
+/// Hands out the `SubgraphInstanceMetrics` of deployments. Deployments
+/// without their own metrics, see `has_own_metrics`, share metrics until
+/// they are stopped; gauges in them show the value that one of these
+/// deployments set last. Metrics are counted per deployment so that an
+/// instance that is restarted before the previous one released its metrics
+/// shares them with that instance instead of registering them again.
+#[derive(Default)]
+pub(crate) struct MetricsCardinality {
+    state: Mutex<MetricsCardinalityState>,
+}
+
+#[derive(Default)]
+struct MetricsCardinalityState {
+    /// The metrics of each deployment that has its own, and how many
+    /// instances of the deployment use them
+    individual: HashMap<SubgraphDeploymentId, (Arc<SubgraphInstanceMetrics>, usize)>,
+    other: Option<Arc<SubgraphInstanceMetrics>>,
+    /// How many instances of each deployment use the shared metrics
+    other_deployments: HashMap<SubgraphDeploymentId, usize>,
+}
+
+impl MetricsCardinality {
+    pub(crate) fn acquire<M: MetricsRegistry>(
+        &self,
+        registry: Arc<M>,
+        id: &SubgraphDeploymentId,
+        labels: &DeploymentLabels,
+        own_metrics: bool,
+    ) -> Arc<SubgraphInstanceMetrics> {
+        let mut state = self.state.lock().unwrap();
+        if own_metrics {
+            let (metrics, users) = state.individual.entry(id.clone()).or_insert_with(|| {
+                (
+                    Arc::new(SubgraphInstanceMetrics::new(registry, id.as_str(), labels)),
+                    0,
+                )
+            });
+            *users += 1;
+            return metrics.cheap_clone();
+        }
+
+        *state.other_deployments.entry(id.clone()).or_default() += 1;
+        state
+            .other
+            .get_or_insert_with(|| {
+                // Keep the label names the same for all deployments
+                let labels = DeploymentLabels::new(
+                    labels
+                        .to_metric_labels()
+                        .into_iter()
+                        .map(|(name, _)| (name, OTHER_DEPLOYMENTS.to_string())),
+                )
+                .expect("the names of valid labels are valid");
+                Arc::new(SubgraphInstanceMetrics::new(
+                    registry,
+                    OTHER_DEPLOYMENTS,
+                    &labels,
+                ))
+            })
+            .cheap_clone()
+    }
+
+    /// Release `metrics` that `acquire` handed out for the deployment `id`,
+    /// and unregister them once no instance of the deployment, or for the
+    /// shared metrics no deployment, uses them anymore.
+    pub(crate) fn release<M: MetricsRegistry>(
+        &self,
+        registry: Arc<M>,
+        id: &SubgraphDeploymentId,
+        metrics: &SubgraphInstanceMetrics,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        match state.individual.get_mut(id) {
+            Some((own, users)) if std::ptr::eq(&**own, metrics) => {
+                *users -= 1;
+                if *users == 0 {
+                    own.unregister(registry);
+                    state.individual.remove(id);
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        if let Some(users) = state.other_deployments.get_mut(id) {
+            *users -= 1;
+            if *users == 0 {
+                state.other_deployments.remove(id);
+            }
+        }
+        if state.other_deployments.is_empty() {
+            if let Some(other) = state.other.take() {
+                other.unregister(registry);
+            }
+        }
+    }
+}
+
+impl SubgraphInstanceMetrics {
+    pub fn new(
+        registry: Arc<impl MetricsRegistry>,
+        subgraph_hash: &str,
+        labels: &DeploymentLabels,
+    ) -> Self {
+        let labels = labels.to_metric_labels();
+        let block_trigger_count = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_block_trigger_count",
+                "Measures the number of triggers in each block for a subgraph deployment",
+                subgraph_hash,
+                &labels,
+                vec![1.0, 5.0, 10.0, 20.0, 50.0],
+            )
+            .expect("failed to create `deployment_block_trigger_count` histogram");
+        let trigger_processing_duration = registry
+            .new_deployment_histogram_vec_with_labels(
+                "deployment_trigger_processing_duration",
+                "Measures duration of trigger processing for a subgraph deployment",
+                subgraph_hash,
+                &labels,
+                vec![String::from("trigger_type")],
+                vec![0.01, 0.05, 0.1, 0.5, 1.5, 5.0, 10.0, 30.0, 120.0],
+            )
+            .expect("failed to create `deployment_trigger_processing_duration` histogram");
+        let block_processing_duration = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_block_processing_duration",
+                "Measures duration of block processing for a subgraph deployment",
+                subgraph_hash,
+                &labels,
+                vec![0.05, 0.2, 0.7, 1.5, 4.0, 10.0, 60.0, 120.0, 240.0],
+            )
+            .expect("failed to create `deployment_block_processing_duration` histogram");
+        let block_ops_transaction_duration = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_transact_block_operations_duration",
+                "Measures duration of commiting all the entity operations in a block and updating the subgraph pointer",
+                subgraph_hash,
+                &labels,
+                vec![0.01, 0.05, 0.1, 0.3, 0.7, 2.0],
+            )
+            .expect("failed to create `deployment_transact_block_operations_duration_{}");
+        let entity_cache_evicted_count = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_entity_cache_evicted_count",
+                "Measures the number of entries evicted from the entity cache after each block",
+                subgraph_hash,
+                &labels,
+                vec![1.0, 10.0, 100.0, 1000.0, 10000.0, 100000.0],
+            )
+            .expect("failed to create `deployment_entity_cache_evicted_count` histogram");
+        let entity_cache_evicted_weight = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_entity_cache_evicted_weight",
+                "Measures the weight in bytes of the entries evicted from the entity cache after each block",
+                subgraph_hash,
+                &labels,
+                vec![1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9],
+            )
+            .expect("failed to create `deployment_entity_cache_evicted_weight` histogram");
+        let transact_block_operations_retries = registry
+            .new_deployment_counter_with_labels(
+                "deployment_transact_block_operations_retries",
+                "Counts how often transacting the entity operations of a block was retried",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_transact_block_operations_retries` counter");
+        let ipfs_retries = registry
+            .new_deployment_counter_with_labels(
+                "deployment_ipfs_retries",
+                "Counts how often IPFS requests were retried while starting a subgraph deployment",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_ipfs_retries` counter");
+        let entity_cache_mismatches = registry
+            .new_deployment_counter_with_labels(
+                "deployment_entity_cache_mismatches",
+                "Counts entity cache entries that differed from the store when checked",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_entity_cache_mismatches` counter");
+        let store_reads_per_block = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_store_reads_per_block",
+                "Measures the number of entities read from the store rather than the entity cache in each block",
+                subgraph_hash,
+                &labels,
+                vec![0.0, 1.0, 10.0, 100.0, 1000.0, 10000.0],
+            )
+            .expect("failed to create `deployment_store_reads_per_block` histogram");
+        let large_block_count = registry
+            .new_deployment_counter_with_labels(
+                "deployment_large_block_count",
+                "Counts the blocks with more triggers than GRAPH_TRIGGER_COUNT_WARN_THRESHOLD",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_large_block_count` counter");
+        let head_lag = registry
+            .new_deployment_gauge_with_labels(
+                "deployment_head_lag_seconds",
+                "The age of the last block processed by the subgraph, based on its timestamp",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_head_lag_seconds` gauge");
+        let stalled = registry
+            .new_deployment_gauge_with_labels(
+                "deployment_stalled",
+                "Set to 1 while a syncing subgraph deployment does not get closer to the chain head",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_stalled` gauge");
+        let data_source_count = registry
+            .new_deployment_gauge_with_labels(
+                "deployment_data_source_count",
+                "The number of data sources of a subgraph deployment, including dynamic ones",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_data_source_count` gauge");
+        let entity_cache_warmup_duration = registry
+            .new_deployment_gauge_with_labels(
+                "deployment_entity_cache_warmup_duration",
+                "How long loading entities into the entity cache took when the subgraph started",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_entity_cache_warmup_duration` gauge");
+        let create_data_sources_duration = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_create_data_sources_duration",
+                "Measures the time spent instantiating the runtime hosts of the data sources created in a block",
+                subgraph_hash,
+                &labels,
+                vec![0.001, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0],
+            )
+            .expect("failed to create `deployment_create_data_sources_duration` histogram");
+        let persist_data_sources_duration = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_persist_data_sources_duration",
+                "Measures the time spent persisting the data sources created in a block and merging their filters",
+                subgraph_hash,
+                &labels,
+                vec![0.001, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0],
+            )
+            .expect("failed to create `deployment_persist_data_sources_duration` histogram");
+        let late_commit_count = registry
+            .new_deployment_counter_with_labels(
+                "deployment_late_commit_count",
+                "Counts the blocks that were committed although the block stream was canceled while they were written",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_late_commit_count` counter");
+        let store_read_only = registry
+            .new_deployment_gauge_with_labels(
+                "deployment_store_read_only",
+                "Whether the subgraph is waiting for the store to become writable again",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_store_read_only` gauge");
+        let read_through_bypass_count = registry
+            .new_deployment_counter_with_labels(
+                "deployment_read_through_bypass_count",
+                "Counts the blocks for which the read-through store was bypassed because it was not up to date",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_read_through_bypass_count` counter");
+        let duplicate_trigger_count = registry
+            .new_deployment_counter_with_labels(
+                "deployment_duplicate_trigger_count",
+                "Counts the duplicate triggers that were dropped before processing them",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_duplicate_trigger_count` counter");
+        let filtered_trigger_count = registry
+            .new_deployment_counter_with_labels(
+                "deployment_filtered_trigger_count",
+                "Counts the triggers that the trigger filter hook dropped before processing them",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_filtered_trigger_count` counter");
+        let connection_checkout_count = registry
+            .new_deployment_counter_with_labels(
+                "deployment_connection_checkout_count",
+                "Counts the database connections that a deployment checked out while indexing",
+                subgraph_hash,
+                &labels,
+            )
+            .expect("failed to create `deployment_connection_checkout_count` counter");
+        let connection_wait_duration = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_connection_wait_duration",
+                "Measures the time a deployment waited for database connections while indexing",
+                subgraph_hash,
+                &labels,
+                vec![0.001, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0],
+            )
+            .expect("failed to create `deployment_connection_wait_duration` histogram");
+        let restart_duration = registry
+            .new_deployment_histogram_with_labels(
+                "deployment_restart_duration",
+                "Measures the time from a subgraph deployment stopping its block stream for a restart until the new block stream is ready",
+                subgraph_hash,
+                &labels,
+                vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0],
+            )
+            .expect("failed to create `deployment_restart_duration` histogram");
+        let error_category_count = registry
+            .new_deployment_counter_vec_with_labels(
+                "deployment_error_category_count",
+                "Counts the blocks with deterministic handler errors of a subgraph deployment by error category",
+                subgraph_hash,
+                &labels,
+                vec![String::from("category")],
+            )
+            .expect("failed to create `deployment_error_category_count` counter");
+        let restart_count = registry
+            .new_deployment_counter_vec_with_labels(
+                "deployment_restart_total",
+                "Counts the restarts of the block stream of a subgraph deployment by reason",
+                subgraph_hash,
+                &labels,
+                vec![String::from("reason")],
+            )
+            .expect("failed to create `deployment_restart_total` counter");
+        let filter_size = registry
+            .new_deployment_gauge_vec_with_labels(
+                "deployment_filter_size",
+                "The number of entries in the log, call and block filters of a subgraph deployment",
+                subgraph_hash,
+                &labels,
+                vec![String::from("filter")],
+            )
+            .expect("failed to create `deployment_filter_size` gauge");
+        let block_commit_count = registry
+            .new_deployment_counter_vec_with_labels(
+                "deployment_block_commit_count",
+                "Counts the processed blocks of a subgraph deployment by whether they were written in a store transaction or deferred, which saves a transaction",
+                subgraph_hash,
+                &labels,
+                vec![String::from("path")],
+            )
+            .expect("failed to create `deployment_block_commit_count` counter");
+
+        Self {
+            block_trigger_count,
+            block_processing_duration,
+            trigger_processing_duration,
+            block_ops_transaction_duration,
+            entity_cache_evicted_count,
+            entity_cache_evicted_weight,
+            transact_block_operations_retries,
+            ipfs_retries,
+            entity_cache_mismatches,
+            store_reads_per_block,
+            large_block_count,
+            head_lag,
+            stalled,
+            data_source_count,
+            entity_cache_warmup_duration,
+            create_data_sources_duration,
+            persist_data_sources_duration,
+            late_commit_count,
+            store_read_only,
+            read_through_bypass_count,
+            duplicate_trigger_count,
+            filtered_trigger_count,
+            connection_checkout_count,
+            connection_wait_duration,
+            restart_duration,
+            error_category_count,
+            block_commit_count,
+            restart_count,
+            filter_size,
+        }
+    }
+
+    pub fn observe_trigger_processing_duration(&self, duration: f64, trigger: TriggerType) {
+        self.trigger_processing_duration
+            .with_label_values(vec![trigger.label_value()].as_slice())
+            .observe(duration);
+    }
+
+    /// The current values of all metrics, for pushing them to a sink.
+    pub(crate) fn samples(&self) -> Vec<MetricSample> {
+        metric_samples(&[
+            self.block_processing_duration.as_ref(),
+            self.block_trigger_count.as_ref(),
+            self.trigger_processing_duration.as_ref(),
+            self.block_ops_transaction_duration.as_ref(),
+            self.entity_cache_evicted_count.as_ref(),
+            self.entity_cache_evicted_weight.as_ref(),
+            self.transact_block_operations_retries.as_ref(),
+            self.ipfs_retries.as_ref(),
+            self.entity_cache_mismatches.as_ref(),
+            self.store_reads_per_block.as_ref(),
+            self.large_block_count.as_ref(),
+            self.head_lag.as_ref(),
+            self.stalled.as_ref(),
+            self.data_source_count.as_ref(),
+            self.entity_cache_warmup_duration.as_ref(),
+            self.create_data_sources_duration.as_ref(),
+            self.persist_data_sources_duration.as_ref(),
+            self.late_commit_count.as_ref(),
+            self.store_read_only.as_ref(),
+            self.read_through_bypass_count.as_ref(),
+            self.duplicate_trigger_count.as_ref(),
+            self.filtered_trigger_count.as_ref(),
+            self.connection_checkout_count.as_ref(),
+            self.connection_wait_duration.as_ref(),
+            self.restart_duration.as_ref(),
+            self.error_category_count.as_ref(),
+            self.block_commit_count.as_ref(),
+            self.restart_count.as_ref(),
+            self.filter_size.as_ref(),
+        ])
+    }
+
+    pub fn unregister<M: MetricsRegistry>(&self, registry: Arc<M>) {
+        registry.unregister(self.block_processing_duration.clone());
+        registry.unregister(self.block_trigger_count.clone());
+        registry.unregister(self.trigger_processing_duration.clone());
+        registry.unregister(self.block_ops_transaction_duration.clone());
+        registry.unregister(self.entity_cache_evicted_count.clone());
+        registry.unregister(self.entity_cache_evicted_weight.clone());
+        registry.unregister(self.transact_block_operations_retries.clone());
+        registry.unregister(self.ipfs_retries.clone());
+        registry.unregister(self.entity_cache_mismatches.clone());
+        registry.unregister(self.store_reads_per_block.clone());
+        registry.unregister(self.large_block_count.clone());
+        registry.unregister(self.head_lag.clone());
+        registry.unregister(self.stalled.clone());
+        registry.unregister(self.data_source_count.clone());
+        registry.unregister(self.entity_cache_warmup_duration.clone());
+        registry.unregister(self.create_data_sources_duration.clone());
+        registry.unregister(self.persist_data_sources_duration.clone());
+        registry.unregister(self.late_commit_count.clone());
+        registry.unregister(self.store_read_only.clone());
+        registry.unregister(self.read_through_bypass_count.clone());
+        registry.unregister(self.duplicate_trigger_count.clone());
+        registry.unregister(self.filtered_trigger_count.clone());
+        registry.unregister(self.connection_checkout_count.clone());
+        registry.unregister(self.connection_wait_duration.clone());
+        registry.unregister(self.restart_duration.clone());
+        registry.unregister(self.error_category_count.clone());
+        registry.unregister(self.block_commit_count.clone());
+        registry.unregister(self.restart_count.clone());
+        registry.unregister(self.filter_size.clone());
+    }
+}
+
+impl ConnectionWaitRecorder for SubgraphInstanceMetrics {
+    fn record(&self, wait: Duration) {
+        self.connection_checkout_count.inc();
+        self.connection_wait_duration.observe(wait.as_secs_f64());
+    }
+}
+
+/// A `MetricsRegistry` that registers metrics nowhere, for metrics that
+/// must not show up next to those of the deployment, like those of dry
+/// runs of injected triggers.
+pub(crate) struct UnregisteredMetrics;
+
+impl MetricsRegistry for UnregisteredMetrics {
+    fn register(&self, _: &str, _: Box<dyn Collector>) {}
+
+    fn unregister(&self, _: Box<dyn Collector>) {}
+
+    fn global_counter(
+        &self,
+        name: &str,
+        help: &str,
+        const_labels: HashMap<String, String>,
+    ) -> Result<Counter, PrometheusError> {
+        Counter::with_opts(Opts::new(name, help).const_labels(const_labels))
+    }
+
+    fn global_gauge(
+        &self,
+        name: &str,
+        help: &str,
+        const_labels: HashMap<String, String>,
+    ) -> Result<Gauge, PrometheusError> {
+        Gauge::with_opts(Opts::new(name, help).const_labels(const_labels))
+    }
+}
@@ -1,10 +1,32 @@
+mod adapter_selector;
+mod breakpoint;
+mod circuit_breaker;
 mod instance;
 mod instance_manager;
+#[cfg(feature = "kafka")]
+mod kafka;
 mod loader;
+mod metrics;
 mod provider;
+mod publisher;
 mod registrar;
+mod verification;
 
+pub use self::circuit_breaker::CircuitBreakerConfig;
 pub use self::instance::SubgraphInstance;
-pub use self::instance_manager::SubgraphInstanceManager;
+pub use self::instance_manager::{
+    AggregateThroughput, BackfillProgress, BlockCommittedHook, CallFilterDump, CommittedBlock,
+    DataSourceDump, DataSourceOrder, DeploymentHealth, DuplicateDataSourcePolicy,
+    EntityCacheCompaction, EntityCacheWarmup, EntityModificationHook, FiltersDump,
+    IndexingContextDump, InjectTriggerError, LogFilterDump, PoiBundle, PoiBundleEntry,
+    PoiBundleSigner, ReadThroughStore, ReindexError, RetryFailedError, RevertBlockFallback,
+    SubgraphInstanceManager, SubgraphInstanceManagerConfig, TriggerCounts, TriggerFilterHook,
+    TriggerInjectionOutcome, WarmupKeySource,
+};
+#[cfg(feature = "kafka")]
+pub use self::kafka::{KafkaModificationsSink, KafkaOffset, KafkaTopics};
+pub use self::metrics::MetricsPush;
 pub use self::provider::SubgraphAssignmentProvider;
+pub use self::publisher::{ModificationsPublisher, ReorgNotifier};
 pub use self::registrar::SubgraphRegistrar;
+pub use self::verification::{DeploymentDifference, Divergence, EntityDivergence, Verification};
@@ -3,6 +3,7 @@ use std::sync::Mutex;
 
 use async_trait::async_trait;
 
+use graph::components::subgraph::DeploymentLabels;
 use graph::prelude::{SubgraphAssignmentProvider as SubgraphAssignmentProviderTrait, *};
 
 pub struct SubgraphAssignmentProvider<L, I> {
@@ -58,7 +59,7 @@ where
 
         self.instance_manager
             .cheap_clone()
-            .start_subgraph(id, raw)
+            .start_subgraph(id, raw, DeploymentLabels::default())
             .await;
 
         Ok(())
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, Weak};
+
+use graph::components::subgraph::{
+    ModificationsEvent, ModificationsSink, ReorgEvent, ReorgListener,
+};
+use graph::prelude::*;
+
+/// Publishes the changes that deployments write to the store to a sink.
+/// Each deployment has its own queue and thread, so that a sink that keeps
+/// failing for one deployment never holds up the others. Queueing an event
+/// never waits and never drops it, so that publishing can't slow indexing
+/// down and consumers can replay every event: events are retried with
+/// backoff until the sink accepts them, and a deployment whose sink falls
+/// more than `backlog_warn_size` events behind is logged. The queue and
+/// thread of a deployment go away once its instance stops and the events
+/// in the queue are published.
+#[derive(Clone)]
+pub struct ModificationsPublisher {
+    sink: Arc<dyn ModificationsSink>,
+    backlog_warn_size: usize,
+    threads: Arc<Mutex<HashMap<SubgraphDeploymentId, PublisherThread>>>,
+    logger: Logger,
+}
+
+/// The thread that publishes the events of a deployment from its queue.
+struct PublisherThread {
+    queue: Weak<ModificationsQueue>,
+    handle: std::thread::JoinHandle<()>,
+}
+
+impl ModificationsPublisher {
+    /// Start publishing to `sink`, and log deployments with more than
+    /// `backlog_warn_size` events that were not published yet.
+    pub fn new(logger: Logger, sink: Arc<dyn ModificationsSink>, backlog_warn_size: usize) -> Self {
+        ModificationsPublisher {
+            sink,
+            backlog_warn_size,
+            threads: Arc::new(Mutex::new(HashMap::new())),
+            logger,
+        }
+    }
+
+    /// The queue of `deployment`, which the instance of the deployment keeps
+    /// while it runs. Instances of the same deployment that run at the same
+    /// time share the queue, and a new queue only publishes its events once
+    /// the events of the previous queue of the deployment are published, so
+    /// that the events of a deployment stay in order across restarts.
+    pub(crate) fn queue(&self, deployment: &SubgraphDeploymentId) -> Arc<ModificationsQueue> {
+        let mut threads = self.threads.lock().unwrap();
+        let previous = match threads.remove(deployment) {
+            Some(thread) => match thread.queue.upgrade() {
+                Some(queue) => {
+                    threads.insert(deployment.clone(), thread);
+                    return queue;
+                }
+                None => Some(thread.handle),
+            },
+            None => None,
+        };
+
+        let (sender, receiver) = channel::<ModificationsEvent>();
+        let logger = self.logger.new(o!("deployment" => deployment.to_string()));
+        let queue = Arc::new(ModificationsQueue {
+            sender,
+            backlog: Arc::new(AtomicUsize::new(0)),
+            backlog_warn_size: self.backlog_warn_size,
+            logger: logger.clone(),
+        });
+        let own_queue = Arc::downgrade(&queue);
+        let backlog = queue.backlog.cheap_clone();
+        let sink = self.sink.cheap_clone();
+        let all_threads = self.threads.cheap_clone();
+        let thread_deployment = deployment.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("modifications-{}", deployment))
+            .spawn(move || {
+                if let Some(previous) = previous {
+                    let _ = previous.join();
+                }
+                for event in receiver {
+                    let mut backoff = Duration::from_secs(1);
+                    while let Err(e) = sink.publish(&event) {
+                        warn!(
+                            logger,
+                            "Failed to publish entity modifications, retrying";
+                            "block_number" => event.block_number(),
+                            "error" => format!("{:#}", e),
+                            "backoff_ms" => backoff.as_millis() as u64,
+                        );
+                        std::thread::sleep(backoff);
+                        backoff = (backoff * 2).min(Duration::from_secs(60));
+                    }
+                    backlog.fetch_sub(1, Ordering::SeqCst);
+                }
+
+                // The deployment may have a new queue and thread by now
+                let mut threads = all_threads.lock().unwrap();
+                if threads
+                    .get(&thread_deployment)
+                    .map_or(false, |thread| thread.queue.ptr_eq(&own_queue))
+                {
+                    threads.remove(&thread_deployment);
+                }
+            })
+            .expect("failed to spawn the entity modifications publisher");
+        threads.insert(
+            deployment.clone(),
+            PublisherThread {
+                queue: Arc::downgrade(&queue),
+                handle,
+            },
+        );
+        queue
+    }
+}
+
+/// The events of a deployment that were not published yet. The thread that
+/// publishes them stops once the queue is dropped and it is empty.
+pub(crate) struct ModificationsQueue {
+    sender: Sender<ModificationsEvent>,
+    backlog: Arc<AtomicUsize>,
+    backlog_warn_size: usize,
+    logger: Logger,
+}
+
+impl ModificationsQueue {
+    /// Queue `event` for publishing without waiting.
+    pub(crate) fn publish(&self, event: ModificationsEvent) {
+        let block_number = event.block_number();
+        let backlog = self.backlog.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.sender.send(event).is_err() {
+            // The publisher thread panicked
+            self.backlog.fetch_sub(1, Ordering::SeqCst);
+            error!(
+                self.logger,
+                "Dropping entity modifications since their publisher is gone";
+                "block_number" => block_number,
+            );
+            return;
+        }
+        if backlog == self.backlog_warn_size + 1 {
+            warn!(
+                self.logger,
+                "Entity modifications are published more slowly than they are written";
+                "backlog" => backlog,
+                "block_number" => block_number,
+            );
+        }
+    }
+}
+
+impl fmt::Debug for ModificationsPublisher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ModificationsPublisher")
+    }
+}
+
+/// Tells a listener when deployments go through a reorg, on a dedicated
+/// thread so that a slow listener never holds up indexing. Events that
+/// don't fit into the queue, and events the listener fails to handle, are
+/// dropped and logged.
+#[derive(Clone)]
+pub struct ReorgNotifier {
+    sender: SyncSender<ReorgEvent>,
+    logger: Logger,
+}
+
+impl ReorgNotifier {
+    /// Start notifying `listener`, queueing at most `queue_size` events
+    /// that it did not handle yet.
+    pub fn new(logger: Logger, listener: Arc<dyn ReorgListener>, queue_size: usize) -> Self {
+        let (sender, receiver) = sync_channel::<ReorgEvent>(queue_size);
+        let thread_logger = logger.clone();
+        std::thread::Builder::new()
+            .name("reorg-notifier".to_string())
+            .spawn(move || {
+                for event in receiver {
+                    if let Err(e) = listener.notify(&event) {
+                        warn!(
+                            thread_logger,
+                            "Failed to notify the reorg listener";
+                            "deployment" => event.deployment().as_str(),
+                            "block_number" => event.block_ptr().number,
+                            "error" => format!("{:#}", e),
+                        );
+                    }
+                }
+            })
+            .expect("failed to spawn the reorg notifier");
+        ReorgNotifier { sender, logger }
+    }
+
+    /// Queue `event` without waiting for room in the queue.
+    pub(crate) fn notify(&self, event: ReorgEvent) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) | Err(TrySendError::Disconnected(event)) => error!(
+                self.logger,
+                "Dropping reorg event since it can't be queued for the listener";
+                "deployment" => event.deployment().as_str(),
+                "block_number" => event.block_ptr().number,
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for ReorgNotifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReorgNotifier")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use web3::types::H256;
+
+    fn block_ptr(number: u64) -> EthereumBlockPointer {
+        EthereumBlockPointer::from((H256::from_low_u64_be(number), number))
+    }
+
+    struct RecordingSink(Mutex<Vec<BlockNumber>>);
+
+    impl ModificationsSink for RecordingSink {
+        fn publish(&self, event: &ModificationsEvent) -> Result<(), Error> {
+            self.0.lock().unwrap().push(event.block_number());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn modifications_publisher_stops_with_deployment() {
+        let logger = Logger::root(slog::Discard, o!());
+        let sink = Arc::new(RecordingSink(Mutex::new(vec![])));
+        let publisher = ModificationsPublisher::new(logger, sink.clone(), 10);
+        let deployment = SubgraphDeploymentId::new("QmPublisher").unwrap();
+        let revert = |number| ModificationsEvent::Revert {
+            deployment: deployment.clone(),
+            block_ptr: block_ptr(number),
+        };
+
+        let queue = publisher.queue(&deployment);
+        // Instances that run at the same time share the queue
+        assert!(Arc::ptr_eq(&queue, &publisher.queue(&deployment)));
+        for number in 1..=3 {
+            queue.publish(revert(number));
+        }
+        drop(queue);
+
+        // The events of the next instance come after the earlier ones even
+        // if they are not all published yet
+        let queue = publisher.queue(&deployment);
+        queue.publish(revert(4));
+        drop(queue);
+
+        // Both threads stop once their queues are dropped and published
+        let start = Instant::now();
+        while !publisher.threads.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(vec![1, 2, 3, 4], *sink.0.lock().unwrap());
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use graph::components::store::{get_entity_at_block, EntityType};
+use graph::data::graphql::DocumentExt;
+use graph::data::schema::SCHEMA_TYPE_NAME;
+use graph::data::subgraph::schema::POI_OBJECT;
+use graph::prelude::*;
+
+/// The state of a verify-only run of a deployment, which processes the
+/// blocks that the deployment has already indexed again and compares the
+/// result with what is in the store, without writing to the store. See
+/// `SubgraphInstanceManager::set_verify_only`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verification {
+    /// Still processing blocks. All blocks up to and including this one
+    /// produced the data in the store.
+    Running(Option<EthereumBlockPointer>),
+
+    /// All blocks up to the block the deployment had indexed when the run
+    /// started produced the data in the store. The pointer is the last block
+    /// that was processed again.
+    Matched(Option<EthereumBlockPointer>),
+
+    /// Processing a block produced data that differs from the store. Later
+    /// blocks were not processed.
+    Diverged(Divergence),
+
+    /// The run stopped before it could tell whether the data matches, for
+    /// example because of a reorg or an error.
+    Inconclusive(String),
+}
+
+/// The first block for which a verify-only run produced data that differs
+/// from the store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub block_ptr: EthereumBlockPointer,
+
+    /// The entities that differ, except for proof of indexing entities.
+    pub entities: Vec<EntityDivergence>,
+
+    /// The causality regions whose proof of indexing differs.
+    pub poi_regions: Vec<String>,
+}
+
+/// An entity as it is in the store at a block and as processing the block
+/// again computed it. `None` means that the entity does not exist.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityDivergence {
+    pub key: EntityKey,
+    pub stored: Option<Entity>,
+    pub computed: Option<Entity>,
+}
+
+/// The first entity that differs between two deployments at a block; see
+/// `SubgraphInstanceManager::compare_deployments`. `None` means that the
+/// entity does not exist in that deployment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeploymentDifference {
+    pub entity_type: EntityType,
+    pub entity_id: String,
+    pub left: Option<Entity>,
+    pub right: Option<Entity>,
+}
+
+/// Record the outcome of a verify-only run.
+pub(crate) fn finish_verification(
+    logger: &Logger,
+    verification: &Mutex<Verification>,
+    outcome: Verification,
+) {
+    match &outcome {
+        Verification::Running(_) => {}
+        Verification::Matched(block_ptr) => info!(
+            logger,
+            "Verified the data of the subgraph, it matches the store";
+            "last_block_number" => block_ptr.as_ref().map(|ptr| ptr.number),
+        ),
+        Verification::Diverged(divergence) => error!(
+            logger,
+            "Verified the data of the subgraph, it differs from the store";
+            "block_number" => divergence.block_ptr.number,
+            "block_hash" => format!("{}", divergence.block_ptr.hash),
+            "entities" => divergence.entities.len(),
+            "poi_regions" => divergence.poi_regions.join(", "),
+        ),
+        Verification::Inconclusive(reason) => warn!(
+            logger,
+            "Could not verify the data of the subgraph";
+            "reason" => reason,
+        ),
+    }
+    *verification.lock().unwrap() = outcome;
+}
+
+/// The attributes of `entity` that are not `null`, for comparing entities
+/// regardless of whether attributes are `null` or not set.
+fn non_null(entity: &Option<Entity>) -> Option<HashMap<&Attribute, &Value>> {
+    entity.as_ref().map(|entity| {
+        entity
+            .iter()
+            .filter(|(_, value)| **value != Value::Null)
+            .collect()
+    })
+}
+
+/// See `SubgraphInstanceManager::compare_deployments`.
+pub(crate) fn compare_deployments<S: SubgraphStore>(
+    store: &S,
+    left: &SubgraphDeploymentId,
+    right: &SubgraphDeploymentId,
+    block: BlockNumber,
+) -> Result<Option<DeploymentDifference>, Error> {
+    for id in &[left, right] {
+        if !store
+            .block_ptr(id)?
+            .map_or(false, |ptr| ptr.number >= block)
+        {
+            return Err(anyhow!(
+                "deployment `{}` has not reached block {} yet",
+                id,
+                block
+            ));
+        }
+    }
+
+    let entity_types = |id: &SubgraphDeploymentId| -> Result<BTreeSet<String>, Error> {
+        Ok(store
+            .input_schema(id)?
+            .document
+            .get_object_type_definitions()
+            .into_iter()
+            .map(|object_type| object_type.name.clone())
+            .filter(|name| name != SCHEMA_TYPE_NAME)
+            .collect())
+    };
+    let left_types = entity_types(left)?;
+    let right_types = entity_types(right)?;
+    if left_types != right_types {
+        let differing: Vec<_> = left_types
+            .symmetric_difference(&right_types)
+            .map(String::as_str)
+            .collect();
+        return Err(anyhow!(
+            "deployments `{}` and `{}` have different entity types: {}",
+            left,
+            right,
+            differing.join(", ")
+        ));
+    }
+
+    for entity_type in left_types {
+        let entity_type = EntityType::new(entity_type);
+
+        // Entities that differ show up when we go through `left`, and
+        // entities that only `right` has when we go through `right`
+        if let Some((entity_id, left_entity, right_entity)) =
+            first_entity_difference(store, left, right, &entity_type, block)?
+        {
+            return Ok(Some(DeploymentDifference {
+                entity_type,
+                entity_id,
+                left: left_entity,
+                right: right_entity,
+            }));
+        }
+        if let Some((entity_id, right_entity, left_entity)) =
+            first_entity_difference(store, right, left, &entity_type, block)?
+        {
+            return Ok(Some(DeploymentDifference {
+                entity_type,
+                entity_id,
+                left: left_entity,
+                right: right_entity,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Go through the entities of type `entity_type` of the deployment `from` as
+/// of `block` by id, and return the first one that `to` does not have in the
+/// same form, as it is in `from` and in `to`.
+fn first_entity_difference<S: SubgraphStore>(
+    store: &S,
+    from: &SubgraphDeploymentId,
+    to: &SubgraphDeploymentId,
+    entity_type: &EntityType,
+    block: BlockNumber,
+) -> Result<Option<(String, Option<Entity>, Option<Entity>)>, Error> {
+    const PAGE_SIZE: u32 = 1000;
+
+    let mut after: Option<String> = None;
+    loop {
+        let mut query = EntityQuery::new(
+            from.clone(),
+            block,
+            EntityCollection::All(vec![entity_type.clone()]),
+        )
+        .range(EntityRange::first(PAGE_SIZE));
+        if let Some(after) = &after {
+            query = query.filter(EntityFilter::GreaterThan(
+                "id".to_owned(),
+                Value::String(after.clone()),
+            ));
+        }
+        let page = store.find(query)?;
+        let last = match page.last() {
+            Some(entity) => entity.id()?,
+            None => return Ok(None),
+        };
+
+        // Look the entities of the page up in `to` by id, so that we don't
+        // depend on how the store orders ids
+        let ids = page
+            .iter()
+            .map(|entity| entity.id().map(Value::String))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut others = store
+            .find(
+                EntityQuery::new(
+                    to.clone(),
+                    block,
+                    EntityCollection::All(vec![entity_type.clone()]),
+                )
+                .filter(EntityFilter::In("id".to_owned(), ids))
+                .range(EntityRange::first(PAGE_SIZE)),
+            )?
+            .into_iter()
+            .map(|entity| Ok((entity.id()?, entity)))
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        for entity in page {
+            let id = entity.id()?;
+            let entity = Some(entity);
+            let other = others.remove(&id);
+            if non_null(&entity) != non_null(&other) {
+                return Ok(Some((id, entity, other)));
+            }
+        }
+        after = Some(last);
+    }
+}
+
+/// Compare the entity operations that processing the block `block_ptr`
+/// produced with the entities in the store as of that block. Returns `None`
+/// if the store has the same data. Attributes that are `null` are treated
+/// like attributes that are not set.
+pub(crate) fn compare_with_store<S: SubgraphStore>(
+    store: &S,
+    block_ptr: &EthereumBlockPointer,
+    mods: &[EntityModification],
+) -> Result<Option<Divergence>, QueryExecutionError> {
+    let mut entities = Vec::new();
+    let mut poi_regions = Vec::new();
+    for md in mods {
+        let (key, computed) = match md {
+            EntityModification::Insert { key, data }
+            | EntityModification::Overwrite { key, data } => (key, Some(data.clone())),
+            EntityModification::Remove { key } => (key, None),
+        };
+        let stored = get_entity_at_block(store, key, block_ptr.number)?.map(|mut entity| {
+            // `__typename` is for queries, the mappings don't set it
+            entity.remove("__typename");
+            entity
+        });
+
+        if non_null(&stored) == non_null(&computed) {
+            continue;
+        }
+        if key.entity_type == *POI_OBJECT {
+            poi_regions.push(key.entity_id.clone());
+        } else {
+            entities.push(EntityDivergence {
+                key: key.clone(),
+                stored,
+                computed,
+            });
+        }
+    }
+
+    if entities.is_empty() && poi_regions.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(Divergence {
+            block_ptr: block_ptr.clone(),
+            entities,
+            poi_regions,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::data::store::scalar::Bytes;
+
+    const THING_SCHEMA: &str = "type Thing @entity { id: ID!, value: String, extra: String }";
+
+    fn thing_key(deployment: &SubgraphDeploymentId, id: &str) -> EntityKey {
+        EntityKey {
+            subgraph_id: deployment.clone(),
+            entity_type: EntityType::new("Thing".to_owned()),
+            entity_id: id.to_owned(),
+        }
+    }
+
+    fn thing(id: &str, value: &str) -> Entity {
+        graph::entity! { id: id, value: value, }
+    }
+
+    /// Create `deployment` with `things` as `(id, value)`, written at the
+    /// genesis block.
+    fn create_things(deployment: &SubgraphDeploymentId, things: &[(&str, &str)]) {
+        test_store::create_test_subgraph(deployment, THING_SCHEMA);
+        let ops = things
+            .iter()
+            .map(|(id, value)| EntityOperation::Set {
+                key: thing_key(deployment, id),
+                data: thing(id, value),
+            })
+            .collect();
+        test_store::transact_entity_operations(
+            &test_store::STORE.subgraph_store(),
+            deployment.clone(),
+            test_store::GENESIS_PTR.clone(),
+            ops,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn compare_with_store_finds_divergence() {
+        let deployment = SubgraphDeploymentId::new("compareWithStore").unwrap();
+        create_things(&deployment, &[("one", "a")]);
+        let store = test_store::STORE.subgraph_store();
+        let genesis = test_store::GENESIS_PTR.clone();
+        let one = thing_key(&deployment, "one");
+        let two = thing_key(&deployment, "two");
+
+        // Attributes that are `null` count as not set
+        let mut same = thing("one", "a");
+        same.set("extra", Value::Null);
+        let mods = vec![EntityModification::Overwrite {
+            key: one.clone(),
+            data: same,
+        }];
+        assert_eq!(
+            None,
+            compare_with_store(store.as_ref(), &genesis, &mods).unwrap()
+        );
+
+        let poi = EntityKey {
+            subgraph_id: deployment.clone(),
+            entity_type: POI_OBJECT.to_owned(),
+            entity_id: "region".to_owned(),
+        };
+        let mods = vec![
+            EntityModification::Overwrite {
+                key: one.clone(),
+                data: thing("one", "b"),
+            },
+            EntityModification::Insert {
+                key: two.clone(),
+                data: thing("two", "c"),
+            },
+            EntityModification::Insert {
+                key: poi,
+                data: graph::entity! {
+                    id: "region",
+                    digest: Value::Bytes(Bytes::from(&[1u8][..])),
+                },
+            },
+        ];
+        let divergence = compare_with_store(store.as_ref(), &genesis, &mods)
+            .unwrap()
+            .unwrap();
+        assert_eq!(genesis, divergence.block_ptr);
+        assert_eq!(vec!["region".to_owned()], divergence.poi_regions);
+        assert_eq!(
+            vec![
+                EntityDivergence {
+                    key: one.clone(),
+                    stored: Some(thing("one", "a")),
+                    computed: Some(thing("one", "b")),
+                },
+                EntityDivergence {
+                    key: two,
+                    stored: None,
+                    computed: Some(thing("two", "c")),
+                },
+            ],
+            divergence.entities
+        );
+
+        let mods = vec![EntityModification::Remove { key: one.clone() }];
+        let divergence = compare_with_store(store.as_ref(), &genesis, &mods)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vec![EntityDivergence {
+                key: one,
+                stored: Some(thing("one", "a")),
+                computed: None,
+            }],
+            divergence.entities
+        );
+    }
+
+    #[test]
+    fn compare_deployments_finds_first_difference() {
+        let left = SubgraphDeploymentId::new("compareDeploymentsLeft").unwrap();
+        let same = SubgraphDeploymentId::new("compareDeploymentsSame").unwrap();
+        let changed = SubgraphDeploymentId::new("compareDeploymentsChanged").unwrap();
+        let more = SubgraphDeploymentId::new("compareDeploymentsMore").unwrap();
+        create_things(&left, &[("one", "a"), ("two", "b")]);
+        create_things(&same, &[("two", "b"), ("one", "a")]);
+        create_things(&changed, &[("one", "a"), ("two", "c")]);
+        create_things(&more, &[("one", "a"), ("two", "b"), ("three", "d")]);
+        let store = test_store::STORE.subgraph_store();
+        let thing_type = EntityType::new("Thing".to_owned());
+        let value = |entity: &Option<Entity>| {
+            entity
+                .as_ref()
+                .and_then(|entity| entity.get("value").cloned())
+        };
+
+        assert_eq!(
+            None,
+            compare_deployments(store.as_ref(), &left, &same, 0).unwrap()
+        );
+
+        let difference = compare_deployments(store.as_ref(), &left, &changed, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(thing_type, difference.entity_type);
+        assert_eq!("two", difference.entity_id);
+        assert_eq!(Some(Value::from("b")), value(&difference.left));
+        assert_eq!(Some(Value::from("c")), value(&difference.right));
+
+        // Entities that only the right deployment has are found, too
+        assert_eq!(
+            None,
+            first_entity_difference(store.as_ref(), &left, &more, &thing_type, 0).unwrap()
+        );
+        let difference = compare_deployments(store.as_ref(), &left, &more, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!("three", difference.entity_id);
+        assert_eq!(None, difference.left);
+        assert_eq!(Some(Value::from("d")), value(&difference.right));
+
+        // Both deployments have to have reached the block
+        assert!(compare_deployments(store.as_ref(), &left, &same, 1).is_err());
+
+        let other = SubgraphDeploymentId::new("compareDeploymentsOther").unwrap();
+        test_store::create_test_subgraph(&other, "type Other @entity { id: ID! }");
+        test_store::transact_entity_operations(
+            &store,
+            other.clone(),
+            test_store::GENESIS_PTR.clone(),
+            vec![],
+        )
+        .unwrap();
+        assert!(compare_deployments(store.as_ref(), &left, &other, 0).is_err());
+    }
+}
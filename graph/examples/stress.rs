@@ -206,15 +206,15 @@ fn stress<T: Template<T, Item = T>>(opt: &Opt) {
     for key in 0..opt.niter {
         should_print = should_print || key % print_mod == 0;
         let before_mem = ALLOCATED.load(SeqCst);
-        if let Some((evicted, _, new_weight)) = cacheable.cache.evict(opt.cache_size) {
+        if let Some(stats) = cacheable.cache.evict(opt.cache_size) {
             let after_mem = ALLOCATED.load(SeqCst);
             if should_print {
                 let heap_factor = (after_mem - base_mem) as f64 / opt.cache_size as f64;
                 println!(
                     "evicted: {:6}  dropped: {:6} new_weight: {:8} heap_factor: {:0.2}  ",
-                    evicted,
+                    stats.evicted_weight,
                     before_mem - after_mem,
-                    new_weight,
+                    stats.new_weight,
                     heap_factor
                 );
                 should_print = false;
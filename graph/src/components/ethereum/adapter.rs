@@ -214,6 +214,12 @@ impl EthereumLogFilter {
         contracts_and_events_graph.edge_count() == 0 && wildcard_events.is_empty()
     }
 
+    /// The number of pairs of contract and event that the filter matches,
+    /// counting each event that matches on all contracts once.
+    pub fn size(&self) -> usize {
+        self.contracts_and_events_graph.edge_count() + self.wildcard_events.len()
+    }
+
     /// Filters for `eth_getLogs` calls. The filters will not return false positives. This attempts
     /// to balance between having granular filters but too many calls and having few calls but too
     /// broad filters causing the Ethereum endpoint to timeout.
@@ -361,6 +367,15 @@ impl EthereumCallFilter {
         } = self;
         contract_addresses_function_signatures.is_empty()
     }
+
+    /// The number of pairs of contract and function that the filter
+    /// matches, counting each contract that matches all functions once.
+    pub fn size(&self) -> usize {
+        self.contract_addresses_function_signatures
+            .values()
+            .map(|(_, functions)| functions.len().max(1))
+            .sum()
+    }
 }
 
 impl FromIterator<(BlockNumber, Address, [u8; 4])> for EthereumCallFilter {
@@ -467,6 +482,11 @@ impl EthereumBlockFilter {
             },
         );
     }
+
+    /// The number of contracts that the filter matches.
+    pub fn size(&self) -> usize {
+        self.contract_addresses.len()
+    }
 }
 
 #[derive(Clone)]
@@ -555,6 +575,7 @@ pub struct BlockStreamMetrics {
     pub ethrpc_metrics: Arc<SubgraphEthRpcMetrics>,
     pub deployment_head: Box<Gauge>,
     pub reverted_blocks: Box<Gauge>,
+    pub decompression_duration: Box<Histogram>,
     pub stopwatch: StopwatchMetrics,
 }
 
@@ -581,10 +602,19 @@ impl BlockStreamMetrics {
                 labels,
             )
             .expect("failed to create `deployment_head` gauge");
+        let decompression_duration = registry
+            .new_deployment_histogram(
+                "deployment_block_decompression_duration",
+                "Measures the time it takes to decompress a block that the block stream buffered",
+                deployment_id.as_str(),
+                vec![0.0001, 0.001, 0.01, 0.1, 1.0],
+            )
+            .expect("failed to create `deployment_block_decompression_duration` histogram");
         Self {
             ethrpc_metrics,
             deployment_head,
             reverted_blocks,
+            decompression_duration,
             stopwatch,
         }
     }
@@ -13,7 +13,9 @@ pub use self::adapter::{
 };
 pub use self::listener::{ChainHeadUpdate, ChainHeadUpdateStream};
 pub use self::network::{EthereumNetworkAdapters, EthereumNetworks, NodeCapabilities};
-pub use self::stream::{BlockStream, BlockStreamBuilder, BlockStreamEvent};
+pub use self::stream::{
+    BlockStream, BlockStreamBuilder, BlockStreamEvent, BlockStreamPointer, BlockStreamProfile,
+};
 pub use self::types::{
     BlockFinality, BlockHash, EthereumBlock, EthereumBlockData, EthereumBlockPointer,
     EthereumBlockTriggerType, EthereumBlockWithCalls, EthereumBlockWithTriggers, EthereumCall,
@@ -107,6 +107,19 @@ impl EthereumNetworkAdapters {
         Ok(&sufficient_adapters.iter().choose(&mut rng).unwrap().adapter)
     }
 
+    /// All adapters that have at least the `required_capabilities`, in the
+    /// order of their capabilities.
+    pub fn all_with(
+        &self,
+        required_capabilities: &NodeCapabilities,
+    ) -> Vec<&Arc<dyn EthereumAdapter>> {
+        self.adapters
+            .iter()
+            .filter(|adapter| &adapter.capabilities >= required_capabilities)
+            .map(|adapter| &adapter.adapter)
+            .collect()
+    }
+
     pub fn cheapest(&self) -> Option<&Arc<dyn EthereumAdapter>> {
         // EthereumAdapters are sorted by their NodeCapabilities when the EthereumNetworks
         // struct is instantiated so they do not need to be sorted here
@@ -1,5 +1,6 @@
 use anyhow::Error;
 use futures::Stream;
+use std::sync::atomic::AtomicUsize;
 
 use crate::prelude::*;
 
@@ -8,21 +9,70 @@ pub enum BlockStreamEvent {
     Revert(EthereumBlockPointer),
 }
 
-pub trait BlockStream: Stream<Item = BlockStreamEvent, Error = Error> {}
+/// Tuning parameters for a block stream. Deployments that are far behind
+/// the chain head use a profile geared towards throughput, while those close
+/// to the head use one that wastes less work when the chain reorgs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockStreamProfile {
+    /// Upper bound for the number of blocks that are scanned for triggers
+    /// ahead of the deployment in one request.
+    pub max_block_range_size: BlockNumber,
+
+    /// Maximum number of blocks the stream buffers before it has to go
+    /// back to the chain for more.
+    pub buffer_depth: usize,
+
+    /// Number of blocks the stream stays behind the chain head.
+    pub confirmations: BlockNumber,
+}
+
+/// Where a block stream gets the block pointer of the deployment from, i.e.,
+/// the block after which it continues.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockStreamPointer {
+    /// Follow the block pointer of the deployment in the store, or the last
+    /// final block that the stream yielded if that is further ahead, since
+    /// the pointer for final blocks that don't change any data does not have
+    /// to be written to the store right away.
+    Store,
+
+    /// Start after the given block, or at the start blocks if it is `None`,
+    /// and keep track of the blocks the stream yielded in memory. The stream
+    /// does not write to the store, e.g., it does not mark the deployment
+    /// as synced.
+    Detached(Option<EthereumBlockPointer>),
+}
+
+pub trait BlockStream: Stream<Item = BlockStreamEvent, Error = Error> {
+    /// The number of blocks that the stream has buffered and yields next
+    /// without looking at the block pointer of the deployment again. The
+    /// number changes as the stream is polled. Streams that don't buffer
+    /// blocks always report 0.
+    fn buffered_blocks(&self) -> Arc<AtomicUsize> {
+        Arc::new(AtomicUsize::new(0))
+    }
+}
 
 pub trait BlockStreamBuilder: Clone + Send + Sync + 'static {
     type Stream: BlockStream + Send + 'static;
 
+    /// Build a block stream for a deployment. The stream loads blocks and
+    /// triggers through `eth_adapter` if it is given, and otherwise through
+    /// an adapter for the network that can provide the calls in blocks if
+    /// `include_calls_in_blocks` is set.
     fn build(
         &self,
         logger: Logger,
         deployment_id: SubgraphDeploymentId,
         network_name: String,
+        eth_adapter: Option<Arc<dyn EthereumAdapter>>,
         start_blocks: Vec<BlockNumber>,
         log_filter: EthereumLogFilter,
         call_filter: EthereumCallFilter,
         block_filter: EthereumBlockFilter,
         include_calls_in_blocks: bool,
+        profile: BlockStreamProfile,
+        pointer: BlockStreamPointer,
         ethrpc_metrics: Arc<BlockStreamMetrics>,
-    ) -> Self::Stream;
+    ) -> Result<Self::Stream, Error>;
 }
@@ -74,7 +74,7 @@ impl LightEthereumBlockExt for LightEthereumBlock {
 /// This is used in `EthereumAdapter::triggers_in_block`, called when re-processing a block for
 /// newly created data sources. This allows the re-processing to be reorg safe without having to
 /// always fetch the full block data.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum BlockFinality {
     /// If a block is final, we only need the header and the triggers.
     Final(LightEthereumBlock),
@@ -97,9 +97,24 @@ impl BlockFinality {
             BlockFinality::NonFinal(block) => block.ethereum_block.block.number(),
         }
     }
+
+    pub fn parent_ptr(&self) -> Option<EthereumBlockPointer> {
+        match self {
+            BlockFinality::Final(block) => block.parent_ptr(),
+            BlockFinality::NonFinal(block) => block.ethereum_block.block.parent_ptr(),
+        }
+    }
+
+    /// The block's timestamp in seconds since the epoch.
+    pub fn timestamp(&self) -> U256 {
+        match self {
+            BlockFinality::Final(block) => block.timestamp,
+            BlockFinality::NonFinal(block) => block.ethereum_block.block.timestamp,
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EthereumBlockWithTriggers {
     pub ethereum_block: BlockFinality,
     pub triggers: Vec<EthereumTrigger>,
@@ -117,7 +132,7 @@ impl EthereumBlockWithTriggers {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EthereumBlockWithCalls {
     pub ethereum_block: EthereumBlock,
     pub calls: Vec<EthereumCall>,
@@ -129,7 +144,7 @@ pub struct EthereumBlock {
     pub transaction_receipts: Vec<TransactionReceipt>,
 }
 
-#[derive(Debug, Default, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
 pub struct EthereumCall {
     pub from: Address,
     pub to: Address,
@@ -181,7 +196,7 @@ impl EthereumCall {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum EthereumTrigger {
     Block(EthereumBlockPointer, EthereumBlockTriggerType),
     Call(Arc<EthereumCall>),
@@ -208,7 +223,7 @@ impl PartialEq for EthereumTrigger {
 
 impl Eq for EthereumTrigger {}
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum EthereumBlockTriggerType {
     Every,
     WithCallTo(Address),
@@ -480,7 +495,7 @@ impl Display for BlockHash {
 /// A block hash and block number from a specific Ethereum block.
 ///
 /// Block numbers are signed 32 bit integers
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct EthereumBlockPointer {
     pub hash: BlockHash,
     pub number: BlockNumber,
@@ -7,7 +7,7 @@ use serde_json::Value;
 use slog::Logger;
 
 use crate::data::subgraph::Link;
-use crate::prelude::Error;
+use crate::prelude::{Counter, Error};
 
 /// The values that `json_stream` returns. The struct contains the deserialized
 /// JSON value from the input stream, together with the line number from which
@@ -33,6 +33,11 @@ pub trait LinkResolver: Send + Sync + 'static {
     where
         Self: Sized;
 
+    /// Increments `counter` every time a request is retried.
+    fn with_retry_counter(self, counter: Counter) -> Self
+    where
+        Self: Sized;
+
     /// Fetches the link contents as bytes.
     async fn cat(&self, logger: &Logger, link: &Link) -> Result<Vec<u8>, Error>;
 
@@ -11,10 +11,24 @@ pub mod stopwatch;
 /// Aggregates over individual values.
 pub mod aggregate;
 
+/// Pushing metrics to systems that don't scrape them.
+pub mod sink;
+
 fn deployment_labels(subgraph: &str) -> HashMap<String, String> {
     labels! { String::from("deployment") => String::from(subgraph), }
 }
 
+/// The deployment label together with additional, operator supplied labels.
+/// The `deployment` label always takes precedence.
+fn deployment_labels_with(
+    subgraph: &str,
+    extra_labels: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut labels = extra_labels.clone();
+    labels.extend(deployment_labels(subgraph));
+    labels
+}
+
 /// Create an unregistered counter with labels
 pub fn counter_with_labels(
     name: &str,
@@ -87,6 +101,20 @@ pub trait MetricsRegistry: Send + Sync + 'static {
         Ok(gauge)
     }
 
+    fn new_deployment_gauge_with_labels(
+        &self,
+        name: &str,
+        help: &str,
+        subgraph: &str,
+        extra_labels: &HashMap<String, String>,
+    ) -> Result<Box<Gauge>, PrometheusError> {
+        let opts = Opts::new(name.clone(), help)
+            .const_labels(deployment_labels_with(subgraph, extra_labels));
+        let gauge = Box::new(Gauge::with_opts(opts)?);
+        self.register(name, gauge.clone());
+        Ok(gauge)
+    }
+
     fn new_gauge_vec(
         &self,
         name: &str,
@@ -159,6 +187,22 @@ pub trait MetricsRegistry: Send + Sync + 'static {
         Ok(counter)
     }
 
+    fn new_deployment_counter_with_labels(
+        &self,
+        name: &str,
+        help: &str,
+        subgraph: &str,
+        extra_labels: &HashMap<String, String>,
+    ) -> Result<Box<Counter>, PrometheusError> {
+        let counter = Box::new(counter_with_labels(
+            name,
+            help,
+            deployment_labels_with(subgraph, extra_labels),
+        )?);
+        self.register(name, counter.clone());
+        Ok(counter)
+    }
+
     fn new_counter_vec(
         &self,
         name: &str,
@@ -213,6 +257,22 @@ pub trait MetricsRegistry: Send + Sync + 'static {
         Ok(histogram)
     }
 
+    fn new_deployment_histogram_with_labels(
+        &self,
+        name: &str,
+        help: &str,
+        subgraph: &str,
+        extra_labels: &HashMap<String, String>,
+        buckets: Vec<f64>,
+    ) -> Result<Box<Histogram>, PrometheusError> {
+        let opts = HistogramOpts::new(name.clone(), help)
+            .const_labels(deployment_labels_with(subgraph, extra_labels))
+            .buckets(buckets);
+        let histogram = Box::new(Histogram::with_opts(opts)?);
+        self.register(name, histogram.clone());
+        Ok(histogram)
+    }
+
     fn new_histogram_vec(
         &self,
         name: &str,
@@ -259,4 +319,74 @@ pub trait MetricsRegistry: Send + Sync + 'static {
         self.register(name, histograms.clone());
         Ok(histograms)
     }
+
+    fn new_deployment_histogram_vec_with_labels(
+        &self,
+        name: &str,
+        help: &str,
+        subgraph: &str,
+        extra_labels: &HashMap<String, String>,
+        variable_labels: Vec<String>,
+        buckets: Vec<f64>,
+    ) -> Result<Box<HistogramVec>, PrometheusError> {
+        let opts = Opts::new(name.clone(), help)
+            .const_labels(deployment_labels_with(subgraph, extra_labels));
+        let histograms = Box::new(HistogramVec::new(
+            HistogramOpts {
+                common_opts: opts,
+                buckets,
+            },
+            variable_labels
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>()
+                .as_slice(),
+        )?);
+        self.register(name, histograms.clone());
+        Ok(histograms)
+    }
+
+    fn new_deployment_gauge_vec_with_labels(
+        &self,
+        name: &str,
+        help: &str,
+        subgraph: &str,
+        extra_labels: &HashMap<String, String>,
+        variable_labels: Vec<String>,
+    ) -> Result<Box<GaugeVec>, PrometheusError> {
+        let opts = Opts::new(name.clone(), help)
+            .const_labels(deployment_labels_with(subgraph, extra_labels));
+        let gauges = Box::new(GaugeVec::new(
+            opts,
+            variable_labels
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>()
+                .as_slice(),
+        )?);
+        self.register(name, gauges.clone());
+        Ok(gauges)
+    }
+
+    fn new_deployment_counter_vec_with_labels(
+        &self,
+        name: &str,
+        help: &str,
+        subgraph: &str,
+        extra_labels: &HashMap<String, String>,
+        variable_labels: Vec<String>,
+    ) -> Result<Box<CounterVec>, PrometheusError> {
+        let opts = Opts::new(name.clone(), help)
+            .const_labels(deployment_labels_with(subgraph, extra_labels));
+        let counters = Box::new(CounterVec::new(
+            opts,
+            variable_labels
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>()
+                .as_slice(),
+        )?);
+        self.register(name, counters.clone());
+        Ok(counters)
+    }
 }
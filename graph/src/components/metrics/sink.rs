@@ -0,0 +1,127 @@
+use prometheus::core::Collector;
+use prometheus::proto::{MetricFamily, MetricType};
+
+/// The value of a single metric sample.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetricValue {
+    Counter(f64),
+    Gauge(f64),
+
+    /// The buckets are pairs of upper bound and the cumulative count of
+    /// observations that were at most that bound, like in Prometheus.
+    Histogram {
+        count: u64,
+        sum: f64,
+        buckets: Vec<(f64, u64)>,
+    },
+}
+
+/// One time series of a metric with its current value. The labels include
+/// both the constant labels of the metric, e.g., `deployment`, and the
+/// values of its variable labels, sorted by label name, so that each sample
+/// maps directly onto a metric with tags in push based systems.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricSample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: MetricValue,
+}
+
+/// A destination for metrics that does not scrape them like Prometheus
+/// does, but has them pushed to it, for example StatsD or OTLP.
+pub trait MetricsSink: Send + Sync + 'static {
+    /// Send the current values of metrics to the sink. This is called on
+    /// indexing threads and should not block for long.
+    fn push(&self, samples: Vec<MetricSample>);
+}
+
+/// Turn the current values of `collectors` into samples. Summaries and
+/// untyped metrics are skipped since we don't use them.
+pub fn metric_samples(collectors: &[&dyn Collector]) -> Vec<MetricSample> {
+    collectors
+        .iter()
+        .flat_map(|collector| collector.collect())
+        .flat_map(|family| family_samples(&family))
+        .collect()
+}
+
+fn family_samples(family: &MetricFamily) -> Vec<MetricSample> {
+    family
+        .get_metric()
+        .iter()
+        .filter_map(|metric| {
+            let value = match family.get_field_type() {
+                MetricType::COUNTER => MetricValue::Counter(metric.get_counter().get_value()),
+                MetricType::GAUGE => MetricValue::Gauge(metric.get_gauge().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    MetricValue::Histogram {
+                        count: histogram.get_sample_count(),
+                        sum: histogram.get_sample_sum(),
+                        buckets: histogram
+                            .get_bucket()
+                            .iter()
+                            .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                            .collect(),
+                    }
+                }
+                MetricType::SUMMARY | MetricType::UNTYPED => return None,
+            };
+            let mut labels: Vec<_> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name().to_owned(), label.get_value().to_owned()))
+                .collect();
+            labels.sort();
+            Some(MetricSample {
+                name: family.get_name().to_owned(),
+                labels,
+                value,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn samples_include_all_labels() {
+    use prometheus::{labels, Counter, HistogramOpts, HistogramVec, Opts};
+
+    let counter = Counter::with_opts(
+        Opts::new("retries", "help")
+            .const_labels(labels! { "deployment".to_owned() => "Qm1".to_owned() }),
+    )
+    .unwrap();
+    counter.inc_by(3.0);
+    let histogram = HistogramVec::new(
+        HistogramOpts::new("duration", "help")
+            .const_labels(labels! { "deployment".to_owned() => "Qm1".to_owned() })
+            .buckets(vec![1.0, 10.0]),
+        &["trigger_type"],
+    )
+    .unwrap();
+    histogram.with_label_values(&["event"]).observe(5.0);
+
+    let samples = metric_samples(&[&counter, &histogram]);
+    assert_eq!(
+        vec![
+            MetricSample {
+                name: "retries".to_owned(),
+                labels: vec![("deployment".to_owned(), "Qm1".to_owned())],
+                value: MetricValue::Counter(3.0),
+            },
+            MetricSample {
+                name: "duration".to_owned(),
+                labels: vec![
+                    ("deployment".to_owned(), "Qm1".to_owned()),
+                    ("trigger_type".to_owned(), "event".to_owned()),
+                ],
+                value: MetricValue::Histogram {
+                    count: 1,
+                    sum: 5.0,
+                    buckets: vec![(1.0, 0), (10.0, 1)],
+                },
+            },
+        ],
+        samples
+    );
+}
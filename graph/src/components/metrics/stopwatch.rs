@@ -6,11 +6,22 @@ use std::time::Instant;
 pub struct Section {
     id: String,
     stopwatch: StopwatchMetrics,
+
+    // The section as a tracing span, which is closed on drop, too.
+    span: tracing::Span,
 }
 
 impl Section {
     /// A more readable `drop`.
     pub fn end(self) {}
+
+    /// Run `f` inside the tracing span of the section, so that the spans
+    /// that `f` creates, like those of nested sections, become its children.
+    /// Since the span is only entered while `f` runs, this is meant for
+    /// sections that do not wait on futures.
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.span.in_scope(f)
+    }
 }
 
 impl Drop for Section {
@@ -77,10 +88,17 @@ impl StopwatchMetrics {
             self.inner.lock().unwrap().start_section(id.clone())
         }
 
+        // Sections are also tracing spans, as children of whatever span is
+        // current, e.g., the block being processed; `Section::in_scope`
+        // enters them. Without a tracing subscriber, creating the span is
+        // next to free
+        let span = tracing::info_span!("section", section = id.as_str());
+
         // If disabled, this will do nothing on drop.
         Section {
             id,
             stopwatch: self.clone(),
+            span,
         }
     }
 
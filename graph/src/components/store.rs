@@ -6,7 +6,7 @@ use mockall::predicate::*;
 use mockall::*;
 use serde::{Deserialize, Serialize};
 use stable_hash::prelude::*;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fmt;
 use std::str::FromStr;
@@ -142,6 +142,36 @@ fn key_stable_hash() {
     );
 }
 
+#[test]
+fn store_event_entity_types() {
+    let id = SubgraphDeploymentId::new("QmP9MRvVzwHxr3sGvujihbvJzcTz2LYLMfi5DyihBg6VUd").unwrap();
+    let event = StoreEvent::new(vec![
+        EntityChange::for_data(EntityKey::data(
+            id.clone(),
+            "Account".to_string(),
+            "1".to_string(),
+        )),
+        EntityChange::for_data(EntityKey::data(
+            id.clone(),
+            "Account".to_string(),
+            "2".to_string(),
+        )),
+        EntityChange::for_data(EntityKey::data(
+            id.clone(),
+            "Token".to_string(),
+            "1".to_string(),
+        )),
+        EntityChange::for_assignment(id, EntityChangeOperation::Set),
+    ]);
+    let expected: BTreeSet<_> = vec![
+        EntityType::new("Account".to_string()),
+        EntityType::new("Token".to_string()),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(expected, event.entity_types());
+}
+
 /// Supported types of store filters.
 #[derive(Clone, Debug, PartialEq)]
 pub enum EntityFilter {
@@ -555,6 +585,18 @@ impl StoreEvent {
         self.changes.extend(other.changes);
         self
     }
+
+    /// The types of the entities that changed; assignment changes are
+    /// ignored.
+    pub fn entity_types(&self) -> BTreeSet<EntityType> {
+        self.changes
+            .iter()
+            .filter_map(|change| match change {
+                EntityChange::Data { entity_type, .. } => Some(entity_type.clone()),
+                EntityChange::Assignment { .. } => None,
+            })
+            .collect()
+    }
 }
 
 impl fmt::Display for StoreEvent {
@@ -816,6 +858,53 @@ impl From<QueryExecutionError> for StoreError {
     }
 }
 
+impl StoreError {
+    /// Whether the error is likely to go away when the operation that caused
+    /// it is retried, for example because of a deadlock or because a database
+    /// connection was lost.
+    pub fn is_transient(&self) -> bool {
+        use ::diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+        let e = match self {
+            StoreError::Unknown(e) => e,
+            _ => return false,
+        };
+
+        if e.downcast_ref::<::diesel::r2d2::PoolError>().is_some() {
+            return true;
+        }
+
+        match e.downcast_ref::<DieselError>() {
+            Some(DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _))
+            | Some(DieselError::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _)) => true,
+            Some(DieselError::DatabaseError(_, info)) => {
+                let message = info.message();
+                message.contains("deadlock detected")
+                    || message.contains("server closed the connection")
+                    || message.contains("terminating connection")
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the error happened because the database only accepts reads,
+    /// like a replica during a failover that was not promoted yet.
+    pub fn is_read_only(&self) -> bool {
+        use ::diesel::result::Error as DieselError;
+
+        match self {
+            StoreError::Unknown(e) => match e.downcast_ref::<DieselError>() {
+                Some(DieselError::DatabaseError(_, info)) => {
+                    info.message().contains("read-only transaction")
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct StoredDynamicDataSource {
     pub name: String,
     pub source: Source,
@@ -871,6 +960,23 @@ pub trait SubgraphStore: Send + Sync + 'static {
         block: EthereumBlockPointer,
     ) -> DynTryFuture<'a, Option<[u8; 32]>>;
 
+    /// The digests of the causality regions that make up the proof of
+    /// indexing of the subgraph at `block`, by the id of the region. `None`
+    /// in the same cases as for `get_proof_of_indexing`.
+    fn get_proof_of_indexing_regions<'a>(
+        self: Arc<Self>,
+        subgraph_id: &'a SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> DynTryFuture<'a, Option<BTreeMap<String, Vec<u8>>>>;
+
+    /// The number of the last block at which a digest of the proof of
+    /// indexing of the subgraph was written. `None` if no digest was ever
+    /// written or the subgraph has no proof of indexing.
+    fn last_proof_of_indexing_block(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError>;
+
     /// Looks up an entity using the given store key at the latest block.
     fn get(&self, key: EntityKey) -> Result<Option<Entity>, QueryExecutionError>;
 
@@ -910,11 +1016,12 @@ pub trait SubgraphStore: Send + Sync + 'static {
     /// subgraph block pointer to `block_ptr_to`.
     ///
     /// `block_ptr_to` must point to the parent block of the subgraph block pointer.
+    /// Returns the types of the entities that the revert changed.
     fn revert_block_operations(
         &self,
         subgraph_id: SubgraphDeploymentId,
         block_ptr_to: EthereumBlockPointer,
-    ) -> Result<(), StoreError>;
+    ) -> Result<BTreeSet<EntityType>, StoreError>;
 
     /// Find the deployment for the current version of subgraph `name` and
     /// return details about it needed for executing queries
@@ -997,6 +1104,107 @@ pub trait SubgraphStore: Send + Sync + 'static {
     /// Remove the fatal error from a subgraph and check if it is healthy or unhealthy.
     fn unfail(&self, subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError>;
 
+    /// Revert all changes the subgraph made after `block_ptr_to`, so that
+    /// indexing resumes from there. The subgraph must not be indexing.
+    fn rewind(
+        &self,
+        subgraph_id: SubgraphDeploymentId,
+        block_ptr_to: EthereumBlockPointer,
+    ) -> Result<(), StoreError>;
+
+    /// Return the error that made the subgraph fail, or `None` if it has
+    /// not failed.
+    fn fatal_error(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Option<SubgraphError>, StoreError>;
+
+    /// Return the first and last block of the chunk of historical blocks
+    /// that the subgraph is syncing, or `None` if it is not syncing in
+    /// chunks. The chunk survives restarts of the node.
+    fn backfill_chunk(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Option<(BlockNumber, BlockNumber)>, StoreError>;
+
+    /// Remember the chunk of historical blocks that the subgraph is
+    /// syncing; see `backfill_chunk`.
+    fn set_backfill_chunk(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        chunk: Option<(BlockNumber, BlockNumber)>,
+    ) -> Result<(), StoreError>;
+
+    /// Return the blocks whose triggers the subgraph does not process
+    fn skip_blocks(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<BTreeSet<BlockNumber>, StoreError>;
+
+    /// Set the blocks whose triggers the subgraph does not process; see
+    /// `skip_blocks`. The blocks survive restarts of the node.
+    fn set_skip_blocks(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        blocks: &BTreeSet<BlockNumber>,
+    ) -> Result<(), StoreError>;
+
+    /// Return the blocks whose triggers the subgraph skipped because they
+    /// were among its `skip_blocks`, in ascending order. Changing the
+    /// `skip_blocks` does not change this record.
+    fn skipped_blocks(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<BlockNumber>, StoreError>;
+
+    /// Record that the subgraph skipped the triggers of `block`; see
+    /// `skipped_blocks`.
+    fn record_skipped_block(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<(), StoreError>;
+
+    /// Return whether deterministic errors of the subgraph are kept
+    /// non-fatal even if it is pending, or not, or `None` if the node's
+    /// `GRAPH_DISABLE_FAIL_FAST` applies
+    fn disable_fail_fast(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Option<bool>, StoreError>;
+
+    /// Set the override of `GRAPH_DISABLE_FAIL_FAST` for the subgraph; see
+    /// `disable_fail_fast`. The override survives restarts of the node.
+    fn set_disable_fail_fast(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        disable: Option<bool>,
+    ) -> Result<(), StoreError>;
+
+    /// Return every how many blocks the subgraph writes its proof of
+    /// indexing, or `None` if the node's `GRAPH_POI_WRITE_INTERVAL` applies
+    fn poi_write_interval(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError>;
+
+    /// Set every how many blocks the subgraph writes its proof of indexing;
+    /// see `poi_write_interval`. The interval survives restarts of the node.
+    fn set_poi_write_interval(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        interval: Option<BlockNumber>,
+    ) -> Result<(), StoreError>;
+
+    /// Determine if the data of `base` can be copied into the deployment
+    /// `subgraph_id`, as grafting does. Returns a list of errors; an empty
+    /// vector indicates that copying is possible
+    fn can_copy_from(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        base: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, StoreError>;
+
     /// Load the dynamic data sources for the given deployment
     async fn load_dynamic_data_sources(
         &self,
@@ -1024,6 +1232,28 @@ pub trait SubgraphStore: Send + Sync + 'static {
     /// Return the name of the network that the subgraph is indexing from. The
     /// names returned are things like `mainnet` or `ropsten`
     fn network_name(&self, subgraph_id: &SubgraphDeploymentId) -> Result<String, StoreError>;
+
+    /// Return the deterministic errors of the subgraph, in the order in
+    /// which they happened. At most `limit` errors are returned, skipping
+    /// the first `offset` errors.
+    fn deterministic_errors(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<SubgraphError>, StoreError>;
+
+    /// Mark the deployment as no longer needed by unassigning it and adding
+    /// it to the unused deployments so that a later cleanup of those
+    /// reclaims its storage. Until that happens, the marker can be removed
+    /// again with `clear_tombstone`. Does nothing if the deployment is
+    /// already marked.
+    fn tombstone(&self, subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError>;
+
+    /// Remove the marker set with `tombstone`. Returns `true` if the
+    /// deployment was marked and its data has not been removed yet. The
+    /// deployment stays unassigned until it is deployed again.
+    fn clear_tombstone(&self, subgraph_id: &SubgraphDeploymentId) -> Result<bool, StoreError>;
 }
 
 #[async_trait]
@@ -1058,6 +1288,42 @@ mock! {
 // connection checkouts
 pub type PoolWaitStats = Arc<RwLock<MovingStats>>;
 
+/// Records the database connections that a thread checks out and how long
+/// it waited for them, so that the waiting can be attributed to whatever
+/// the thread works on, like a deployment. See
+/// `set_connection_wait_recorder`.
+pub trait ConnectionWaitRecorder: Send + Sync + 'static {
+    /// A connection was checked out after waiting for `wait`.
+    fn record(&self, wait: Duration);
+}
+
+thread_local! {
+    static CONNECTION_WAIT_RECORDER: std::cell::RefCell<Option<Arc<dyn ConnectionWaitRecorder>>> =
+        std::cell::RefCell::new(None);
+}
+
+/// Record the connections that the current thread checks out with
+/// `recorder` from now on, or stop recording them if it is `None`.
+pub fn set_connection_wait_recorder(recorder: Option<Arc<dyn ConnectionWaitRecorder>>) {
+    CONNECTION_WAIT_RECORDER.with(|current| *current.borrow_mut() = recorder);
+}
+
+/// The recorder of the current thread, for stores that check out
+/// connections on other threads on its behalf.
+pub fn connection_wait_recorder() -> Option<Arc<dyn ConnectionWaitRecorder>> {
+    CONNECTION_WAIT_RECORDER.with(|current| current.borrow().clone())
+}
+
+/// Record that the current thread checked out a connection after waiting
+/// for `wait`.
+pub fn record_connection_wait(wait: Duration) {
+    CONNECTION_WAIT_RECORDER.with(|current| {
+        if let Some(recorder) = &*current.borrow() {
+            recorder.record(wait);
+        }
+    });
+}
+
 // The store trait must be implemented manually because mockall does not support async_trait, nor borrowing from arguments.
 #[async_trait]
 impl SubgraphStore for MockStore {
@@ -1084,6 +1350,21 @@ impl SubgraphStore for MockStore {
         unimplemented!();
     }
 
+    fn get_proof_of_indexing_regions<'a>(
+        self: Arc<Self>,
+        _subgraph_id: &'a SubgraphDeploymentId,
+        _block: EthereumBlockPointer,
+    ) -> DynTryFuture<'a, Option<BTreeMap<String, Vec<u8>>>> {
+        unimplemented!();
+    }
+
+    fn last_proof_of_indexing_block(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        unimplemented!();
+    }
+
     fn get(&self, _key: EntityKey) -> Result<Option<Entity>, QueryExecutionError> {
         unimplemented!()
     }
@@ -1124,7 +1405,7 @@ impl SubgraphStore for MockStore {
         &self,
         _subgraph_id: SubgraphDeploymentId,
         _block_ptr_to: EthereumBlockPointer,
-    ) -> Result<(), StoreError> {
+    ) -> Result<BTreeSet<EntityType>, StoreError> {
         unimplemented!()
     }
 
@@ -1190,6 +1471,88 @@ impl SubgraphStore for MockStore {
         unimplemented!()
     }
 
+    fn rewind(&self, _: SubgraphDeploymentId, _: EthereumBlockPointer) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn fatal_error(&self, _: &SubgraphDeploymentId) -> Result<Option<SubgraphError>, StoreError> {
+        unimplemented!()
+    }
+
+    fn backfill_chunk(
+        &self,
+        _: &SubgraphDeploymentId,
+    ) -> Result<Option<(BlockNumber, BlockNumber)>, StoreError> {
+        unimplemented!()
+    }
+
+    fn set_backfill_chunk(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: Option<(BlockNumber, BlockNumber)>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn skip_blocks(&self, _: &SubgraphDeploymentId) -> Result<BTreeSet<BlockNumber>, StoreError> {
+        unimplemented!()
+    }
+
+    fn set_skip_blocks(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: &BTreeSet<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn skipped_blocks(&self, _: &SubgraphDeploymentId) -> Result<Vec<BlockNumber>, StoreError> {
+        unimplemented!()
+    }
+
+    fn record_skipped_block(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: BlockNumber,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn disable_fail_fast(&self, _: &SubgraphDeploymentId) -> Result<Option<bool>, StoreError> {
+        unimplemented!()
+    }
+
+    fn set_disable_fail_fast(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: Option<bool>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn poi_write_interval(
+        &self,
+        _: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        unimplemented!()
+    }
+
+    fn set_poi_write_interval(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: Option<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn can_copy_from(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, StoreError> {
+        unimplemented!()
+    }
+
     fn is_deployment_synced(&self, _: &SubgraphDeploymentId) -> Result<bool, Error> {
         unimplemented!()
     }
@@ -1228,6 +1591,23 @@ impl SubgraphStore for MockStore {
     fn network_name(&self, _: &SubgraphDeploymentId) -> Result<String, StoreError> {
         unimplemented!()
     }
+
+    fn deterministic_errors(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: usize,
+        _: usize,
+    ) -> Result<Vec<SubgraphError>, StoreError> {
+        unimplemented!()
+    }
+
+    fn tombstone(&self, _: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn clear_tombstone(&self, _: &SubgraphDeploymentId) -> Result<bool, StoreError> {
+        unimplemented!()
+    }
 }
 
 pub trait BlockStore: Send + Sync + 'static {
@@ -1501,6 +1881,15 @@ pub struct EntityCache {
     /// The accumulated changes to an entity.
     updates: HashMap<EntityKey, EntityOp>,
 
+    /// For a fork, the changes that the cache it was forked from had made
+    /// at that point. They are visible through `get` but are not part of
+    /// `updates`, so that merging the fork back only adds its own changes.
+    base_updates: Option<Arc<HashMap<EntityKey, EntityOp>>>,
+
+    /// For a fork, the entities it read, to check that forks that are
+    /// merged back did not depend on each other's changes.
+    fork_reads: Option<HashSet<EntityKey>>,
+
     // Updates for a currently executing handler.
     handler_updates: HashMap<EntityKey, EntityOp>,
 
@@ -1509,8 +1898,37 @@ pub struct EntityCache {
 
     data_sources: Vec<StoredDynamicDataSource>,
 
+    /// The number of entities that were not in the cache and had to be
+    /// read from the store.
+    store_reads: usize,
+
+    /// Read entities from the store as of this block rather than the
+    /// latest block.
+    read_block: Option<BlockNumber>,
+
     /// The store is only used to read entities.
     pub store: Arc<dyn SubgraphStore>,
+
+    /// Looked up before `store` for entities that are not in the cache.
+    secondary: Option<Arc<dyn SecondaryEntityStore>>,
+}
+
+/// A fast store with a copy of the entities of deployments, for example a
+/// cache replicated to the region a deployment is indexed in, that the
+/// entity cache reads from before it reads from the primary store. See
+/// `EntityCache::read_through`.
+pub trait SecondaryEntityStore: Send + Sync + 'static {
+    /// The block up to which the secondary store has the entities of the
+    /// deployment `subgraph_id`. It is only used if that is the block the
+    /// deployment last processed, so that it never returns stale entities.
+    fn block_ptr(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Option<EthereumBlockPointer>, Error>;
+
+    /// Look up the entity for `key`. Returns `None` if the secondary store
+    /// does not have it, in which case it is read from the primary store.
+    fn get(&self, key: &EntityKey) -> Result<Option<Entity>, Error>;
 }
 
 impl Debug for EntityCache {
@@ -1526,6 +1944,10 @@ pub struct ModificationsAndCache {
     pub modifications: Vec<EntityModification>,
     pub data_sources: Vec<StoredDynamicDataSource>,
     pub entity_lfu_cache: LfuCache<EntityKey, Option<Entity>>,
+
+    /// The number of entities that had to be read from the store, in
+    /// handlers and to compute the modifications.
+    pub store_reads: usize,
 }
 
 impl EntityCache {
@@ -1533,10 +1955,15 @@ impl EntityCache {
         Self {
             current: LfuCache::new(),
             updates: HashMap::new(),
+            base_updates: None,
+            fork_reads: None,
             handler_updates: HashMap::new(),
             in_handler: false,
             data_sources: vec![],
+            store_reads: 0,
+            read_block: None,
             store,
+            secondary: None,
         }
     }
 
@@ -1547,11 +1974,105 @@ impl EntityCache {
         EntityCache {
             current,
             updates: HashMap::new(),
+            base_updates: None,
+            fork_reads: None,
             handler_updates: HashMap::new(),
             in_handler: false,
             data_sources: vec![],
+            store_reads: 0,
+            read_block: None,
             store,
+            secondary: None,
+        }
+    }
+
+    /// Read entities that are not in the cache from `secondary` before
+    /// reading them from the store. The caller has to make sure that
+    /// `secondary` has the entities as of the block that is being
+    /// processed. Entities that fail to load from `secondary` are read from
+    /// the store.
+    pub fn read_through(&mut self, secondary: Arc<dyn SecondaryEntityStore>) {
+        self.secondary = Some(secondary);
+    }
+
+    /// Read entities that are not in the cache as they were at `block`
+    /// rather than their latest version. This is needed to process a block
+    /// again after the deployment has already processed later blocks.
+    pub fn read_at_block(&mut self, block: BlockNumber) {
+        self.read_block = Some(block);
+    }
+
+    /// Create `count` caches that see the entities as this cache does but
+    /// track their changes separately, so that they can be used
+    /// independently of each other. The changes of this cache are shared
+    /// with the forks rather than copied; they have to be merged back with
+    /// `join_forks` before this cache is changed again.
+    pub(crate) fn forks(&mut self, count: usize) -> Vec<EntityCache> {
+        assert!(!self.in_handler);
+        assert!(self.base_updates.is_none());
+
+        let base_updates = Arc::new(std::mem::take(&mut self.updates));
+        self.base_updates = Some(base_updates.cheap_clone());
+        (0..count)
+            .map(|_| EntityCache {
+                current: LfuCache::new(),
+                updates: HashMap::new(),
+                base_updates: Some(base_updates.cheap_clone()),
+                fork_reads: Some(HashSet::new()),
+                handler_updates: HashMap::new(),
+                in_handler: false,
+                data_sources: vec![],
+                store_reads: 0,
+                read_block: self.read_block,
+                store: self.store.cheap_clone(),
+                secondary: self.secondary.clone(),
+            })
+            .collect()
+    }
+
+    /// Merge the changes of `forks` that were created with `forks` back in
+    /// their order. That only gives the same result as making the changes
+    /// one after the other if no fork changed an entity that another fork
+    /// read or changed; otherwise, nothing is merged and `false` is
+    /// returned. Either way, this cache is usable again afterwards.
+    pub(crate) fn join_forks(&mut self, forks: Vec<EntityCache>) -> bool {
+        assert!(!self.in_handler);
+
+        let independent = forks.iter().enumerate().all(|(i, fork)| {
+            fork.updates.keys().all(|key| {
+                forks.iter().enumerate().all(|(j, other)| {
+                    i == j
+                        || !(other.updates.contains_key(key)
+                            || other
+                                .fork_reads
+                                .as_ref()
+                                .map_or(false, |reads| reads.contains(key)))
+                })
+            })
+        });
+
+        // The forks have to let go of the shared changes before this cache
+        // can take them back without copying them
+        let forks: Vec<_> = forks
+            .into_iter()
+            .map(|mut fork| {
+                fork.base_updates = None;
+                fork
+            })
+            .collect();
+        let base_updates = self
+            .base_updates
+            .take()
+            .expect("`join_forks` is only called after `forks`");
+        self.updates = Arc::try_unwrap(base_updates).unwrap_or_else(|base| (*base).clone());
+
+        if !independent {
+            return false;
         }
+        for fork in forks {
+            self.extend(fork);
+        }
+        true
     }
 
     pub(crate) fn enter_handler(&mut self) {
@@ -1577,8 +2098,24 @@ impl EntityCache {
     }
 
     pub fn get(&mut self, key: &EntityKey) -> Result<Option<Entity>, QueryExecutionError> {
-        // Get the current entity, apply any updates from `updates`, then from `handler_updates`.
-        let mut entity = self.current.get_entity(&*self.store, &key)?;
+        if let Some(reads) = &mut self.fork_reads {
+            reads.insert(key.clone());
+        }
+        if !self.current.contains_key(key) {
+            match self.get_secondary(key) {
+                Some(entity) => self.current.insert(key.clone(), Some(entity)),
+                None => self.store_reads += 1,
+            }
+        }
+
+        // Get the current entity, apply any updates from `base_updates` and
+        // `updates`, then from `handler_updates`.
+        let mut entity = self
+            .current
+            .get_entity(&*self.store, &key, self.read_block)?;
+        if let Some(op) = self.base_updates.as_ref().and_then(|base| base.get(&key)) {
+            entity = op.clone().apply_to(entity)
+        }
         if let Some(op) = self.updates.get(&key).cloned() {
             entity = op.apply_to(entity)
         }
@@ -1588,10 +2125,71 @@ impl EntityCache {
         Ok(entity)
     }
 
+    /// Look up `key` in the secondary store, if there is one and we read
+    /// the latest version of entities.
+    fn get_secondary(&self, key: &EntityKey) -> Option<Entity> {
+        match (&self.secondary, self.read_block) {
+            (Some(secondary), None) => secondary.get(key).ok().flatten().map(|mut entity| {
+                // `__typename` is for queries not for mappings.
+                entity.remove("__typename");
+                entity
+            }),
+            _ => None,
+        }
+    }
+
     pub fn remove(&mut self, key: EntityKey) {
         self.entity_op(key, EntityOp::Remove);
     }
 
+    /// Load the entities for `keys` that are not in the cache yet with one
+    /// query per entity type, so that handlers find them in the cache.
+    /// Entities that don't exist are cached as missing. Returns the number
+    /// of keys that had to be looked up.
+    pub fn prefetch(&mut self, keys: Vec<EntityKey>) -> Result<usize, QueryExecutionError> {
+        let missing: BTreeSet<EntityKey> = keys
+            .into_iter()
+            .filter(|key| !self.current.contains_key(key))
+            .collect();
+
+        let mut missing_by_subgraph: BTreeMap<_, BTreeMap<&EntityType, Vec<&str>>> =
+            BTreeMap::new();
+        for key in &missing {
+            missing_by_subgraph
+                .entry(&key.subgraph_id)
+                .or_default()
+                .entry(&key.entity_type)
+                .or_default()
+                .push(&key.entity_id);
+        }
+
+        for (subgraph_id, keys) in missing_by_subgraph {
+            let entities = match self.read_block {
+                None => self.store.get_many(subgraph_id, keys)?,
+                Some(block) => get_many_at_block(&*self.store, subgraph_id, keys, block)?,
+            };
+            for (entity_type, entities) in entities {
+                for entity in entities {
+                    let key = EntityKey {
+                        subgraph_id: subgraph_id.clone(),
+                        entity_type: entity_type.clone(),
+                        entity_id: entity.id().unwrap(),
+                    };
+                    self.current.insert(key, Some(entity));
+                }
+            }
+        }
+
+        let count = missing.len();
+        for key in missing {
+            if !self.current.contains_key(&key) {
+                self.current.insert(key, None);
+            }
+        }
+        self.store_reads += count;
+        Ok(count)
+    }
+
     pub fn set(&mut self, key: EntityKey, entity: Entity) {
         self.entity_op(key, EntityOp::Update(entity))
     }
@@ -1636,6 +2234,7 @@ impl EntityCache {
         assert!(!other.in_handler);
 
         self.current.extend(other.current);
+        self.store_reads += other.store_reads;
         for (key, op) in other.updates {
             self.entity_op(key, op);
         }
@@ -1651,6 +2250,7 @@ impl EntityCache {
         store: &(impl SubgraphStore + ?Sized),
     ) -> Result<ModificationsAndCache, QueryExecutionError> {
         assert!(!self.in_handler);
+        assert!(self.base_updates.is_none());
 
         // The first step is to make sure all entities being set are in `self.current`.
         // For each subgraph, we need a map of entity type to missing entity ids.
@@ -1661,6 +2261,7 @@ impl EntityCache {
 
         let mut missing_by_subgraph: BTreeMap<_, BTreeMap<&EntityType, Vec<&str>>> =
             BTreeMap::new();
+        let mut missing_count = 0;
         for key in missing {
             missing_by_subgraph
                 .entry(&key.subgraph_id)
@@ -1668,10 +2269,16 @@ impl EntityCache {
                 .entry(&key.entity_type)
                 .or_default()
                 .push(&key.entity_id);
+            missing_count += 1;
         }
+        self.store_reads += missing_count;
 
         for (subgraph_id, keys) in missing_by_subgraph {
-            for (entity_type, entities) in store.get_many(subgraph_id, keys)? {
+            let entities = match self.read_block {
+                None => store.get_many(subgraph_id, keys)?,
+                Some(block) => get_many_at_block(store, subgraph_id, keys, block)?,
+            };
+            for (entity_type, entities) in entities {
                 for entity in entities {
                     let key = EntityKey {
                         subgraph_id: subgraph_id.clone(),
@@ -1732,6 +2339,7 @@ impl EntityCache {
             modifications: mods,
             data_sources: self.data_sources,
             entity_lfu_cache: self.current,
+            store_reads: self.store_reads,
         })
     }
 }
@@ -1742,10 +2350,14 @@ impl LfuCache<EntityKey, Option<Entity>> {
         &mut self,
         store: &(impl SubgraphStore + ?Sized),
         key: &EntityKey,
+        block: Option<BlockNumber>,
     ) -> Result<Option<Entity>, QueryExecutionError> {
         match self.get(&key) {
             None => {
-                let mut entity = store.get(key.clone())?;
+                let mut entity = match block {
+                    None => store.get(key.clone())?,
+                    Some(block) => get_entity_at_block(store, key, block)?,
+                };
                 if let Some(entity) = &mut entity {
                     // `__typename` is for queries not for mappings.
                     entity.remove("__typename");
@@ -1757,3 +2369,52 @@ impl LfuCache<EntityKey, Option<Entity>> {
         }
     }
 }
+
+/// Look up an entity as it was at `block`.
+pub fn get_entity_at_block(
+    store: &(impl SubgraphStore + ?Sized),
+    key: &EntityKey,
+    block: BlockNumber,
+) -> Result<Option<Entity>, QueryExecutionError> {
+    let query = EntityQuery::new(
+        key.subgraph_id.clone(),
+        block,
+        EntityCollection::All(vec![key.entity_type.clone()]),
+    )
+    .filter(EntityFilter::Equal(
+        "id".to_owned(),
+        Value::String(key.entity_id.clone()),
+    ))
+    .first(1);
+    store.find_one(query)
+}
+
+/// Look up multiple entities as they were at `block`. Returns a map of
+/// entities by type, like `SubgraphStore::get_many`.
+fn get_many_at_block(
+    store: &(impl SubgraphStore + ?Sized),
+    subgraph_id: &SubgraphDeploymentId,
+    ids_for_type: BTreeMap<&EntityType, Vec<&str>>,
+    block: BlockNumber,
+) -> Result<BTreeMap<EntityType, Vec<Entity>>, QueryExecutionError> {
+    let mut entities_for_type = BTreeMap::new();
+    for (entity_type, ids) in ids_for_type {
+        let query = EntityQuery::new(
+            subgraph_id.clone(),
+            block,
+            EntityCollection::All(vec![entity_type.clone()]),
+        )
+        .filter(EntityFilter::In(
+            "id".to_owned(),
+            ids.iter().map(|id| Value::String(id.to_string())).collect(),
+        ))
+        .first(ids.len() as u32);
+        let mut entities = store.find(query)?;
+        for entity in &mut entities {
+            // `__typename` is for queries not for mappings.
+            entity.remove("__typename");
+        }
+        entities_for_type.insert(entity_type.clone(), entities);
+    }
+    Ok(entities_for_type)
+}
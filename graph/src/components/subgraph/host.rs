@@ -34,6 +34,23 @@ impl MappingError {
     }
 }
 
+/// A seed for randomness in mappings that is the same on every node that
+/// processes a block, so that mappings that use it stay deterministic. The
+/// seed is the Keccak-256 hash of the 32 bytes of the block hash. Mappings
+/// with apiVersion 0.0.5 or later get it from the `crypto.randomSeed` host
+/// export; since all handlers for a block get the same seed, they need to
+/// mix in something like the log index to get different random values for
+/// different triggers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockSeed(pub [u8; 32]);
+
+impl BlockSeed {
+    pub fn from_block(block: &LightEthereumBlock) -> Self {
+        let block_hash = EthereumBlockPointer::from(block).hash;
+        BlockSeed(tiny_keccak::keccak256(block_hash.as_bytes()))
+    }
+}
+
 /// Common trait for runtime host implementations.
 #[async_trait]
 pub trait RuntimeHost: Send + Sync + Debug + 'static {
@@ -51,11 +68,15 @@ pub trait RuntimeHost: Send + Sync + Debug + 'static {
         trigger: MappingTrigger,
         state: BlockState,
         proof_of_indexing: SharedProofOfIndexing,
+        seed: BlockSeed,
     ) -> Result<BlockState, MappingError>;
 
     /// Block number in which this host was created.
     /// Returns `None` for static data sources.
     fn creation_block_number(&self) -> Option<BlockNumber>;
+
+    /// The data source for which this host was created.
+    fn data_source(&self) -> &DataSource;
 }
 
 pub struct HostMetrics {
@@ -145,10 +166,14 @@ pub trait RuntimeHostBuilder: Clone + Send + Sync + 'static {
     type Host: RuntimeHost + PartialEq;
     type Req: 'static + Send;
 
-    /// Build a new runtime host for a subgraph data source.
+    /// Build a new runtime host for a subgraph data source. Ethereum calls
+    /// from its mappings go to `ethereum_adapter` if it is given, and
+    /// otherwise to an adapter for the network that has the capabilities
+    /// the data source requires.
     fn build(
         &self,
         network_name: String,
+        ethereum_adapter: Option<Arc<dyn EthereumAdapter>>,
         subgraph_id: SubgraphDeploymentId,
         data_source: DataSource,
         top_level_templates: Arc<Vec<DataSourceTemplate>>,
@@ -165,3 +190,14 @@ pub trait RuntimeHostBuilder: Clone + Send + Sync + 'static {
         metrics: Arc<HostMetrics>,
     ) -> Result<mpsc::Sender<Self::Req>, anyhow::Error>;
 }
+
+#[test]
+fn block_seed_is_hash_of_block_hash() {
+    let mut block = LightEthereumBlock::default();
+    block.hash = Some(web3::types::H256::zero());
+    block.number = Some(1.into());
+    assert_eq!(
+        "290decd9548b62a8d60345a988386fc84ba6bc95484008f6362f93160ef3e563",
+        hex::encode(BlockSeed::from_block(&block).0)
+    );
+}
@@ -1,6 +1,55 @@
 use crate::data::subgraph::schema::SubgraphError;
 use crate::prelude::*;
 use crate::util::lfu_cache::LfuCache;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// The kind of problem that caused a deterministic error in a handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The mapping trapped, for example because of an integer overflow or
+    /// an out of bounds memory access.
+    MappingTrap,
+    /// A contract call made by the mapping reverted.
+    EthCall,
+    /// A host function failed deterministically, for example because the
+    /// mapping passed an invalid argument.
+    HostFunction,
+    /// The mapping called `abort`, which it does when an assertion fails or
+    /// it throws. Unlike the other categories, this usually points to a bug
+    /// in the mapping rather than to data it did not expect.
+    Abort,
+}
+
+impl FromStr for ErrorCategory {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mapping_trap" => Ok(ErrorCategory::MappingTrap),
+            "eth_call" => Ok(ErrorCategory::EthCall),
+            "host_function" => Ok(ErrorCategory::HostFunction),
+            "abort" => Ok(ErrorCategory::Abort),
+            _ => Err(anyhow!(
+                "invalid error category `{}`, expected one of `mapping_trap`, `eth_call`, `host_function` or `abort`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCategory::MappingTrap => write!(f, "mapping_trap"),
+            ErrorCategory::EthCall => write!(f, "eth_call"),
+            ErrorCategory::HostFunction => write!(f, "host_function"),
+            ErrorCategory::Abort => write!(f, "abort"),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct DataSourceTemplateInfo {
@@ -14,6 +63,9 @@ pub struct DataSourceTemplateInfo {
 pub struct BlockState {
     pub entity_cache: EntityCache,
     pub deterministic_errors: Vec<SubgraphError>,
+
+    /// The categories of the errors in `deterministic_errors`.
+    pub error_categories: BTreeSet<ErrorCategory>,
     created_data_sources: Vec<DataSourceTemplateInfo>,
 
     // Data sources created in the current handler.
@@ -31,6 +83,7 @@ impl BlockState {
         BlockState {
             entity_cache: EntityCache::with_current(store, lfu_cache),
             deterministic_errors: Vec::new(),
+            error_categories: BTreeSet::new(),
             created_data_sources: Vec::new(),
             handler_created_data_sources: Vec::new(),
             in_handler: false,
@@ -43,6 +96,7 @@ impl BlockState {
         let BlockState {
             entity_cache,
             deterministic_errors,
+            error_categories,
             created_data_sources,
             handler_created_data_sources,
             in_handler,
@@ -53,9 +107,62 @@ impl BlockState {
             false => created_data_sources.extend(other.created_data_sources),
         }
         deterministic_errors.extend(other.deterministic_errors);
+        error_categories.extend(other.error_categories);
         entity_cache.extend(other.entity_cache);
     }
 
+    /// Create `count` block states that start out with the entities of
+    /// this one and nothing else, for handlers that run independently of
+    /// each other. They have to be merged back with `join_forks` before
+    /// this block state is used again.
+    pub fn forks(&mut self, count: usize) -> Vec<BlockState> {
+        assert!(!self.in_handler);
+
+        self.entity_cache
+            .forks(count)
+            .into_iter()
+            .map(|entity_cache| BlockState {
+                entity_cache,
+                deterministic_errors: Vec::new(),
+                error_categories: BTreeSet::new(),
+                created_data_sources: Vec::new(),
+                handler_created_data_sources: Vec::new(),
+                in_handler: false,
+            })
+            .collect()
+    }
+
+    /// Merge `forks` back in their order, which gives the same result no
+    /// matter in which order the handlers ran. If the handlers of the forks
+    /// depended on each other's entity changes, the result would differ
+    /// from running them one after the other; then nothing is merged and
+    /// `false` is returned, see `EntityCache::join_forks`.
+    pub fn join_forks(&mut self, forks: Vec<BlockState>) -> bool {
+        assert!(!self.in_handler);
+
+        let mut entity_caches = Vec::with_capacity(forks.len());
+        let mut rest = Vec::with_capacity(forks.len());
+        for fork in forks {
+            assert!(!fork.in_handler);
+            entity_caches.push(fork.entity_cache);
+            rest.push((
+                fork.deterministic_errors,
+                fork.error_categories,
+                fork.created_data_sources,
+            ));
+        }
+        if !self.entity_cache.join_forks(entity_caches) {
+            return false;
+        }
+
+        for (deterministic_errors, error_categories, created_data_sources) in rest {
+            self.deterministic_errors.extend(deterministic_errors);
+            self.error_categories.extend(error_categories);
+            self.created_data_sources.extend(created_data_sources);
+        }
+        true
+    }
+
     pub fn has_errors(&self) -> bool {
         !self.deterministic_errors.is_empty()
     }
@@ -84,11 +191,17 @@ impl BlockState {
         self.entity_cache.exit_handler()
     }
 
+    /// Discard the changes of the current handler because it failed with
+    /// the deterministic error `e`. The category of the error is taken from
+    /// its details.
     pub fn exit_handler_and_discard_changes_due_to_error(&mut self, e: SubgraphError) {
         assert!(self.in_handler);
         self.in_handler = false;
         self.handler_created_data_sources.clear();
         self.entity_cache.exit_handler_and_discard_changes();
+        if let Some(details) = &e.details {
+            self.error_categories.insert(details.code);
+        }
         self.deterministic_errors.push(e);
     }
 
@@ -97,3 +210,19 @@ impl BlockState {
         self.handler_created_data_sources.push(ds);
     }
 }
+
+#[test]
+fn error_category_from_str() {
+    for category in &[
+        ErrorCategory::MappingTrap,
+        ErrorCategory::EthCall,
+        ErrorCategory::HostFunction,
+        ErrorCategory::Abort,
+    ] {
+        assert_eq!(
+            *category,
+            category.to_string().parse::<ErrorCategory>().unwrap()
+        );
+    }
+    assert!("schema".parse::<ErrorCategory>().is_err());
+}
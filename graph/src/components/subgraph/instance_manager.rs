@@ -1,6 +1,179 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 
-use crate::prelude::SubgraphDeploymentId;
+use anyhow::{anyhow, Error};
+
+use crate::prelude::{StoreError, SubgraphDeploymentId};
+
+/// The maximum number of labels that can be attached to a deployment.
+pub const MAX_DEPLOYMENT_LABELS: usize = 4;
+
+/// The maximum length of the value of a deployment label.
+pub const MAX_DEPLOYMENT_LABEL_VALUE_LENGTH: usize = 64;
+
+/// Labels that identify metric labels that graph-node sets itself and
+/// that therefore can not be overridden by deployment labels.
+const RESERVED_LABELS: &[&str] = &["deployment", "network", "shard"];
+
+/// Metadata, like a tenant or project, that an operator attaches to a
+/// deployment. The labels are added as extra dimensions to the
+/// deployment's metrics. Since every distinct set of labels creates new
+/// time series, the number and size of labels is bounded. Prometheus
+/// requires that all series of a metric have the same label names; see
+/// `NodeDeploymentLabels` for how all deployments on a node get the same
+/// label names.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeploymentLabels(BTreeMap<String, String>);
+
+impl DeploymentLabels {
+    pub fn new(labels: impl IntoIterator<Item = (String, String)>) -> Result<Self, Error> {
+        let labels: BTreeMap<_, _> = labels.into_iter().collect();
+
+        if labels.len() > MAX_DEPLOYMENT_LABELS {
+            return Err(anyhow!(
+                "a deployment can have at most {} labels, but {} were given",
+                MAX_DEPLOYMENT_LABELS,
+                labels.len()
+            ));
+        }
+
+        for (name, value) in &labels {
+            let valid_name = name
+                .chars()
+                .next()
+                .map(|c| c.is_ascii_alphabetic() || c == '_')
+                .unwrap_or(false)
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !name.starts_with("__");
+            if !valid_name {
+                return Err(anyhow!("invalid deployment label name `{}`", name));
+            }
+            if RESERVED_LABELS.contains(&name.as_str()) {
+                return Err(anyhow!("deployment label `{}` is reserved", name));
+            }
+            if value.len() > MAX_DEPLOYMENT_LABEL_VALUE_LENGTH {
+                return Err(anyhow!(
+                    "the value of deployment label `{}` is longer than {} characters",
+                    name,
+                    MAX_DEPLOYMENT_LABEL_VALUE_LENGTH
+                ));
+            }
+        }
+
+        Ok(DeploymentLabels(labels))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The labels in the form expected by the `MetricsRegistry`.
+    pub fn to_metric_labels(&self) -> HashMap<String, String> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Parse the labels of several deployments, given as a semicolon
+    /// separated list of entries like `<deployment>:tenant=acme,project=x`.
+    pub fn parse_list(s: &str) -> Result<HashMap<SubgraphDeploymentId, Self>, Error> {
+        let mut deployments = HashMap::new();
+        for entry in s
+            .split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (id, labels) = match entry.find(':') {
+                Some(pos) => (&entry[..pos], &entry[pos + 1..]),
+                None => return Err(anyhow!("deployment labels `{}` lack a deployment", entry)),
+            };
+            let id = SubgraphDeploymentId::new(id.trim())
+                .map_err(|id| anyhow!("invalid deployment `{}`", id))?;
+            let labels = labels
+                .split(',')
+                .map(str::trim)
+                .filter(|label| !label.is_empty())
+                .map(|label| match label.find('=') {
+                    Some(pos) => Ok((
+                        label[..pos].trim().to_string(),
+                        label[pos + 1..].trim().to_string(),
+                    )),
+                    None => Err(anyhow!("deployment label `{}` lacks a value", label)),
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            let labels = DeploymentLabels::new(labels)?;
+            if deployments.insert(id.clone(), labels).is_some() {
+                return Err(anyhow!("deployment `{}` is labeled more than once", id));
+            }
+        }
+        Ok(deployments)
+    }
+}
+
+/// The labels of all deployments on a node. Prometheus rejects a series
+/// whose label names differ from those of the other series of the metric,
+/// and the deployment metrics of all deployments share their names. Every
+/// deployment therefore gets every label name that is configured for any
+/// deployment, and the labels that a deployment lacks are empty.
+#[derive(Clone, Debug, Default)]
+pub struct NodeDeploymentLabels {
+    names: BTreeSet<String>,
+    deployments: HashMap<SubgraphDeploymentId, DeploymentLabels>,
+}
+
+impl NodeDeploymentLabels {
+    /// Parse the labels of the deployments as `DeploymentLabels::parse_list`
+    /// does. Since every deployment gets all label names, there can be at
+    /// most `MAX_DEPLOYMENT_LABELS` different names across deployments.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let deployments = DeploymentLabels::parse_list(s)?;
+        let names: BTreeSet<_> = deployments
+            .values()
+            .flat_map(|labels| labels.0.keys().cloned())
+            .collect();
+        if names.len() > MAX_DEPLOYMENT_LABELS {
+            return Err(anyhow!(
+                "deployments can have at most {} different labels, but {} were given: {}",
+                MAX_DEPLOYMENT_LABELS,
+                names.len(),
+                names.iter().cloned().collect::<Vec<_>>().join(", ")
+            ));
+        }
+        Ok(NodeDeploymentLabels { names, deployments })
+    }
+
+    /// The labels for the metrics of the deployment `id` that was started
+    /// with `labels`, which take precedence over the labels configured for
+    /// the deployment. Fails if `labels` have a name that is not configured
+    /// for any deployment, since the deployment's metrics would then be
+    /// rejected.
+    pub fn labels_of(
+        &self,
+        id: &SubgraphDeploymentId,
+        labels: &DeploymentLabels,
+    ) -> Result<DeploymentLabels, Error> {
+        if let Some(name) = labels.0.keys().find(|name| !self.names.contains(*name)) {
+            return Err(anyhow!(
+                "deployment label `{}` is not configured for any deployment on this node; \
+                 the labels of the deployments are: {}",
+                name,
+                self.names.iter().cloned().collect::<Vec<_>>().join(", ")
+            ));
+        }
+
+        let configured = self.deployments.get(id);
+        DeploymentLabels::new(self.names.iter().map(|name| {
+            let value = labels
+                .0
+                .get(name)
+                .or_else(|| configured.and_then(|configured| configured.0.get(name)))
+                .cloned()
+                .unwrap_or_default();
+            (name.clone(), value)
+        }))
+    }
+}
 
 /// A `SubgraphInstanceManager` loads and manages subgraph instances.
 ///
@@ -13,6 +186,120 @@ pub trait SubgraphInstanceManager: Send + Sync + 'static {
         self: Arc<Self>,
         id: SubgraphDeploymentId,
         manifest: serde_yaml::Mapping,
+        labels: DeploymentLabels,
     );
     fn stop_subgraph(&self, id: SubgraphDeploymentId);
+
+    /// Stop the subgraph like `stop_subgraph`, unassign it, and mark its
+    /// data as removable in the store. The data is only reclaimed by a
+    /// later cleanup of unused deployments, and the mark can be cleared
+    /// with `SubgraphStore::clear_tombstone` until then.
+    fn stop_and_tombstone_subgraph(&self, id: SubgraphDeploymentId) -> Result<(), StoreError>;
+}
+
+#[test]
+fn deployment_labels() {
+    fn labels(labels: &[(&str, &str)]) -> Result<DeploymentLabels, Error> {
+        DeploymentLabels::new(
+            labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+    }
+
+    assert!(labels(&[]).unwrap().is_empty());
+    assert_eq!(
+        labels(&[("tenant", "acme"), ("project", "x")])
+            .unwrap()
+            .to_metric_labels()
+            .get("tenant"),
+        Some(&"acme".to_string())
+    );
+
+    // Invalid or reserved names
+    assert!(labels(&[("1tenant", "acme")]).is_err());
+    assert!(labels(&[("ten-ant", "acme")]).is_err());
+    assert!(labels(&[("__tenant", "acme")]).is_err());
+    assert!(labels(&[("deployment", "acme")]).is_err());
+
+    // Too many or too long labels
+    assert!(labels(&[("a", ""), ("b", ""), ("c", ""), ("d", ""), ("e", "")]).is_err());
+    assert!(labels(&[("tenant", &"x".repeat(MAX_DEPLOYMENT_LABEL_VALUE_LENGTH + 1))]).is_err());
+}
+
+#[test]
+fn deployment_labels_list() {
+    let list =
+        DeploymentLabels::parse_list("QmA:tenant=acme, project=x; QmB:tenant=other;QmC:").unwrap();
+    assert_eq!(list.len(), 3);
+    assert_eq!(
+        list[&SubgraphDeploymentId::new("QmA").unwrap()]
+            .to_metric_labels()
+            .get("project"),
+        Some(&"x".to_string())
+    );
+    assert!(list[&SubgraphDeploymentId::new("QmC").unwrap()].is_empty());
+    assert!(DeploymentLabels::parse_list("").unwrap().is_empty());
+
+    // Missing deployments or values, invalid labels and duplicates
+    assert!(DeploymentLabels::parse_list("tenant=acme").is_err());
+    assert!(DeploymentLabels::parse_list("QmA:tenant").is_err());
+    assert!(DeploymentLabels::parse_list("QmA:deployment=x").is_err());
+    assert!(DeploymentLabels::parse_list("QmA:tenant=a;QmA:tenant=b").is_err());
+}
+
+#[test]
+fn node_deployment_labels() {
+    let id = |id: &str| SubgraphDeploymentId::new(id).unwrap();
+    let names = |labels: DeploymentLabels| {
+        let mut labels: Vec<_> = labels.to_metric_labels().into_iter().collect();
+        labels.sort();
+        labels
+    };
+    let pair = |name: &str, value: &str| (name.to_string(), value.to_string());
+
+    let node = NodeDeploymentLabels::parse("QmA:tenant=acme,project=x;QmB:tenant=other").unwrap();
+
+    // Every deployment gets all label names, also ones without labels
+    assert_eq!(
+        vec![pair("project", "x"), pair("tenant", "acme")],
+        names(
+            node.labels_of(&id("QmA"), &DeploymentLabels::default())
+                .unwrap()
+        )
+    );
+    assert_eq!(
+        vec![pair("project", ""), pair("tenant", "other")],
+        names(
+            node.labels_of(&id("QmB"), &DeploymentLabels::default())
+                .unwrap()
+        )
+    );
+    assert_eq!(
+        vec![pair("project", ""), pair("tenant", "")],
+        names(
+            node.labels_of(&id("QmC"), &DeploymentLabels::default())
+                .unwrap()
+        )
+    );
+
+    // Labels given when starting a deployment take precedence, but can't
+    // add label names
+    let started = DeploymentLabels::new(vec![pair("project", "y")]).unwrap();
+    assert_eq!(
+        vec![pair("project", "y"), pair("tenant", "acme")],
+        names(node.labels_of(&id("QmA"), &started).unwrap())
+    );
+    let started = DeploymentLabels::new(vec![pair("team", "y")]).unwrap();
+    assert!(node.labels_of(&id("QmA"), &started).is_err());
+
+    // Without any labels, deployments have none
+    let node = NodeDeploymentLabels::default();
+    assert!(node
+        .labels_of(&id("QmA"), &DeploymentLabels::default())
+        .unwrap()
+        .is_empty());
+
+    // Too many different label names across deployments
+    assert!(NodeDeploymentLabels::parse("QmA:a=1,b=2,c=3;QmB:d=4,e=5").is_err());
 }
@@ -1,18 +1,22 @@
 mod host;
 mod instance;
 mod instance_manager;
+mod modifications_sink;
 mod proof_of_indexing;
 mod provider;
 mod registrar;
+mod reorg_listener;
 
 pub use crate::prelude::Entity;
 
-pub use self::host::{HostMetrics, MappingError, RuntimeHost, RuntimeHostBuilder};
-pub use self::instance::{BlockState, DataSourceTemplateInfo};
-pub use self::instance_manager::SubgraphInstanceManager;
+pub use self::host::{BlockSeed, HostMetrics, MappingError, RuntimeHost, RuntimeHostBuilder};
+pub use self::instance::{BlockState, DataSourceTemplateInfo, ErrorCategory};
+pub use self::instance_manager::{DeploymentLabels, NodeDeploymentLabels, SubgraphInstanceManager};
+pub use self::modifications_sink::{ModificationsEvent, ModificationsSink};
 pub use self::proof_of_indexing::{
     BlockEventStream, ProofOfIndexing, ProofOfIndexingEvent, ProofOfIndexingFinisher,
     SharedProofOfIndexing,
 };
 pub use self::provider::SubgraphAssignmentProvider;
 pub use self::registrar::{SubgraphRegistrar, SubgraphVersionSwitchingMode};
+pub use self::reorg_listener::{ReorgEvent, ReorgListener};
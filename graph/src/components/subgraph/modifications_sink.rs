@@ -0,0 +1,178 @@
+use anyhow::Error;
+use serde_json::json;
+
+use crate::components::store::EntityModification;
+use crate::prelude::{BlockNumber, EthereumBlockPointer, SubgraphDeploymentId};
+
+/// A change to the data of a deployment that was written to the store.
+#[derive(Clone, Debug)]
+pub enum ModificationsEvent {
+    /// The entity modifications of a block. Blocks that don't change any
+    /// entities are left out.
+    Block {
+        deployment: SubgraphDeploymentId,
+        block_ptr: EthereumBlockPointer,
+        modifications: Vec<EntityModification>,
+    },
+
+    /// All blocks after `block_ptr` were reverted. Consumers have to undo
+    /// the modifications of the blocks after it that they received.
+    Revert {
+        deployment: SubgraphDeploymentId,
+        block_ptr: EthereumBlockPointer,
+    },
+}
+
+impl ModificationsEvent {
+    pub fn deployment(&self) -> &SubgraphDeploymentId {
+        match self {
+            ModificationsEvent::Block { deployment, .. }
+            | ModificationsEvent::Revert { deployment, .. } => deployment,
+        }
+    }
+
+    /// The block the deployment is at after the event. Its number is the
+    /// offset from which consumers can replay the events of a deployment.
+    pub fn block_ptr(&self) -> &EthereumBlockPointer {
+        match self {
+            ModificationsEvent::Block { block_ptr, .. }
+            | ModificationsEvent::Revert { block_ptr, .. } => block_ptr,
+        }
+    }
+
+    pub fn block_number(&self) -> BlockNumber {
+        self.block_ptr().number
+    }
+
+    /// The event as a JSON document that sinks can publish as is.
+    pub fn to_json(&self) -> serde_json::Value {
+        let block = json!({
+            "number": self.block_number(),
+            "hash": format!("0x{:x}", self.block_ptr().hash),
+        });
+        match self {
+            ModificationsEvent::Block {
+                deployment,
+                modifications,
+                ..
+            } => {
+                let modifications: Vec<_> = modifications
+                    .iter()
+                    .map(|modification| {
+                        let key = modification.entity_key();
+                        let (op, data) = match modification {
+                            EntityModification::Insert { data, .. } => ("insert", Some(data)),
+                            EntityModification::Overwrite { data, .. } => ("overwrite", Some(data)),
+                            EntityModification::Remove { .. } => ("remove", None),
+                        };
+                        json!({
+                            "op": op,
+                            "entity": key.entity_type.as_str(),
+                            "id": key.entity_id,
+                            "data": data,
+                        })
+                    })
+                    .collect();
+                json!({
+                    "type": "block",
+                    "deployment": deployment.as_str(),
+                    "block": block,
+                    "modifications": modifications,
+                })
+            }
+            ModificationsEvent::Revert { deployment, .. } => json!({
+                "type": "revert",
+                "deployment": deployment.as_str(),
+                "block": block,
+            }),
+        }
+    }
+}
+
+/// A destination for the changes that deployments write to the store, for
+/// example the Kafka topics of `graph_core::KafkaModificationsSink`. Sinks
+/// have to keep the events of each deployment in order.
+pub trait ModificationsSink: Send + Sync + 'static {
+    /// Publish `event`. This is called on a dedicated thread, for one event
+    /// at a time and in the order in which the events happened. Events that
+    /// fail to publish are retried until they succeed.
+    fn publish(&self, event: &ModificationsEvent) -> Result<(), Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::store::{EntityKey, EntityType};
+    use crate::prelude::{web3::types::H256, Entity, Value};
+
+    fn key(id: &str) -> EntityKey {
+        EntityKey {
+            subgraph_id: SubgraphDeploymentId::new("QmModifications").unwrap(),
+            entity_type: EntityType::new("Thing".to_owned()),
+            entity_id: id.to_owned(),
+        }
+    }
+
+    #[test]
+    fn block_to_json() {
+        let mut data = Entity::new();
+        data.set("id", Value::from("one"));
+        let event = ModificationsEvent::Block {
+            deployment: SubgraphDeploymentId::new("QmModifications").unwrap(),
+            block_ptr: EthereumBlockPointer::from((H256::from_low_u64_be(7), 12u64)),
+            modifications: vec![
+                EntityModification::Insert {
+                    key: key("one"),
+                    data,
+                },
+                EntityModification::Remove { key: key("two") },
+            ],
+        };
+
+        assert_eq!(
+            json!({
+                "type": "block",
+                "deployment": "QmModifications",
+                "block": {
+                    "number": 12,
+                    "hash": format!("0x{:064x}", 7),
+                },
+                "modifications": [
+                    {
+                        "op": "insert",
+                        "entity": "Thing",
+                        "id": "one",
+                        "data": { "id": { "type": "String", "data": "one" } },
+                    },
+                    {
+                        "op": "remove",
+                        "entity": "Thing",
+                        "id": "two",
+                        "data": null,
+                    },
+                ],
+            }),
+            event.to_json()
+        );
+    }
+
+    #[test]
+    fn revert_to_json() {
+        let event = ModificationsEvent::Revert {
+            deployment: SubgraphDeploymentId::new("QmModifications").unwrap(),
+            block_ptr: EthereumBlockPointer::from((H256::from_low_u64_be(7), 11u64)),
+        };
+
+        assert_eq!(
+            json!({
+                "type": "revert",
+                "deployment": "QmModifications",
+                "block": {
+                    "number": 11,
+                    "hash": format!("0x{:064x}", 7),
+                },
+            }),
+            event.to_json()
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Error;
+
+use crate::prelude::{BlockNumber, EthereumBlockPointer, SubgraphDeploymentId};
+
+/// A reorg that a deployment went through, reported once when the
+/// deployment starts to revert blocks and once when it processes blocks
+/// again.
+#[derive(Clone, Debug)]
+pub enum ReorgEvent {
+    /// The deployment reverted `block_ptr`, the first block that is no
+    /// longer on the main chain, and may revert more blocks.
+    Started {
+        deployment: SubgraphDeploymentId,
+        block_ptr: EthereumBlockPointer,
+    },
+
+    /// The deployment reverted `depth` blocks and continues from
+    /// `block_ptr` on the main chain.
+    Finished {
+        deployment: SubgraphDeploymentId,
+        block_ptr: EthereumBlockPointer,
+        depth: BlockNumber,
+    },
+}
+
+impl ReorgEvent {
+    pub fn deployment(&self) -> &SubgraphDeploymentId {
+        match self {
+            ReorgEvent::Started { deployment, .. } | ReorgEvent::Finished { deployment, .. } => {
+                deployment
+            }
+        }
+    }
+
+    pub fn block_ptr(&self) -> &EthereumBlockPointer {
+        match self {
+            ReorgEvent::Started { block_ptr, .. } | ReorgEvent::Finished { block_ptr, .. } => {
+                block_ptr
+            }
+        }
+    }
+}
+
+/// Something outside of graph-node that keeps state derived from
+/// deployments, like a cache or a downstream indexer, and needs to know
+/// when a deployment reverts blocks.
+pub trait ReorgListener: Send + Sync + 'static {
+    /// Handle `event`. This is called on a dedicated thread, for one event
+    /// at a time and in the order in which the events happened. Events
+    /// that fail are logged and not retried.
+    fn notify(&self, event: &ReorgEvent) -> Result<(), Error>;
+}
@@ -1,6 +1,8 @@
 use crate::components::store::{EntityType, SubgraphStore};
-use crate::data::graphql::ext::{DirectiveExt, DirectiveFinder, DocumentExt, TypeExt, ValueExt};
-use crate::data::store::ValueType;
+use crate::data::graphql::ext::{
+    DirectiveExt, DirectiveFinder, DocumentExt, ObjectTypeExt, TypeExt, ValueExt,
+};
+use crate::data::store::{Entity, Value as StoreValue, ValueType};
 use crate::data::subgraph::{SubgraphDeploymentId, SubgraphName};
 use crate::prelude::{
     q::Value,
@@ -400,6 +402,30 @@ impl ApiSchema {
     }
 }
 
+/// A violation of the schema by an entity that is about to be written
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EntityValidationError {
+    #[error("Entity {entity_type}[{entity_id}]: unknown entity type")]
+    UnknownEntityType {
+        entity_type: String,
+        entity_id: String,
+    },
+
+    #[error("Entity {entity_type}[{entity_id}]: unknown field `{field}`")]
+    UnknownField {
+        entity_type: String,
+        entity_id: String,
+        field: String,
+    },
+
+    #[error("Entity {entity_type}[{entity_id}]: missing value for non-nullable field `{field}`")]
+    MissingValue {
+        entity_type: String,
+        entity_id: String,
+        field: String,
+    },
+}
+
 /// A validated and preprocessed GraphQL schema for a subgraph.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Schema {
@@ -523,6 +549,60 @@ impl Schema {
         Ok((interfaces_for_type, types_for_interface))
     }
 
+    /// Check that `entity` only has fields that are declared for
+    /// `entity_type` and that it has values for all non-nullable fields.
+    /// Derived fields are not stored and therefore not checked.
+    pub fn validate_entity(
+        &self,
+        entity_type: &EntityType,
+        entity_id: &str,
+        entity: &Entity,
+    ) -> Vec<EntityValidationError> {
+        let object_type = match self
+            .document
+            .get_object_type_definition(entity_type.as_str())
+        {
+            Some(object_type) => object_type,
+            None => {
+                return vec![EntityValidationError::UnknownEntityType {
+                    entity_type: entity_type.to_string(),
+                    entity_id: entity_id.to_string(),
+                }]
+            }
+        };
+
+        let mut errors = vec![];
+        for name in entity.keys() {
+            if object_type.field(name).is_none() {
+                errors.push(EntityValidationError::UnknownField {
+                    entity_type: entity_type.to_string(),
+                    entity_id: entity_id.to_string(),
+                    field: name.clone(),
+                });
+            }
+        }
+        for field in &object_type.fields {
+            let non_null = match field.field_type {
+                s::Type::NonNullType(_) => true,
+                _ => false,
+            };
+            let derived = field.find_directive("derivedFrom".to_string()).is_some();
+            if non_null && !derived {
+                match entity.get(&field.name) {
+                    None | Some(StoreValue::Null) => {
+                        errors.push(EntityValidationError::MissingValue {
+                            entity_type: entity_type.to_string(),
+                            entity_id: entity_id.to_string(),
+                            field: field.name.clone(),
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        errors
+    }
+
     pub fn parse(raw: &str, id: SubgraphDeploymentId) -> Result<Self, Error> {
         let document = graphql_parser::parse_schema(&raw)?.into_static();
 
@@ -1294,6 +1374,46 @@ impl Schema {
     }
 }
 
+#[test]
+fn validate_entity() {
+    use crate::entity;
+
+    let schema = "
+        type Thing @entity { id: ID!, name: String!, nick: String, others: [Thing!]! @derivedFrom(field: \"id\") }
+    ";
+    let schema = Schema::parse(schema, SubgraphDeploymentId::new("dummy").unwrap()).unwrap();
+    let thing = EntityType::new("Thing".to_owned());
+
+    let entity = entity! { id: "1", name: "one", };
+    assert_eq!(schema.validate_entity(&thing, "1", &entity), vec![]);
+
+    let entity = entity! { id: "1", nick: StoreValue::Null, color: "red", };
+    assert_eq!(
+        schema.validate_entity(&thing, "1", &entity),
+        vec![
+            EntityValidationError::UnknownField {
+                entity_type: "Thing".to_owned(),
+                entity_id: "1".to_owned(),
+                field: "color".to_owned()
+            },
+            EntityValidationError::MissingValue {
+                entity_type: "Thing".to_owned(),
+                entity_id: "1".to_owned(),
+                field: "name".to_owned()
+            }
+        ]
+    );
+
+    let other = EntityType::new("Other".to_owned());
+    assert_eq!(
+        schema.validate_entity(&other, "1", &entity! { id: "1", }),
+        vec![EntityValidationError::UnknownEntityType {
+            entity_type: "Other".to_owned(),
+            entity_id: "1".to_owned()
+        }]
+    );
+}
+
 #[test]
 fn non_existing_interface() {
     let schema = "type Foo implements Bar @entity { foo: Int }";
@@ -1,7 +1,7 @@
 mod data_source;
 
 use anyhow::{anyhow, ensure, Error};
-use ethabi::Contract;
+use ethabi::{Contract, Token};
 use futures03::{
     future::{try_join, try_join3},
     stream::FuturesOrdered,
@@ -24,13 +24,13 @@ use crate::components::store::{StoreError, SubgraphStore};
 use crate::data::graphql::TryFromValue;
 use crate::data::query::QueryExecutionError;
 use crate::data::schema::{Schema, SchemaImportError, SchemaValidationError};
-use crate::data::store::Entity;
-use crate::prelude::CheapClone;
+use crate::data::store::{scalar::BigInt, Entity};
+use crate::prelude::{CheapClone, MappingTrigger};
 
 use crate::prelude::{impl_slog_value, q, BlockNumber, Deserialize, Serialize};
 use crate::util::ethereum::string_to_h256;
 
-use crate::components::ethereum::NodeCapabilities;
+use crate::components::ethereum::{LightEthereumBlock, NodeCapabilities};
 use std::fmt;
 use std::ops::Deref;
 use std::str::FromStr;
@@ -386,6 +386,34 @@ pub enum SubgraphManifestValidationError {
     SchemaValidationError(Vec<SchemaValidationError>),
     #[error("the graft base is invalid: {0}")]
     GraftBaseInvalid(String),
+    #[error("the block predicate is invalid: {0}")]
+    BlockPredicateInvalid(String),
+}
+
+/// Why a deployment can not be grafted onto its base when it starts
+#[derive(Error, Debug)]
+pub enum GraftError {
+    #[error("the graft base `{0}` does not exist")]
+    BaseNotFound(SubgraphDeploymentId),
+    #[error(
+        "failed to graft onto `{base}` at block {block} since it has only processed block {}",
+        .processed.map_or("none".to_string(), |number| number.to_string())
+    )]
+    BaseNotSynced {
+        base: SubgraphDeploymentId,
+        block: BlockNumber,
+        processed: Option<BlockNumber>,
+    },
+    #[error(
+        "the schema is incompatible with the schema of the graft base `{base}`: {}",
+        .errors.join("; ")
+    )]
+    IncompatibleSchema {
+        base: SubgraphDeploymentId,
+        errors: Vec<String>,
+    },
+    #[error("store error while validating the graft: {0}")]
+    Store(#[from] StoreError),
 }
 
 #[derive(Error, Debug)]
@@ -517,6 +545,8 @@ pub struct MappingEventHandler {
     pub event: String,
     pub topic0: Option<H256>,
     pub handler: String,
+    #[serde(default)]
+    pub preload: Vec<PreloadHint>,
 }
 
 impl MappingEventHandler {
@@ -526,6 +556,58 @@ impl MappingEventHandler {
     }
 }
 
+/// An entity that an event handler loads, with an id that is taken from the
+/// event. The entities of all hints are loaded from the store in one batch
+/// before the triggers of a block are processed, instead of one by one
+/// while the handlers run.
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize)]
+pub struct PreloadHint {
+    pub entity: String,
+
+    /// Where the id comes from: `event.address`, `event.transaction.hash`,
+    /// `event.transaction.from` or `event.params.<name>`.
+    pub id: String,
+}
+
+impl PreloadHint {
+    /// The id of the entity to load for `trigger`, formatted the way
+    /// mappings commonly turn values into ids: addresses, hashes and bytes
+    /// as `0x` prefixed hex strings and integers as decimal strings. Returns
+    /// `None` if `trigger` is not an event or the hint can't be resolved
+    /// for it.
+    pub fn entity_id(&self, trigger: &MappingTrigger) -> Option<String> {
+        let (transaction, log, params) = match trigger {
+            MappingTrigger::Log {
+                transaction,
+                log,
+                params,
+                ..
+            } => (transaction, log, params),
+            _ => return None,
+        };
+
+        match self.id.as_str() {
+            "event.address" => Some(format!("0x{:x}", log.address)),
+            "event.transaction.hash" => Some(format!("0x{:x}", transaction.hash)),
+            "event.transaction.from" => Some(format!("0x{:x}", transaction.from)),
+            id => {
+                let name = id.strip_prefix("event.params.")?;
+                let param = params.iter().find(|param| param.name == name)?;
+                match &param.value {
+                    Token::Address(address) => Some(format!("0x{:x}", address)),
+                    Token::FixedBytes(bytes) | Token::Bytes(bytes) => {
+                        Some(format!("0x{}", hex::encode(bytes)))
+                    }
+                    Token::Uint(n) => Some(BigInt::from_unsigned_u256(n).to_string()),
+                    Token::Int(n) => Some(BigInt::from_signed_u256(n).to_string()),
+                    Token::String(s) => Some(s.clone()),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Hash, Eq, PartialEq, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UnresolvedMapping {
@@ -630,8 +712,8 @@ impl UnresolvedMapping {
 
         let api_version = Version::parse(&api_version)?;
 
-        ensure!(VersionReq::parse("<= 0.0.4").unwrap().matches(&api_version),
-            "The maximum supported mapping API version of this indexer is 0.0.4, but `{}` was found",
+        ensure!(VersionReq::parse("<= 0.0.5").unwrap().matches(&api_version),
+            "The maximum supported mapping API version of this indexer is 0.0.5, but `{}` was found",
             api_version
         );
 
@@ -771,9 +853,103 @@ impl Graft {
             }
         }
     }
+
+    /// Check that the deployment `id` can be grafted onto the base, i.e.,
+    /// that the base exists, has processed the graft block, and has a
+    /// compatible schema. This only matters until the data of the base has
+    /// been copied; after that, the base is no longer needed.
+    pub fn validate_start<S: SubgraphStore>(
+        &self,
+        store: &S,
+        id: &SubgraphDeploymentId,
+    ) -> Result<(), GraftError> {
+        let processed = match store.block_ptr(&self.base) {
+            Ok(ptr) => ptr.map(|ptr| ptr.number),
+            Err(e) => {
+                return Err(match e.downcast::<StoreError>() {
+                    Ok(StoreError::DeploymentNotFound(_)) => {
+                        GraftError::BaseNotFound(self.base.clone())
+                    }
+                    Ok(e) => e.into(),
+                    Err(e) => StoreError::Unknown(e).into(),
+                })
+            }
+        };
+        if processed.map_or(true, |number| number < self.block) {
+            return Err(GraftError::BaseNotSynced {
+                base: self.base.clone(),
+                block: self.block,
+                processed,
+            });
+        }
+
+        let errors = store.can_copy_from(id, &self.base)?;
+        if !errors.is_empty() {
+            return Err(GraftError::IncompatibleSchema {
+                base: self.base.clone(),
+                errors,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Which blocks a subgraph processes the triggers of. The triggers of other
+/// blocks are dropped, but the subgraph still moves past these blocks. All
+/// conditions only look at the block itself, and every node therefore
+/// processes the same blocks.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BlockPredicate {
+    /// The first block to process
+    pub min_block: Option<BlockNumber>,
+    /// The last block to process
+    pub max_block: Option<BlockNumber>,
+    /// Only process blocks whose number is a multiple of this
+    pub every: Option<BlockNumber>,
+    /// Only process blocks that used at least this much gas
+    pub min_gas_used: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+impl BlockPredicate {
+    fn validate(&self) -> Vec<SubgraphManifestValidationError> {
+        let mut errors = vec![];
+        if let (Some(min_block), Some(max_block)) = (self.min_block, self.max_block) {
+            if min_block > max_block {
+                errors.push(SubgraphManifestValidationError::BlockPredicateInvalid(
+                    format!(
+                        "`minBlock` {} is greater than `maxBlock` {}",
+                        min_block, max_block
+                    ),
+                ));
+            }
+        }
+        if self.every.map_or(false, |every| every <= 0) {
+            errors.push(SubgraphManifestValidationError::BlockPredicateInvalid(
+                "`every` must be positive".to_owned(),
+            ));
+        }
+        errors
+    }
+
+    /// Whether the triggers of `block` should be processed
+    pub fn matches(&self, block: &LightEthereumBlock) -> bool {
+        let number = block
+            .number
+            .map_or(0, |number| number.as_u64() as BlockNumber);
+        self.min_block.map_or(true, |min_block| number >= min_block)
+            && self.max_block.map_or(true, |max_block| number <= max_block)
+            && self
+                .every
+                .and_then(|every| number.checked_rem(every))
+                .map_or(true, |rem| rem == 0)
+            && self
+                .min_gas_used
+                .map_or(true, |min_gas_used| block.gas_used >= min_gas_used.into())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BaseSubgraphManifest<S, D, T> {
     pub id: SubgraphDeploymentId,
@@ -787,6 +963,7 @@ pub struct BaseSubgraphManifest<S, D, T> {
     pub graft: Option<Graft>,
     #[serde(default)]
     pub templates: Vec<T>,
+    pub block_predicate: Option<BlockPredicate>,
 }
 
 /// SubgraphManifest with IPFS links unresolved
@@ -906,6 +1083,10 @@ impl UnvalidatedSubgraphManifest {
             errors.extend(graft.validate(store));
         }
 
+        if let Some(block_predicate) = &self.0.block_predicate {
+            errors.extend(block_predicate.validate());
+        }
+
         match errors.is_empty() {
             true => Ok((self.0, validation_warnings)),
             false => Err(errors),
@@ -1037,6 +1218,7 @@ impl UnresolvedSubgraphManifest {
             data_sources,
             graft,
             templates,
+            block_predicate,
         } = self;
 
         match semver::Version::parse(&spec_version) {
@@ -1078,6 +1260,7 @@ impl UnresolvedSubgraphManifest {
             data_sources,
             graft,
             templates,
+            block_predicate,
         })
     }
 }
@@ -6,10 +6,12 @@ use lazy_static::lazy_static;
 use rand::rngs::OsRng;
 use rand::Rng;
 use stable_hash::{SequenceNumber, StableHash, StableHasher};
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::{fmt, fmt::Display};
 
 use super::SubgraphDeploymentId;
+use crate::components::subgraph::ErrorCategory;
 use crate::components::{ethereum::EthereumBlockPointer, store::EntityType};
 use crate::data::graphql::TryFromValue;
 use crate::data::store::Value;
@@ -173,7 +175,7 @@ impl<'a> From<&'a super::SubgraphManifest> for SubgraphManifestEntity {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SubgraphError {
     pub subgraph_id: SubgraphDeploymentId,
     pub message: String,
@@ -182,6 +184,38 @@ pub struct SubgraphError {
 
     // `true` if we are certain the error is deterministic. If in doubt, this is `false`.
     pub deterministic: bool,
+
+    /// Structured data about the error, for tools that analyze errors
+    /// without parsing `message`. Only set for errors in handlers, and not
+    /// for errors that were stored before it was introduced.
+    pub details: Option<SubgraphErrorDetails>,
+}
+
+/// What is known about a deterministic error in a handler besides its
+/// message. It is stored as JSON alongside the error.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubgraphErrorDetails {
+    pub code: ErrorCategory,
+
+    /// The entity the handler tried to write, if writing it failed.
+    pub entity_type: Option<String>,
+    pub entity_id: Option<String>,
+
+    /// Further information that depends on `code`, for example the trap
+    /// code for `mapping_trap`, the contract and function for `eth_call`,
+    /// or the message and source location for `abort`.
+    pub params: BTreeMap<String, String>,
+}
+
+impl SubgraphErrorDetails {
+    pub fn new(code: ErrorCategory) -> Self {
+        SubgraphErrorDetails {
+            code,
+            entity_type: None,
+            entity_id: None,
+            params: BTreeMap::new(),
+        }
+    }
 }
 
 impl Display for SubgraphError {
@@ -205,6 +239,10 @@ impl StableHash for SubgraphError {
             block_ptr,
             handler,
             deterministic,
+            // The details are derived from the same failure as the message
+            // and are left out so that the ids of errors, and the proof of
+            // indexing, are the same as before they were introduced
+            details: _,
         } = self;
         subgraph_id.stable_hash(sequence_number.next_child(), state);
         message.stable_hash(sequence_number.next_child(), state);
@@ -123,6 +123,7 @@ impl IntoValue for Info {
                 block_ptr,
                 handler,
                 deterministic,
+                details: _,
             } = subgraph_error;
 
             object! {
@@ -0,0 +1,100 @@
+use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use slog::*;
+
+/// A log level that can be changed while loggers that use it are running.
+/// Cloning the handle yields a handle to the same level.
+#[derive(Clone, Debug)]
+pub struct LogLevelHandle(Arc<AtomicUsize>);
+
+impl LogLevelHandle {
+    pub fn new(level: Level) -> Self {
+        LogLevelHandle(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    pub fn level(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Trace)
+    }
+
+    pub fn set_level(&self, level: Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+}
+
+impl Default for LogLevelHandle {
+    /// A level that lets all messages through
+    fn default() -> Self {
+        Self::new(Level::Trace)
+    }
+}
+
+/// An slog `Drain` that only forwards messages that are at least as severe
+/// as the current level of a `LogLevelHandle`.
+struct DynamicLevelFilter<D: Drain> {
+    drain: D,
+    level: LogLevelHandle,
+}
+
+impl<D: Drain> Drain for DynamicLevelFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> StdResult<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.level.level()) {
+            self.drain.log(record, values).map(|_| ())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Creates a logger that forwards messages to `logger` if they are at least
+/// as severe as the current level of `level`. Since `logger` still filters
+/// messages itself, the level can only be used to make logging less verbose
+/// than `logger` is.
+pub fn dynamic_level_logger(logger: Logger, level: LogLevelHandle) -> Logger {
+    Logger::root(
+        DynamicLevelFilter {
+            drain: logger,
+            level,
+        },
+        o!(),
+    )
+}
+
+#[test]
+fn dynamic_level_logger_follows_level() {
+    struct CountingDrain(Arc<AtomicUsize>);
+
+    impl Drain for CountingDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, _: &Record, _: &OwnedKVList) -> StdResult<(), Never> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let level = LogLevelHandle::default();
+    let logger = dynamic_level_logger(
+        Logger::root(CountingDrain(count.clone()), o!()),
+        level.clone(),
+    );
+
+    debug!(logger, "logged");
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    // Changing the level affects the logger right away
+    level.set_level(Level::Warning);
+    debug!(logger, "dropped");
+    warn!(logger, "logged");
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    level.set_level(Level::Trace);
+    debug!(logger, "logged");
+    assert_eq!(count.load(Ordering::SeqCst), 3);
+}
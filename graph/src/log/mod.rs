@@ -28,6 +28,7 @@ use std::{env, fmt, io, result};
 pub mod codes;
 pub mod elastic;
 pub mod factory;
+pub mod level;
 pub mod split;
 
 pub fn logger(show_debug: bool) -> Logger {
@@ -45,24 +45,56 @@ impl<K, V: Default + CacheWeight> CacheEntry<K, V> {
 impl<K: CacheWeight, V: Default + CacheWeight> CacheEntry<K, V> {
     /// Estimate the size of a `CacheEntry` with the given key and value
     fn weight(key: &K, value: &V) -> usize {
-        value.indirect_weight() + key.indirect_weight() + std::mem::size_of::<Self>()
+        value.indirect_weight()
+            + key.indirect_weight()
+            + std::mem::size_of::<Self>()
+            + std::mem::size_of::<Priority>()
     }
 }
 
-// The priorities are `(stale, frequency)` tuples, first all stale entries will be popped and
-// then non-stale entries by least frequency.
-type Priority = (bool, Reverse<u64>);
+// The priorities are `(stale, frequency, insertion)` tuples, first all stale entries will be popped
+// and then non-stale entries by least frequency. Entries with the same staleness and frequency are
+// popped in the order in which they were inserted.
+type Priority = (bool, Reverse<u64>, Reverse<u64>);
+
+/// Statistics about a call to `evict` that actually evicted entries.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EvictStats {
+    /// The weight of the cache before anything was evicted.
+    pub old_weight: usize,
+    /// The weight of the cache after eviction.
+    pub new_weight: usize,
+    /// The sum of the weight of all evicted entries.
+    pub evicted_weight: usize,
+    /// The number of entries in the cache before anything was evicted.
+    pub old_count: usize,
+    /// The number of evicted entries.
+    pub evicted_count: usize,
+}
+
+impl EvictStats {
+    /// The fraction of entries that were evicted, between 0 and 1.
+    pub fn evicted_fraction(&self) -> f64 {
+        if self.old_count == 0 {
+            0.0
+        } else {
+            self.evicted_count as f64 / self.old_count as f64
+        }
+    }
+}
 
 /// Each entry in the cache has a frequency, which is incremented by 1 on access. Entries also have
 /// a weight, upon eviction first stale entries will be removed and then non-stale entries by order
-/// of least frequency until the max weight is respected. This cache only removes entries on calls
-/// to `evict`, so the max weight may be exceeded until `evict` is called. Every STALE_PERIOD
-/// evictions entities are checked for staleness.
+/// of least frequency until the max weight is respected. Entries with the same priority are
+/// removed in the order in which they were inserted, so that caches that saw the same accesses
+/// evict the same entries. This cache only removes entries on calls to `evict`, so the max weight may be exceeded
+/// until `evict` is called. Every STALE_PERIOD evictions entities are checked for staleness.
 #[derive(Debug)]
 pub struct LfuCache<K: Eq + Hash, V> {
     queue: PriorityQueue<CacheEntry<K, V>, Priority>,
     total_weight: usize,
     stale_counter: u64,
+    insertions: u64,
 }
 
 impl<K: Ord + Eq + Hash, V> Default for LfuCache<K, V> {
@@ -71,6 +103,7 @@ impl<K: Ord + Eq + Hash, V> Default for LfuCache<K, V> {
             queue: PriorityQueue::new(),
             total_weight: 0,
             stale_counter: 0,
+            insertions: 0,
         }
     }
 }
@@ -81,6 +114,7 @@ impl<K: Clone + Ord + Eq + Hash + Debug + CacheWeight, V: CacheWeight + Default>
             queue: PriorityQueue::new(),
             total_weight: 0,
             stale_counter: 0,
+            insertions: 0,
         }
     }
 
@@ -90,6 +124,7 @@ impl<K: Clone + Ord + Eq + Hash + Debug + CacheWeight, V: CacheWeight + Default>
         match self.get_mut(key.clone()) {
             None => {
                 self.total_weight += weight;
+                self.insertions += 1;
                 self.queue.push(
                     CacheEntry {
                         weight,
@@ -97,7 +132,7 @@ impl<K: Clone + Ord + Eq + Hash + Debug + CacheWeight, V: CacheWeight + Default>
                         value,
                         will_stale: false,
                     },
-                    (false, Reverse(1)),
+                    (false, Reverse(1), Reverse(self.insertions)),
                 );
             }
             Some(entry) => {
@@ -123,7 +158,7 @@ impl<K: Clone + Ord + Eq + Hash + Debug + CacheWeight, V: CacheWeight + Default>
         // Increment the frequency by 1
         let key_entry = CacheEntry::cache_key(key);
         self.queue
-            .change_priority_by(&key_entry, |(s, Reverse(f))| (s, Reverse(f + 1)));
+            .change_priority_by(&key_entry, |(s, Reverse(f), k)| (s, Reverse(f + 1), k));
         self.queue.get_mut(&key_entry).map(|x| {
             x.0.will_stale = false;
             x.0
@@ -139,7 +174,10 @@ impl<K: Clone + Ord + Eq + Hash + Debug + CacheWeight, V: CacheWeight + Default>
         // the absolute minimum and popping.
         let key_entry = CacheEntry::cache_key(key.clone());
         self.queue
-            .change_priority(&key_entry, (true, Reverse(u64::min_value())))
+            .change_priority(
+                &key_entry,
+                (true, Reverse(u64::min_value()), Reverse(u64::min_value())),
+            )
             .and_then(|_| {
                 self.queue.pop().map(|(e, _)| {
                     assert_eq!(e.key, key_entry.key);
@@ -163,8 +201,21 @@ impl<K: Clone + Ord + Eq + Hash + Debug + CacheWeight, V: CacheWeight + Default>
         self.queue.len()
     }
 
+    /// The sum of the weights of all entries in the cache.
+    pub fn total_weight(&self) -> usize {
+        self.total_weight
+    }
+
+    /// Iterate over the entries in the cache in no particular order. Unlike
+    /// `get`, this does not count as an access to the entries.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.queue
+            .iter()
+            .map(|(entry, _)| (&entry.key, &entry.value))
+    }
+
     /// Same as `evict_with_period(max_weight, STALE_PERIOD)`
-    pub fn evict(&mut self, max_weight: usize) -> Option<(usize, usize, usize)> {
+    pub fn evict(&mut self, max_weight: usize) -> Option<EvictStats> {
         self.evict_with_period(max_weight, STALE_PERIOD)
     }
 
@@ -172,15 +223,14 @@ impl<K: Clone + Ord + Eq + Hash + Debug + CacheWeight, V: CacheWeight + Default>
     /// equal to or smaller than `max_weight`.
     ///
     /// The return value is mostly useful for testing and diagnostics and can
-    /// safely ignored in normal use. It gives the sum of the weight of all
-    /// evicted entries, the weight before anything was evicted and the new
-    /// total weight of the cache, in that order, if anything was evicted
-    /// at all. If there was no reason to evict, `None` is returned.
+    /// safely ignored in normal use. It describes how much was evicted, if
+    /// anything was evicted at all. If there was no reason to evict, `None`
+    /// is returned.
     pub fn evict_with_period(
         &mut self,
         max_weight: usize,
         stale_period: u64,
-    ) -> Option<(usize, usize, usize)> {
+    ) -> Option<EvictStats> {
         if self.total_weight <= max_weight {
             return None;
         }
@@ -199,18 +249,23 @@ impl<K: Clone + Ord + Eq + Hash + Debug + CacheWeight, V: CacheWeight + Default>
             }
         }
 
-        let mut evicted = 0;
-        let old_weight = self.total_weight;
+        let mut stats = EvictStats {
+            old_weight: self.total_weight,
+            old_count: self.queue.len(),
+            ..EvictStats::default()
+        };
         while self.total_weight > max_weight {
             let entry = self
                 .queue
                 .pop()
                 .expect("empty cache but total_weight > max_weight")
                 .0;
-            evicted += entry.weight;
+            stats.evicted_weight += entry.weight;
+            stats.evicted_count += 1;
             self.total_weight -= entry.weight;
         }
-        return Some((evicted, old_weight, self.total_weight));
+        stats.new_weight = self.total_weight;
+        return Some(stats);
     }
 }
 
@@ -225,7 +280,15 @@ impl<K: Ord + Eq + Hash + 'static, V: 'static> IntoIterator for LfuCache<K, V> {
 
 impl<K: Ord + Eq + Hash, V> Extend<(CacheEntry<K, V>, Priority)> for LfuCache<K, V> {
     fn extend<T: IntoIterator<Item = (CacheEntry<K, V>, Priority)>>(&mut self, iter: T) {
-        self.queue.extend(iter);
+        // The entries count as inserted after the entries in this cache, in
+        // the order in which they were inserted into their own cache
+        let mut entries: Vec<_> = iter.into_iter().collect();
+        entries.sort_by_key(|(_, (_, _, Reverse(insertion)))| *insertion);
+        for (entry, (stale, frequency, _)) in entries {
+            self.insertions += 1;
+            self.queue
+                .push(entry, (stale, frequency, Reverse(self.insertions)));
+        }
     }
 }
 
@@ -254,13 +317,23 @@ fn entity_lru_cache() {
     assert_eq!(cache.get(&"panda"), Some(&Weight(2)));
 
     // Nothing is evicted.
-    cache.evict(panda_weight + cow_weight);
+    assert_eq!(cache.evict(panda_weight + cow_weight), None);
     assert_eq!(cache.len(), 2);
 
+    let mut entries: Vec<_> = cache.iter().map(|(key, _)| *key).collect();
+    entries.sort();
+    assert_eq!(entries, vec!["cow", "panda"]);
+
     // "cow" was accessed twice, so "panda" is evicted.
     cache.get(&"cow");
-    cache.evict(cow_weight);
+    let stats = cache.evict(cow_weight).unwrap();
     assert!(cache.get(&"panda").is_none());
+    assert_eq!(stats.evicted_count, 1);
+    assert_eq!(stats.old_count, 2);
+    assert_eq!(stats.evicted_weight, panda_weight);
+    assert_eq!(stats.old_weight, panda_weight + cow_weight);
+    assert_eq!(stats.new_weight, cow_weight);
+    assert_eq!(stats.evicted_fraction(), 0.5);
 
     cache.insert("alligator", Weight(2));
     let alligator_weight = cache.weight("alligator");
@@ -294,3 +367,38 @@ fn entity_lru_cache() {
     assert!(cache.get(&"alligator").is_none());
     assert_eq!(cache.get(&"lion"), Some(&Weight(lion_inner_weight)));
 }
+
+#[test]
+fn evict_ties_by_insertion() {
+    #[derive(Default, Debug, PartialEq, Eq)]
+    struct Weight(usize);
+
+    impl CacheWeight for Weight {
+        fn weight(&self) -> usize {
+            self.indirect_weight()
+        }
+
+        fn indirect_weight(&self) -> usize {
+            self.0
+        }
+    }
+
+    // Entries with the same frequency are evicted in the order in which
+    // they were inserted
+    for (keys, kept) in &[
+        (["b", "c", "a", "d"], ["a", "d"]),
+        (["d", "a", "c", "b"], ["b", "c"]),
+    ] {
+        let mut cache: LfuCache<&'static str, Weight> = LfuCache::new();
+        for key in keys {
+            cache.insert(*key, Weight(1));
+        }
+        let weight = cache.weight("a");
+
+        let stats = cache.evict(2 * weight).unwrap();
+        assert_eq!(stats.evicted_count, 2);
+        let mut entries: Vec<_> = cache.iter().map(|(key, _)| *key).collect();
+        entries.sort();
+        assert_eq!(entries, kept.to_vec());
+    }
+}
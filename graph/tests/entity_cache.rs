@@ -2,8 +2,9 @@ use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use graph::prelude::{
-    Entity, EntityCache, EntityKey, EntityModification, SubgraphDeploymentId, Value,
+    BlockState, Entity, EntityCache, EntityKey, EntityModification, SubgraphDeploymentId, Value,
 };
+use graph::util::lfu_cache::LfuCache;
 use graph::{components::store::EntityType, mock::MockStore};
 
 fn make_band(id: &'static str, data: Vec<(&str, Value)>) -> (EntityKey, Entity) {
@@ -199,3 +200,132 @@ fn consecutive_modifications() {
         },])
     );
 }
+
+#[test]
+fn store_reads() {
+    let mut store = MockStore::new();
+    store
+        .expect_get_many_mock()
+        .returning(|_, _| Ok(BTreeMap::new()));
+
+    let store = Arc::new(store);
+    let mut cache = EntityCache::new(store.clone());
+
+    let (mogwai_key, mogwai_data) = make_band(
+        "mogwai",
+        vec![("id", "mogwai".into()), ("name", "Mogwai".into())],
+    );
+    cache.set(mogwai_key.clone(), mogwai_data.clone());
+
+    let result = cache.as_modifications(&*store).unwrap();
+    assert_eq!(result.store_reads, 1);
+
+    // The entity is cached now and not read again
+    let mut cache = EntityCache::with_current(store.clone(), result.entity_lfu_cache);
+    cache.set(mogwai_key, mogwai_data);
+
+    let result = cache.as_modifications(&*store).unwrap();
+    assert_eq!(result.store_reads, 0);
+}
+
+fn fork_test_store() -> Arc<MockStore> {
+    let mut store = MockStore::new();
+    store
+        .expect_get_many_mock()
+        .returning(|_, _| Ok(BTreeMap::new()));
+    Arc::new(store)
+}
+
+#[test]
+fn independent_forks_match_serial_changes() {
+    let store = fork_test_store();
+    let (mogwai_key, mogwai_data) = make_band(
+        "mogwai",
+        vec![("id", "mogwai".into()), ("name", "Mogwai".into())],
+    );
+    let (sigurros_key, sigurros_data) = make_band(
+        "sigurros",
+        vec![("id", "sigurros".into()), ("name", "Sigur Ros".into())],
+    );
+    let (slint_key, slint_data) = make_band(
+        "slint",
+        vec![("id", "slint".into()), ("name", "Slint".into())],
+    );
+
+    let mut serial = BlockState::new(store.clone(), LfuCache::new());
+    serial
+        .entity_cache
+        .set(mogwai_key.clone(), mogwai_data.clone());
+    serial
+        .entity_cache
+        .set(sigurros_key.clone(), sigurros_data.clone());
+    serial
+        .entity_cache
+        .set(slint_key.clone(), slint_data.clone());
+
+    let mut state = BlockState::new(store.clone(), LfuCache::new());
+    state.entity_cache.set(mogwai_key, mogwai_data);
+    let mut forks = state.forks(2);
+    forks[0].entity_cache.set(sigurros_key, sigurros_data);
+    forks[1].entity_cache.set(slint_key, slint_data);
+    assert!(state.join_forks(forks));
+
+    assert_eq!(
+        sort_by_entity_key(
+            state
+                .entity_cache
+                .as_modifications(&*store)
+                .unwrap()
+                .modifications
+        ),
+        sort_by_entity_key(
+            serial
+                .entity_cache
+                .as_modifications(&*store)
+                .unwrap()
+                .modifications
+        )
+    );
+}
+
+#[test]
+fn overlapping_forks_are_not_merged() {
+    let store = fork_test_store();
+    let (mogwai_key, mogwai_data) = make_band(
+        "mogwai",
+        vec![("id", "mogwai".into()), ("name", "Mogwai".into())],
+    );
+    let (_, renamed_data) = make_band(
+        "mogwai",
+        vec![("id", "mogwai".into()), ("name", "Mogwai!".into())],
+    );
+    let (sigurros_key, sigurros_data) = make_band(
+        "sigurros",
+        vec![("id", "sigurros".into()), ("name", "Sigur Ros".into())],
+    );
+
+    let mut state = BlockState::new(store.clone(), LfuCache::new());
+    state
+        .entity_cache
+        .set(sigurros_key.clone(), sigurros_data.clone());
+    let mut forks = state.forks(2);
+    forks[0]
+        .entity_cache
+        .set(mogwai_key.clone(), mogwai_data.clone());
+    forks[1].entity_cache.set(mogwai_key, renamed_data);
+    assert!(!state.join_forks(forks));
+
+    // Only the changes from before forking are left, so that the handlers
+    // can be run again one after the other
+    assert_eq!(
+        state
+            .entity_cache
+            .as_modifications(&*store)
+            .unwrap()
+            .modifications,
+        vec![EntityModification::Insert {
+            key: sigurros_key,
+            data: sigurros_data,
+        }]
+    );
+}
@@ -9,8 +9,8 @@ use graph::components::{
     store::EntityType,
 };
 use graph::prelude::{
-    anyhow, Entity, Link, SubgraphDeploymentId, SubgraphManifest, SubgraphManifestValidationError,
-    UnvalidatedSubgraphManifest,
+    anyhow, Counter, Entity, Link, SubgraphDeploymentId, SubgraphManifest,
+    SubgraphManifestValidationError, UnvalidatedSubgraphManifest,
 };
 
 use test_store::LOGGER;
@@ -36,6 +36,10 @@ impl LinkResolverTrait for TextResolver {
         self
     }
 
+    fn with_retry_counter(self, _counter: Counter) -> Self {
+        self
+    }
+
     async fn cat(&self, _logger: &Logger, link: &Link) -> Result<Vec<u8>, anyhow::Error> {
         self.texts
             .get(&link.link)
@@ -121,6 +125,68 @@ specVersion: 0.0.2
     assert_eq!(12345, graft.block);
 }
 
+#[tokio::test]
+async fn block_predicate_manifest() {
+    const YAML: &str = "
+dataSources: []
+schema:
+  file:
+    /: /ipfs/Qmschema
+blockPredicate:
+  minBlock: 100
+  every: 10
+specVersion: 0.0.2
+";
+
+    let manifest = resolve_manifest(YAML).await;
+
+    let predicate = manifest
+        .block_predicate
+        .expect("The manifest has a block predicate");
+    assert_eq!(Some(100), predicate.min_block);
+    assert_eq!(None, predicate.max_block);
+    assert_eq!(Some(10), predicate.every);
+    assert_eq!(None, predicate.min_gas_used);
+}
+
+#[test]
+fn block_predicate_invalid_manifest() {
+    const YAML: &str = "
+dataSources: []
+schema:
+  file:
+    /: /ipfs/Qmschema
+blockPredicate:
+  minBlock: 100
+  maxBlock: 10
+  every: 0
+specVersion: 0.0.2
+";
+
+    let store = test_store::STORE.subgraph_store();
+
+    test_store::STORE_RUNTIME.lock().unwrap().block_on(async {
+        let unvalidated = resolve_unvalidated(YAML).await;
+
+        // The test manifest has other problems, too; we only care about
+        // the checks of the block predicate
+        let msgs: Vec<_> = unvalidated
+            .validate(store)
+            .expect_err("Validation must fail")
+            .into_iter()
+            .filter(|e| matches!(e, SubgraphManifestValidationError::BlockPredicateInvalid(_)))
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "the block predicate is invalid: `minBlock` 100 is greater than `maxBlock` 10",
+                "the block predicate is invalid: `every` must be positive",
+            ],
+            msgs
+        );
+    })
+}
+
 #[test]
 fn graft_invalid_manifest() {
     const YAML: &str = "
@@ -57,6 +57,7 @@ fn setup_with_features(id: &str, features: BTreeSet<SubgraphFeature>) -> Subgrap
         data_sources: vec![],
         graft: None,
         templates: vec![],
+        block_predicate: None,
     };
 
     insert_test_entities(STORE.subgraph_store().as_ref(), manifest);
@@ -1701,6 +1702,7 @@ fn non_fatal_errors() {
                 block_ptr: Some(BLOCK_TWO.block_ptr()),
                 handler: Some("handleMoo".to_string()),
                 deterministic: true,
+                details: None,
             };
 
             transact_errors(&*STORE, id.clone(), BLOCK_TWO.block_ptr(), vec![err]).unwrap();
@@ -52,13 +52,16 @@ impl BlockStreamBuilder for MockBlockStreamBuilder {
         _logger: Logger,
         _deployment_id: SubgraphDeploymentId,
         _network_name: String,
+        _: Option<Arc<dyn EthereumAdapter>>,
         _start_blocks: Vec<BlockNumber>,
         _: EthereumLogFilter,
         _: EthereumCallFilter,
         _: EthereumBlockFilter,
         _: bool,
+        _: BlockStreamProfile,
+        _: BlockStreamPointer,
         _: Arc<BlockStreamMetrics>,
-    ) -> Self::Stream {
-        MockBlockStream::new()
+    ) -> Result<Self::Stream, Error> {
+        Ok(MockBlockStream::new())
     }
 }
@@ -1,6 +1,6 @@
 use mockall::predicate::*;
 use mockall::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use graph::components::store::EntityType;
 use graph::components::store::StoredDynamicDataSource;
@@ -92,6 +92,21 @@ impl SubgraphStore for MockStore {
         unimplemented!()
     }
 
+    fn get_proof_of_indexing_regions<'a>(
+        self: Arc<Self>,
+        _subgraph_id: &'a SubgraphDeploymentId,
+        _block: EthereumBlockPointer,
+    ) -> DynTryFuture<'a, Option<BTreeMap<String, Vec<u8>>>> {
+        unimplemented!()
+    }
+
+    fn last_proof_of_indexing_block(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        unimplemented!()
+    }
+
     fn find(&self, _query: EntityQuery) -> Result<Vec<Entity>, QueryExecutionError> {
         unimplemented!()
     }
@@ -120,7 +135,7 @@ impl SubgraphStore for MockStore {
         &self,
         _subgraph_id: SubgraphDeploymentId,
         _block_ptr_to: EthereumBlockPointer,
-    ) -> Result<(), StoreError> {
+    ) -> Result<BTreeSet<EntityType>, StoreError> {
         unimplemented!()
     }
 
@@ -191,6 +206,88 @@ impl SubgraphStore for MockStore {
         unimplemented!()
     }
 
+    fn rewind(&self, _: SubgraphDeploymentId, _: EthereumBlockPointer) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn fatal_error(&self, _: &SubgraphDeploymentId) -> Result<Option<SubgraphError>, StoreError> {
+        unimplemented!()
+    }
+
+    fn backfill_chunk(
+        &self,
+        _: &SubgraphDeploymentId,
+    ) -> Result<Option<(BlockNumber, BlockNumber)>, StoreError> {
+        unimplemented!()
+    }
+
+    fn set_backfill_chunk(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: Option<(BlockNumber, BlockNumber)>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn skip_blocks(&self, _: &SubgraphDeploymentId) -> Result<BTreeSet<BlockNumber>, StoreError> {
+        unimplemented!()
+    }
+
+    fn set_skip_blocks(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: &BTreeSet<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn skipped_blocks(&self, _: &SubgraphDeploymentId) -> Result<Vec<BlockNumber>, StoreError> {
+        unimplemented!()
+    }
+
+    fn record_skipped_block(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: BlockNumber,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn disable_fail_fast(&self, _: &SubgraphDeploymentId) -> Result<Option<bool>, StoreError> {
+        unimplemented!()
+    }
+
+    fn set_disable_fail_fast(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: Option<bool>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn poi_write_interval(
+        &self,
+        _: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        unimplemented!()
+    }
+
+    fn set_poi_write_interval(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: Option<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn can_copy_from(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, StoreError> {
+        unimplemented!()
+    }
+
     fn is_deployment_synced(&self, _: &SubgraphDeploymentId) -> Result<bool, Error> {
         unimplemented!()
     }
@@ -229,4 +326,21 @@ impl SubgraphStore for MockStore {
     fn network_name(&self, _: &SubgraphDeploymentId) -> Result<String, StoreError> {
         unimplemented!()
     }
+
+    fn deterministic_errors(
+        &self,
+        _: &SubgraphDeploymentId,
+        _: usize,
+        _: usize,
+    ) -> Result<Vec<SubgraphError>, StoreError> {
+        unimplemented!()
+    }
+
+    fn tombstone(&self, _: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn clear_tombstone(&self, _: &SubgraphDeploymentId) -> Result<bool, StoreError> {
+        unimplemented!()
+    }
 }
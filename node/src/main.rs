@@ -23,7 +23,7 @@ use graph_chain_ethereum::{network_indexer, BlockIngestor, BlockStreamBuilder, T
 use graph_core::{
     three_box::ThreeBoxAdapter, LinkResolver, MetricsRegistry,
     SubgraphAssignmentProvider as IpfsSubgraphAssignmentProvider, SubgraphInstanceManager,
-    SubgraphRegistrar as IpfsSubgraphRegistrar,
+    SubgraphInstanceManagerConfig, SubgraphRegistrar as IpfsSubgraphRegistrar,
 };
 use graph_graphql::prelude::GraphQlRunner;
 use graph_runtime_wasm::RuntimeHostBuilder as WASMRuntimeHostBuilder;
@@ -321,6 +321,7 @@ async fn main() {
             block_stream_builder,
             metrics_registry.clone(),
             link_resolver.cheap_clone(),
+            SubgraphInstanceManagerConfig::from_env(),
         );
 
         // Create IPFS-based subgraph provider
@@ -11,7 +11,7 @@ use strum::AsStaticRef as _;
 use graph::components::arweave::ArweaveAdapter;
 use graph::components::ethereum::*;
 use graph::components::store::SubgraphStore;
-use graph::components::subgraph::{MappingError, SharedProofOfIndexing};
+use graph::components::subgraph::{BlockSeed, MappingError, SharedProofOfIndexing};
 use graph::components::three_box::ThreeBoxAdapter;
 use graph::prelude::{
     RuntimeHost as RuntimeHostTrait, RuntimeHostBuilder as RuntimeHostBuilderTrait, *,
@@ -116,6 +116,7 @@ where
     fn build(
         &self,
         network_name: String,
+        ethereum_adapter: Option<Arc<dyn EthereumAdapter>>,
         subgraph_id: SubgraphDeploymentId,
         data_source: DataSource,
         templates: Arc<Vec<DataSourceTemplate>>,
@@ -132,14 +133,18 @@ where
                 )
             })?;
 
-        let required_capabilities = data_source.mapping.required_capabilities();
-
-        let ethereum_adapter = self
-            .ethereum_networks
-            .adapter_with_capabilities(network_name.clone(), &required_capabilities)?;
+        let ethereum_adapter = match ethereum_adapter {
+            Some(ethereum_adapter) => ethereum_adapter,
+            None => {
+                let required_capabilities = data_source.mapping.required_capabilities();
+                self.ethereum_networks
+                    .adapter_with_capabilities(network_name.clone(), &required_capabilities)?
+                    .clone()
+            }
+        };
 
         RuntimeHost::new(
-            ethereum_adapter.clone(),
+            ethereum_adapter,
             self.link_resolver.clone(),
             self.store.clone(),
             cache,
@@ -210,6 +215,7 @@ impl RuntimeHost {
         trigger: MappingTrigger,
         block: &Arc<LightEthereumBlock>,
         proof_of_indexing: SharedProofOfIndexing,
+        seed: BlockSeed,
     ) -> Result<BlockState, MappingError> {
         let trigger_type = trigger.as_static();
         let handler = trigger.handler_name().to_string();
@@ -236,6 +242,7 @@ impl RuntimeHost {
                     host_exports: self.host_exports.cheap_clone(),
                     block: block.cheap_clone(),
                     proof_of_indexing,
+                    seed,
                 },
                 trigger,
                 result_sender,
@@ -282,14 +289,19 @@ impl RuntimeHostTrait for RuntimeHost {
         trigger: MappingTrigger,
         state: BlockState,
         proof_of_indexing: SharedProofOfIndexing,
+        seed: BlockSeed,
     ) -> Result<BlockState, MappingError> {
-        self.send_mapping_request(logger, state, trigger, block, proof_of_indexing)
+        self.send_mapping_request(logger, state, trigger, block, proof_of_indexing, seed)
             .await
     }
 
     fn creation_block_number(&self) -> Option<BlockNumber> {
         self.data_source.creation_block
     }
+
+    fn data_source(&self) -> &DataSource {
+        &self.data_source
+    }
 }
 
 impl PartialEq for RuntimeHost {
@@ -161,6 +161,8 @@ impl HostExports {
         )))
     }
 
+    /// Set the entity `entity_type` with id `entity_id`. Errors come with
+    /// the key of the entity, so that callers can tell which entity failed.
     pub(crate) fn store_set(
         &self,
         logger: &Logger,
@@ -168,6 +170,26 @@ impl HostExports {
         proof_of_indexing: &SharedProofOfIndexing,
         entity_type: String,
         entity_id: String,
+        data: HashMap<String, Value>,
+        stopwatch: &StopwatchMetrics,
+    ) -> Result<(), (anyhow::Error, EntityKey)> {
+        let key = EntityKey {
+            subgraph_id: self.subgraph_id.clone(),
+            entity_type: EntityType::new(entity_type),
+            entity_id,
+        };
+        match self.set_entity(logger, state, proof_of_indexing, &key, data, stopwatch) {
+            Ok(()) => Ok(()),
+            Err(e) => Err((e, key)),
+        }
+    }
+
+    fn set_entity(
+        &self,
+        logger: &Logger,
+        state: &mut BlockState,
+        proof_of_indexing: &SharedProofOfIndexing,
+        key: &EntityKey,
         mut data: HashMap<String, Value>,
         stopwatch: &StopwatchMetrics,
     ) -> Result<(), anyhow::Error> {
@@ -178,8 +200,8 @@ impl HostExports {
                 logger,
                 &self.causality_region,
                 &ProofOfIndexingEvent::SetEntity {
-                    entity_type: &entity_type,
-                    id: &entity_id,
+                    entity_type: key.entity_type.as_str(),
+                    id: &key.entity_id,
                     data: &data,
                 },
             );
@@ -188,14 +210,14 @@ impl HostExports {
 
         let id_insert_section = stopwatch.start_section("host_export_store_set__insert_id");
         // Automatically add an "id" value
-        match data.insert("id".to_string(), Value::String(entity_id.clone())) {
-            Some(ref v) if v != &Value::String(entity_id.clone()) => {
+        match data.insert("id".to_string(), Value::String(key.entity_id.clone())) {
+            Some(ref v) if v != &Value::String(key.entity_id.clone()) => {
                 return Err(anyhow!(
                     "Value of {} attribute 'id' conflicts with ID passed to `store.set()`: \
                      {} != {}",
-                    entity_type,
+                    key.entity_type,
                     v,
-                    entity_id,
+                    key.entity_id,
                 ));
             }
             _ => (),
@@ -203,14 +225,9 @@ impl HostExports {
 
         id_insert_section.end();
         let validation_section = stopwatch.start_section("host_export_store_set__validation");
-        let key = EntityKey {
-            subgraph_id: self.subgraph_id.clone(),
-            entity_type: EntityType::new(entity_type),
-            entity_id,
-        };
         let entity = Entity::from(data);
         let schema = self.store.input_schema(&self.subgraph_id)?;
-        let is_valid = validate_entity(&schema.document, &key, &entity).is_ok();
+        let is_valid = validate_entity(&schema.document, key, &entity).is_ok();
         state.entity_cache.set(key.clone(), entity);
 
         validation_section.end();
@@ -220,10 +237,10 @@ impl HostExports {
             stopwatch.start_section("host_export_store_set__post_validation");
             let entity = state
                 .entity_cache
-                .get(&key)
+                .get(key)
                 .map_err(|e| HostExportError::Unknown(e.into()))?
                 .expect("we just stored this entity");
-            validate_entity(&schema.document, &key, &entity)?;
+            validate_entity(&schema.document, key, &entity)?;
         }
         Ok(())
     }
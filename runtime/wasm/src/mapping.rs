@@ -2,7 +2,7 @@ use crate::module::{ExperimentalFeatures, WasmInstance};
 use futures::sync::mpsc;
 use futures03::channel::oneshot::Sender;
 use graph::components::ethereum::*;
-use graph::components::subgraph::{MappingError, SharedProofOfIndexing};
+use graph::components::subgraph::{BlockSeed, MappingError, SharedProofOfIndexing};
 use graph::prelude::*;
 use std::collections::BTreeMap;
 use std::sync::Arc;
@@ -128,6 +128,9 @@ pub(crate) struct MappingContext {
     pub(crate) block: Arc<LightEthereumBlock>,
     pub(crate) state: BlockState,
     pub(crate) proof_of_indexing: SharedProofOfIndexing,
+
+    /// The seed for randomness in the mapping; see `BlockSeed`.
+    pub(crate) seed: BlockSeed,
 }
 
 impl MappingContext {
@@ -138,6 +141,7 @@ impl MappingContext {
             block: self.block.clone(),
             state: BlockState::new(self.state.entity_cache.store.clone(), Default::default()),
             proof_of_indexing: self.proof_of_indexing.cheap_clone(),
+            seed: self.seed,
         }
     }
 }
@@ -1,5 +1,5 @@
 use std::cell::{RefCell, RefMut};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::ops::Deref;
 use std::rc::Rc;
@@ -14,9 +14,9 @@ use crate::host_exports;
 use crate::mapping::MappingContext;
 use anyhow::Error;
 use ethabi::LogParam;
-use graph::components::subgraph::MappingError;
+use graph::components::subgraph::{ErrorCategory, MappingError};
 use graph::data::store;
-use graph::data::subgraph::schema::SubgraphError;
+use graph::data::subgraph::schema::{SubgraphError, SubgraphErrorDetails};
 use graph::prelude::*;
 use host_exports::HostExportError;
 use web3::types::{Log, Transaction, U256};
@@ -203,7 +203,7 @@ impl WasmInstance {
         self.instance_ctx_mut().ctx.state.enter_handler();
 
         // This `match` will return early if there was a non-deterministic trap.
-        let deterministic_error: Option<Error> = match func.typed()?.call(arg.wasm_ptr()) {
+        let deterministic_error = match func.typed()?.call(arg.wasm_ptr()) {
             Ok(()) => None,
             Err(trap) if self.instance_ctx().possible_reorg => {
                 self.instance_ctx_mut().ctx.state.exit_handler();
@@ -221,6 +221,7 @@ impl WasmInstance {
                 use wasmtime::TrapCode::*;
                 let trap_code = trap.trap_code();
                 let e = Error::from(trap);
+                let host_trap = self.instance_ctx().deterministic_host_trap;
                 match trap_code {
                     Some(MemoryOutOfBounds)
                     | Some(HeapMisaligned)
@@ -230,17 +231,24 @@ impl WasmInstance {
                     | Some(IntegerOverflow)
                     | Some(IntegerDivisionByZero)
                     | Some(BadConversionToInteger)
-                    | Some(UnreachableCodeReached) => Some(e),
-                    _ if self.instance_ctx().deterministic_host_trap => Some(e),
-                    _ => {
-                        self.instance_ctx_mut().ctx.state.exit_handler();
-                        return Err(MappingError::Unknown(e));
+                    | Some(UnreachableCodeReached) => {
+                        self.instance_ctx_mut()
+                            .error_params
+                            .insert("trap".to_string(), format!("{:?}", trap_code.unwrap()));
+                        Some((e, ErrorCategory::MappingTrap))
                     }
+                    _ => match host_trap {
+                        Some(category) => Some((e, category)),
+                        None => {
+                            self.instance_ctx_mut().ctx.state.exit_handler();
+                            return Err(MappingError::Unknown(e));
+                        }
+                    },
                 }
             }
         };
 
-        if let Some(deterministic_error) = deterministic_error {
+        if let Some((deterministic_error, category)) = deterministic_error {
             let message = format!("{:#}", deterministic_error).replace("\n", "\t");
 
             // Log the error and restore the updates snapshot, effectively reverting the handler.
@@ -249,12 +257,19 @@ impl WasmInstance {
                 "handler" => handler,
                 "error" => &message,
             );
+            let mut details = SubgraphErrorDetails::new(category);
+            if let Some((entity_type, entity_id)) = self.instance_ctx_mut().error_entity.take() {
+                details.entity_type = Some(entity_type);
+                details.entity_id = Some(entity_id);
+            }
+            details.params = std::mem::take(&mut self.instance_ctx_mut().error_params);
             let subgraph_error = SubgraphError {
                 subgraph_id: self.instance_ctx().ctx.host_exports.subgraph_id.clone(),
                 message,
                 block_ptr: Some(self.instance_ctx().ctx.block.block_ptr()),
                 handler: Some(handler.to_string()),
                 deterministic: true,
+                details: Some(details),
             };
             self.instance_ctx_mut()
                 .ctx
@@ -309,7 +324,12 @@ pub(crate) struct WasmInstanceContext {
     possible_reorg: bool,
 
     // A host export trap ocurred for a deterministic reason.
-    deterministic_host_trap: bool,
+    deterministic_host_trap: Option<ErrorCategory>,
+
+    // The entity that a host export failed to write, and what else host
+    // exports know about the deterministic error; see `SubgraphErrorDetails`.
+    error_entity: Option<(String, String)>,
+    error_params: BTreeMap<String, String>,
 
     pub(crate) experimental_features: ExperimentalFeatures,
 }
@@ -406,7 +426,15 @@ impl WasmInstance {
                                 Err(e) => {
                                     match IntoTrap::determinism_level(&e) {
                                         DeterminismLevel::Deterministic => {
-                                            instance.deterministic_host_trap = true;
+                                            // Keep a more specific category
+                                            // the host function set
+                                            instance
+                                                .deterministic_host_trap
+                                                .get_or_insert(ErrorCategory::HostFunction);
+                                            instance
+                                                .error_params
+                                                .entry("host_function".to_string())
+                                                .or_insert_with(|| $wasm_name.to_string());
                                         },
                                         _ => {},
                                     }
@@ -449,21 +477,30 @@ impl WasmInstance {
                 // For apiVersion >= 0.0.4 the call passed from the mapping includes the
                 // function signature; subgraphs using an apiVersion < 0.0.4 don't pass
                 // the the signature along with the call.
-                let arg = if instance.ctx.host_exports.api_version >= Version::new(0, 0, 4) {
-                    instance.asc_get::<_, AscUnresolvedContractCall_0_0_4>(call_ptr.into())
-                } else {
-                    instance.asc_get::<_, AscUnresolvedContractCall>(call_ptr.into())
-                }
-                .map_err(|e| {
-                    instance.deterministic_host_trap = true;
-                    e.0
-                })?;
+                let arg: UnresolvedContractCall =
+                    if instance.ctx.host_exports.api_version >= Version::new(0, 0, 4) {
+                        instance.asc_get::<_, AscUnresolvedContractCall_0_0_4>(call_ptr.into())
+                    } else {
+                        instance.asc_get::<_, AscUnresolvedContractCall>(call_ptr.into())
+                    }
+                    .map_err(|e| {
+                        instance.deterministic_host_trap = Some(ErrorCategory::HostFunction);
+                        e.0
+                    })?;
 
+                let contract = format!("{} ({:?})", arg.contract_name, arg.contract_address);
+                let function = arg.function_name.clone();
                 let ret = instance
                     .ethereum_call(arg)
                     .map_err(|e| match e {
                         HostExportError::Deterministic(e) => {
-                            instance.deterministic_host_trap = true;
+                            instance.deterministic_host_trap = Some(ErrorCategory::EthCall);
+                            instance
+                                .error_params
+                                .insert("contract".to_string(), contract);
+                            instance
+                                .error_params
+                                .insert("function".to_string(), function);
                             e
                         }
                         HostExportError::Unknown(e) => e,
@@ -519,6 +556,7 @@ impl WasmInstance {
         link!("json.toBigInt", json_to_big_int, ptr);
 
         link!("crypto.keccak256", crypto_keccak_256, ptr);
+        link!("crypto.randomSeed", crypto_random_seed,);
 
         link!("bigInt.plus", big_int_plus, x_ptr, y_ptr);
         link!("bigInt.minus", big_int_minus, x_ptr, y_ptr);
@@ -663,7 +701,9 @@ impl WasmInstanceContext {
             arena_free_size: 0,
             arena_start_ptr: 0,
             possible_reorg: false,
-            deterministic_host_trap: false,
+            deterministic_host_trap: None,
+            error_entity: None,
+            error_params: BTreeMap::new(),
             experimental_features,
         })
     }
@@ -700,7 +740,9 @@ impl WasmInstanceContext {
             arena_free_size: 0,
             arena_start_ptr: 0,
             possible_reorg: false,
-            deterministic_host_trap: false,
+            deterministic_host_trap: None,
+            error_entity: None,
+            error_params: BTreeMap::new(),
             experimental_features,
         })
     }
@@ -734,6 +776,21 @@ impl WasmInstanceContext {
             _ => Some(column_number),
         };
 
+        self.deterministic_host_trap = Some(ErrorCategory::Abort);
+        let params = vec![
+            ("message", message.clone()),
+            ("file_name", file_name.clone()),
+            ("line_number", line_number.map(|line| line.to_string())),
+            (
+                "column_number",
+                column_number.map(|column| column.to_string()),
+            ),
+        ];
+        for (name, value) in params {
+            if let Some(value) = value {
+                self.error_params.insert(name.to_string(), value);
+            }
+        }
         self.ctx
             .host_exports
             .abort(message, file_name, line_number, column_number)
@@ -749,19 +806,25 @@ impl WasmInstanceContext {
         let stopwatch = &self.host_metrics.stopwatch;
         stopwatch.start_section("host_export_store_set__wasm_instance_context_store_set");
 
-        let entity = self.asc_get(entity_ptr)?;
-        let id = self.asc_get(id_ptr)?;
+        let entity: String = self.asc_get(entity_ptr)?;
+        let id: String = self.asc_get(id_ptr)?;
         let data = self.try_asc_get(data_ptr)?;
 
-        self.ctx.host_exports.store_set(
-            &self.ctx.logger,
-            &mut self.ctx.state,
-            &self.ctx.proof_of_indexing,
-            entity,
-            id,
-            data,
-            stopwatch,
-        )?;
+        self.ctx
+            .host_exports
+            .store_set(
+                &self.ctx.logger,
+                &mut self.ctx.state,
+                &self.ctx.proof_of_indexing,
+                entity,
+                id,
+                data,
+                stopwatch,
+            )
+            .map_err(|(e, key)| {
+                self.error_entity = Some((key.entity_type.into_string(), key.entity_id));
+                e
+            })?;
         Ok(())
     }
 
@@ -1052,6 +1115,22 @@ impl WasmInstanceContext {
         self.asc_new(input.as_ref())
     }
 
+    /// function crypto.randomSeed(): Bytes
+    ///
+    /// Only available from apiVersion 0.0.5 on, so that the mappings of
+    /// existing subgraphs can't start to depend on it.
+    fn crypto_random_seed(&mut self) -> Result<AscPtr<Uint8Array>, DeterministicHostError> {
+        let api_version = &self.ctx.host_exports.api_version;
+        if *api_version < Version::new(0, 0, 5) {
+            return Err(DeterministicHostError(anyhow!(
+                "crypto.randomSeed requires apiVersion 0.0.5 or later, but the mapping uses {}",
+                api_version
+            )));
+        }
+        let seed = self.ctx.seed.0;
+        self.asc_new(seed.as_ref())
+    }
+
     /// function bigInt.plus(x: BigInt, y: BigInt): BigInt
     fn big_int_plus(
         &mut self,
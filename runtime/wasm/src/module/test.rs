@@ -4,6 +4,7 @@ use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 
 use crate::host_exports::HostExports;
+use graph::components::subgraph::BlockSeed;
 use graph::data::store::scalar;
 use graph::data::subgraph::*;
 use graph::mock::MockEthereumAdapter;
@@ -202,6 +203,7 @@ fn mock_context(
     block.number = Some(0.into());
     MappingContext {
         logger: test_store::LOGGER.clone(),
+        seed: BlockSeed::from_block(&block),
         block: Arc::new(block),
         host_exports: Arc::new(mock_host_exports(
             subgraph_id,
@@ -492,6 +494,24 @@ async fn crypto_keccak256() {
     );
 }
 
+#[tokio::test]
+async fn crypto_random_seed() {
+    let mut module = test_module(
+        "cryptoRandomSeed",
+        mock_data_source("wasm_test/crypto.wasm"),
+    );
+    let seed: AscPtr<Uint8Array> = module.instance_ctx_mut().crypto_random_seed().unwrap();
+    let seed: Vec<u8> = module.asc_get(seed).unwrap();
+    let block = module.instance_ctx().ctx.block.cheap_clone();
+    assert_eq!(BlockSeed::from_block(&block).0.to_vec(), seed);
+
+    // Mappings with an older apiVersion don't get a seed
+    let mut data_source = mock_data_source("wasm_test/crypto.wasm");
+    data_source.mapping.api_version = Version::new(0, 0, 4);
+    let mut module = test_module("cryptoRandomSeedOldApi", data_source);
+    assert!(module.instance_ctx_mut().crypto_random_seed().is_err());
+}
+
 #[tokio::test]
 async fn big_int_to_hex() {
     let mut module = test_module(
@@ -0,0 +1,6 @@
+alter table
+    subgraphs.subgraph_deployment
+drop
+    column backfill_chunk_start,
+drop
+    column backfill_chunk_end;
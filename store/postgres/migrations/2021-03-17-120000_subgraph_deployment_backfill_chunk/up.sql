@@ -0,0 +1,6 @@
+alter table
+    subgraphs.subgraph_deployment
+add
+    column backfill_chunk_start int,
+add
+    column backfill_chunk_end int;
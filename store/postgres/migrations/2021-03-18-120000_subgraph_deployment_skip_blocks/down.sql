@@ -0,0 +1,6 @@
+alter table
+    subgraphs.subgraph_deployment
+drop
+    column skip_blocks,
+drop
+    column skipped_blocks;
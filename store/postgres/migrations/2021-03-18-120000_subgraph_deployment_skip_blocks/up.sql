@@ -0,0 +1,6 @@
+alter table
+    subgraphs.subgraph_deployment
+add
+    column skip_blocks int[] not null default '{}',
+add
+    column skipped_blocks int[] not null default '{}';
@@ -0,0 +1,4 @@
+alter table
+    subgraphs.subgraph_deployment
+drop
+    column disable_fail_fast;
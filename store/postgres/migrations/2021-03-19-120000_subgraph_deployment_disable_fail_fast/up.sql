@@ -0,0 +1,4 @@
+alter table
+    subgraphs.subgraph_deployment
+add
+    column disable_fail_fast boolean;
@@ -0,0 +1,4 @@
+alter table
+    subgraphs.subgraph_deployment
+drop
+    column poi_write_interval;
@@ -0,0 +1,4 @@
+alter table
+    subgraphs.subgraph_deployment
+add
+    column poi_write_interval int;
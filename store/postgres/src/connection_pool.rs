@@ -5,6 +5,7 @@ use diesel::{
 };
 
 use graph::{
+    components::store::{connection_wait_recorder, record_connection_wait},
     prelude::{
         anyhow::{self, anyhow, bail},
         debug, error, info, o,
@@ -17,7 +18,7 @@ use graph::{
 
 use std::fmt;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, sync::RwLock};
 
 use postgres::config::{Config, Host};
@@ -240,9 +241,11 @@ impl HandleEvent for EventHandler {
     fn handle_checkout(&self, event: e::CheckoutEvent) {
         self.count_gauge.inc();
         self.add_wait_time(event.duration());
+        record_connection_wait(event.duration());
     }
     fn handle_timeout(&self, event: e::TimeoutEvent) {
         self.add_wait_time(event.timeout());
+        record_connection_wait(event.timeout());
         error!(self.logger, "Connection checkout timed out";
            "wait_ms" => event.timeout().as_millis(),
            "backtrace" => format!("{:?}", backtrace::Backtrace::new()),
@@ -378,6 +381,11 @@ impl ConnectionPool {
                 &CancelHandle,
             ) -> Result<T, CancelableError<StoreError>>,
     ) -> Result<T, StoreError> {
+        // The connection is checked out on another thread, and the wait
+        // for it, including the wait for a permit, is recorded for the
+        // current one
+        let start = Instant::now();
+        let recorder = connection_wait_recorder();
         let _permit = self.limiter.acquire().await;
         let pool = self.clone();
 
@@ -394,6 +402,9 @@ impl ConnectionPool {
             let conn = pool
                 .get()
                 .map_err(|e| CancelableError::Error(StoreError::Unknown(e.into())))?;
+            if let Some(recorder) = recorder {
+                recorder.record(start.elapsed());
+            }
 
             // It is possible time has passed while establishing a connection.
             // Time to check for cancel.
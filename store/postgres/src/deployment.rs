@@ -13,7 +13,7 @@ use diesel::{
 };
 use graph::data::subgraph::{schema::SubgraphManifestEntity, SubgraphFeature};
 use graph::prelude::{
-    anyhow, bigdecimal::ToPrimitive, hex, web3::types::H256, BigDecimal, BlockNumber,
+    anyhow, bigdecimal::ToPrimitive, hex, serde_json, web3::types::H256, BigDecimal, BlockNumber,
     DeploymentState, EthereumBlockPointer, Schema, StoreError, SubgraphDeploymentId,
 };
 use graph::{data::subgraph::schema::SubgraphError, prelude::SubgraphDeploymentEntity};
@@ -70,6 +70,12 @@ table! {
         reorg_count -> Integer,
         current_reorg_depth -> Integer,
         max_reorg_depth -> Integer,
+        backfill_chunk_start -> Nullable<Integer>,
+        backfill_chunk_end -> Nullable<Integer>,
+        skip_blocks -> Array<Integer>,
+        skipped_blocks -> Array<Integer>,
+        disable_fail_fast -> Nullable<Bool>,
+        poi_write_interval -> Nullable<Integer>,
     }
 }
 
@@ -83,6 +89,7 @@ table! {
         handler -> Nullable<Text>,
         deterministic -> Bool,
         block_range -> Range<Integer>,
+        details -> Nullable<Jsonb>,
     }
 }
 
@@ -398,7 +405,9 @@ fn insert_subgraph_error(conn: &PgConnection, error: SubgraphError) -> anyhow::R
         handler,
         block_ptr,
         deterministic,
+        details,
     } = error;
+    let details = details.map(serde_json::to_value).transpose()?;
 
     let block_num = match &block_ptr {
         None => {
@@ -417,6 +426,7 @@ fn insert_subgraph_error(conn: &PgConnection, error: SubgraphError) -> anyhow::R
             e::deterministic.eq(deterministic),
             e::block_hash.eq(block_ptr.as_ref().map(|ptr| ptr.hash_slice())),
             e::block_range.eq((Bound::Included(block_num), Bound::Unbounded)),
+            e::details.eq(details),
         ))
         .on_conflict_do_nothing()
         .execute(conn)?;
@@ -473,6 +483,158 @@ pub(crate) fn has_non_fatal_errors(
     .map_err(|e| e.into())
 }
 
+/// The first and last block of the backfill chunk that the deployment is
+/// processing, or `None` if it is not processing one
+pub fn backfill_chunk(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<Option<(BlockNumber, BlockNumber)>, StoreError> {
+    use subgraph_deployment as d;
+
+    let chunk = d::table
+        .filter(d::deployment.eq(id.as_str()))
+        .select((d::backfill_chunk_start, d::backfill_chunk_end))
+        .first::<(Option<i32>, Option<i32>)>(conn)?;
+    match chunk {
+        (Some(start), Some(end)) => Ok(Some((start, end))),
+        _ => Ok(None),
+    }
+}
+
+pub fn set_backfill_chunk(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    chunk: Option<(BlockNumber, BlockNumber)>,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set((
+            d::backfill_chunk_start.eq(chunk.map(|(start, _)| start)),
+            d::backfill_chunk_end.eq(chunk.map(|(_, end)| end)),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The blocks whose triggers the deployment does not process
+pub fn skip_blocks(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<BTreeSet<BlockNumber>, StoreError> {
+    use subgraph_deployment as d;
+
+    let blocks = d::table
+        .filter(d::deployment.eq(id.as_str()))
+        .select(d::skip_blocks)
+        .first::<Vec<i32>>(conn)?;
+    Ok(blocks.into_iter().collect())
+}
+
+pub fn set_skip_blocks(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    blocks: &BTreeSet<BlockNumber>,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set(d::skip_blocks.eq(blocks.iter().cloned().collect::<Vec<_>>()))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The blocks whose triggers the deployment skipped because they were in
+/// its `skip_blocks`, in ascending order
+pub fn skipped_blocks(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<Vec<BlockNumber>, StoreError> {
+    use subgraph_deployment as d;
+
+    let mut blocks = d::table
+        .filter(d::deployment.eq(id.as_str()))
+        .select(d::skipped_blocks)
+        .first::<Vec<i32>>(conn)?;
+    blocks.sort_unstable();
+    Ok(blocks)
+}
+
+pub fn record_skipped_block(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    block: BlockNumber,
+) -> Result<(), StoreError> {
+    use diesel::sql_types::Text;
+
+    // Processing a block again, for example after a restart, does not
+    // record it twice
+    let query = "
+        update subgraphs.subgraph_deployment
+           set skipped_blocks = array_append(skipped_blocks, $1)
+         where deployment = $2
+           and not $1 = any(skipped_blocks)";
+    diesel::sql_query(query)
+        .bind::<Integer, _>(block)
+        .bind::<Text, _>(id.as_str())
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Whether deterministic errors of the deployment are kept non-fatal even
+/// if it is pending, or `None` if the node's default applies
+pub fn disable_fail_fast(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<Option<bool>, StoreError> {
+    use subgraph_deployment as d;
+
+    Ok(d::table
+        .filter(d::deployment.eq(id.as_str()))
+        .select(d::disable_fail_fast)
+        .first::<Option<bool>>(conn)?)
+}
+
+pub fn set_disable_fail_fast(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    disable: Option<bool>,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set(d::disable_fail_fast.eq(disable))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Every how many blocks the deployment writes its proof of indexing, or
+/// `None` if the node's default applies
+pub fn poi_write_interval(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<Option<BlockNumber>, StoreError> {
+    use subgraph_deployment as d;
+
+    Ok(d::table
+        .filter(d::deployment.eq(id.as_str()))
+        .select(d::poi_write_interval)
+        .first::<Option<i32>>(conn)?)
+}
+
+pub fn set_poi_write_interval(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    interval: Option<BlockNumber>,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as d;
+
+    update(d::table.filter(d::deployment.eq(id.as_str())))
+        .set(d::poi_write_interval.eq(interval))
+        .execute(conn)?;
+    Ok(())
+}
+
 /// Clear the `SubgraphHealth::Failed` status of a subgraph and mark it as
 /// healthy or unhealthy depending on whether it also had non-fatal errors
 pub fn unfail(conn: &PgConnection, id: &SubgraphDeploymentId) -> Result<(), StoreError> {
@@ -11,7 +11,7 @@ use graph::prelude::{
 };
 use lru_time_cache::LruCache;
 use rand::{seq::SliceRandom, thread_rng};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryInto;
 use std::iter::FromIterator;
 use std::ops::Deref;
@@ -552,6 +552,11 @@ impl DeploymentStore {
         Ok(cache.get(&subgraph_id).unwrap().clone())
     }
 
+    pub(crate) fn find_layout(&self, site: Arc<Site>) -> Result<Arc<Layout>, StoreError> {
+        let conn = self.get_conn()?;
+        self.layout(&conn, site)
+    }
+
     pub(crate) fn subgraph_info(
         &self,
         subgraph_id: &SubgraphDeploymentId,
@@ -667,24 +672,30 @@ impl DeploymentStore {
         .boxed()
     }
 
-    pub(crate) fn get_proof_of_indexing<'a>(
+    pub(crate) fn last_proof_of_indexing_block(
+        &self,
+        site: Arc<Site>,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let conn = self.get_conn()?;
+        let layout = self.layout(&conn, site)?;
+        layout.last_proof_of_indexing_block(&conn)
+    }
+
+    /// The digests of the causality regions of the proof of indexing of
+    /// the deployment at `block`, by the id of the region.
+    pub(crate) fn get_proof_of_indexing_regions<'a>(
         self: Arc<Self>,
         site: Arc<Site>,
-        indexer: &'a Option<Address>,
         block: EthereumBlockPointer,
-    ) -> DynTryFuture<'a, Option<[u8; 32]>> {
-        let indexer = indexer.clone();
-        let site3 = site.clone();
-        let site4 = site.clone();
+    ) -> DynTryFuture<'a, Option<BTreeMap<String, Vec<u8>>>> {
         let store = self.clone();
-        let block2 = block.clone();
 
         async move {
             let entities = self
                 .with_conn(move |conn, cancel| {
                     cancel.check_cancel()?;
 
-                    let layout = store.layout(conn, site4.clone())?;
+                    let layout = store.layout(conn, site.clone())?;
 
                     if !layout.supports_proof_of_indexing() {
                         return Ok(None);
@@ -711,12 +722,12 @@ impl DeploymentStore {
                         }
 
                         let query = EntityQuery::new(
-                            site4.deployment.clone(),
+                            site.deployment.clone(),
                             block.number.try_into().unwrap(),
                             EntityCollection::All(vec![POI_OBJECT.cheap_clone()]),
                         );
                         let entities = store
-                            .execute_query::<Entity>(conn, site4, query)
+                            .execute_query::<Entity>(conn, site, query)
                             .map_err(anyhow::Error::from)?;
 
                         Ok(Some(entities))
@@ -731,12 +742,12 @@ impl DeploymentStore {
                 return Ok(None);
             };
 
-            let mut by_causality_region = entities
+            let by_causality_region = entities
                 .into_iter()
                 .map(|e| {
                     let causality_region = e.id()?;
                     let digest = match e.get("digest") {
-                        Some(Value::Bytes(b)) => Ok(b.to_owned()),
+                        Some(Value::Bytes(b)) => Ok(b.to_vec()),
                         other => Err(anyhow::anyhow!(
                             "Entity has non-bytes digest attribute: {:?}",
                             other
@@ -745,10 +756,33 @@ impl DeploymentStore {
 
                     Ok((causality_region, digest))
                 })
-                .collect::<Result<HashMap<_, _>, anyhow::Error>>()?;
+                .collect::<Result<BTreeMap<_, _>, anyhow::Error>>()?;
+
+            Ok(Some(by_causality_region))
+        }
+        .boxed()
+    }
+
+    pub(crate) fn get_proof_of_indexing<'a>(
+        self: Arc<Self>,
+        site: Arc<Site>,
+        indexer: &'a Option<Address>,
+        block: EthereumBlockPointer,
+    ) -> DynTryFuture<'a, Option<[u8; 32]>> {
+        let indexer = indexer.clone();
 
-            let mut finisher = ProofOfIndexingFinisher::new(&block2, &site3.deployment, &indexer);
-            for (name, region) in by_causality_region.drain() {
+        async move {
+            let deployment = site.deployment.clone();
+            let regions = match self
+                .get_proof_of_indexing_regions(site, block.clone())
+                .await?
+            {
+                Some(regions) => regions,
+                None => return Ok(None),
+            };
+
+            let mut finisher = ProofOfIndexingFinisher::new(&block, &deployment, &indexer);
+            for (name, region) in regions {
                 finisher.add_causality_region(&name, &region);
             }
 
@@ -868,13 +902,15 @@ impl DeploymentStore {
             // Make the changes
             let layout = self.layout(&conn, site.clone())?;
             let section = stopwatch.start_section("apply_entity_modifications");
-            let count = self.apply_entity_modifications(
-                &conn,
-                layout.as_ref(),
-                mods,
-                &block_ptr_to,
-                stopwatch,
-            )?;
+            let count = section.in_scope(|| {
+                self.apply_entity_modifications(
+                    &conn,
+                    layout.as_ref(),
+                    mods,
+                    &block_ptr_to,
+                    stopwatch,
+                )
+            })?;
             deployment::update_entity_count(
                 &conn,
                 site.as_ref(),
@@ -1090,6 +1126,109 @@ impl DeploymentStore {
         conn.transaction(|| deployment::unfail(&conn, &site.deployment))
     }
 
+    pub(crate) fn backfill_chunk(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Option<(BlockNumber, BlockNumber)>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::backfill_chunk(&conn, id)
+    }
+
+    pub(crate) fn set_backfill_chunk(
+        &self,
+        id: &SubgraphDeploymentId,
+        chunk: Option<(BlockNumber, BlockNumber)>,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        deployment::set_backfill_chunk(&conn, id, chunk)
+    }
+
+    pub(crate) fn skip_blocks(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<BTreeSet<BlockNumber>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::skip_blocks(&conn, id)
+    }
+
+    pub(crate) fn set_skip_blocks(
+        &self,
+        id: &SubgraphDeploymentId,
+        blocks: &BTreeSet<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        deployment::set_skip_blocks(&conn, id, blocks)
+    }
+
+    pub(crate) fn skipped_blocks(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Vec<BlockNumber>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::skipped_blocks(&conn, id)
+    }
+
+    pub(crate) fn record_skipped_block(
+        &self,
+        id: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        deployment::record_skipped_block(&conn, id, block)
+    }
+
+    pub(crate) fn disable_fail_fast(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Option<bool>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::disable_fail_fast(&conn, id)
+    }
+
+    pub(crate) fn set_disable_fail_fast(
+        &self,
+        id: &SubgraphDeploymentId,
+        disable: Option<bool>,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        deployment::set_disable_fail_fast(&conn, id, disable)
+    }
+
+    pub(crate) fn poi_write_interval(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let conn = self.get_conn()?;
+        deployment::poi_write_interval(&conn, id)
+    }
+
+    pub(crate) fn set_poi_write_interval(
+        &self,
+        id: &SubgraphDeploymentId,
+        interval: Option<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        deployment::set_poi_write_interval(&conn, id, interval)
+    }
+
+    pub(crate) fn fatal_error(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Option<SubgraphError>, StoreError> {
+        let conn = self.get_conn()?;
+        detail::fatal_error(&conn, id)
+    }
+
+    pub(crate) fn deterministic_errors(
+        &self,
+        id: &SubgraphDeploymentId,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<SubgraphError>, StoreError> {
+        let conn = self.get_conn()?;
+        detail::deterministic_errors(&conn, id, limit, offset)
+    }
+
     #[cfg(debug_assertions)]
     pub fn error_count(&self, id: &SubgraphDeploymentId) -> Result<usize, StoreError> {
         let conn = self.get_conn()?;
@@ -1,13 +1,15 @@
 //! Queries to support the index node API
 use diesel::pg::PgConnection;
 use diesel::prelude::{
-    ExpressionMethods, JoinOnDsl, NullableExpressionMethods, QueryDsl, RunQueryDsl,
+    ExpressionMethods, JoinOnDsl, NullableExpressionMethods, OptionalExtension, QueryDsl,
+    RunQueryDsl,
 };
 use graph::{
     constraint_violation,
     data::subgraph::schema::SubgraphError,
     prelude::{
-        bigdecimal::ToPrimitive, BigDecimal, EthereumBlockPointer, StoreError, SubgraphDeploymentId,
+        bigdecimal::ToPrimitive, serde_json, BigDecimal, EthereumBlockPointer, StoreError,
+        SubgraphDeploymentId,
     },
 };
 use graph::{data::subgraph::status, prelude::web3::types::H256};
@@ -46,6 +48,12 @@ pub struct DeploymentDetail {
     reorg_count: i32,
     current_reorg_depth: i32,
     max_reorg_depth: i32,
+    backfill_chunk_start: Option<i32>,
+    backfill_chunk_end: Option<i32>,
+    skip_blocks: Vec<i32>,
+    skipped_blocks: Vec<i32>,
+    disable_fail_fast: Option<bool>,
+    poi_write_interval: Option<i32>,
 }
 
 #[derive(Queryable, QueryableByName)]
@@ -62,6 +70,7 @@ struct ErrorDetail {
     handler: Option<String>,
     deterministic: bool,
     block_range: (Bound<i32>, Bound<i32>),
+    details: Option<serde_json::Value>,
 }
 
 struct DetailAndError<'a>(DeploymentDetail, Option<ErrorDetail>, &'a Vec<Arc<Site>>);
@@ -111,6 +120,7 @@ impl TryFrom<ErrorDetail> for SubgraphError {
             handler,
             deterministic,
             block_range,
+            details,
         } = value;
         let block_number = crate::block_range::first_block_in_range(&block_range);
         let block_hash = block_hash.map(|hash| H256::from_slice(hash.as_slice()));
@@ -125,12 +135,22 @@ impl TryFrom<ErrorDetail> for SubgraphError {
         let subgraph_id = SubgraphDeploymentId::new(subgraph_id).map_err(|id| {
             StoreError::ConstraintViolation(format!("invalid subgraph id `{}` in fatal error", id))
         })?;
+        let details = details
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|e| {
+                StoreError::ConstraintViolation(format!(
+                    "invalid details for error in subgraph `{}`: {}",
+                    subgraph_id, e
+                ))
+            })?;
         Ok(SubgraphError {
             subgraph_id,
             message,
             block_ptr,
             handler,
             deterministic,
+            details,
         })
     }
 }
@@ -226,6 +246,46 @@ pub(crate) fn deployment_details(
     Ok(details)
 }
 
+/// Return the error that made the deployment `id` fail, if it failed
+pub(crate) fn fatal_error(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<Option<SubgraphError>, StoreError> {
+    use subgraph_deployment as d;
+    use subgraph_error as e;
+
+    d::table
+        .inner_join(e::table.on(d::fatal_error.eq(e::id.nullable())))
+        .filter(d::deployment.eq(id.as_str()))
+        .select(e::all_columns)
+        .first::<ErrorDetail>(conn)
+        .optional()?
+        .map(SubgraphError::try_from)
+        .transpose()
+}
+
+/// Return the deterministic errors for `id`, in the order in which they were
+/// inserted
+pub(crate) fn deterministic_errors(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    limit: usize,
+    offset: usize,
+) -> Result<Vec<SubgraphError>, StoreError> {
+    use subgraph_error as e;
+
+    e::table
+        .filter(e::subgraph_id.eq(id.as_str()))
+        .filter(e::deterministic)
+        .order_by(e::vid)
+        .limit(limit as i64)
+        .offset(offset as i64)
+        .load::<ErrorDetail>(conn)?
+        .into_iter()
+        .map(SubgraphError::try_from)
+        .collect()
+}
+
 pub(crate) fn deployment_statuses(
     conn: &PgConnection,
     sites: &Vec<Arc<Site>>,
@@ -1001,6 +1001,53 @@ impl<'a> Connection<'a> {
         Ok(())
     }
 
+    /// Add the deployment `id` to `unused_deployments` regardless of
+    /// whether it is still in use. Returns `true` if the deployment was not
+    /// in `unused_deployments` yet. Only values that are available in the
+    /// primary will be filled in `unused_deployments`
+    pub fn record_unused_deployment(&self, id: &SubgraphDeploymentId) -> Result<bool, StoreError> {
+        use deployment_schemas as ds;
+        use subgraph as s;
+        use subgraph_version as v;
+        use unused_deployments as u;
+
+        // Subgraphs that used a deployment
+        let used_by = s::table
+            .inner_join(v::table.on(s::id.eq(v::subgraph)))
+            .filter(v::deployment.eq(ds::subgraph))
+            .select(sql::<Array<Text>>("array_agg(name)"))
+            .single_value();
+
+        let site = ds::table.filter(ds::subgraph.eq(id.as_str())).select((
+            ds::subgraph,
+            ds::name,
+            ds::shard,
+            used_by,
+        ));
+
+        let inserted = insert_into(u::table)
+            .values(site)
+            .into_columns((u::id, u::namespace, u::shard, u::subgraphs))
+            .on_conflict(u::id)
+            .do_nothing()
+            .execute(self.0.as_ref())?;
+        Ok(inserted > 0)
+    }
+
+    /// Remove the deployment `id` from `unused_deployments` unless it has
+    /// already been removed. Returns `true` if there was such an entry
+    pub fn clear_unused_deployment(&self, id: &SubgraphDeploymentId) -> Result<bool, StoreError> {
+        use unused_deployments as u;
+
+        let deleted = delete(
+            u::table
+                .filter(u::id.eq(id.as_str()))
+                .filter(u::removed_at.is_null()),
+        )
+        .execute(self.0.as_ref())?;
+        Ok(deleted > 0)
+    }
+
     pub fn list_unused_deployments(
         &self,
         filter: unused::Filter,
@@ -385,6 +385,34 @@ impl Layout {
         self.tables.contains_key(&*POI_OBJECT)
     }
 
+    /// The number of the last block at which any digest of the proof of
+    /// indexing was written, or `None` if no digest was ever written or the
+    /// layout has no proof of indexing table
+    pub fn last_proof_of_indexing_block(
+        &self,
+        conn: &PgConnection,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        use diesel::sql_types::{Integer, Nullable};
+
+        #[derive(QueryableByName)]
+        struct LastBlock {
+            #[sql_type = "Nullable<Integer>"]
+            block: Option<i32>,
+        }
+
+        let table = match self.tables.get(&*POI_OBJECT) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let query = format!(
+            "select max(lower({})) as block from {}",
+            BLOCK_RANGE_COLUMN, table.qualified_name
+        );
+        Ok(diesel::sql_query(query)
+            .get_result::<LastBlock>(conn)?
+            .block)
+    }
+
     pub fn create_relational_schema(
         conn: &PgConnection,
         site: Arc<Site>,
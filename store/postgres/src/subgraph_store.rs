@@ -6,7 +6,7 @@ use diesel::{
 };
 use std::iter::FromIterator;
 use std::sync::RwLock;
-use std::{collections::BTreeMap, collections::HashMap, sync::Arc};
+use std::{collections::BTreeMap, collections::BTreeSet, collections::HashMap, sync::Arc};
 use std::{fmt, io::Write};
 
 use graph::{
@@ -21,7 +21,7 @@ use graph::{
     prelude::StoreEvent,
     prelude::SubgraphDeploymentEntity,
     prelude::{
-        futures03::future::join_all, lazy_static, o, web3::types::Address, ApiSchema,
+        futures03::future::join_all, lazy_static, o, web3::types::Address, ApiSchema, BlockNumber,
         DeploymentState, DynTryFuture, Entity, EntityKey, EntityModification, EntityQuery, Error,
         EthereumBlockPointer, Logger, NodeId, QueryExecutionError, Schema, StopwatchMetrics,
         StoreError, SubgraphDeploymentId, SubgraphName, SubgraphStore as SubgraphStoreTrait,
@@ -528,6 +528,19 @@ impl SubgraphStore {
         self.primary_conn()?.list_unused_deployments(filter)
     }
 
+    /// Add the deployment `id` to the `unused_deployments` table even if it
+    /// is still in use, so that it will be removed together with other
+    /// unused deployments once it is no longer used
+    pub fn record_unused_deployment(&self, id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        let (store, _) = self.store(id)?;
+
+        if self.primary_conn()?.record_unused_deployment(id)? {
+            let details = store.deployment_details(vec![id.to_string()])?;
+            self.primary_conn()?.update_unused_deployments(&details)?;
+        }
+        Ok(())
+    }
+
     /// Remove a deployment, i.e., all its data and metadata. This is only permissible
     /// if the deployment is unused in the sense that it is neither the current nor
     /// pending version of any subgraph, and is not currently assigned to any node
@@ -692,6 +705,23 @@ impl SubgraphStoreTrait for SubgraphStore {
         store.clone().get_proof_of_indexing(site, indexer, block)
     }
 
+    fn get_proof_of_indexing_regions<'a>(
+        self: Arc<Self>,
+        id: &'a SubgraphDeploymentId,
+        block: EthereumBlockPointer,
+    ) -> DynTryFuture<'a, Option<BTreeMap<String, Vec<u8>>>> {
+        let (store, site) = self.store(&id).unwrap();
+        store.clone().get_proof_of_indexing_regions(site, block)
+    }
+
+    fn last_proof_of_indexing_block(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let (store, site) = self.store(id)?;
+        store.last_proof_of_indexing_block(site)
+    }
+
     fn get(&self, key: EntityKey) -> Result<Option<Entity>, QueryExecutionError> {
         let (store, site) = self.store(&key.subgraph_id)?;
         store.get(site, key)
@@ -749,10 +779,11 @@ impl SubgraphStoreTrait for SubgraphStore {
         &self,
         id: SubgraphDeploymentId,
         block_ptr_to: EthereumBlockPointer,
-    ) -> Result<(), StoreError> {
+    ) -> Result<BTreeSet<EntityType>, StoreError> {
         let (store, site) = self.store(&id)?;
         let event = store.revert_block_operations(site, block_ptr_to)?;
-        self.send_store_event(&event)
+        self.send_store_event(&event)?;
+        Ok(event.entity_types())
     }
 
     async fn deployment_state_from_name(
@@ -795,6 +826,107 @@ impl SubgraphStoreTrait for SubgraphStore {
         store.unfail(site)
     }
 
+    fn rewind(
+        &self,
+        id: SubgraphDeploymentId,
+        block_ptr_to: EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        SubgraphStore::rewind(self, id, block_ptr_to)
+    }
+
+    fn fatal_error(&self, id: &SubgraphDeploymentId) -> Result<Option<SubgraphError>, StoreError> {
+        let (store, _) = self.store(id)?;
+        store.fatal_error(id)
+    }
+
+    fn backfill_chunk(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Option<(BlockNumber, BlockNumber)>, StoreError> {
+        let (store, _) = self.store(id)?;
+        store.backfill_chunk(id)
+    }
+
+    fn can_copy_from(
+        &self,
+        id: &SubgraphDeploymentId,
+        base: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, StoreError> {
+        let (store, site) = self.store(id)?;
+        let (base_store, base_site) = self.store(base)?;
+        let layout = store.find_layout(site)?;
+        let base = base_store.find_layout(base_site)?;
+        Ok(layout.can_copy_from(&base))
+    }
+
+    fn set_backfill_chunk(
+        &self,
+        id: &SubgraphDeploymentId,
+        chunk: Option<(BlockNumber, BlockNumber)>,
+    ) -> Result<(), StoreError> {
+        let (store, _) = self.store(id)?;
+        store.set_backfill_chunk(id, chunk)
+    }
+
+    fn skip_blocks(&self, id: &SubgraphDeploymentId) -> Result<BTreeSet<BlockNumber>, StoreError> {
+        let (store, _) = self.store(id)?;
+        store.skip_blocks(id)
+    }
+
+    fn set_skip_blocks(
+        &self,
+        id: &SubgraphDeploymentId,
+        blocks: &BTreeSet<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        let (store, _) = self.store(id)?;
+        store.set_skip_blocks(id, blocks)
+    }
+
+    fn skipped_blocks(&self, id: &SubgraphDeploymentId) -> Result<Vec<BlockNumber>, StoreError> {
+        let (store, _) = self.store(id)?;
+        store.skipped_blocks(id)
+    }
+
+    fn record_skipped_block(
+        &self,
+        id: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<(), StoreError> {
+        let (store, _) = self.store(id)?;
+        store.record_skipped_block(id, block)
+    }
+
+    fn disable_fail_fast(&self, id: &SubgraphDeploymentId) -> Result<Option<bool>, StoreError> {
+        let (store, _) = self.store(id)?;
+        store.disable_fail_fast(id)
+    }
+
+    fn set_disable_fail_fast(
+        &self,
+        id: &SubgraphDeploymentId,
+        disable: Option<bool>,
+    ) -> Result<(), StoreError> {
+        let (store, _) = self.store(id)?;
+        store.set_disable_fail_fast(id, disable)
+    }
+
+    fn poi_write_interval(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let (store, _) = self.store(id)?;
+        store.poi_write_interval(id)
+    }
+
+    fn set_poi_write_interval(
+        &self,
+        id: &SubgraphDeploymentId,
+        interval: Option<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        let (store, _) = self.store(id)?;
+        store.set_poi_write_interval(id, interval)
+    }
+
     fn is_deployment_synced(&self, id: &SubgraphDeploymentId) -> Result<bool, Error> {
         let (store, _) = self.store(&id)?;
         Ok(store.exists_and_synced(&id)?)
@@ -920,6 +1052,27 @@ impl SubgraphStoreTrait for SubgraphStore {
         let (_, site) = self.store(&id)?;
         Ok(site.network.to_string())
     }
+
+    fn deterministic_errors(
+        &self,
+        id: &SubgraphDeploymentId,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<SubgraphError>, StoreError> {
+        let (store, _) = self.store(id)?;
+        store.deterministic_errors(id, limit, offset)
+    }
+
+    fn tombstone(&self, id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        // Deployments can only be removed once they are not assigned, and
+        // they must not start again when their node restarts
+        self.unassign_subgraph(id)?;
+        self.record_unused_deployment(id)
+    }
+
+    fn clear_tombstone(&self, id: &SubgraphDeploymentId) -> Result<bool, StoreError> {
+        self.primary_conn()?.clear_unused_deployment(id)
+    }
 }
 
 fn same_subgraph(mods: &Vec<EntityModification>, id: &SubgraphDeploymentId) -> bool {
@@ -126,6 +126,7 @@ fn insert_test_data(store: Arc<DieselSubgraphStore>) {
         data_sources: vec![],
         graft: None,
         templates: vec![],
+        block_predicate: None,
     };
 
     // Create SubgraphDeploymentEntity
@@ -156,6 +156,7 @@ fn insert_test_data(store: Arc<DieselSubgraphStore>) {
         data_sources: vec![],
         graft: None,
         templates: vec![],
+        block_predicate: None,
     };
 
     // Create SubgraphDeploymentEntity
@@ -1329,6 +1330,7 @@ fn entity_changes_are_fired_and_forwarded_to_subscriptions() {
             data_sources: vec![],
             graft: None,
             templates: vec![],
+            block_predicate: None,
         };
 
         // Create SubgraphDeploymentEntity
@@ -16,7 +16,7 @@ use graph::{
 use graph_store_postgres::layout_for_tests::Connection as Primary;
 use graph_store_postgres::SubgraphStore;
 
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use test_store::*;
 
 const SUBGRAPH_GQL: &str = "
@@ -123,6 +123,7 @@ fn create_subgraph() {
             data_sources: vec![],
             graft: None,
             templates: vec![],
+            block_predicate: None,
         };
         let deployment = SubgraphDeploymentEntity::new(&manifest, false, None);
         let node_id = NodeId::new("left").unwrap();
@@ -378,6 +379,7 @@ fn status() {
             block_ptr: Some(GENESIS_PTR.clone()),
             handler: None,
             deterministic: true,
+            details: None,
         };
 
         store
@@ -463,6 +465,7 @@ fn subgraph_error() {
                 block_ptr: None,
                 handler: None,
                 deterministic: false,
+                details: None,
             };
 
             assert!(count() == 0);
@@ -476,6 +479,7 @@ fn subgraph_error() {
                 block_ptr: None,
                 handler: None,
                 deterministic: false,
+                details: None,
             };
 
             // Inserting the same error is allowed but ignored.
@@ -488,6 +492,7 @@ fn subgraph_error() {
                 block_ptr: None,
                 handler: None,
                 deterministic: false,
+                details: None,
             };
 
             transact_errors(&store, subgraph_id.clone(), BLOCKS[3].clone(), vec![error2]).unwrap();
@@ -519,6 +524,7 @@ fn fatal_vs_non_fatal() {
             block_ptr: Some(BLOCKS[1].clone()),
             handler: None,
             deterministic: true,
+            details: None,
         };
 
         store
@@ -562,6 +568,7 @@ fn fail_unfail() {
             block_ptr: Some(BLOCKS[1].clone()),
             handler: None,
             deterministic: true,
+            details: None,
         };
 
         store
@@ -596,3 +603,125 @@ fn fail_unfail() {
         test_store::remove_subgraphs();
     })
 }
+
+#[test]
+fn backfill_chunk() {
+    fn setup() -> SubgraphDeploymentId {
+        let id = SubgraphDeploymentId::new("backfillChunk").unwrap();
+        remove_subgraphs();
+        create_test_subgraph(&id, SUBGRAPH_GQL);
+        id
+    }
+
+    run_test_sequentially(setup, |store, id| async move {
+        let store = store.subgraph_store();
+
+        assert_eq!(None, store.backfill_chunk(&id).unwrap());
+
+        store.set_backfill_chunk(&id, Some((100, 199))).unwrap();
+        assert_eq!(Some((100, 199)), store.backfill_chunk(&id).unwrap());
+
+        store.set_backfill_chunk(&id, None).unwrap();
+        assert_eq!(None, store.backfill_chunk(&id).unwrap());
+    })
+}
+
+#[test]
+fn tombstone_unassigns() {
+    fn setup() -> SubgraphDeploymentId {
+        let id = SubgraphDeploymentId::new("tombstoneUnassigns").unwrap();
+        remove_subgraphs();
+        create_test_subgraph(&id, SUBGRAPH_GQL);
+        id
+    }
+
+    run_test_sequentially(setup, |store, id| async move {
+        let store = store.subgraph_store();
+
+        // A tombstoned deployment must be unassigned so that it can be
+        // removed
+        let expected = vec![StoreEvent::new(vec![unassigned(&id)])];
+        let events = tap_store_events(|| store.tombstone(&id).unwrap());
+        assert_eq!(expected, events);
+        assert_eq!(None, store.assigned_node(&id).unwrap());
+
+        assert!(store.clear_tombstone(&id).unwrap());
+        assert!(!store.clear_tombstone(&id).unwrap());
+    })
+}
+
+#[test]
+fn skip_blocks() {
+    fn setup() -> SubgraphDeploymentId {
+        let id = SubgraphDeploymentId::new("skipBlocks").unwrap();
+        remove_subgraphs();
+        create_test_subgraph(&id, SUBGRAPH_GQL);
+        id
+    }
+
+    run_test_sequentially(setup, |store, id| async move {
+        let store = store.subgraph_store();
+
+        assert!(store.skip_blocks(&id).unwrap().is_empty());
+        assert!(store.skipped_blocks(&id).unwrap().is_empty());
+
+        let blocks: BTreeSet<_> = vec![7, 3].into_iter().collect();
+        store.set_skip_blocks(&id, &blocks).unwrap();
+        assert_eq!(blocks, store.skip_blocks(&id).unwrap());
+
+        // Skipped blocks are recorded once and stay recorded when the
+        // blocks to skip change
+        store.record_skipped_block(&id, 7).unwrap();
+        store.record_skipped_block(&id, 3).unwrap();
+        store.record_skipped_block(&id, 7).unwrap();
+        store.set_skip_blocks(&id, &BTreeSet::new()).unwrap();
+        assert!(store.skip_blocks(&id).unwrap().is_empty());
+        assert_eq!(vec![3, 7], store.skipped_blocks(&id).unwrap());
+    })
+}
+
+#[test]
+fn disable_fail_fast() {
+    fn setup() -> SubgraphDeploymentId {
+        let id = SubgraphDeploymentId::new("disableFailFast").unwrap();
+        remove_subgraphs();
+        create_test_subgraph(&id, SUBGRAPH_GQL);
+        id
+    }
+
+    run_test_sequentially(setup, |store, id| async move {
+        let store = store.subgraph_store();
+
+        assert_eq!(None, store.disable_fail_fast(&id).unwrap());
+
+        store.set_disable_fail_fast(&id, Some(true)).unwrap();
+        assert_eq!(Some(true), store.disable_fail_fast(&id).unwrap());
+        store.set_disable_fail_fast(&id, Some(false)).unwrap();
+        assert_eq!(Some(false), store.disable_fail_fast(&id).unwrap());
+
+        store.set_disable_fail_fast(&id, None).unwrap();
+        assert_eq!(None, store.disable_fail_fast(&id).unwrap());
+    })
+}
+
+#[test]
+fn poi_write_interval() {
+    fn setup() -> SubgraphDeploymentId {
+        let id = SubgraphDeploymentId::new("poiWriteInterval").unwrap();
+        remove_subgraphs();
+        create_test_subgraph(&id, SUBGRAPH_GQL);
+        id
+    }
+
+    run_test_sequentially(setup, |store, id| async move {
+        let store = store.subgraph_store();
+
+        assert_eq!(None, store.poi_write_interval(&id).unwrap());
+
+        store.set_poi_write_interval(&id, Some(10)).unwrap();
+        assert_eq!(Some(10), store.poi_write_interval(&id).unwrap());
+
+        store.set_poi_write_interval(&id, None).unwrap();
+        assert_eq!(None, store.poi_write_interval(&id).unwrap());
+    })
+}
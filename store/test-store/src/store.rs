@@ -160,6 +160,7 @@ fn create_subgraph(
         data_sources: vec![],
         graft: None,
         templates: vec![],
+        block_predicate: None,
     };
 
     let deployment = SubgraphDeploymentEntity::new(&manifest, false, None).graft(base);